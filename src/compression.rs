@@ -0,0 +1,101 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::fs_model::{FsError, FsResult};
+
+/// Level used for [`Compression::Zstd`], the default one of zstd, a good balance between speed and
+/// ratio.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The compression algorithms we support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Zstd => write!(f, "zstd"),
+            Self::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(format!("unknown compression {s}")),
+        }
+    }
+}
+
+/// How a block is stored. It's kept with each block, so files stay readable after the algorithm is
+/// changed for the mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Method {
+    /// As it is, it didn't compress well enough.
+    Stored,
+    Zstd,
+    Lz4,
+}
+
+impl Method {
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            Self::Stored => 0,
+            Self::Zstd => 1,
+            Self::Lz4 => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> FsResult<Self> {
+        match value {
+            0 => Ok(Self::Stored),
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Lz4),
+            _ => Err(FsError::Compression("unknown compression method")),
+        }
+    }
+}
+
+/// Compress `data`. If it doesn't save at least 1/8 of the size it's kept as it is, so we don't
+/// pay for decompression on data like media or archives which are already compressed.
+pub(crate) fn compress(compression: Compression, data: &[u8]) -> FsResult<(Method, Vec<u8>)> {
+    let (method, compressed) = match compression {
+        Compression::Zstd => (
+            Method::Zstd,
+            zstd::bulk::compress(data, ZSTD_LEVEL)
+                .map_err(|_| FsError::Compression("compress failed"))?,
+        ),
+        Compression::Lz4 => (Method::Lz4, lz4_flex::block::compress(data)),
+    };
+    if compressed.len() > data.len() - data.len() / 8 {
+        return Ok((Method::Stored, data.to_vec()));
+    }
+    Ok((method, compressed))
+}
+
+/// Decompress a block, `plain_len` is the size it had before compression.
+pub(crate) fn decompress(method: Method, data: &[u8], plain_len: usize) -> FsResult<Vec<u8>> {
+    let plain = match method {
+        Method::Stored => Ok(data.to_vec()),
+        Method::Zstd => zstd::bulk::decompress(data, plain_len).map_err(|_| ()),
+        Method::Lz4 => lz4_flex::block::decompress(data, plain_len).map_err(|_| ()),
+    };
+    match plain {
+        Ok(plain) if plain.len() == plain_len => Ok(plain),
+        _ => {
+            error!(?method, "decompress failed");
+            Err(FsError::Compression("decompress failed"))
+        }
+    }
+}
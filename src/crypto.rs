@@ -0,0 +1,212 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use aes_gcm::Aes256Gcm;
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::thread_rng;
+use rand_core::RngCore;
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::fs_model::{FsError, FsResult};
+
+pub(crate) const KEY_LEN: usize = 32;
+pub(crate) const NONCE_LEN: usize = 12;
+pub(crate) const TAG_LEN: usize = 16;
+pub(crate) const SALT_LEN: usize = 16;
+const NAME_AAD: &[u8] = b"name";
+
+/// The encryption algorithms we support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl Cipher {
+    /// How many bytes encryption adds to the plaintext, nonce and tag.
+    #[must_use]
+    pub const fn overhead(self) -> usize {
+        NONCE_LEN + TAG_LEN
+    }
+}
+
+impl Display for Cipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ChaCha20Poly1305 => write!(f, "ChaCha20Poly1305"),
+            Self::Aes256Gcm => write!(f, "Aes256Gcm"),
+        }
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "chacha20poly1305" => Ok(Self::ChaCha20Poly1305),
+            "aes256gcm" => Ok(Self::Aes256Gcm),
+            _ => Err(format!("unknown cipher {s}")),
+        }
+    }
+}
+
+/// Provides the password used to derive the encryption key.
+pub trait PasswordProvider: Send + Sync + 'static {
+    fn get_password(&self) -> Option<SecretString>;
+}
+
+enum AeadImpl {
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+/// Encrypts and decrypts with an AEAD [`Cipher`]. The nonce is stored in front of the ciphertext.
+pub(crate) struct Encryptor {
+    aead: AeadImpl,
+}
+
+impl Encryptor {
+    pub fn new(cipher: Cipher, key: &[u8]) -> FsResult<Self> {
+        let aead = match cipher {
+            Cipher::ChaCha20Poly1305 => AeadImpl::ChaCha20Poly1305(Box::new(
+                ChaCha20Poly1305::new_from_slice(key)
+                    .map_err(|_| FsError::Encryption("invalid key length"))?,
+            )),
+            Cipher::Aes256Gcm => AeadImpl::Aes256Gcm(Box::new(
+                Aes256Gcm::new_from_slice(key)
+                    .map_err(|_| FsError::Encryption("invalid key length"))?,
+            )),
+        };
+        Ok(Self { aead })
+    }
+
+    /// Encrypt with a random nonce. `aad` is authenticated but not encrypted, the same must be
+    /// given on decrypt.
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> FsResult<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        self.encrypt_with_nonce(&nonce, plaintext, aad)
+    }
+
+    pub fn encrypt_with_nonce(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> FsResult<Vec<u8>> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = match &self.aead {
+            AeadImpl::ChaCha20Poly1305(aead) => aead.encrypt(nonce.into(), payload),
+            AeadImpl::Aes256Gcm(aead) => aead.encrypt(nonce.into(), payload),
+        }
+        .map_err(|_| FsError::Encryption("encrypt failed"))?;
+        let mut data = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        data.extend_from_slice(nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    pub fn decrypt(&self, data: &[u8], aad: &[u8]) -> FsResult<Vec<u8>> {
+        if data.len() < NONCE_LEN + TAG_LEN {
+            return Err(FsError::Encryption("ciphertext too short"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        match &self.aead {
+            AeadImpl::ChaCha20Poly1305(aead) => aead.decrypt(nonce.into(), payload),
+            AeadImpl::Aes256Gcm(aead) => aead.decrypt(nonce.into(), payload),
+        }
+        .map_err(|_| {
+            error!("decrypt failed");
+            FsError::Encryption("decrypt failed")
+        })
+    }
+}
+
+/// Encrypts names of directory entries. It's deterministic, the same name in the same directory
+/// always gives the same encrypted name, so we can still find entries by name. The nonce is a
+/// keyed hash of the name and of a `tweak`, like in SIV modes, so only equal names share a nonce.
+/// The tweak is authenticated too, it's the id of the directory, so equal names in different
+/// directories don't look the same and entries cannot be moved to another directory.
+///
+/// The result is encoded with URL safe base64, which doesn't contain `/`.
+pub(crate) struct NameEncryptor {
+    encryptor: Encryptor,
+    nonce_key: [u8; KEY_LEN],
+}
+
+impl NameEncryptor {
+    /// `key` is the master key, separate keys are derived from it for names.
+    pub fn new(cipher: Cipher, key: &[u8]) -> FsResult<Self> {
+        let key = blake3::derive_key("fuse3-template 2024 name encryption key", key);
+        let nonce_key = blake3::derive_key("fuse3-template 2024 name nonce key", &key);
+        Ok(Self {
+            encryptor: Encryptor::new(cipher, &key)?,
+            nonce_key,
+        })
+    }
+
+    pub fn encrypt(&self, tweak: &[u8], name: &str) -> FsResult<String> {
+        let hash = blake3::Hasher::new_keyed(&self.nonce_key)
+            .update(&name_aad(tweak))
+            .update(name.as_bytes())
+            .finalize();
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&hash.as_bytes()[..NONCE_LEN]);
+        let data = self
+            .encryptor
+            .encrypt_with_nonce(&nonce, name.as_bytes(), &name_aad(tweak))?;
+        Ok(URL_SAFE_NO_PAD.encode(data))
+    }
+
+    pub fn decrypt(&self, tweak: &[u8], name: &str) -> FsResult<String> {
+        let data = URL_SAFE_NO_PAD
+            .decode(name)
+            .map_err(|_| FsError::Encryption("invalid encrypted name"))?;
+        String::from_utf8(self.encryptor.decrypt(&data, &name_aad(tweak))?)
+            .map_err(|_| FsError::Encryption("invalid encrypted name"))
+    }
+}
+
+/// [`NAME_AAD`] and the tweak, prefixed by its length so they cannot be confused.
+fn name_aad(tweak: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(NAME_AAD.len() + 8 + tweak.len());
+    aad.extend_from_slice(NAME_AAD);
+    aad.extend_from_slice(&(tweak.len() as u64).to_le_bytes());
+    aad.extend_from_slice(tweak);
+    aad
+}
+
+/// The longest name which, once encrypted, still fits in `max_len`.
+pub(crate) const fn max_plain_name_len(max_len: usize) -> usize {
+    // base64 encodes every 3 bytes in 4 chars
+    (max_len * 3 / 4).saturating_sub(NONCE_LEN + TAG_LEN)
+}
+
+/// Derive a key from the password with `Argon2id`.
+pub(crate) fn derive_key(password: &SecretString, salt: &[u8]) -> FsResult<SecretVec<u8>> {
+    let mut key = vec![0; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(password.expose_secret().as_bytes(), salt, &mut key)
+        .map_err(|_| FsError::Encryption("cannot derive key"))?;
+    Ok(SecretVec::new(key))
+}
+
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut buf = [0; N];
+    thread_rng().fill_bytes(&mut buf);
+    buf
+}
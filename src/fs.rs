@@ -1,36 +1,41 @@
 use std::cmp::{max, min};
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::fmt::Debug;
-use std::fs::{DirEntry, File, OpenOptions, ReadDir};
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::num::{NonZeroUsize, ParseIntError};
-use std::path::{Path, PathBuf};
-use std::str::FromStr;
-use std::sync::atomic::AtomicU64;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
 use std::sync::{Arc, Weak};
-use std::time::{Duration, SystemTime};
-use std::{fs, io};
 
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
-use num_format::{Locale, ToFormattedString};
-use rand::thread_rng;
-use rand_core::RngCore;
-use serde::{Deserialize, Serialize};
-use thiserror::Error;
-use tokio::runtime::Runtime;
-use tokio::sync::{Mutex, RwLock};
-use tokio::task::{JoinError, JoinSet};
-use tokio_stream::wrappers::ReadDirStream;
-use tracing::{debug, error, instrument, warn};
+use futures_util::stream::BoxStream;
+use futures_util::{stream, StreamExt};
 
 use crate::fs_model::{
-    CreateFileAttr, DirectoryEntry, DirectoryEntryIterator, DirectoryEntryPlus,
-    DirectoryEntryPlusIterator, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    CreateFileAttr, DirectoryEntryPlusStream, DirectoryEntryStream, FallocateMode, FileAttr,
+    FsError, FsResult, SetFileAttr, SetXattrMode, StatFs, Whence,
 };
-use crate::stream_util;
+
+pub(crate) mod archive;
+pub(crate) mod capacity;
+pub(crate) mod compressed;
+pub(crate) mod dedup;
+pub(crate) mod directory;
+pub(crate) mod encrypted;
+pub(crate) mod memory;
+pub(crate) mod overlay;
+pub(crate) mod passthrough;
+pub(crate) mod persistent;
+pub(crate) mod snapshot;
+pub(crate) mod tar;
+#[cfg(test)]
+pub(crate) mod testing;
+pub(crate) mod zip;
 
 #[async_trait]
+#[allow(dead_code)]
 pub(crate) trait Filesystem: Send + Sync {
     fn exists(&self, ino: u64) -> bool;
 
@@ -48,23 +53,49 @@ pub(crate) trait Filesystem: Send + Sync {
         write: bool,
     ) -> FsResult<(u64, FileAttr)>;
 
+    /// Create a symbolic link to `target`. The size of the link is the length of the target.
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr>;
+
+    /// Read the target of a symbolic link.
+    async fn read_link(&self, ino: u64) -> FsResult<String>;
+
+    /// Add a new name for `ino`, a hard link. Directories cannot be linked, that fails with
+    /// [`FsError::DirectoryLink`].
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr>;
+
     async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>>;
 
     /// Count children of a directory. This **EXCLUDES** "." and "..".
-    fn len(&self, ino: u64) -> FsResult<usize>;
+    async fn len(&self, ino: u64) -> FsResult<usize>;
 
     /// Delete a directory
     async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()>;
 
-    /// Delete a file
+    /// Delete a name of a file. The file is freed after the last name is removed and the last
+    /// handle is released.
     async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()>;
 
-    fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool>;
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool>;
 
-    async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator>;
+    /// List the entries of a directory. This **INCLUDES** "." and "..". The entries are read as
+    /// the stream is polled, each one has a cookie and listing again from it goes on with the
+    /// entries after it, 0 starts from the beginning. Cookies stay valid when entries are added or
+    /// removed, the entries which are there all along are listed once. Cookies must stay below
+    /// `i64::MAX - 1`, the offset the mount lists `/.snapshots` at after the entries of the root.
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream>;
 
-    /// Like [`crate::fs::FilesystemImpl::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
-    async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator>;
+    /// Like [`Filesystem::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream>;
 
     /// Get metadata
     async fn get_attr(&self, ino: u64) -> FsResult<FileAttr>;
@@ -93,6 +124,7 @@ pub(crate) trait Filesystem: Send + Sync {
     async fn flush(&self, handle: u64) -> FsResult<()>;
 
     /// Helpful when we want to copy just some portions of the file.
+    #[allow(clippy::too_many_arguments)]
     async fn copy_file_range(
         &self,
         src_ino: u64,
@@ -110,6 +142,17 @@ pub(crate) trait Filesystem: Send + Sync {
     /// Truncates or extends the underlying file, updating the size of this file to become size.
     async fn set_len(&self, ino: u64, size: u64) -> FsResult<()>;
 
+    /// Preallocate, zero or punch a hole in `len` bytes from `offset`, see [`FallocateMode`].
+    /// If the file is not opened for writing, it will return an error of type ['FsError::InvalidFileHandle'].
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()>;
+
     async fn rename(
         &self,
         parent: u64,
@@ -117,492 +160,490 @@ pub(crate) trait Filesystem: Send + Sync {
         new_parent: u64,
         new_name: &str,
     ) -> FsResult<()>;
-}
 
-pub(crate) const ROOT_INODE: u64 = 1;
+    /// Get the value of an extended attribute, [`FsError::XattrNotFound`] if it's not set.
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>>;
 
-static mut FILENAME: Option<String> = None;
-
-static mut CONTENT: Option<Cursor<Vec<u8>>> = None;
-
-static mut FILE: Option<FileAttr> = None;
+    /// Set an extended attribute, `mode` tells if it should exist already or not.
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()>;
 
-static mut ROOT: Option<FileAttr> = None;
+    /// Names of all the extended attributes of a node, with their namespace.
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>>;
 
-/// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
-pub(crate) struct FilesystemImpl {
-    direct_io: bool,
-    suid_support: bool,
-}
+    /// Remove an extended attribute, [`FsError::XattrNotFound`] if it's not set.
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()>;
 
-impl FilesystemImpl {
-    pub async fn new(direct_io: bool, suid_support: bool) -> FsResult<Arc<Self>> {
-        let fs = Self {
-            direct_io,
-            suid_support,
-        };
-        fs.ensure_root_exists().await?;
-        let arc = Arc::new(fs);
-        Ok(arc)
-    }
-
-    async fn ensure_root_exists(&self) -> FsResult<()> {
-        unsafe {
-            FILENAME = Some(String::from_str("hello").unwrap());
-            CONTENT = Some(Cursor::new(b"hello world".to_vec()));
-            ROOT = Some(FileAttr {
-                ino: 1,
-                size: 0,
-                blocks: 0,
-                atime: SystemTime::now(),
-                mtime: SystemTime::now(),
-                ctime: SystemTime::now(),
-                crtime: SystemTime::now(),
-                kind: FileType::Directory,
-                perm: 0x755,
-                nlink: 1,
-                uid: libc::getuid(),
-                gid: libc::getgid(),
-                rdev: 0,
-                blksize: 0,
-                flags: 0,
-            });
-            FILE = Some(FileAttr {
-                ino: 42,
-                size: 0,
-                blocks: 1,
-                atime: SystemTime::now(),
-                mtime: SystemTime::now(),
-                ctime: SystemTime::now(),
-                crtime: SystemTime::now(),
-                kind: FileType::RegularFile,
-                perm: 0o644,
-                nlink: 1,
-                uid: libc::getuid(),
-                gid: libc::getgid(),
-                rdev: 0,
-                blksize: 0,
-                flags: 0,
-            });
+    /// Where the data or the hole at or after `offset` starts, `None` if `offset` is at or after
+    /// the end of the file or there is no more data. The default is for filesystems without
+    /// holes, where it's all data.
+    async fn seek(&self, ino: u64, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+        let size = self.get_attr(ino).await?.size;
+        if offset >= size {
+            return Ok(None);
         }
-        Ok(())
+        Ok(Some(match whence {
+            Whence::Data => offset,
+            Whence::Hole => size,
+        }))
     }
-}
 
-#[async_trait]
-impl Filesystem for FilesystemImpl {
-    fn exists(&self, ino: u64) -> bool {
-        ino == ROOT_INODE || ino == 42
-    }
+    /// Total and free blocks and inodes.
+    async fn statfs(&self) -> FsResult<StatFs>;
 
-    fn is_dir(&self, ino: u64) -> bool {
-        ino == ROOT_INODE
+    /// The kernel got its first reference to `ino`, from a lookup or a new entry. It's not called
+    /// again for `ino` until [`Filesystem::forgotten`], the node is kept until then, even after
+    /// its last link is removed. It returns the generation of the node, for filesystems which
+    /// reuse inode numbers it must be different each time a number is reused.
+    fn referenced(&self, _ino: u64) -> u64 {
+        0
     }
 
-    fn is_file(&self, ino: u64) -> bool {
-        ino == 42
-    }
+    /// The kernel dropped its last reference to `ino`, the node can be freed if it has no more
+    /// links or opened handles.
+    async fn forgotten(&self, _ino: u64) {}
 
-    async fn create(
-        &self,
-        parent: u64,
-        name: &str,
-        create_attr: CreateFileAttr,
-        read: bool,
-        write: bool,
-    ) -> FsResult<(u64, FileAttr)> {
-        if name == "." || name == ".." {
-            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
-        }
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if self.exists_by_name(parent, name)? {
-            return Err(FsError::AlreadyExists);
-        }
-        Err(FsError::Other("not implemented"))
-    }
+    /// The kernel opened the directory `ino` to list it. The cookies given while it's opened must
+    /// stay valid until [`Filesystem::released_dir`] is called as many times.
+    fn opened_dir(&self, _ino: u64) {}
 
-    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-        return if name == "hello" {
-            Ok(Some(file()))
-        } else {
-            Ok(None)
-        };
-    }
+    /// The kernel closed a directory it opened with [`Filesystem::opened_dir`].
+    fn released_dir(&self, _ino: u64) {}
 
-    fn len(&self, ino: u64) -> FsResult<usize> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        return if ino == ROOT_INODE {
-            Ok(2)
-        } else {
-            Err(FsError::InodeNotFound)
-        };
+    /// The longest name, in bytes, a directory entry can have.
+    fn max_name_len(&self) -> usize {
+        MAX_NAME_LENGTH
     }
+}
 
-    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
-        }
+pub(crate) const ROOT_INODE: u64 = 1;
 
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::Directory) {
-            return Err(FsError::InvalidInodeType);
-        }
-        // check if it's empty
-        if self.len(attr.ino)? > 0 {
-            return Err(FsError::NotEmpty);
-        }
+/// Same as `NAME_MAX` on most filesystems.
+pub(crate) const MAX_NAME_LENGTH: usize = 255;
 
-        Ok(())
-    }
+/// How many zeros are written at once when `fallocate` is done with writes.
+const ZEROS_LEN: u64 = 128 * 1024;
 
-    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
-        }
+/// How much `copy_file_range` copies at once when it's done with reads and writes, the kernel can
+/// ask for any size.
+pub(crate) const COPY_CHUNK_LEN: u64 = 1024 * 1024;
 
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::RegularFile) {
-            return Err(FsError::InvalidInodeType);
-        }
+/// Entries [`read_dir_in_batches`] reads at first, a `readdir` from the kernel only takes a page of
+/// them. Each next batch is twice as big, up to [`MAX_READ_DIR_BATCH`], for longer listings.
+const READ_DIR_BATCH: usize = 32;
+const MAX_READ_DIR_BATCH: usize = 1024;
 
-        Ok(())
+pub(crate) fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr) {
+    if let Some(size) = set_attr.size {
+        attr.size = size;
     }
-
-    fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-        unsafe {
-            return if name == FILENAME.as_ref().unwrap() {
-                Ok(true)
-            } else {
-                Ok(false)
-            };
-        }
+    if let Some(atime) = set_attr.atime {
+        attr.atime = max(atime, attr.atime);
     }
-
-    async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        let mut vec = VecDeque::new();
-        unsafe {
-            vec.push_back(Ok(DirectoryEntry {
-                ino: 42,
-                name: FILENAME.as_ref().unwrap().to_owned(),
-                kind: FileType::RegularFile,
-            }));
-        }
-        Ok(DirectoryEntryIterator(vec))
+    if let Some(mtime) = set_attr.mtime {
+        attr.mtime = max(mtime, attr.mtime);
     }
-
-    async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        let mut vec = VecDeque::new();
-        unsafe {
-            vec.push_back(Ok(DirectoryEntryPlus {
-                ino: 42,
-                name: FILENAME.as_ref().unwrap().to_owned(),
-                kind: FileType::RegularFile,
-                attr: file(),
-            }));
-        }
-        Ok(DirectoryEntryPlusIterator(vec))
+    if let Some(ctime) = set_attr.ctime {
+        attr.ctime = max(ctime, attr.ctime);
     }
-
-    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
-        if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
-        }
-        if ino == ROOT_INODE {
-            unsafe { Ok(*ROOT.as_ref().unwrap()) }
-        } else {
-            Ok(file())
-        }
+    if let Some(crtime) = set_attr.crtime {
+        attr.crtime = max(crtime, attr.crtime);
     }
-
-    async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
-        if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
-        }
-        unsafe {
-            if self.is_file(ino)
-                && set_attr.size.is_some()
-                && *set_attr.size.as_ref().unwrap()
-                    != CONTENT.as_ref().unwrap().get_ref().len() as u64
-            {
-                self.set_len(ino, *set_attr.size.as_ref().unwrap()).await?;
-            }
-            merge_attr(FILE.as_mut().unwrap(), &set_attr);
-        }
-        Ok(())
+    if let Some(perm) = set_attr.perm {
+        attr.perm = perm;
     }
-
-    #[instrument(skip(self, buf))]
-    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
-        if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_file(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        let attr = file();
-        if offset > attr.size {
-            return Ok(0);
-        }
-        let len = min(attr.size - offset, buf.len() as u64) as usize;
-        unsafe {
-            let content = CONTENT.as_mut().unwrap();
-            content.seek(SeekFrom::Start(offset))?;
-            content.read_exact(&mut buf[..len])?;
-        }
-        Ok(len)
+    if let Some(uid) = set_attr.uid {
+        attr.uid = uid;
     }
-
-    async fn release(&self, handle: u64) -> FsResult<()> {
-        Ok(())
+    if let Some(gid) = set_attr.gid {
+        attr.gid = gid;
     }
-
-    async fn is_read_handle(&self, fh: u64) -> bool {
-        true
+    if let Some(rdev) = set_attr.rdev {
+        attr.rdev = rdev;
     }
-
-    async fn is_write_handle(&self, fh: u64) -> bool {
-        true
+    if let Some(flags) = set_attr.flags {
+        attr.flags = flags;
     }
+}
 
-    #[instrument(skip(self, buf))]
-    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
-        if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_file(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        if buf.is_empty() {
-            // no-op
-            return Ok(0);
+/// Extended attributes by name, for filesystems which keep them together with the node.
+pub(crate) type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Set an extended attribute in `xattrs`, respecting `mode`.
+pub(crate) fn set_xattr_in(
+    xattrs: &mut Xattrs,
+    name: &str,
+    value: &[u8],
+    mode: SetXattrMode,
+) -> FsResult<()> {
+    match (mode, xattrs.contains_key(name)) {
+        (SetXattrMode::Create, true) => Err(FsError::AlreadyExists),
+        (SetXattrMode::Replace, false) => Err(FsError::XattrNotFound),
+        _ => {
+            xattrs.insert(name.to_string(), value.to_vec());
+            Ok(())
         }
-        let len = unsafe {
-            let content = CONTENT.as_mut().unwrap();
-            if offset > content.get_ref().len() as u64 {
-                content.seek(SeekFrom::End(0))?;
-                stream_util::fill_zeros(content, offset - content.get_ref().len() as u64)?;
-                content.write(buf)?
-            } else {
-                content.seek(SeekFrom::Start(offset))?;
-                content.write(buf)?
-            }
-        };
-        Ok(len)
     }
+}
 
-    async fn flush(&self, handle: u64) -> FsResult<()> {
-        Ok(())
+/// Validate a name of a directory entry, it cannot be empty, `.`, `..`, contain `/` or be longer
+/// than [`MAX_NAME_LENGTH`].
+pub(crate) fn check_name(name: &str) -> FsResult<()> {
+    if name.is_empty() {
+        return Err(FsError::InvalidInput("name cannot be empty"));
     }
-
-    async fn copy_file_range(
-        &self,
-        src_ino: u64,
-        src_offset: u64,
-        dest_ino: u64,
-        dest_offset: u64,
-        size: usize,
-        src_fh: u64,
-        dest_fh: u64,
-    ) -> FsResult<usize> {
-        if self.is_dir(src_ino) || self.is_dir(dest_ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-
-        let mut buf = vec![0; size];
-        let len = self.read(src_ino, src_offset, &mut buf, src_fh).await?;
-        if len == 0 {
-            return Ok(0);
-        }
-        let mut copied = 0;
-        while copied < size {
-            let len = self
-                .write(dest_ino, dest_offset, &buf[copied..len], dest_fh)
-                .await?;
-            if len == 0 && copied < size {
-                error!(len, "Failed to copy all read bytes");
-                return Err(FsError::Other("Failed to copy all read bytes"));
-            }
-            copied += len;
-        }
-        Ok(len)
+    if name == "." || name == ".." {
+        return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
     }
-
-    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
-        if !read && !write {
-            return Err(FsError::InvalidInput(
-                "read and write cannot be false at the same time",
-            ));
-        }
-        if self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        Ok(thread_rng().next_u64())
+    if name.contains('/') {
+        return Err(FsError::InvalidInput("name cannot contain '/'"));
     }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
 
-    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
-        let attr = self.get_attr(ino).await?;
-        if matches!(attr.kind, FileType::Directory) {
-            return Err(FsError::InvalidInodeType);
-        }
-
-        if size == attr.size {
-            // no-op
-            return Ok(());
-        }
+/// Entries of a directory read at once, with the cookie of the last entry read, it can be one which
+/// was skipped, or `None` at the end of the directory.
+pub(crate) type Batch<T> = (Vec<T>, Option<u64>);
+
+/// The stream of [`Filesystem::read_dir`] for filesystems which read the entries in batches, so
+/// nothing is locked while it's polled. `read_batch` gets the cookie to go on after and how many
+/// entries to read at most.
+pub(crate) fn read_dir_in_batches<T, F>(
+    cookie: u64,
+    read_batch: F,
+) -> BoxStream<'static, FsResult<T>>
+where
+    T: Send + 'static,
+    F: FnMut(u64, usize) -> FsResult<Batch<T>> + Send + 'static,
+{
+    batches(cookie, read_batch, false)
+}
 
-        if size == 0 {
-            debug!("truncate to zero");
-            // truncate to zero
-            unsafe {
-                CONTENT = Some(Cursor::new(vec![]));
-            }
-        } else {
-            debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
+/// Like [`read_dir_in_batches`], for filesystems which read the entries from the host, each batch
+/// is read on the threads for blocking work.
+pub(crate) fn read_host_dir_in_batches<T, F>(
+    cookie: u64,
+    read_batch: F,
+) -> BoxStream<'static, FsResult<T>>
+where
+    T: Send + 'static,
+    F: FnMut(u64, usize) -> FsResult<Batch<T>> + Send + 'static,
+{
+    batches(cookie, read_batch, true)
+}
 
-            let len = if size > attr.size {
-                // increase size, copy existing data until existing size
-                attr.size
+fn batches<T, F>(cookie: u64, read_batch: F, blocking: bool) -> BoxStream<'static, FsResult<T>>
+where
+    T: Send + 'static,
+    F: FnMut(u64, usize) -> FsResult<Batch<T>> + Send + 'static,
+{
+    stream::unfold(
+        Some((cookie, READ_DIR_BATCH, read_batch)),
+        move |next| async move {
+            let (cookie, len, mut read_batch) = next?;
+            let (res, read_batch) = if blocking {
+                let res = tokio::task::spawn_blocking(move || {
+                    let res = read_batch(cookie, len);
+                    (res, read_batch)
+                })
+                .await;
+                match res {
+                    Ok(res) => res,
+                    Err(err) => return Some((vec![Err(err.into())], None)),
+                }
             } else {
-                // decrease size, copy existing data until new size
-                size
+                (read_batch(cookie, len), read_batch)
             };
-            let mut new_content = Cursor::new(vec![0; size as usize]);
-            unsafe {
-                let content = CONTENT.as_mut().unwrap();
-                content.seek(SeekFrom::Start(0))?;
-                stream_util::copy_exact(content, &mut new_content, len)?;
-                if size > attr.size {
-                    // increase size, seek to new size will write zeros
-                    stream_util::fill_zeros(&mut new_content, size - attr.size)?;
-                }
-                CONTENT = Some(new_content);
-            }
+            Some(match res {
+                Ok((entries, next)) => (
+                    entries.into_iter().map(Ok).collect(),
+                    next.map(|cookie| (cookie, min(len * 2, MAX_READ_DIR_BATCH), read_batch)),
+                ),
+                Err(err) => (vec![Err(err)], None),
+            })
+        },
+    )
+    .flat_map(stream::iter)
+    .boxed()
+}
+
+/// Run `f` with the filesystem `fs` on the threads for blocking work. For the filesystems which use
+/// the host with `std::fs`, so the async workers are not blocked by it.
+pub(crate) async fn run_blocking<S, T, F>(fs: &Weak<S>, f: F) -> FsResult<T>
+where
+    S: Send + Sync + 'static,
+    T: Send + 'static,
+    F: FnOnce(&S) -> FsResult<T> + Send + 'static,
+{
+    let fs = fs
+        .upgrade()
+        .ok_or(FsError::Other("filesystem is dropped"))?;
+    tokio::task::spawn_blocking(move || f(&fs)).await?
+}
+
+/// How many locks a [`StripedLocks`] has.
+const LOCK_STRIPES: usize = 64;
+
+/// Locks picked by a key, like an inode, to serialize the operations on the same key without a
+/// lock for each one. Different keys can share a lock. Tokio's locks are not poisoned when a task
+/// holding them panics, so neither are these.
+pub(crate) struct StripedLocks {
+    locks: Vec<tokio::sync::RwLock<()>>,
+}
+
+impl StripedLocks {
+    pub(crate) fn new() -> Self {
+        Self {
+            locks: (0..LOCK_STRIPES)
+                .map(|_| tokio::sync::RwLock::new(()))
+                .collect(),
         }
+    }
 
-        let set_attr = SetFileAttr::default()
-            .with_size(size)
-            .with_mtime(SystemTime::now())
-            .with_ctime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
+    #[allow(clippy::cast_possible_truncation)]
+    const fn stripe(key: u64) -> usize {
+        key as usize % LOCK_STRIPES
+    }
 
-        Ok(())
+    pub(crate) fn get(&self, key: u64) -> &tokio::sync::RwLock<()> {
+        &self.locks[Self::stripe(key)]
     }
 
-    async fn rename(
+    /// Write lock the locks of two keys, in the order of the stripes, so two tasks locking the
+    /// same keys in opposite order don't deadlock. The second is `None` when they share a lock.
+    pub(crate) async fn write_both(
         &self,
-        parent: u64,
-        name: &str,
-        new_parent: u64,
-        new_name: &str,
-    ) -> FsResult<()> {
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-        if !self.exists(new_parent) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_dir(new_parent) {
-            return Err(FsError::InvalidInodeType);
-        }
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
-        }
-
-        if parent == new_parent && name == new_name {
-            // no-op
-            return Ok(());
-        }
+        a: u64,
+        b: u64,
+    ) -> (
+        tokio::sync::RwLockWriteGuard<'_, ()>,
+        Option<tokio::sync::RwLockWriteGuard<'_, ()>>,
+    ) {
+        let mut stripes = [Self::stripe(a), Self::stripe(b)];
+        stripes.sort_unstable();
+        let first = self.locks[stripes[0]].write().await;
+        let second = if stripes[1] == stripes[0] {
+            None
+        } else {
+            Some(self.locks[stripes[1]].write().await)
+        };
+        (first, second)
+    }
+}
 
+/// Read up to `len` entries of a host directory after `cookie`, 0 for the start, "." and ".."
+/// included. The cookie of each entry is the `d_off` the host gives it, where `seekdir` goes on
+/// after it, filesystems like ext4 and tmpfs keep it valid while entries are added and removed.
+pub(crate) fn host_read_dir(
+    path: &Path,
+    cookie: u64,
+    len: usize,
+) -> FsResult<Vec<(OsString, u64)>> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| FsError::InvalidInput("path contains a nul byte"))?;
+    let dir = unsafe { libc::opendir(path.as_ptr()) };
+    if dir.is_null() {
+        return Err(io::Error::last_os_error().into());
+    }
+    if cookie != 0 {
+        #[allow(clippy::cast_possible_wrap)]
         unsafe {
-            if parent != ROOT_INODE || new_parent != parent || name != FILENAME.as_ref().unwrap() {
-                return Err(FsError::InvalidInput("cannot rename"));
-            }
-            FILENAME = Some(String::from_str(new_name).unwrap());
+            libc::seekdir(dir, cookie as libc::c_long);
         }
+    }
+    let mut entries = Vec::with_capacity(len);
+    let result = loop {
+        if entries.len() == len {
+            break Ok(());
+        }
+        // NULL is returned at the end and on errors, only errors set errno
+        unsafe { *libc::__errno_location() = 0 };
+        let entry = unsafe { libc::readdir(dir) };
+        if entry.is_null() {
+            let err = io::Error::last_os_error();
+            break if err.raw_os_error() == Some(0) {
+                Ok(())
+            } else {
+                Err(err)
+            };
+        }
+        let entry = unsafe { &*entry };
+        let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
+        #[allow(clippy::cast_sign_loss)]
+        entries.push((
+            OsStr::from_bytes(name.to_bytes()).to_os_string(),
+            entry.d_off as u64,
+        ));
+    };
+    unsafe { libc::closedir(dir) };
+    result?;
+    Ok(entries)
+}
 
-        let mut attr = unsafe { FILE.as_mut().unwrap() };
-
-        let mut parent_attr = self.get_attr(parent).await?;
-        parent_attr.mtime = SystemTime::now();
-        parent_attr.ctime = SystemTime::now();
+/// Usage of the host filesystem with `path`, for backends which store in a host directory.
+pub(crate) fn host_statfs(path: &Path) -> FsResult<StatFs> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| FsError::InvalidInput("path contains a nul byte"))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::unnecessary_cast)]
+    Ok(StatFs {
+        bsize: stat.f_frsize as u32,
+        blocks: stat.f_blocks as u64,
+        bfree: stat.f_bfree as u64,
+        bavail: stat.f_bavail as u64,
+        files: stat.f_files as u64,
+        ffree: stat.f_ffree as u64,
+    })
+}
 
-        attr.ctime = SystemTime::now();
+/// `fallocate` with writes of zeros and `set_len`, for filesystems which change the content before
+/// storing it, so they cannot pass the range as it is to the one they wrap.
+pub(crate) async fn fallocate_with_writes(
+    fs: &dyn Filesystem,
+    ino: u64,
+    offset: u64,
+    len: u64,
+    mode: FallocateMode,
+    handle: u64,
+) -> FsResult<()> {
+    if !fs.is_write_handle(handle).await {
+        return Err(FsError::InvalidFileHandle);
+    }
+    let size = fs.get_attr(ino).await?.size;
+    if let Some((mut pos, end)) = mode.zeroed(size, offset, len) {
+        #[allow(clippy::cast_possible_truncation)]
+        let zeros = vec![0; min(end - pos, ZEROS_LEN) as usize];
+        while pos < end {
+            #[allow(clippy::cast_possible_truncation)]
+            let buf_len = min(zeros.len() as u64, end - pos) as usize;
+            fs.write(ino, pos, &zeros[..buf_len], handle).await?;
+            pos += buf_len as u64;
+        }
+    }
+    let new_size = mode.new_size(size, offset, len);
+    if new_size > size {
+        fs.set_len(ino, new_size).await?;
+    }
+    Ok(())
+}
 
-        Ok(())
-    }
+/// `copy_file_range` with reads and writes, for filesystems which cannot pass it to the one they
+/// wrap. It copies up to the end of the source, in chunks of [`COPY_CHUNK_LEN`].
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn copy_with_writes(
+    fs: &dyn Filesystem,
+    src_ino: u64,
+    src_offset: u64,
+    dest_ino: u64,
+    dest_offset: u64,
+    size: usize,
+    src_fh: u64,
+    dest_fh: u64,
+) -> FsResult<usize> {
+    let src_size = fs.get_attr(src_ino).await?.size;
+    let len = min(size as u64, src_size.saturating_sub(src_offset));
+    #[allow(clippy::cast_possible_truncation)]
+    let mut buf = vec![0; min(len, COPY_CHUNK_LEN) as usize];
+    let mut copied = 0;
+    while copied < len {
+        #[allow(clippy::cast_possible_truncation)]
+        let buf_len = min(buf.len() as u64, len - copied) as usize;
+        let read = fs
+            .read(src_ino, src_offset + copied, &mut buf[..buf_len], src_fh)
+            .await?;
+        if read == 0 {
+            // it was truncated meanwhile
+            break;
+        }
+        fs.write(dest_ino, dest_offset + copied, &buf[..read], dest_fh)
+            .await?;
+        copied += read as u64;
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    Ok(copied as usize)
 }
 
-fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr) {
-    if let Some(size) = set_attr.size {
-        attr.size = size;
-    }
-    if let Some(atime) = set_attr.atime {
-        attr.atime = max(atime, attr.atime);
-    }
-    if let Some(mtime) = set_attr.mtime {
-        attr.mtime = max(mtime, attr.mtime);
-    }
-    if let Some(ctime) = set_attr.ctime {
-        attr.ctime = max(ctime, attr.ctime);
-    }
-    if let Some(crtime) = set_attr.crtime {
-        attr.crtime = max(crtime, attr.crtime);
-    }
-    if let Some(perm) = set_attr.perm {
-        attr.perm = perm;
-    }
-    if let Some(uid) = set_attr.uid {
-        attr.uid = uid;
+/// `fallocate` on a host file, for backends which store in a host directory. Where the host
+/// doesn't support a mode it's done with writes of zeros.
+pub(crate) fn host_fallocate(
+    file: &File,
+    offset: u64,
+    len: u64,
+    mode: FallocateMode,
+) -> FsResult<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let (flags, keep_size) = match mode {
+            FallocateMode::Allocate { keep_size } => (0, keep_size),
+            FallocateMode::PunchHole => (libc::FALLOC_FL_PUNCH_HOLE, true),
+            FallocateMode::ZeroRange { keep_size } => (libc::FALLOC_FL_ZERO_RANGE, keep_size),
+        };
+        let flags = if keep_size {
+            flags | libc::FALLOC_FL_KEEP_SIZE
+        } else {
+            flags
+        };
+        #[allow(clippy::cast_possible_wrap)]
+        if unsafe { libc::fallocate(file.as_raw_fd(), flags, offset as i64, len as i64) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err.into());
+        }
     }
-    if let Some(gid) = set_attr.gid {
-        attr.gid = gid;
+    let size = file.metadata()?.len();
+    if let Some((mut pos, end)) = mode.zeroed(size, offset, len) {
+        #[allow(clippy::cast_possible_truncation)]
+        let zeros = vec![0; min(end - pos, ZEROS_LEN) as usize];
+        while pos < end {
+            #[allow(clippy::cast_possible_truncation)]
+            let buf_len = min(zeros.len() as u64, end - pos) as usize;
+            file.write_all_at(&zeros[..buf_len], pos)?;
+            pos += buf_len as u64;
+        }
     }
-    if let Some(flags) = set_attr.flags {
-        attr.flags = flags;
+    let new_size = mode.new_size(size, offset, len);
+    if new_size > size {
+        file.set_len(new_size)?;
     }
+    Ok(())
 }
 
-fn file() -> FileAttr {
-    unsafe {
-        FILE.as_mut().unwrap().size = CONTENT.as_ref().unwrap().get_ref().len() as u64;
-        *FILE.as_ref().unwrap()
-    }
+/// `lseek` with `SEEK_DATA` or `SEEK_HOLE` on a host file, for backends which store in a host
+/// directory.
+pub(crate) fn host_seek(path: &Path, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    let file = File::open(path)?;
+    let whence = match whence {
+        Whence::Data => libc::SEEK_DATA,
+        Whence::Hole => libc::SEEK_HOLE,
+    };
+    #[allow(clippy::cast_possible_wrap)]
+    let pos = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if pos < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENXIO) {
+            return Ok(None);
+        }
+        return Err(err.into());
+    }
+    #[allow(clippy::cast_sign_loss)]
+    Ok(Some(pos as u64))
 }
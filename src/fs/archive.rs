@@ -0,0 +1,656 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use tracing::{instrument, warn};
+
+use crate::fs::directory::Directory;
+use crate::fs::{read_dir_in_batches, run_blocking, Filesystem, ROOT_INODE};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, DirectoryEntryPlusStream,
+    DirectoryEntryStream, FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    SetXattrMode, StatFs,
+};
+
+pub(crate) mod inflate;
+
+pub(crate) const BLOCK_SIZE: u64 = 4096;
+
+/// The format specific part of a read-only archive backend, [`ArchiveFilesystem`] does the rest.
+pub(crate) trait Archive: Send + Sync + 'static {
+    /// Where the content of a file is in the archive.
+    type Entry: Send + Sync;
+    /// State kept for each opened handle, like a decompression cache.
+    type Reader: Send;
+
+    fn reader(&self, entry: &Self::Entry) -> FsResult<Self::Reader>;
+
+    /// Read the content of `entry` from `offset`, `offset` is inside the file.
+    fn read(
+        &self,
+        entry: &Self::Entry,
+        reader: &mut Self::Reader,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> FsResult<usize>;
+}
+
+struct Node<E> {
+    attr: FileAttr,
+    parent: u64,
+    /// Entries for directories.
+    children: Directory,
+    /// Only for files.
+    entry: Option<E>,
+    /// Only for symbolic links.
+    target: Option<String>,
+}
+
+/// The inode tree of an archive, built once when we open it. Inode of a node is its index + 1.
+pub(crate) struct ArchiveTree<E> {
+    nodes: Vec<Node<E>>,
+    /// Attributes for directories which are not in the archive but we need for the paths.
+    dir_attr: FileAttr,
+}
+
+impl<E> ArchiveTree<E> {
+    /// `archive` is the metadata of the archive file, we take owner and times for the root and
+    /// for the directories which are not in the archive from it.
+    pub fn new(archive: &Metadata) -> Self {
+        let mut dir_attr: FileAttr = CreateFileAttr {
+            kind: FileType::Directory,
+            perm: 0o755,
+            uid: archive.uid(),
+            gid: archive.gid(),
+            rdev: 0,
+            flags: 0,
+        }
+        .into();
+        let mtime = archive.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        dir_attr.atime = mtime;
+        dir_attr.mtime = mtime;
+        dir_attr.ctime = mtime;
+        dir_attr.crtime = mtime;
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            dir_attr.blksize = BLOCK_SIZE as u32;
+        }
+        let mut root_attr = dir_attr;
+        root_attr.ino = ROOT_INODE;
+        Self {
+            nodes: vec![Node {
+                attr: root_attr,
+                parent: ROOT_INODE,
+                children: Directory::default(),
+                entry: None,
+                target: None,
+            }],
+            dir_attr,
+        }
+    }
+
+    fn node(&self, ino: u64) -> FsResult<&Node<E>> {
+        usize::try_from(ino)
+            .ok()
+            .and_then(|ino| ino.checked_sub(1))
+            .and_then(|idx| self.nodes.get(idx))
+            .ok_or(FsError::InodeNotFound)
+    }
+
+    fn children(&self, ino: u64) -> FsResult<&Directory> {
+        let node = self.node(ino)?;
+        if node.attr.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(&node.children)
+    }
+
+    /// Up to `len` entries of a directory after `cookie`.
+    fn entries(&self, ino: u64, cookie: u64, len: usize) -> FsResult<Vec<DirectoryEntryPlus>> {
+        let parent = self.node(ino)?.parent;
+        self.children(ino)?
+            .after(cookie, ino, parent)
+            .take(len)
+            .map(|(name, child, cookie)| {
+                let attr = self.node(child)?.attr;
+                Ok(DirectoryEntryPlus {
+                    ino: child,
+                    name: name.to_string(),
+                    kind: attr.kind,
+                    attr,
+                    cookie,
+                })
+            })
+            .collect()
+    }
+
+    fn add_node(
+        &mut self,
+        parent: u64,
+        name: &str,
+        mut attr: FileAttr,
+        entry: Option<E>,
+        target: Option<String>,
+    ) -> u64 {
+        let ino = self.nodes.len() as u64 + 1;
+        attr.ino = ino;
+        self.nodes.push(Node {
+            attr,
+            parent,
+            children: Directory::default(),
+            entry,
+            target,
+        });
+        self.nodes[parent as usize - 1]
+            .children
+            .insert(name.to_string(), ino);
+        ino
+    }
+
+    /// Add an entry from the archive at `path`, missing parent directories are created. If the
+    /// entry is already there, like a directory we created for a previous path or a file which
+    /// is twice in the archive, the last one wins.
+    ///
+    /// `attr.kind` should be [`FileType::Directory`] or [`FileType::RegularFile`], `entry`
+    /// should be set for files.
+    pub fn insert(&mut self, path: &Path, attr: FileAttr, entry: Option<E>) -> FsResult<()> {
+        self.insert_node(path, attr, entry, None)
+    }
+
+    /// Add a symbolic link from the archive at `path`, like [`ArchiveTree::insert`].
+    pub fn insert_symlink(
+        &mut self,
+        path: &Path,
+        mut attr: FileAttr,
+        target: String,
+    ) -> FsResult<()> {
+        attr.kind = FileType::Symlink;
+        attr.size = target.len() as u64;
+        attr.blocks = attr.size.div_ceil(512);
+        self.insert_node(path, attr, None, Some(target))
+    }
+
+    /// Add a hard link from the archive at `path` to the entry at `target`, which must be added
+    /// before. Links to directories or to entries which are not there are skipped.
+    pub fn insert_link(&mut self, path: &Path, target: &Path) -> FsResult<()> {
+        let Some(target_names) = split_path(target) else {
+            return Ok(());
+        };
+        let mut ino = ROOT_INODE;
+        for name in target_names {
+            let child = self.children(ino).ok().and_then(|c| c.get(name));
+            let Some(child) = child else {
+                warn!(path = %path.display(), target = %target.display(), "target of hard link not found, skipping entry");
+                return Ok(());
+            };
+            ino = child;
+        }
+        if self.node(ino)?.attr.kind == FileType::Directory {
+            warn!(path = %path.display(), "hard link to a directory, skipping entry");
+            return Ok(());
+        }
+        let Some(names) = split_path(path) else {
+            return Ok(());
+        };
+        let Some((name, dirs)) = names.split_last() else {
+            warn!(path = %path.display(), "hard link in place of the root, skipping entry");
+            return Ok(());
+        };
+        let Some(parent) = self.make_dirs(path, dirs)? else {
+            return Ok(());
+        };
+        let children = &mut self.nodes[parent as usize - 1].children;
+        if children.contains_key(name) {
+            warn!(path = %path.display(), "hard link over an existing entry, skipping entry");
+            return Ok(());
+        }
+        children.insert((*name).to_string(), ino);
+        Ok(())
+    }
+
+    fn insert_node(
+        &mut self,
+        path: &Path,
+        attr: FileAttr,
+        entry: Option<E>,
+        target: Option<String>,
+    ) -> FsResult<()> {
+        let Some(names) = split_path(path) else {
+            return Ok(());
+        };
+        let Some((name, dirs)) = names.split_last() else {
+            // the root itself
+            if attr.kind == FileType::Directory {
+                self.update_dir(ROOT_INODE, attr);
+            }
+            return Ok(());
+        };
+        let Some(parent) = self.make_dirs(path, dirs)? else {
+            return Ok(());
+        };
+
+        match self.nodes[parent as usize - 1].children.get(name) {
+            Some(ino) if attr.kind == FileType::Directory => {
+                if self.node(ino)?.attr.kind == FileType::Directory {
+                    self.update_dir(ino, attr);
+                } else {
+                    warn!(path = %path.display(), "file and directory with the same name, keeping the file");
+                }
+            }
+            Some(ino) => {
+                if self.node(ino)?.attr.kind == FileType::Directory {
+                    warn!(path = %path.display(), "file and directory with the same name, keeping the directory");
+                    return Ok(());
+                }
+                // a new node, the old one might have other hard links
+                self.add_node(parent, name, attr, entry, target);
+            }
+            None => {
+                self.add_node(parent, name, attr, entry, target);
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk the directories of a path from the root, creating the missing ones. It returns
+    /// `None` if one of them is a file.
+    fn make_dirs(&mut self, path: &Path, dirs: &[&str]) -> FsResult<Option<u64>> {
+        let mut parent = ROOT_INODE;
+        for dir in dirs {
+            parent = match self.nodes[parent as usize - 1].children.get(dir) {
+                Some(ino) if self.node(ino)?.attr.kind == FileType::Directory => ino,
+                Some(_) => {
+                    warn!(path = %path.display(), "parent is not a directory, skipping entry");
+                    return Ok(None);
+                }
+                None => self.add_node(parent, dir, self.dir_attr, None, None),
+            };
+        }
+        Ok(Some(parent))
+    }
+
+    /// Keep the place in the tree but take the attributes from the archive.
+    fn update_dir(&mut self, ino: u64, attr: FileAttr) {
+        let node = &mut self.nodes[ino as usize - 1];
+        node.attr = FileAttr { ino, ..attr };
+    }
+
+    /// Call after all entries are added, it sets the link counts, for directories 2 plus the
+    /// subdirectories and for the rest the number of names.
+    pub fn finish(&mut self) {
+        let mut links = vec![0; self.nodes.len()];
+        for node in &self.nodes {
+            for (_, child) in node.children.iter() {
+                let kind = self.nodes[child as usize - 1].attr.kind;
+                if kind == FileType::Directory {
+                    links[node.attr.ino as usize - 1] += 1;
+                } else {
+                    links[child as usize - 1] += 1;
+                }
+            }
+        }
+        for (node, links) in self.nodes.iter_mut().zip(links) {
+            node.attr.nlink = if node.attr.kind == FileType::Directory {
+                2 + links
+            } else {
+                links
+            };
+        }
+    }
+}
+
+/// The names in `path`, `None` if it goes outside of the root or a name is not UTF-8.
+fn split_path(path: &Path) -> Option<Vec<&str>> {
+    let mut names = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                let Some(name) = name.to_str() else {
+                    warn!(path = %path.display(), "name is not valid UTF-8, skipping entry");
+                    return None;
+                };
+                names.push(name);
+            }
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                warn!(path = %path.display(), "skipping entry outside of archive root");
+                return None;
+            }
+        }
+    }
+    Some(names)
+}
+
+struct Handle<R> {
+    ino: u64,
+    reader: R,
+}
+
+type Handles<R> = HashMap<u64, Arc<Mutex<Handle<R>>>>;
+
+/// Read-only filesystem exposing the content of an archive, every method which would change
+/// something fails with [`FsError::ReadOnly`]. The content is read and decompressed on the threads
+/// for blocking work, with [`run_blocking`].
+pub(crate) struct ArchiveFilesystem<A: Archive> {
+    archive: A,
+    tree: ArchiveTree<A::Entry>,
+    handles: Mutex<Handles<A::Reader>>,
+    current_handle: AtomicU64,
+    this: Weak<Self>,
+}
+
+impl<A: Archive> ArchiveFilesystem<A> {
+    pub fn from_archive(archive: A, mut tree: ArchiveTree<A::Entry>) -> Arc<Self> {
+        tree.finish();
+        Arc::new_cyclic(|this| Self {
+            archive,
+            tree,
+            handles: Mutex::new(HashMap::new()),
+            current_handle: AtomicU64::new(0),
+            this: this.clone(),
+        })
+    }
+
+    fn handles(&self) -> MutexGuard<'_, Handles<A::Reader>> {
+        self.handles.lock().expect("handles lock poisoned")
+    }
+}
+
+#[async_trait]
+impl<A: Archive> Filesystem for ArchiveFilesystem<A> {
+    fn exists(&self, ino: u64) -> bool {
+        self.tree.node(ino).is_ok()
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.tree
+            .node(ino)
+            .is_ok_and(|node| node.attr.kind == FileType::Directory)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.tree
+            .node(ino)
+            .is_ok_and(|node| node.attr.kind == FileType::RegularFile)
+    }
+
+    async fn create(
+        &self,
+        _parent: u64,
+        _name: &str,
+        _create_attr: CreateFileAttr,
+        _read: bool,
+        _write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn symlink(
+        &self,
+        _parent: u64,
+        _name: &str,
+        _target: &str,
+        _create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        self.tree
+            .node(ino)?
+            .target
+            .clone()
+            .ok_or(FsError::InvalidInodeType)
+    }
+
+    async fn link(&self, _ino: u64, _new_parent: u64, _new_name: &str) -> FsResult<FileAttr> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        let Some(ino) = self.tree.children(parent)?.get(name) else {
+            return Ok(None);
+        };
+        Ok(Some(self.tree.node(ino)?.attr))
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        Ok(self.tree.children(ino)?.len())
+    }
+
+    async fn remove_dir(&self, _parent: u64, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn remove_file(&self, _parent: u64, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        Ok(self.tree.children(parent)?.contains_key(name))
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        let stream = self.read_dir_plus(ino, cookie).await?;
+        Ok(stream.map(|entry| entry.map(DirectoryEntry::from)).boxed())
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        self.tree.children(ino)?;
+        Ok(read_dir_in_batches(cookie, move |cookie, len| {
+            let entries = self.tree.entries(ino, cookie, len)?;
+            let next = entries
+                .last()
+                .filter(|_| entries.len() == len)
+                .map(|entry| entry.cookie);
+            Ok((entries, next))
+        }))
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        Ok(self.tree.node(ino)?.attr)
+    }
+
+    async fn set_attr(&self, _ino: u64, _set_attr: SetFileAttr) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    #[instrument(skip(self, buf))]
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        let handle = self
+            .handles()
+            .get(&handle)
+            .cloned()
+            .ok_or(FsError::InvalidFileHandle)?;
+        let size = buf.len();
+        let data = run_blocking(&self.this, move |fs| {
+            let mut handle = handle.lock().expect("handle lock poisoned");
+            if handle.ino != ino {
+                return Err(FsError::InvalidFileHandle);
+            }
+            let node = fs.tree.node(ino)?;
+            let Some(entry) = &node.entry else {
+                return Err(FsError::InvalidInodeType);
+            };
+            if offset >= node.attr.size {
+                return Ok(vec![]);
+            }
+            let mut buf = vec![0; size.min((node.attr.size - offset) as usize)];
+            let len = fs
+                .archive
+                .read(entry, &mut handle.reader, offset, &mut buf)?;
+            buf.truncate(len);
+            Ok(buf)
+        })
+        .await?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        self.handles()
+            .remove(&handle)
+            .ok_or(FsError::InvalidFileHandle)?;
+        Ok(())
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.handles().contains_key(&fh)
+    }
+
+    async fn is_write_handle(&self, _fh: u64) -> bool {
+        false
+    }
+
+    async fn write(&self, _ino: u64, _offset: u64, _buf: &[u8], _handle: u64) -> FsResult<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        // nothing to flush, we never write
+        if !self.handles().contains_key(&handle) {
+            return Err(FsError::InvalidFileHandle);
+        }
+        Ok(())
+    }
+
+    async fn copy_file_range(
+        &self,
+        _src_ino: u64,
+        _src_offset: u64,
+        _dest_ino: u64,
+        _dest_offset: u64,
+        _size: usize,
+        _src_fh: u64,
+        _dest_fh: u64,
+    ) -> FsResult<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if write {
+            return Err(FsError::ReadOnly);
+        }
+        if !read {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        let Some(entry) = &self.tree.node(ino)?.entry else {
+            return Err(FsError::InvalidInodeType);
+        };
+        let reader = self.archive.reader(entry)?;
+        let fh = self.current_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        self.handles()
+            .insert(fh, Arc::new(Mutex::new(Handle { ino, reader })));
+        Ok(fh)
+    }
+
+    async fn set_len(&self, _ino: u64, _size: u64) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn fallocate(
+        &self,
+        _ino: u64,
+        _offset: u64,
+        _len: u64,
+        _mode: FallocateMode,
+        _handle: u64,
+    ) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn rename(
+        &self,
+        _parent: u64,
+        _name: &str,
+        _new_parent: u64,
+        _new_name: &str,
+    ) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Extended attributes are not read from the archive, there are none.
+    async fn get_xattr(&self, ino: u64, _name: &str) -> FsResult<Vec<u8>> {
+        self.tree.node(ino)?;
+        Err(FsError::XattrNotFound)
+    }
+
+    async fn set_xattr(
+        &self,
+        _ino: u64,
+        _name: &str,
+        _value: &[u8],
+        _mode: SetXattrMode,
+    ) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        self.tree.node(ino)?;
+        Ok(vec![])
+    }
+
+    async fn remove_xattr(&self, _ino: u64, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// As big as the content, with nothing free.
+    async fn statfs(&self) -> FsResult<StatFs> {
+        let blocks = self
+            .tree
+            .nodes
+            .iter()
+            .map(|node| node.attr.size.div_ceil(BLOCK_SIZE))
+            .sum();
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(StatFs {
+            bsize: BLOCK_SIZE as u32,
+            blocks,
+            bfree: 0,
+            bavail: 0,
+            files: self.tree.nodes.len() as u64,
+            ffree: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+    use crate::fs::testing::{file_attr, TempDir};
+
+    #[test]
+    fn skips_names_which_are_not_utf8() {
+        let dir = TempDir::new();
+        let mut tree = ArchiveTree::<()>::new(&std::fs::metadata(dir.path()).unwrap());
+        let attr: FileAttr = file_attr(0).into();
+        tree.insert(Path::new(OsStr::from_bytes(b"dir/bad\xff")), attr, Some(()))
+            .unwrap();
+        tree.insert(Path::new("dir/good"), attr, Some(())).unwrap();
+
+        let dir = tree.children(ROOT_INODE).unwrap().get("dir").unwrap();
+        let names: Vec<_> = tree
+            .children(dir)
+            .unwrap()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect();
+        assert_eq!(names, ["good"]);
+    }
+}
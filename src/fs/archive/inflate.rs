@@ -0,0 +1,407 @@
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_HAS_MORE_INPUT;
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide, TINFL_LZ_DICT_SIZE};
+use miniz_oxide::inflate::TINFLStatus;
+
+use crate::fs_model::{FsError, FsResult};
+
+/// How much uncompressed data is between two checkpoints, at least. Each one keeps the deflate
+/// window and decompressor state, around 43KB, so this is a tradeoff between memory and how much we
+/// need to decompress for a random read.
+const CHECKPOINT_SPAN: u64 = 4 * 1024 * 1024;
+/// When there would be more checkpoints the span is doubled, so big archives take at most around
+/// 11MB for them.
+const MAX_CHECKPOINTS: usize = 256;
+const INPUT_CHUNK_SIZE: usize = 64 * 1024;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_TRAILER_LEN: u64 = 8;
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+pub(crate) fn is_gzip(file: &File) -> io::Result<bool> {
+    let mut magic = [0; 2];
+    Ok(file.read_at(&mut magic, 0)? == 2 && magic == GZIP_MAGIC)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Header,
+    Deflate,
+    Trailer,
+    Eof,
+}
+
+/// Decompresses deflate data sequentially, either a gzip file or a raw deflate stream from a
+/// region of a file, like an entry in a zip. Multiple gzip members are read one after another,
+/// like `gzip -d` does.
+///
+/// We drive the decompressor ourselves, instead of using a [`Read`] adapter, so we can clone its
+/// state at any point and continue from there later, see [`GzIndex`].
+pub(crate) struct InflateStream {
+    file: Arc<File>,
+    /// Where the compressed data ends in the file.
+    len: u64,
+    gzip: bool,
+    /// Offset in the compressed file of the next byte to be consumed.
+    in_offset: u64,
+    /// Offset in the uncompressed data of the next byte we return.
+    out_offset: u64,
+    phase: Phase,
+    state: Box<DecompressorOxide>,
+    /// The output of the decompressor, used as a circular buffer. It also holds the last 32KB
+    /// which back-references point into.
+    window: Box<[u8]>,
+    window_pos: usize,
+    /// Decompressed bytes not returned yet, they are right before `window_pos`.
+    pending: usize,
+    /// Buffered compressed data, starting at `input_offset` in the file.
+    input: Vec<u8>,
+    input_offset: u64,
+}
+
+impl InflateStream {
+    /// Decompress a gzip file.
+    pub fn gzip(file: Arc<File>) -> FsResult<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self::new(file, 0, len, true))
+    }
+
+    /// Decompress the raw deflate data from `offset` to `offset + len` in `file`.
+    pub fn raw(file: Arc<File>, offset: u64, len: u64) -> Self {
+        Self::new(file, offset, offset + len, false)
+    }
+
+    fn new(file: Arc<File>, offset: u64, len: u64, gzip: bool) -> Self {
+        let mut state = Box::<DecompressorOxide>::default();
+        state.init();
+        Self {
+            file,
+            len,
+            gzip,
+            in_offset: offset,
+            out_offset: 0,
+            phase: if gzip { Phase::Header } else { Phase::Deflate },
+            state,
+            window: vec![0; TINFL_LZ_DICT_SIZE].into_boxed_slice(),
+            window_pos: 0,
+            pending: 0,
+            input: vec![],
+            input_offset: 0,
+        }
+    }
+
+    pub const fn position(&self) -> u64 {
+        self.out_offset
+    }
+
+    /// A copy we can continue from later. The buffered input is not copied, it will be read again
+    /// if needed.
+    fn checkpoint(&self) -> Self {
+        Self {
+            file: self.file.clone(),
+            len: self.len,
+            gzip: self.gzip,
+            in_offset: self.in_offset,
+            out_offset: self.out_offset,
+            phase: self.phase,
+            state: self.state.clone(),
+            window: self.window.clone(),
+            window_pos: self.window_pos,
+            pending: self.pending,
+            input: vec![],
+            input_offset: 0,
+        }
+    }
+
+    /// Compressed data from `in_offset`, empty at the end of file.
+    #[allow(clippy::cast_possible_truncation)]
+    fn fill_input(&mut self) -> FsResult<()> {
+        let end = self.input_offset + self.input.len() as u64;
+        if self.in_offset >= self.input_offset && self.in_offset < end {
+            return Ok(());
+        }
+        if self.in_offset > self.len {
+            // the gzip header can skip more than the file has
+            return Err(FsError::Other("truncated gzip"));
+        }
+        self.input.resize(
+            min(INPUT_CHUNK_SIZE as u64, self.len - self.in_offset) as usize,
+            0,
+        );
+        self.file.read_exact_at(&mut self.input, self.in_offset)?;
+        self.input_offset = self.in_offset;
+        Ok(())
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn next_byte(&mut self) -> FsResult<u8> {
+        if self.in_offset >= self.len {
+            return Err(FsError::Other("truncated gzip header"));
+        }
+        self.fill_input()?;
+        let byte = self.input[(self.in_offset - self.input_offset) as usize];
+        self.in_offset += 1;
+        Ok(byte)
+    }
+
+    fn skip_header(&mut self) -> FsResult<()> {
+        let mut header = [0; 10];
+        for byte in &mut header {
+            *byte = self.next_byte()?;
+        }
+        if header[..2] != GZIP_MAGIC || header[2] != 8 {
+            return Err(FsError::Other("not a gzip file"));
+        }
+        let flags = header[3];
+        if flags & FEXTRA != 0 {
+            let len = u16::from_le_bytes([self.next_byte()?, self.next_byte()?]);
+            self.in_offset += u64::from(len);
+        }
+        if flags & FNAME != 0 {
+            while self.next_byte()? != 0 {}
+        }
+        if flags & FCOMMENT != 0 {
+            while self.next_byte()? != 0 {}
+        }
+        if flags & FHCRC != 0 {
+            self.in_offset += 2;
+        }
+        Ok(())
+    }
+
+    /// After a member ends there could be another one, or padding we ignore.
+    fn next_member(&mut self) -> FsResult<Phase> {
+        self.in_offset += GZIP_TRAILER_LEN;
+        if self.in_offset + 2 > self.len {
+            return Ok(Phase::Eof);
+        }
+        let mut magic = [0; 2];
+        self.file.read_exact_at(&mut magic, self.in_offset)?;
+        Ok(if magic == GZIP_MAGIC {
+            Phase::Header
+        } else {
+            Phase::Eof
+        })
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn inflate(&mut self) -> FsResult<()> {
+        self.fill_input()?;
+        let input = &self.input[(self.in_offset - self.input_offset) as usize..];
+        let flags = if self.in_offset + (input.len() as u64) < self.len {
+            TINFL_FLAG_HAS_MORE_INPUT
+        } else {
+            0
+        };
+        let out_pos = self.window_pos & (TINFL_LZ_DICT_SIZE - 1);
+        let (status, consumed, written) =
+            decompress(&mut self.state, input, &mut self.window, out_pos, flags);
+        self.in_offset += consumed as u64;
+        self.window_pos = out_pos + written;
+        self.pending = written;
+        match status {
+            TINFLStatus::Done if self.gzip => self.phase = Phase::Trailer,
+            TINFLStatus::Done => self.phase = Phase::Eof,
+            TINFLStatus::NeedsMoreInput | TINFLStatus::HasMoreOutput => {}
+            TINFLStatus::FailedCannotMakeProgress => {
+                return Err(FsError::Other("truncated deflate data"))
+            }
+            _ => return Err(FsError::Other("corrupted deflate data")),
+        }
+        Ok(())
+    }
+
+    /// Read the next bytes, it only returns less than `buf.len()` at the end.
+    pub fn read(&mut self, buf: &mut [u8]) -> FsResult<usize> {
+        let mut len = 0;
+        while len < buf.len() {
+            if self.pending > 0 {
+                let start = self.window_pos - self.pending;
+                let n = min(self.pending, buf.len() - len);
+                buf[len..len + n].copy_from_slice(&self.window[start..start + n]);
+                self.pending -= n;
+                self.out_offset += n as u64;
+                len += n;
+                continue;
+            }
+            match self.phase {
+                Phase::Header => {
+                    self.skip_header()?;
+                    self.state.init();
+                    self.phase = Phase::Deflate;
+                }
+                Phase::Deflate => self.inflate()?,
+                Phase::Trailer => self.phase = self.next_member()?,
+                Phase::Eof => break,
+            }
+        }
+        Ok(len)
+    }
+
+    /// Skip the next `len` bytes.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn skip(&mut self, mut len: u64) -> FsResult<()> {
+        let mut buf = vec![0; min(len, INPUT_CHUNK_SIZE as u64) as usize];
+        while len > 0 {
+            let n = min(len, buf.len() as u64) as usize;
+            if self.read(&mut buf[..n])? == 0 {
+                break;
+            }
+            len -= n as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Random access into a gzip file. We go through the whole file once, reading it with
+/// [`GzIndexer`], and keep a checkpoint every [`CHECKPOINT_SPAN`] bytes, or more for big files so
+/// there are at most [`MAX_CHECKPOINTS`]. To read at an offset we continue from the closest
+/// checkpoint before it instead of decompressing from the start.
+pub(crate) struct GzIndex {
+    checkpoints: Vec<InflateStream>,
+}
+
+impl GzIndex {
+    /// A stream positioned at `offset` in the uncompressed data. If `current` is a bit before it
+    /// it's used instead of a checkpoint, useful for sequential reads.
+    pub fn seek(&self, current: Option<InflateStream>, offset: u64) -> FsResult<InflateStream> {
+        let idx = self
+            .checkpoints
+            .partition_point(|checkpoint| checkpoint.out_offset <= offset)
+            .saturating_sub(1);
+        let checkpoint = &self.checkpoints[idx];
+        let mut stream = match current {
+            Some(current)
+                if current.out_offset <= offset && current.out_offset >= checkpoint.out_offset =>
+            {
+                current
+            }
+            _ => checkpoint.checkpoint(),
+        };
+        stream.skip(offset - stream.out_offset)?;
+        Ok(stream)
+    }
+}
+
+/// Reads a gzip file sequentially, used to index its content, and records checkpoints as it goes.
+pub(crate) struct GzIndexer {
+    stream: InflateStream,
+    checkpoints: Vec<InflateStream>,
+    span: u64,
+}
+
+impl GzIndexer {
+    pub fn new(file: Arc<File>) -> FsResult<Self> {
+        let stream = InflateStream::gzip(file)?;
+        Ok(Self {
+            checkpoints: vec![stream.checkpoint()],
+            stream,
+            span: CHECKPOINT_SPAN,
+        })
+    }
+
+    /// Read until the end, so we have checkpoints for all the file, and build the index.
+    pub fn finish(mut self) -> FsResult<GzIndex> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(GzIndex {
+            checkpoints: self.checkpoints,
+        })
+    }
+}
+
+impl Read for GzIndexer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let last = self.checkpoints.last().map_or(0, |c| c.out_offset);
+        if self.stream.out_offset >= last + self.span {
+            self.checkpoints.push(self.stream.checkpoint());
+            if self.checkpoints.len() > MAX_CHECKPOINTS {
+                // keep every other one, starting with the first
+                let mut keep = false;
+                self.checkpoints.retain(|_| {
+                    keep = !keep;
+                    keep
+                });
+                self.span *= 2;
+            }
+        }
+        self.stream.read(buf).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::fs::testing::{gzip, noise, TempDir};
+
+    #[test]
+    fn reads_across_checkpoints() {
+        let dir = TempDir::new();
+        let path = dir.path().join("data.gz");
+        // it repeats, so there are back-references to before the checkpoints
+        let pattern = noise(10_000);
+        #[allow(clippy::cast_possible_truncation)]
+        let data: Vec<u8> = (0..1024 * 1024)
+            .map(|i| {
+                if i % 1000 == 0 {
+                    (i / 1000) as u8
+                } else {
+                    pattern[i % pattern.len()]
+                }
+            })
+            .collect();
+        let gzip = gzip(&data);
+        assert!(gzip.len() < data.len() / 2);
+        fs::write(&path, gzip).unwrap();
+        let mut indexer = GzIndexer::new(Arc::new(File::open(&path).unwrap())).unwrap();
+        indexer.span = 100_000;
+        let index = indexer.finish().unwrap();
+        assert!(index.checkpoints.len() > 5);
+
+        let mut buf = [0; 1000];
+        let mut current = None;
+        // backwards, forwards past a few checkpoints and inside the same span
+        for offset in [
+            900_000,
+            150_000,
+            150_500,
+            520_000,
+            99_999,
+            0,
+            1024 * 1024 - 1000,
+        ] {
+            let mut stream = index.seek(current.take(), offset).unwrap();
+            assert_eq!(stream.read(&mut buf).unwrap(), buf.len());
+            #[allow(clippy::cast_possible_truncation)]
+            let offset = offset as usize;
+            assert_eq!(buf, data[offset..offset + buf.len()]);
+            current = Some(stream);
+        }
+    }
+
+    #[test]
+    fn truncated_header() {
+        let dir = TempDir::new();
+        let path = dir.path().join("truncated.gz");
+        // the extra field and the header CRC go past the end
+        let mut header = vec![0x1f, 0x8b, 8, FEXTRA | FHCRC, 0, 0, 0, 0, 0, 0xff];
+        header.extend_from_slice(&[0, 0]);
+        fs::write(&path, &header).unwrap();
+
+        let mut stream = InflateStream::gzip(Arc::new(File::open(&path).unwrap())).unwrap();
+        assert!(matches!(
+            stream.read(&mut [0; 16]),
+            Err(FsError::Other("truncated gzip"))
+        ));
+    }
+}
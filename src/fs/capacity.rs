@@ -0,0 +1,927 @@
+use std::cmp::min;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use tracing::{info, instrument, warn};
+
+use crate::fs::{Filesystem, StripedLocks, ROOT_INODE};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntryPlusStream, DirectoryEntryStream, FallocateMode, FileAttr,
+    FileType, FsError, FsResult, SetFileAttr, SetXattrMode, StatFs, Whence,
+};
+use crate::mount::Capacity;
+use crate::quota;
+use crate::quota::{Graces, Owner, QuotaLimits, QuotaStatus, Resource, Usage};
+
+/// Used when the inner filesystem doesn't say its block size.
+const BLOCK_SIZE: u32 = 4096;
+
+/// What a node is charged with and to whom.
+#[derive(Debug, Clone, Copy)]
+struct Charge {
+    /// The space allocated to it, only regular files have one, the other nodes count only as
+    /// inodes.
+    bytes: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl Charge {
+    fn new(attr: &FileAttr) -> Self {
+        Self {
+            bytes: if attr.kind == FileType::RegularFile {
+                attr.blocks * 512
+            } else {
+                0
+            },
+            uid: attr.uid,
+            gid: attr.gid,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    total: Usage,
+    owners: HashMap<Owner, Usage>,
+    nodes: HashMap<u64, Charge>,
+    /// Since when an owner is over a soft limit.
+    over_soft: Graces,
+    /// Incremented on each change of `over_soft`, so an older one is not saved over a newer one.
+    graces_version: u64,
+}
+
+impl State {
+    fn add(&mut self, ino: u64, charge: Charge) {
+        self.nodes.insert(ino, charge);
+        let usage = Usage {
+            bytes: charge.bytes,
+            inodes: 1,
+        };
+        self.total.bytes += usage.bytes;
+        self.total.inodes += 1;
+        self.charge(charge.uid, charge.gid, usage);
+    }
+
+    fn remove(&mut self, ino: u64) {
+        if let Some(charge) = self.nodes.remove(&ino) {
+            self.total.bytes -= charge.bytes;
+            self.total.inodes -= 1;
+            self.discharge(
+                charge.uid,
+                charge.gid,
+                Usage {
+                    bytes: charge.bytes,
+                    inodes: 1,
+                },
+            );
+        }
+    }
+
+    fn charge(&mut self, uid: u32, gid: u32, usage: Usage) {
+        for owner in [Owner::User(uid), Owner::Group(gid)] {
+            let used = self.owners.entry(owner).or_default();
+            used.bytes += usage.bytes;
+            used.inodes += usage.inodes;
+        }
+    }
+
+    fn discharge(&mut self, uid: u32, gid: u32, usage: Usage) {
+        for owner in [Owner::User(uid), Owner::Group(gid)] {
+            let used = self.owners.entry(owner).or_default();
+            used.bytes -= usage.bytes;
+            used.inodes -= usage.inodes;
+        }
+    }
+
+    fn used(&self, owner: Owner) -> Usage {
+        self.owners.get(&owner).copied().unwrap_or_default()
+    }
+}
+
+/// Keeps the usage of `inner` under a [`Capacity`], changes which would go over it fail with
+/// [`FsError::NoSpace`], or with [`FsError::QuotaExceeded`] when it's over the quota of the user
+/// or the group owning the node.
+///
+/// The bytes are the space allocated to the regular files, the holes of sparse files don't count,
+/// the inodes are all the nodes. They are counted once on mount by walking the tree and kept up to
+/// date after. A change which can allocate space is let through if the space it can take at most
+/// fits, after it the file is charged with what it really took. The space of a file is freed when
+/// its last name is removed. The grace periods are saved in the
+/// [`Quotas::grace_file`](crate::quota::Quotas::grace_file), so they go on after a remount.
+pub(crate) struct CapacityFilesystem {
+    inner: Arc<dyn Filesystem>,
+    capacity: Capacity,
+    state: Mutex<State>,
+    /// Changes of the same file are serialized with these, picked by `ino`.
+    locks: StripedLocks,
+    /// The version of the grace periods last saved.
+    graces_saved: Arc<Mutex<u64>>,
+}
+
+impl CapacityFilesystem {
+    pub async fn new(inner: Arc<dyn Filesystem>, capacity: Capacity) -> FsResult<Arc<Self>> {
+        let mut state = count_usage(&inner).await?;
+        if let Some(path) = capacity.quotas.grace_file.clone() {
+            state.over_soft =
+                tokio::task::spawn_blocking(move || quota::load_graces(&path)).await?;
+        }
+        info!(
+            bytes = state.total.bytes,
+            inodes = state.total.inodes,
+            "current usage"
+        );
+        let fs = Self {
+            inner,
+            capacity,
+            state: Mutex::new(state),
+            locks: StripedLocks::new(),
+            graces_saved: Arc::default(),
+        };
+        {
+            let mut state = fs.state();
+            let mut owners: BTreeSet<_> = state.owners.keys().copied().collect();
+            owners.extend(state.over_soft.keys().map(|(owner, _)| *owner));
+            for owner in owners {
+                fs.update_grace(&mut state, owner);
+            }
+        }
+        Ok(Arc::new(fs))
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("state lock poisoned")
+    }
+
+    fn limits(&self, owner: Owner) -> Option<&QuotaLimits> {
+        match owner {
+            Owner::User(uid) => self.capacity.quotas.users.get(&uid),
+            Owner::Group(gid) => self.capacity.quotas.groups.get(&gid),
+        }
+    }
+
+    /// Check `usage` more can be charged to the owners, and to the filesystem if `total`.
+    fn check(&self, state: &State, uid: u32, gid: u32, usage: Usage, total: bool) -> FsResult<()> {
+        if total
+            && (self
+                .capacity
+                .max_size
+                .is_some_and(|max| usage.bytes > 0 && state.total.bytes + usage.bytes > max)
+                || self
+                    .capacity
+                    .max_inodes
+                    .is_some_and(|max| usage.inodes > 0 && state.total.inodes + usage.inodes > max))
+        {
+            return Err(FsError::NoSpace);
+        }
+        for owner in [Owner::User(uid), Owner::Group(gid)] {
+            let Some(limits) = self.limits(owner) else {
+                continue;
+            };
+            let used = state.used(owner);
+            for (resource, used, more, soft, hard) in [
+                (
+                    Resource::Bytes,
+                    used.bytes,
+                    usage.bytes,
+                    limits.soft_bytes,
+                    limits.hard_bytes,
+                ),
+                (
+                    Resource::Inodes,
+                    used.inodes,
+                    usage.inodes,
+                    limits.soft_inodes,
+                    limits.hard_inodes,
+                ),
+            ] {
+                if more == 0 {
+                    continue;
+                }
+                let new = used + more;
+                // after the grace period the soft limit is enforced like the hard one
+                let grace_over = state
+                    .over_soft
+                    .get(&(owner, resource))
+                    .is_some_and(|since| {
+                        since.elapsed().unwrap_or_default() > self.capacity.quotas.grace_period
+                    });
+                if hard.is_some_and(|hard| new > hard)
+                    || (grace_over && soft.is_some_and(|soft| new > soft))
+                {
+                    return Err(FsError::QuotaExceeded);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start or stop the grace period of an owner, after its usage changed.
+    fn update_grace(&self, state: &mut State, owner: Owner) {
+        let limits = self.limits(owner).copied().unwrap_or_default();
+        let used = state.used(owner);
+        let mut changed = false;
+        for (resource, used, soft) in [
+            (Resource::Bytes, used.bytes, limits.soft_bytes),
+            (Resource::Inodes, used.inodes, limits.soft_inodes),
+        ] {
+            if soft.is_some_and(|soft| used > soft) {
+                if let Entry::Vacant(entry) = state.over_soft.entry((owner, resource)) {
+                    entry.insert(SystemTime::now());
+                    changed = true;
+                }
+            } else {
+                changed |= state.over_soft.remove(&(owner, resource)).is_some();
+            }
+        }
+        if changed {
+            self.save_graces(state);
+        }
+    }
+
+    /// Save the grace periods in the background, the blocking IO is not done on our threads.
+    fn save_graces(&self, state: &mut State) {
+        let Some(path) = self.capacity.quotas.grace_file.clone() else {
+            return;
+        };
+        state.graces_version += 1;
+        let version = state.graces_version;
+        let graces = state.over_soft.clone();
+        let saved = self.graces_saved.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut saved = saved.lock().expect("graces lock poisoned");
+            // a newer one was saved already
+            if *saved >= version {
+                return;
+            }
+            if let Err(err) = quota::save_graces(&path, &graces) {
+                warn!(%err, path = %path.display(), "cannot save grace periods");
+            }
+            *saved = version;
+        });
+    }
+
+    /// Usage, limits and grace periods of the owners which have usage or limits.
+    pub fn quota_status(&self) -> Vec<QuotaStatus> {
+        let state = self.state();
+        let quotas = &self.capacity.quotas;
+        let mut owners: BTreeSet<Owner> = state
+            .owners
+            .iter()
+            .filter(|(_, usage)| **usage != Usage::default())
+            .map(|(owner, _)| *owner)
+            .collect();
+        owners.extend(quotas.users.keys().map(|uid| Owner::User(*uid)));
+        owners.extend(quotas.groups.keys().map(|gid| Owner::Group(*gid)));
+        let grace = |owner, resource| {
+            state
+                .over_soft
+                .get(&(owner, resource))
+                .map(|since| *since + quotas.grace_period)
+        };
+        owners
+            .into_iter()
+            .map(|owner| QuotaStatus {
+                owner,
+                usage: state.used(owner),
+                limits: self.limits(owner).copied().unwrap_or_default(),
+                bytes_grace: grace(owner, Resource::Bytes),
+                inodes_grace: grace(owner, Resource::Inodes),
+            })
+            .collect()
+    }
+
+    fn update_graces(&self, state: &mut State, uid: u32, gid: u32) {
+        self.update_grace(state, Owner::User(uid));
+        self.update_grace(state, Owner::Group(gid));
+    }
+
+    /// Charge a new node, it fails if it's over the capacity or the quotas.
+    fn add_node(&self, uid: u32, gid: u32) -> FsResult<()> {
+        let mut state = self.state();
+        let usage = Usage {
+            bytes: 0,
+            inodes: 1,
+        };
+        self.check(&state, uid, gid, usage, true)?;
+        state.total.inodes += 1;
+        state.charge(uid, gid, usage);
+        self.update_graces(&mut state, uid, gid);
+        Ok(())
+    }
+
+    /// Undo [`Self::add_node`] after the node couldn't be created.
+    fn cancel_node(&self, uid: u32, gid: u32) {
+        let mut state = self.state();
+        let usage = Usage {
+            bytes: 0,
+            inodes: 1,
+        };
+        state.total.inodes -= 1;
+        state.discharge(uid, gid, usage);
+        self.update_graces(&mut state, uid, gid);
+    }
+
+    /// The node [`Self::add_node`] was for is created.
+    fn node_added(&self, ino: u64, uid: u32, gid: u32) {
+        self.state()
+            .nodes
+            .insert(ino, Charge { bytes: 0, uid, gid });
+    }
+
+    /// What a node is charged with, nodes we didn't see yet, like the ones added to the host
+    /// directory of a passthrough backend, are charged now.
+    async fn charged(&self, ino: u64) -> FsResult<Charge> {
+        if let Some(charge) = self.state().nodes.get(&ino) {
+            return Ok(*charge);
+        }
+        let attr = self.inner.get_attr(ino).await?;
+        let mut state = self.state();
+        if !state.nodes.contains_key(&ino) {
+            state.add(ino, Charge::new(&attr));
+        }
+        Ok(state.nodes[&ino])
+    }
+
+    /// The most space writing `len` bytes at `offset` can allocate, the parts of the range which
+    /// are holes or after the end, rounded up to whole blocks.
+    async fn unallocated(&self, ino: u64, offset: u64, len: u64) -> FsResult<u64> {
+        let end = offset.saturating_add(len);
+        let mut unallocated = 0;
+        let mut pos = offset;
+        while pos < end {
+            let Some(hole) = self.inner.seek(ino, pos, Whence::Hole).await? else {
+                // past the end
+                unallocated += end - pos;
+                break;
+            };
+            if hole >= end {
+                break;
+            }
+            let data = self.inner.seek(ino, hole, Whence::Data).await?;
+            let hole_end = data.map_or(end, |data| min(data, end));
+            unallocated += hole_end - hole;
+            pos = hole_end;
+        }
+        // a range which doesn't start on a block boundary can touch one more
+        let block = u64::from(BLOCK_SIZE);
+        Ok(unallocated.div_ceil(block) * block + if unallocated > 0 { block } else { 0 })
+    }
+
+    /// Check a file can take `more` bytes, before a change which can allocate them.
+    async fn check_more(&self, ino: u64, more: u64) -> FsResult<()> {
+        let charge = self.charged(ino).await?;
+        let usage = Usage {
+            bytes: more,
+            inodes: 0,
+        };
+        self.check(&self.state(), charge.uid, charge.gid, usage, true)
+    }
+
+    /// Charge a file with the space it has in `inner`, after it was changed. A file which lost its
+    /// last link meanwhile stays free.
+    async fn settle(&self, ino: u64) {
+        if let Ok(attr) = self.inner.get_attr(ino).await {
+            let mut state = self.state();
+            if !state.nodes.contains_key(&ino) {
+                return;
+            }
+            state.remove(ino);
+            state.add(ino, Charge::new(&attr));
+            self.update_graces(&mut state, attr.uid, attr.gid);
+        }
+    }
+
+    /// Make a change of a file which can allocate up to `more` bytes, if they fit. After it the
+    /// file is charged with what it has.
+    async fn allocating<T>(
+        &self,
+        ino: u64,
+        more: u64,
+        change: impl std::future::Future<Output = FsResult<T>>,
+    ) -> FsResult<T> {
+        self.check_more(ino, more).await?;
+        let res = change.await;
+        self.settle(ino).await;
+        res
+    }
+
+    /// Move the charge of a node to a new owner, it fails if it's over their quotas.
+    async fn chown(&self, ino: u64, uid: Option<u32>, gid: Option<u32>) -> FsResult<Charge> {
+        let old = self.charged(ino).await?;
+        let new = Charge {
+            uid: uid.unwrap_or(old.uid),
+            gid: gid.unwrap_or(old.gid),
+            ..old
+        };
+        let usage = Usage {
+            bytes: old.bytes,
+            inodes: 1,
+        };
+        let mut state = self.state();
+        state.discharge(old.uid, old.gid, usage);
+        if let Err(err) = self.check(&state, new.uid, new.gid, usage, false) {
+            state.charge(old.uid, old.gid, usage);
+            return Err(err);
+        }
+        state.charge(new.uid, new.gid, usage);
+        state.nodes.insert(ino, new);
+        self.update_graces(&mut state, old.uid, old.gid);
+        self.update_graces(&mut state, new.uid, new.gid);
+        Ok(old)
+    }
+
+    /// Charge `bytes` kept outside of the files, like the blocks of the snapshots. They only count
+    /// for the capacity, not for the quotas of the owners.
+    pub fn reserve(&self, bytes: u64) -> FsResult<()> {
+        let mut state = self.state();
+        if self
+            .capacity
+            .max_size
+            .is_some_and(|max| bytes > 0 && state.total.bytes + bytes > max)
+        {
+            return Err(FsError::NoSpace);
+        }
+        state.total.bytes += bytes;
+        Ok(())
+    }
+
+    /// Free what [`Self::reserve`] charged.
+    pub fn unreserve(&self, bytes: u64) {
+        self.state().total.bytes -= bytes;
+    }
+
+    /// A node lost its last link.
+    fn forget(&self, ino: u64) {
+        let mut state = self.state();
+        if let Some(charge) = state.nodes.get(&ino).copied() {
+            state.remove(ino);
+            self.update_graces(&mut state, charge.uid, charge.gid);
+        }
+    }
+}
+
+/// Walk the whole tree, hard links are counted once.
+async fn count_usage(fs: &Arc<dyn Filesystem>) -> FsResult<State> {
+    let mut state = State::default();
+    let root = fs.get_attr(ROOT_INODE).await?;
+    state.add(ROOT_INODE, Charge::new(&root));
+    let mut seen = HashSet::new();
+    let mut dirs = vec![ROOT_INODE];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs.clone().read_dir_plus(dir, 0).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            if entry.name == "." || entry.name == ".." || !seen.insert(entry.ino) {
+                continue;
+            }
+            state.add(entry.ino, Charge::new(&entry.attr));
+            if entry.kind == FileType::Directory {
+                dirs.push(entry.ino);
+            }
+        }
+    }
+    Ok(state)
+}
+
+#[async_trait]
+impl Filesystem for CapacityFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.inner.exists(ino)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.inner.is_dir(ino)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.inner.is_file(ino)
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let (uid, gid) = (create_attr.uid, create_attr.gid);
+        self.add_node(uid, gid)?;
+        match self
+            .inner
+            .create(parent, name, create_attr, read, write)
+            .await
+        {
+            Ok((fh, attr)) => {
+                self.node_added(attr.ino, uid, gid);
+                Ok((fh, attr))
+            }
+            Err(err) => {
+                self.cancel_node(uid, gid);
+                Err(err)
+            }
+        }
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        let (uid, gid) = (create_attr.uid, create_attr.gid);
+        self.add_node(uid, gid)?;
+        match self.inner.symlink(parent, name, target, create_attr).await {
+            Ok(attr) => {
+                self.node_added(attr.ino, uid, gid);
+                Ok(attr)
+            }
+            Err(err) => {
+                self.cancel_node(uid, gid);
+                Err(err)
+            }
+        }
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        self.inner.read_link(ino).await
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        self.inner.link(ino, new_parent, new_name).await
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        self.inner.find_by_name(parent, name).await
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        self.inner.len(ino).await
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        let attr = self.inner.find_by_name(parent, name).await?;
+        self.inner.remove_dir(parent, name).await?;
+        if let Some(attr) = attr {
+            self.forget(attr.ino);
+        }
+        Ok(())
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        let attr = self.inner.find_by_name(parent, name).await?;
+        self.inner.remove_file(parent, name).await?;
+        if let Some(attr) = attr.filter(|attr| attr.nlink <= 1) {
+            self.forget(attr.ino);
+        }
+        Ok(())
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        self.inner.exists_by_name(parent, name).await
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        self.inner.clone().read_dir(ino, cookie).await
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        self.inner.clone().read_dir_plus(ino, cookie).await
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        self.inner.get_attr(ino).await
+    }
+
+    async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        let old = if set_attr.uid.is_some() || set_attr.gid.is_some() {
+            Some(self.chown(ino, set_attr.uid, set_attr.gid).await?)
+        } else {
+            None
+        };
+        let res = if set_attr.size.is_some() {
+            let _guard = self.locks.get(ino).write().await;
+            // a larger size is a hole, it doesn't take space
+            self.allocating(ino, 0, self.inner.set_attr(ino, set_attr))
+                .await
+        } else {
+            self.inner.set_attr(ino, set_attr).await
+        };
+        if let (Err(_), Some(old)) = (&res, old) {
+            // back to the owner it has
+            let _ = self.chown(ino, Some(old.uid), Some(old.gid)).await;
+        }
+        res
+    }
+
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        self.inner.read(ino, offset, buf, handle).await
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        self.inner.release(handle).await
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.inner.is_read_handle(fh).await
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        self.inner.is_write_handle(fh).await
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let _guard = self.locks.get(ino).write().await;
+        let more = self.unallocated(ino, offset, buf.len() as u64).await?;
+        self.allocating(ino, more, self.inner.write(ino, offset, buf, handle))
+            .await
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        self.inner.flush(handle).await
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        let _guard = self.locks.get(dest_ino).write().await;
+        // it copies less if the source ends before
+        let src_size = self.inner.get_attr(src_ino).await?.size;
+        let len = min(size as u64, src_size.saturating_sub(src_offset));
+        let more = self.unallocated(dest_ino, dest_offset, len).await?;
+        self.allocating(
+            dest_ino,
+            more,
+            self.inner.copy_file_range(
+                src_ino,
+                src_offset,
+                dest_ino,
+                dest_offset,
+                size,
+                src_fh,
+                dest_fh,
+            ),
+        )
+        .await
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        self.inner.open(ino, read, write).await
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        let _guard = self.locks.get(ino).write().await;
+        self.allocating(ino, 0, self.inner.set_len(ino, size)).await
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        let _guard = self.locks.get(ino).write().await;
+        let more = match mode {
+            FallocateMode::PunchHole => 0,
+            _ => self.unallocated(ino, offset, len).await?,
+        };
+        self.allocating(
+            ino,
+            more,
+            self.inner.fallocate(ino, offset, len, mode, handle),
+        )
+        .await
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        let attr = self.inner.find_by_name(parent, name).await?;
+        let replaced = self.inner.find_by_name(new_parent, new_name).await?;
+        self.inner
+            .rename(parent, name, new_parent, new_name)
+            .await?;
+        if let Some(replaced) = replaced {
+            let same = attr.is_some_and(|attr| attr.ino == replaced.ino);
+            if !same && (replaced.kind == FileType::Directory || replaced.nlink <= 1) {
+                self.forget(replaced.ino);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        self.inner.get_xattr(ino, name).await
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        self.inner.set_xattr(ino, name, value, mode).await
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        self.inner.list_xattr(ino).await
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        self.inner.remove_xattr(ino, name).await
+    }
+
+    /// The capacity, or what `inner` has if it's less.
+    async fn seek(&self, ino: u64, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+        self.inner.seek(ino, offset, whence).await
+    }
+
+    async fn statfs(&self) -> FsResult<StatFs> {
+        let mut stat = self.inner.statfs().await?;
+        if stat.bsize == 0 {
+            stat.bsize = BLOCK_SIZE;
+        }
+        let usage = self.state().total;
+        if let Some(max_size) = self.capacity.max_size {
+            let bsize = u64::from(stat.bsize);
+            let blocks = max_size / bsize;
+            let free = blocks.saturating_sub(usage.bytes.div_ceil(bsize));
+            stat.blocks = blocks;
+            stat.bfree = min(stat.bfree, free);
+            stat.bavail = min(stat.bavail, free);
+        }
+        if let Some(max_inodes) = self.capacity.max_inodes {
+            let free = max_inodes.saturating_sub(usage.inodes);
+            stat.files = max_inodes;
+            stat.ffree = min(stat.ffree, free);
+        }
+        Ok(stat)
+    }
+
+    fn max_name_len(&self) -> usize {
+        self.inner.max_name_len()
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        self.inner.referenced(ino)
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        // the charge was already removed with the last link
+        self.inner.forgotten(ino).await;
+    }
+
+    fn opened_dir(&self, ino: u64) {
+        self.inner.opened_dir(ino);
+    }
+
+    fn released_dir(&self, ino: u64) {
+        self.inner.released_dir(ino);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fs::memory::MemoryFilesystem;
+    use crate::fs::testing::file_attr;
+    use crate::quota::Quotas;
+
+    const UID: u32 = 1000;
+
+    async fn mount(limits: QuotaLimits, grace_period: Duration) -> Arc<CapacityFilesystem> {
+        let capacity = Capacity {
+            quotas: Quotas {
+                grace_period,
+                users: HashMap::from([(UID, limits)]),
+                ..Quotas::default()
+            },
+            ..Capacity::default()
+        };
+        CapacityFilesystem::new(MemoryFilesystem::new(), capacity)
+            .await
+            .unwrap()
+    }
+
+    /// A file of the user with the quota, opened for writing.
+    async fn create(fs: &CapacityFilesystem, name: &str) -> FsResult<(u64, u64)> {
+        let (fh, attr) = fs
+            .create(ROOT_INODE, name, file_attr(UID), false, true)
+            .await?;
+        Ok((fh, attr.ino))
+    }
+
+    fn status(fs: &CapacityFilesystem) -> QuotaStatus {
+        fs.quota_status()
+            .into_iter()
+            .find(|status| status.owner == Owner::User(UID))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn soft_and_hard_inodes() {
+        let limits = QuotaLimits {
+            soft_inodes: Some(2),
+            hard_inodes: Some(4),
+            ..QuotaLimits::default()
+        };
+        let fs = mount(limits, Duration::from_secs(60)).await;
+        for name in ["a", "b"] {
+            create(&fs, name).await.unwrap();
+        }
+        assert_eq!(status(&fs).inodes_grace, None);
+        // over the soft limit it starts the grace period
+        create(&fs, "c").await.unwrap();
+        assert!(status(&fs).inodes_grace.is_some());
+        create(&fs, "d").await.unwrap();
+        assert!(matches!(
+            create(&fs, "e").await,
+            Err(FsError::QuotaExceeded)
+        ));
+        assert_eq!(status(&fs).usage.inodes, 4);
+        // back under it, it ends
+        for name in ["c", "d"] {
+            fs.remove_file(ROOT_INODE, name).await.unwrap();
+        }
+        assert_eq!(status(&fs).usage.inodes, 2);
+        assert_eq!(status(&fs).inodes_grace, None);
+    }
+
+    #[tokio::test]
+    async fn soft_and_hard_bytes() {
+        let limits = QuotaLimits {
+            soft_bytes: Some(8192),
+            hard_bytes: Some(16384),
+            ..QuotaLimits::default()
+        };
+        let fs = mount(limits, Duration::from_secs(60)).await;
+        let (fh, ino) = create(&fs, "a").await.unwrap();
+        fs.write(ino, 0, &[1; 12288], fh).await.unwrap();
+        assert_eq!(status(&fs).usage.bytes, 12288);
+        assert!(status(&fs).bytes_grace.is_some());
+        assert!(matches!(
+            fs.write(ino, 12288, &[1; 8192], fh).await,
+            Err(FsError::QuotaExceeded)
+        ));
+        // overwriting takes no more space
+        fs.write(ino, 0, &[2; 4096], fh).await.unwrap();
+        fs.set_attr(ino, SetFileAttr::default().with_size(4096))
+            .await
+            .unwrap();
+        assert_eq!(status(&fs).usage.bytes, 4096);
+        assert_eq!(status(&fs).bytes_grace, None);
+        fs.release(fh).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn grace_period_ends() {
+        let limits = QuotaLimits {
+            soft_inodes: Some(1),
+            hard_inodes: Some(10),
+            ..QuotaLimits::default()
+        };
+        let fs = mount(limits, Duration::from_millis(50)).await;
+        create(&fs, "a").await.unwrap();
+        create(&fs, "b").await.unwrap();
+        create(&fs, "c").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // after it the soft limit is enforced like the hard one
+        assert!(matches!(
+            create(&fs, "d").await,
+            Err(FsError::QuotaExceeded)
+        ));
+        // until the usage goes under it, then it starts again
+        fs.remove_file(ROOT_INODE, "b").await.unwrap();
+        fs.remove_file(ROOT_INODE, "c").await.unwrap();
+        assert_eq!(status(&fs).inodes_grace, None);
+        create(&fs, "d").await.unwrap();
+        assert!(status(&fs).inodes_grace.is_some());
+    }
+}
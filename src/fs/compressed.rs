@@ -0,0 +1,1000 @@
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use async_trait::async_trait;
+use futures_util::{future, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+use crate::compression::{self, Compression, Method};
+use crate::fs::{copy_with_writes, fallocate_with_writes, Filesystem, StripedLocks, ROOT_INODE};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntryPlus, DirectoryEntryPlusStream, DirectoryEntryStream,
+    FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr, SetXattrMode, StatFs,
+};
+
+/// Size of the uncompressed block, each one is compressed separately so we can read and write at
+/// any offset by only touching the blocks in that range.
+const BLOCK_SIZE: u64 = 64 * 1024;
+/// The space of a block is rounded up to this, so it can grow a bit and still be rewritten in
+/// place.
+const SLOT_ALIGN: u64 = 512;
+
+/// Stored in the root of the inner filesystem, hidden from the user. It marks the filesystem as
+/// compressed, so we don't mix compressed and plain files.
+const MARKER_FILE_NAME: &str = ".compression";
+const FORMAT_VERSION: u32 = 1;
+
+const FILE_MAGIC: &[u8; 4] = b"FCZ1";
+/// `magic | size`
+const FILE_HEADER_LEN: u64 = 12;
+/// `block index | plain len | stored len | capacity | method`
+const RECORD_HEADER_LEN: u64 = 21;
+/// Block index of a record which is not used anymore, its space can be reused.
+const FREE_RECORD: u64 = u64::MAX;
+
+#[derive(Serialize, Deserialize)]
+struct MarkerFile {
+    version: u32,
+}
+
+struct Handle {
+    read: bool,
+    write: bool,
+}
+
+/// Header of a block in the inner file, followed by `capacity` bytes of which the first
+/// `stored_len` are the block.
+struct Record {
+    index: u64,
+    plain_len: u32,
+    stored_len: u32,
+    capacity: u32,
+    method: Method,
+}
+
+impl Record {
+    fn free(capacity: u64) -> Self {
+        #[allow(clippy::cast_possible_truncation)]
+        Self {
+            index: FREE_RECORD,
+            plain_len: 0,
+            stored_len: 0,
+            capacity: capacity as u32,
+            method: Method::Stored,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut buf = Vec::with_capacity(RECORD_HEADER_LEN as usize);
+        buf.extend_from_slice(&self.index.to_le_bytes());
+        buf.extend_from_slice(&self.plain_len.to_le_bytes());
+        buf.extend_from_slice(&self.stored_len.to_le_bytes());
+        buf.extend_from_slice(&self.capacity.to_le_bytes());
+        buf.push(self.method.to_u8());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> FsResult<Self> {
+        let u32_at = |pos: usize| u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap());
+        Ok(Self {
+            index: u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            plain_len: u32_at(8),
+            stored_len: u32_at(12),
+            capacity: u32_at(16),
+            method: Method::from_u8(buf[20])?,
+        })
+    }
+}
+
+/// Where a record is in the inner file.
+#[derive(Clone, Copy)]
+struct Slot {
+    offset: u64,
+    capacity: u64,
+    stored_len: u64,
+}
+
+impl Slot {
+    const fn end(&self) -> u64 {
+        self.offset + RECORD_HEADER_LEN + self.capacity
+    }
+}
+
+/// Block index and where it is, if it's stored.
+type BlockSlots = Vec<(u64, Option<Slot>)>;
+
+/// Where the blocks of a file are, read from the inner file the first time we need it.
+#[derive(Default)]
+struct Layout {
+    /// The uncompressed size.
+    size: u64,
+    /// By block index. Blocks which are missing are all zeros, like the data after the end of a
+    /// block which was stored shorter.
+    blocks: BTreeMap<u64, Slot>,
+    /// Records we can reuse.
+    free: Vec<Slot>,
+    /// Old records of blocks whose move was interrupted before they were freed, they are freed
+    /// when the layout is taken to change it.
+    stale: Vec<Slot>,
+    /// Size of the inner file, 0 if it doesn't have the header yet.
+    end: u64,
+}
+
+impl Layout {
+    /// Find space for a record of `len` bytes, the smallest free slot it fits in or a new one at
+    /// the end.
+    fn allocate(&mut self, len: u64) -> Slot {
+        let best = self
+            .free
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.capacity >= len)
+            .min_by_key(|(_, slot)| slot.capacity)
+            .map(|(i, _)| i);
+        let mut slot = if let Some(i) = best {
+            self.free.swap_remove(i)
+        } else {
+            let slot = Slot {
+                offset: max(self.end, FILE_HEADER_LEN),
+                capacity: len.next_multiple_of(SLOT_ALIGN),
+                stored_len: 0,
+            };
+            self.end = slot.end();
+            slot
+        };
+        slot.stored_len = len;
+        slot
+    }
+}
+
+/// Compresses the content of the files of another [`Filesystem`].
+///
+/// Files are split into blocks of [`BLOCK_SIZE`] bytes, each compressed separately and stored as
+/// a record after a small header with the uncompressed size. So reading at an offset only
+/// decompresses the blocks in that range. When a block grows over the space it had it's moved to
+/// a free record or to the end of the file, the space it leaves is reused for other blocks.
+/// Blocks which don't compress well are stored as they are.
+///
+/// Sizes reported to the user are the uncompressed ones, while `blocks` is what the inner
+/// filesystem uses.
+pub(crate) struct CompressedFilesystem {
+    inner: Arc<dyn Filesystem>,
+    compression: Compression,
+    /// The inner handles are always opened for read, as we need to read partially written blocks.
+    /// Here we keep what the user asked for.
+    handles: RwLock<HashMap<u64, Handle>>,
+    /// Loaded on first use and kept until the file is removed.
+    layouts: RwLock<HashMap<u64, Layout>>,
+    /// Operations on the same inode are serialized with these, picked by `ino`.
+    locks: StripedLocks,
+}
+
+impl CompressedFilesystem {
+    /// Wraps `inner`, new blocks are compressed with `compression`. It fails if `inner` already
+    /// has content which is not compressed.
+    pub async fn new(inner: Arc<dyn Filesystem>, compression: Compression) -> FsResult<Arc<Self>> {
+        if let Some(attr) = inner.find_by_name(ROOT_INODE, MARKER_FILE_NAME).await? {
+            check_marker(&*inner, attr).await?;
+        } else {
+            if inner.len(ROOT_INODE).await? > 0 {
+                return Err(FsError::Compression(
+                    "cannot enable compression over existing uncompressed content",
+                ));
+            }
+            create_marker(&*inner).await?;
+        }
+
+        Ok(Arc::new(Self {
+            inner,
+            compression,
+            handles: RwLock::new(HashMap::new()),
+            layouts: RwLock::new(HashMap::new()),
+            locks: StripedLocks::new(),
+        }))
+    }
+
+    fn handles(&self) -> RwLockReadGuard<'_, HashMap<u64, Handle>> {
+        self.handles.read().expect("handles lock poisoned")
+    }
+
+    fn handles_mut(&self) -> RwLockWriteGuard<'_, HashMap<u64, Handle>> {
+        self.handles.write().expect("handles lock poisoned")
+    }
+
+    fn layouts(&self) -> RwLockReadGuard<'_, HashMap<u64, Layout>> {
+        self.layouts.read().expect("layouts lock poisoned")
+    }
+
+    fn layouts_mut(&self) -> RwLockWriteGuard<'_, HashMap<u64, Layout>> {
+        self.layouts.write().expect("layouts lock poisoned")
+    }
+
+    fn check_handle(&self, fh: u64, write: bool) -> FsResult<()> {
+        match self.handles().get(&fh) {
+            Some(handle) if (write && handle.write) || (!write && handle.read) => Ok(()),
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read_exact(&self, ino: u64, fh: u64, offset: u64, len: u64) -> FsResult<Vec<u8>> {
+        let mut buf = vec![0; len as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            let len = self
+                .inner
+                .read(ino, offset + read as u64, &mut buf[read..], fh)
+                .await?;
+            if len == 0 {
+                return Err(FsError::Compression("unexpected end of compressed file"));
+            }
+            read += len;
+        }
+        Ok(buf)
+    }
+
+    async fn write_all(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> FsResult<()> {
+        let mut written = 0;
+        while written < data.len() {
+            written += self
+                .inner
+                .write(ino, offset + written as u64, &data[written..], fh)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn read_size(&self, ino: u64, fh: u64) -> FsResult<u64> {
+        let header = self.read_exact(ino, fh, 0, FILE_HEADER_LEN).await?;
+        if &header[..4] != FILE_MAGIC {
+            return Err(FsError::Compression("not a compressed file"));
+        }
+        Ok(u64::from_le_bytes(header[4..].try_into().unwrap()))
+    }
+
+    async fn write_size(&self, ino: u64, fh: u64, layout: &mut Layout, size: u64) -> FsResult<()> {
+        let mut header = FILE_MAGIC.to_vec();
+        header.extend_from_slice(&size.to_le_bytes());
+        self.write_all(ino, fh, 0, &header).await?;
+        layout.size = size;
+        layout.end = max(layout.end, FILE_HEADER_LEN);
+        Ok(())
+    }
+
+    /// The uncompressed size, from the layout if we have it, or else from the header.
+    async fn size(&self, ino: u64) -> FsResult<u64> {
+        let _guard = self.locks.get(ino).read().await;
+        let cached = self.layouts().get(&ino).map(|layout| layout.size);
+        if let Some(size) = cached {
+            return Ok(size);
+        }
+        if self.inner.get_attr(ino).await?.size == 0 {
+            return Ok(0);
+        }
+        let fh = self.inner.open(ino, true, false).await?;
+        let res = self.read_size(ino, fh).await;
+        self.inner.release(fh).await?;
+        res
+    }
+
+    async fn plain_attr(&self, mut attr: FileAttr) -> FsResult<FileAttr> {
+        if attr.kind == FileType::RegularFile {
+            attr.size = self.size(attr.ino).await?;
+        }
+        Ok(attr)
+    }
+
+    /// Walk the records of the inner file.
+    async fn load_layout(&self, ino: u64, fh: u64) -> FsResult<Layout> {
+        let len = self.inner.get_attr(ino).await?.size;
+        if len == 0 {
+            return Ok(Layout::default());
+        }
+        let mut layout = Layout {
+            size: self.read_size(ino, fh).await?,
+            end: FILE_HEADER_LEN,
+            ..Layout::default()
+        };
+        while layout.end < len {
+            let header = self
+                .read_exact(ino, fh, layout.end, RECORD_HEADER_LEN)
+                .await?;
+            let record = Record::decode(&header)?;
+            let slot = Slot {
+                offset: layout.end,
+                capacity: u64::from(record.capacity),
+                stored_len: u64::from(record.stored_len),
+            };
+            if record.index == FREE_RECORD {
+                layout.free.push(slot);
+            } else if let Some(&other) = layout.blocks.get(&record.index) {
+                // the block was moved, keep the record we can read
+                if self.read_block(ino, fh, record.index, other).await.is_ok() {
+                    layout.stale.push(slot);
+                } else {
+                    layout.stale.push(other);
+                    layout.blocks.insert(record.index, slot);
+                }
+            } else {
+                layout.blocks.insert(record.index, slot);
+            }
+            layout.end = slot.end();
+        }
+        debug!(ino, blocks = layout.blocks.len(), "loaded layout");
+        Ok(layout)
+    }
+
+    async fn ensure_layout(&self, ino: u64, fh: u64) -> FsResult<()> {
+        if self.layouts().contains_key(&ino) {
+            return Ok(());
+        }
+        let layout = self.load_layout(ino, fh).await?;
+        self.layouts_mut().entry(ino).or_insert(layout);
+        Ok(())
+    }
+
+    /// Take the layout out to change it, the caller must hold the write lock of `ino` and put it
+    /// back after the change succeeds. If it fails it's loaded again next time.
+    async fn take_layout(&self, ino: u64, fh: u64) -> FsResult<Layout> {
+        let layout = self.layouts_mut().remove(&ino);
+        let mut layout = match layout {
+            Some(layout) => layout,
+            None => self.load_layout(ino, fh).await?,
+        };
+        for slot in mem::take(&mut layout.stale) {
+            self.free_slot(ino, fh, &mut layout, slot).await?;
+        }
+        Ok(layout)
+    }
+
+    /// The size and the slots of the blocks in `offset..offset + len`, up to the end of the file.
+    fn slots(&self, ino: u64, offset: u64, len: u64) -> FsResult<(u64, BlockSlots)> {
+        let layouts = self.layouts();
+        let layout = layouts.get(&ino).ok_or(FsError::InodeNotFound)?;
+        let end = min(layout.size, offset + len);
+        if offset >= end {
+            return Ok((layout.size, vec![]));
+        }
+        let slots = (offset / BLOCK_SIZE..=(end - 1) / BLOCK_SIZE)
+            .map(|index| (index, layout.blocks.get(&index).copied()))
+            .collect();
+        Ok((layout.size, slots))
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read_block(&self, ino: u64, fh: u64, index: u64, slot: Slot) -> FsResult<Vec<u8>> {
+        let data = self
+            .read_exact(ino, fh, slot.offset, RECORD_HEADER_LEN + slot.stored_len)
+            .await?;
+        let record = Record::decode(&data)?;
+        if record.index != index {
+            return Err(FsError::Compression("block is not where expected"));
+        }
+        compression::decompress(
+            record.method,
+            &data[RECORD_HEADER_LEN as usize..],
+            record.plain_len as usize,
+        )
+    }
+
+    /// Compress and store block `index`, in place if it still fits. When it's moved the old record
+    /// is freed after the new one is written, so one of them is always there.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn store_block(
+        &self,
+        ino: u64,
+        fh: u64,
+        layout: &mut Layout,
+        index: u64,
+        data: &[u8],
+    ) -> FsResult<()> {
+        let (method, stored) = compression::compress(self.compression, data)?;
+        let len = stored.len() as u64;
+        let old = layout.blocks.remove(&index);
+        let slot = match old {
+            Some(mut slot) if slot.capacity >= len => {
+                slot.stored_len = len;
+                slot
+            }
+            _ => layout.allocate(len),
+        };
+        let mut buf = Record {
+            index,
+            plain_len: data.len() as u32,
+            stored_len: len as u32,
+            capacity: slot.capacity as u32,
+            method,
+        }
+        .encode();
+        buf.extend(stored);
+        if slot.end() == layout.end {
+            // it's the last one, the inner file must reach its end
+            buf.resize((RECORD_HEADER_LEN + slot.capacity) as usize, 0);
+        }
+        self.write_all(ino, fh, slot.offset, &buf).await?;
+        layout.blocks.insert(index, slot);
+        if let Some(old) = old.filter(|old| old.offset != slot.offset) {
+            self.free_slot(ino, fh, layout, old).await?;
+        }
+        Ok(())
+    }
+
+    async fn free_slot(&self, ino: u64, fh: u64, layout: &mut Layout, slot: Slot) -> FsResult<()> {
+        self.write_all(ino, fh, slot.offset, &Record::free(slot.capacity).encode())
+            .await?;
+        layout.free.push(slot);
+        Ok(())
+    }
+
+    /// Give back to the inner filesystem the free records at the end of the file.
+    async fn trim(&self, ino: u64, layout: &mut Layout) -> FsResult<()> {
+        let end = layout.end;
+        layout.free.sort_unstable_by_key(|slot| slot.offset);
+        while let Some(slot) = layout.free.last().filter(|slot| slot.end() == layout.end) {
+            layout.end = slot.offset;
+            layout.free.pop();
+        }
+        if layout.end < end {
+            self.inner.set_len(ino, layout.end).await?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` at `offset`. Blocks which are only partially overwritten are decompressed,
+    /// patched and compressed again. Nothing is written for a gap after the end of the file, the
+    /// missing blocks are zeros.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn write_blocks(
+        &self,
+        ino: u64,
+        fh: u64,
+        layout: &mut Layout,
+        offset: u64,
+        buf: &[u8],
+    ) -> FsResult<()> {
+        let end = offset + buf.len() as u64;
+        for index in offset / BLOCK_SIZE..=(end - 1) / BLOCK_SIZE {
+            let block_start = index * BLOCK_SIZE;
+            let from = max(offset, block_start);
+            let to = min(end, block_start + BLOCK_SIZE);
+            let existing_end = min(layout.size, block_start + BLOCK_SIZE);
+            let mut block = match layout.blocks.get(&index) {
+                Some(slot) if from > block_start || to < existing_end => {
+                    self.read_block(ino, fh, index, *slot).await?
+                }
+                _ => Vec::new(),
+            };
+            if (block.len() as u64) < to - block_start {
+                block.resize((to - block_start) as usize, 0);
+            }
+            block[(from - block_start) as usize..(to - block_start) as usize]
+                .copy_from_slice(&buf[(from - offset) as usize..(to - offset) as usize]);
+            self.store_block(ino, fh, layout, index, &block).await?;
+        }
+        if end > layout.size {
+            self.write_size(ino, fh, layout, end).await?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn resize(&self, ino: u64, fh: u64, layout: &mut Layout, new_size: u64) -> FsResult<()> {
+        if new_size == layout.size {
+            return Ok(());
+        }
+        if new_size == 0 {
+            self.inner.set_len(ino, 0).await?;
+            *layout = Layout::default();
+            return Ok(());
+        }
+        if new_size < layout.size {
+            for (_, slot) in layout.blocks.split_off(&new_size.div_ceil(BLOCK_SIZE)) {
+                self.free_slot(ino, fh, layout, slot).await?;
+            }
+            let index = new_size / BLOCK_SIZE;
+            let len = (new_size % BLOCK_SIZE) as usize;
+            if let Some(slot) = layout.blocks.get(&index).copied().filter(|_| len > 0) {
+                // the last block is now shorter, drop what's after the end so it's not visible if
+                // the file is extended again
+                let mut block = self.read_block(ino, fh, index, slot).await?;
+                if block.len() > len {
+                    block.truncate(len);
+                    self.store_block(ino, fh, layout, index, &block).await?;
+                }
+            }
+        }
+        self.write_size(ino, fh, layout, new_size).await?;
+        self.trim(ino, layout).await
+    }
+
+    /// Forget the layout of a file which is not there anymore, the inner filesystem may reuse the
+    /// inode. Not while it has other links or it's still opened.
+    async fn forget(&self, ino: u64) {
+        let _guard = self.locks.get(ino).write().await;
+        self.layouts_mut().remove(&ino);
+    }
+}
+
+/// [`MARKER_FILE_NAME`] is reserved in the root.
+fn is_reserved(parent: u64, name: &str) -> bool {
+    parent == ROOT_INODE && name == MARKER_FILE_NAME
+}
+
+#[async_trait]
+impl Filesystem for CompressedFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.inner.exists(ino)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.inner.is_dir(ino)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.inner.is_file(ino)
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        if is_reserved(parent, name) {
+            return Err(FsError::InvalidInput("name is reserved for compression"));
+        }
+        let (fh, attr) = self
+            .inner
+            .create(parent, name, create_attr, read || write, write)
+            .await?;
+        if attr.kind == FileType::RegularFile {
+            self.layouts_mut().insert(attr.ino, Layout::default());
+        }
+        if read || write {
+            self.handles_mut().insert(fh, Handle { read, write });
+        }
+        Ok((fh, attr))
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        if is_reserved(parent, name) {
+            return Err(FsError::InvalidInput("name is reserved for compression"));
+        }
+        self.inner.symlink(parent, name, target, create_attr).await
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        self.inner.read_link(ino).await
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        if is_reserved(new_parent, new_name) {
+            return Err(FsError::InvalidInput("name is reserved for compression"));
+        }
+        let attr = self.inner.link(ino, new_parent, new_name).await?;
+        self.plain_attr(attr).await
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        if is_reserved(parent, name) {
+            return Ok(None);
+        }
+        match self.inner.find_by_name(parent, name).await? {
+            Some(attr) => Ok(Some(self.plain_attr(attr).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        let len = self.inner.len(ino).await?;
+        if ino == ROOT_INODE && self.inner.exists_by_name(ino, MARKER_FILE_NAME).await? {
+            return Ok(len - 1);
+        }
+        Ok(len)
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        self.inner.remove_dir(parent, name).await
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        let attr = self.inner.find_by_name(parent, name).await?;
+        self.inner.remove_file(parent, name).await?;
+        if let Some(attr) = attr {
+            if !self.inner.exists(attr.ino) {
+                self.forget(attr.ino).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        if is_reserved(parent, name) {
+            return Ok(false);
+        }
+        self.inner.exists_by_name(parent, name).await
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        let stream = self.inner.clone().read_dir(ino, cookie).await?;
+        if ino != ROOT_INODE {
+            return Ok(stream);
+        }
+        Ok(stream
+            .filter(|entry| {
+                future::ready(
+                    entry
+                        .as_ref()
+                        .map_or(true, |entry| entry.name != MARKER_FILE_NAME),
+                )
+            })
+            .boxed())
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        let stream = self.inner.clone().read_dir_plus(ino, cookie).await?;
+        Ok(stream
+            .filter_map(move |entry| {
+                let fs = self.clone();
+                async move {
+                    match entry {
+                        Ok(entry) if is_reserved(ino, &entry.name) => None,
+                        Ok(entry) => Some(
+                            fs.plain_attr(entry.attr)
+                                .await
+                                .map(|attr| DirectoryEntryPlus { attr, ..entry }),
+                        ),
+                        Err(err) => Some(Err(err)),
+                    }
+                }
+            })
+            .boxed())
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        self.plain_attr(self.inner.get_attr(ino).await?).await
+    }
+
+    async fn set_attr(&self, ino: u64, mut set_attr: SetFileAttr) -> FsResult<()> {
+        if let Some(size) = set_attr.size.take() {
+            self.set_len(ino, size).await?;
+        }
+        self.inner.set_attr(ino, set_attr).await
+    }
+
+    #[instrument(skip(self, buf))]
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        self.check_handle(handle, false)?;
+        let _guard = self.locks.get(ino).read().await;
+        self.ensure_layout(ino, handle).await?;
+        let (size, slots) = self.slots(ino, offset, buf.len() as u64)?;
+        let end = min(size, offset + buf.len() as u64);
+        let mut pos = offset;
+        for (index, slot) in slots {
+            let block_start = index * BLOCK_SIZE;
+            let block = match slot {
+                Some(slot) => self.read_block(ino, handle, index, slot).await?,
+                None => Vec::new(),
+            };
+            let from = (pos - block_start) as usize;
+            let to = (min(end, block_start + BLOCK_SIZE) - block_start) as usize;
+            let dest = &mut buf[(pos - offset) as usize..][..to - from];
+            // after the data we have it's zeros
+            let data = block.get(from..min(block.len(), to)).unwrap_or_default();
+            dest[..data.len()].copy_from_slice(data);
+            dest[data.len()..].fill(0);
+            pos += (to - from) as u64;
+        }
+        Ok((pos - offset) as usize)
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        self.handles_mut().remove(&handle);
+        self.inner.release(handle).await
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.handles().get(&fh).is_some_and(|h| h.read)
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        self.handles().get(&fh).is_some_and(|h| h.write)
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        self.check_handle(handle, true)?;
+        if buf.is_empty() {
+            // no-op
+            return Ok(0);
+        }
+        let _guard = self.locks.get(ino).write().await;
+        let mut layout = self.take_layout(ino, handle).await?;
+        self.write_blocks(ino, handle, &mut layout, offset, buf)
+            .await?;
+        self.layouts_mut().insert(ino, layout);
+        Ok(buf.len())
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        self.inner.flush(handle).await
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        copy_with_writes(
+            self,
+            src_ino,
+            src_offset,
+            dest_ino,
+            dest_offset,
+            size,
+            src_fh,
+            dest_fh,
+        )
+        .await
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if !read && !write {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        let fh = self.inner.open(ino, true, write).await?;
+        self.handles_mut().insert(fh, Handle { read, write });
+        Ok(fh)
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        let fh = self.inner.open(ino, true, true).await?;
+        let res = {
+            let _guard = self.locks.get(ino).write().await;
+            match self.take_layout(ino, fh).await {
+                Ok(mut layout) => self.resize(ino, fh, &mut layout, size).await.map(|()| {
+                    self.layouts_mut().insert(ino, layout);
+                }),
+                Err(err) => Err(err),
+            }
+        };
+        self.inner.release(fh).await?;
+        res
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        // the content is compressed, so the zeros are too
+        fallocate_with_writes(self, ino, offset, len, mode, handle).await
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        if is_reserved(new_parent, new_name) {
+            return Err(FsError::InvalidInput("name is reserved for compression"));
+        }
+        let replaced = self.inner.find_by_name(new_parent, new_name).await?;
+        let src = self.inner.find_by_name(parent, name).await?;
+        self.inner
+            .rename(parent, name, new_parent, new_name)
+            .await?;
+        if let Some(replaced) = replaced {
+            if src.is_some_and(|src| src.ino != replaced.ino) {
+                self.forget(replaced.ino).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        self.inner.get_xattr(ino, name).await
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        self.inner.set_xattr(ino, name, value, mode).await
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        self.inner.list_xattr(ino).await
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        self.inner.remove_xattr(ino, name).await
+    }
+
+    async fn statfs(&self) -> FsResult<StatFs> {
+        self.inner.statfs().await
+    }
+
+    fn max_name_len(&self) -> usize {
+        self.inner.max_name_len()
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        self.inner.referenced(ino)
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        self.inner.forgotten(ino).await;
+        if !self.inner.exists(ino) {
+            self.forget(ino).await;
+        }
+    }
+
+    fn opened_dir(&self, ino: u64) {
+        self.inner.opened_dir(ino);
+    }
+
+    fn released_dir(&self, ino: u64) {
+        self.inner.released_dir(ino);
+    }
+}
+
+async fn check_marker(inner: &dyn Filesystem, attr: FileAttr) -> FsResult<()> {
+    #[allow(clippy::cast_possible_truncation)]
+    let mut buf = vec![0; attr.size as usize];
+    let fh = inner.open(attr.ino, true, false).await?;
+    let res = inner.read(attr.ino, 0, &mut buf, fh).await;
+    inner.release(fh).await?;
+    buf.truncate(res?);
+    let marker: MarkerFile = bincode::deserialize(&buf)?;
+    if marker.version != FORMAT_VERSION {
+        return Err(FsError::Compression(
+            "unsupported compression format version",
+        ));
+    }
+    Ok(())
+}
+
+async fn create_marker(inner: &dyn Filesystem) -> FsResult<()> {
+    let data = bincode::serialize(&MarkerFile {
+        version: FORMAT_VERSION,
+    })?;
+    let (fh, attr) = inner
+        .create(
+            ROOT_INODE,
+            MARKER_FILE_NAME,
+            CreateFileAttr {
+                kind: FileType::RegularFile,
+                perm: 0o600,
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
+                rdev: 0,
+                flags: 0,
+            },
+            false,
+            true,
+        )
+        .await?;
+    let res = inner.write(attr.ino, 0, &data, fh).await;
+    let res = match res {
+        Ok(_) => inner.flush(fh).await,
+        Err(err) => Err(err),
+    };
+    inner.release(fh).await?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryFilesystem;
+    use crate::fs::testing::{file_attr, noise, read_all};
+
+    async fn round_trip(compression: Compression) {
+        let inner: Arc<dyn Filesystem> = MemoryFilesystem::new();
+        let fs = CompressedFilesystem::new(inner.clone(), compression)
+            .await
+            .unwrap();
+        let (fh, attr) = fs
+            .create(ROOT_INODE, "a", file_attr(0), true, true)
+            .await
+            .unwrap();
+        let mut data = b"compress me ".repeat(15_000);
+        fs.write(attr.ino, 0, &data, fh).await.unwrap();
+        // a block which doesn't compress, across a block boundary
+        let noise = noise(BLOCK_SIZE as usize);
+        let offset = BLOCK_SIZE / 2;
+        fs.write(attr.ino, offset, &noise, fh).await.unwrap();
+        data[offset as usize..(offset + BLOCK_SIZE) as usize].copy_from_slice(&noise);
+        fs.release(fh).await.unwrap();
+        assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, data.len() as u64);
+        assert_eq!(read_all(&*fs, attr.ino).await, data);
+        let stored = inner.get_attr(attr.ino).await.unwrap().size;
+        assert!(stored < data.len() as u64);
+
+        // the layout is loaded back from the inner file
+        let fs = CompressedFilesystem::new(inner, compression).await.unwrap();
+        assert_eq!(read_all(&*fs, attr.ino).await, data);
+        fs.set_attr(attr.ino, SetFileAttr::default().with_size(100))
+            .await
+            .unwrap();
+        assert_eq!(read_all(&*fs, attr.ino).await, data[..100]);
+    }
+
+    #[tokio::test]
+    async fn copy_file_range() {
+        let fs = CompressedFilesystem::new(MemoryFilesystem::new(), Compression::Zstd)
+            .await
+            .unwrap();
+        let (src_fh, src) = fs
+            .create(ROOT_INODE, "a", file_attr(0), true, true)
+            .await
+            .unwrap();
+        let data = b"copy me ".repeat(200_000);
+        fs.write(src.ino, 0, &data, src_fh).await.unwrap();
+        let (dest_fh, dest) = fs
+            .create(ROOT_INODE, "b", file_attr(0), true, true)
+            .await
+            .unwrap();
+        // the kernel can ask for more than there is
+        assert_eq!(
+            fs.copy_file_range(src.ino, 0, dest.ino, 0, usize::MAX, src_fh, dest_fh)
+                .await
+                .unwrap(),
+            data.len()
+        );
+        fs.release(dest_fh).await.unwrap();
+        assert_eq!(read_all(&*fs, dest.ino).await, data);
+    }
+
+    #[tokio::test]
+    async fn round_trip_zstd() {
+        round_trip(Compression::Zstd).await;
+    }
+
+    #[tokio::test]
+    async fn round_trip_lz4() {
+        round_trip(Compression::Lz4).await;
+    }
+}
@@ -0,0 +1,1328 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use async_trait::async_trait;
+use fastcdc::v2020::FastCDC;
+use futures_util::{future, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, instrument, warn};
+
+use crate::fs::{copy_with_writes, fallocate_with_writes, Filesystem, StripedLocks, ROOT_INODE};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntryPlus, DirectoryEntryPlusStream, DirectoryEntryStream,
+    FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr, SetXattrMode, StatFs,
+};
+
+/// Stored in the root of the inner filesystem, hidden from the user. It has a directory for each
+/// first byte of the hash, with the chunks in it named by their hash.
+const CHUNKS_DIR: &str = ".chunks";
+/// Chunks are written with this suffix and renamed in place after, so a chunk is never partially
+/// written.
+const TMP_SUFFIX: &str = ".tmp";
+/// A chunk list being saved is first written in [`CHUNKS_DIR`] with the inode of the file and this
+/// suffix as the name, it's the journal.
+const JOURNAL_SUFFIX: &str = ".list";
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+/// Serialized size of a [`ChunkRef`].
+const CHUNK_REF_LEN: u64 = 36;
+/// After this many references to chunks are dropped we look for the chunks which are not used
+/// anymore.
+const GC_THRESHOLD: u64 = 1024;
+
+type Hash = [u8; 32];
+
+/// What the inner file has instead of the content, a list of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct ChunkRef {
+    hash: Hash,
+    len: u32,
+}
+
+/// The chunks of a file.
+struct ChunkList {
+    chunks: Vec<ChunkRef>,
+    /// Where each chunk starts, with the end of the last chunk at the end.
+    starts: Vec<u64>,
+    /// Data appended after the chunks and not cut yet. The chunks are cut from it when it's big
+    /// enough that their boundaries cannot move with more data, and all of it on flush and
+    /// release.
+    tail: Vec<u8>,
+    /// Changed since it was saved in the inner file.
+    dirty: bool,
+}
+
+impl ChunkList {
+    fn new(chunks: Vec<ChunkRef>) -> Self {
+        let mut list = Self {
+            chunks,
+            starts: vec![0],
+            tail: vec![],
+            dirty: false,
+        };
+        list.update_starts(0);
+        list
+    }
+
+    fn update_starts(&mut self, from: usize) {
+        self.starts.truncate(from + 1);
+        let mut pos = self.starts[from];
+        for chunk in &self.chunks[from..] {
+            pos += u64::from(chunk.len);
+            self.starts.push(pos);
+        }
+    }
+
+    /// Size of the content in chunks, the tail is after it.
+    fn stored(&self) -> u64 {
+        self.starts[self.chunks.len()]
+    }
+
+    fn size(&self) -> u64 {
+        self.stored() + self.tail.len() as u64
+    }
+
+    /// Index of the chunk with the byte at `offset`, it must be before the end of the chunks.
+    fn find(&self, offset: u64) -> usize {
+        self.starts.partition_point(|start| *start <= offset) - 1
+    }
+
+    /// Index of the chunk starting at `offset`, the end of the chunks counts as one too.
+    fn boundary(&self, offset: u64) -> Option<usize> {
+        self.starts.binary_search(&offset).ok()
+    }
+
+    /// Replace the chunks in `range`, returns how many were removed.
+    fn splice(&mut self, range: Range<usize>, chunks: Vec<ChunkRef>) -> usize {
+        let from = range.start;
+        let removed = self.chunks.splice(range, chunks).count();
+        self.update_starts(from);
+        self.dirty = true;
+        removed
+    }
+}
+
+struct Handle {
+    ino: u64,
+    read: bool,
+    write: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// Inode of each stored chunk.
+    chunks: HashMap<Hash, u64>,
+    /// Inode of the directories in [`CHUNKS_DIR`], by the first byte of the hash.
+    chunk_dirs: HashMap<u8, u64>,
+    /// Loaded the first time we need them and kept until the file is removed.
+    lists: HashMap<u64, ChunkList>,
+    handles: HashMap<u64, Handle>,
+    /// References to chunks dropped since the last garbage collection.
+    dropped: u64,
+}
+
+/// Deduplicates the content of the files of another [`Filesystem`].
+///
+/// Content is split in chunks with content-defined boundaries (`FastCDC`), so inserting data in a
+/// file only changes the chunks around it. Each chunk is stored once in [`CHUNKS_DIR`], named by
+/// its hash, and the inner file only has the list of chunks. Identical files and the regions they
+/// share are stored once.
+///
+/// Writes cut again only the chunks they touch, appended data is kept in memory until the chunks
+/// can be cut from it. The chunk lists are kept in memory and saved on flush and release, through
+/// a journal so a crash doesn't leave a partially written one. When the range is on chunk
+/// boundaries in both files `copy_file_range` only copies the references to the chunks.
+///
+/// Chunks are not reference counted, [`DedupFilesystem::collect_garbage`] finds the ones not used
+/// anymore. It runs on mount and after enough references are dropped.
+pub(crate) struct DedupFilesystem {
+    inner: Arc<dyn Filesystem>,
+    chunks_dir: u64,
+    state: RwLock<State>,
+    /// Operations on the same inode are serialized with these, picked by `ino`.
+    locks: StripedLocks,
+    /// Storing a chunk is serialized with these, picked by its hash.
+    chunk_locks: StripedLocks,
+    /// Held for read while chunks are stored and referenced and for write while collecting
+    /// garbage, so we don't remove chunks which are about to be used.
+    gc: tokio::sync::RwLock<()>,
+}
+
+impl DedupFilesystem {
+    /// Wraps `inner`. It fails if `inner` already has content which is not deduplicated.
+    pub async fn new(inner: Arc<dyn Filesystem>) -> FsResult<Arc<Self>> {
+        let chunks_dir = if let Some(attr) = inner.find_by_name(ROOT_INODE, CHUNKS_DIR).await? {
+            attr.ino
+        } else {
+            if inner.len(ROOT_INODE).await? > 0 {
+                return Err(FsError::Other(
+                    "cannot enable deduplication over existing content",
+                ));
+            }
+            inner
+                .create(
+                    ROOT_INODE,
+                    CHUNKS_DIR,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await?
+                .1
+                .ino
+        };
+
+        let fs = Self {
+            inner,
+            chunks_dir,
+            state: RwLock::new(State::default()),
+            locks: StripedLocks::new(),
+            chunk_locks: StripedLocks::new(),
+            gc: tokio::sync::RwLock::new(()),
+        };
+        fs.load_chunks().await?;
+        if let Err(err) = fs.collect_garbage().await {
+            warn!(%err, "cannot collect garbage");
+        }
+        Ok(Arc::new(fs))
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().expect("state lock poisoned")
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().expect("state lock poisoned")
+    }
+
+    fn check_handle(&self, fh: u64, ino: u64, write: bool) -> FsResult<()> {
+        match self.state().handles.get(&fh) {
+            Some(handle)
+                if handle.ino == ino && ((write && handle.write) || (!write && handle.read)) =>
+            {
+                Ok(())
+            }
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+
+    /// Find the chunks we have, removing the ones left partially written and restoring the chunk
+    /// lists from the journals if we crashed.
+    async fn load_chunks(&self) -> FsResult<()> {
+        let mut chunks = HashMap::new();
+        let mut chunk_dirs = HashMap::new();
+        let mut journals = vec![];
+        let mut dirs = self.inner.clone().read_dir(self.chunks_dir, 0).await?;
+        while let Some(dir) = dirs.next().await {
+            let dir = dir?;
+            if let Some(ino) = parse_journal(&dir.name) {
+                journals.push(ino);
+                continue;
+            }
+            if dir.name.ends_with(TMP_SUFFIX) {
+                debug!(name = dir.name, "removing partially written journal");
+                self.inner.remove_file(self.chunks_dir, &dir.name).await?;
+                continue;
+            }
+            let Some(prefix) = parse_prefix(&dir.name) else {
+                continue;
+            };
+            chunk_dirs.insert(prefix, dir.ino);
+            let mut entries = self.inner.clone().read_dir(dir.ino, 0).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                if let Some(hash) = parse_hash(&entry.name) {
+                    chunks.insert(hash, entry.ino);
+                } else if entry.name.ends_with(TMP_SUFFIX) {
+                    debug!(name = entry.name, "removing partially written chunk");
+                    self.inner.remove_file(dir.ino, &entry.name).await?;
+                } else {
+                    warn!(name = entry.name, "unknown file in chunks dir");
+                }
+            }
+        }
+        debug!(chunks = chunks.len(), "loaded chunks");
+        {
+            let mut state = self.state_mut();
+            state.chunks = chunks;
+            state.chunk_dirs = chunk_dirs;
+        }
+        for ino in journals {
+            self.replay_journal(ino).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the chunk list of `ino` from its journal, we crashed while saving it.
+    async fn replay_journal(&self, ino: u64) -> FsResult<()> {
+        let name = journal_name(ino);
+        let journal = self
+            .inner
+            .find_by_name(self.chunks_dir, &name)
+            .await?
+            .ok_or(FsError::NotFound("journal not found"))?;
+        let fh = self.inner.open(journal.ino, true, false).await?;
+        let res = read_exact(&*self.inner, journal.ino, fh, journal.size).await;
+        self.inner.release(fh).await?;
+        let data = res?;
+        match self.inner.get_attr(ino).await {
+            Ok(attr) if attr.kind == FileType::RegularFile => {
+                let fh = self.inner.open(ino, true, true).await?;
+                let res = write_list(&*self.inner, ino, fh, &data).await;
+                self.inner.release(fh).await?;
+                res?;
+                info!(ino, "restored chunk list from journal");
+            }
+            // removed before we crashed
+            _ => {}
+        }
+        self.inner.remove_file(self.chunks_dir, &name).await
+    }
+
+    /// Create a file in `dir` with `data`. It's written with a temporary name and renamed after,
+    /// so it's never there partially written.
+    async fn write_renamed(&self, dir: u64, name: &str, data: &[u8]) -> FsResult<u64> {
+        let tmp_name = format!("{name}{TMP_SUFFIX}");
+        let (fh, attr) = self
+            .inner
+            .create(
+                dir,
+                &tmp_name,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await?;
+        let res = write_all(&*self.inner, attr.ino, fh, data).await;
+        let res = match res {
+            Ok(()) => self.inner.flush(fh).await,
+            Err(err) => Err(err),
+        };
+        self.inner.release(fh).await?;
+        if let Err(err) = res {
+            self.inner.remove_file(dir, &tmp_name).await?;
+            return Err(err);
+        }
+        self.inner.rename(dir, &tmp_name, dir, name).await?;
+        Ok(attr.ino)
+    }
+
+    async fn chunk_dir(&self, prefix: u8) -> FsResult<u64> {
+        let existing = self.state().chunk_dirs.get(&prefix).copied();
+        if let Some(ino) = existing {
+            return Ok(ino);
+        }
+        // only called with the chunk lock of this prefix held, no one else creates it
+        let (_, attr) = self
+            .inner
+            .create(
+                self.chunks_dir,
+                &format!("{prefix:02x}"),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await?;
+        self.state_mut().chunk_dirs.insert(prefix, attr.ino);
+        Ok(attr.ino)
+    }
+
+    /// Store a chunk, unless we already have one with the same content.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn store_chunk(&self, data: &[u8]) -> FsResult<ChunkRef> {
+        let chunk = ChunkRef {
+            hash: *blake3::hash(data).as_bytes(),
+            len: data.len() as u32,
+        };
+        let _guard = self.chunk_locks.get(u64::from(chunk.hash[0])).write().await;
+        if self.state().chunks.contains_key(&chunk.hash) {
+            return Ok(chunk);
+        }
+        let dir = self.chunk_dir(chunk.hash[0]).await?;
+        let ino = self
+            .write_renamed(dir, &hex::encode(chunk.hash), data)
+            .await?;
+        self.state_mut().chunks.insert(chunk.hash, ino);
+        Ok(chunk)
+    }
+
+    /// Cut `data` in chunks and store them.
+    async fn store_chunks(&self, data: &[u8]) -> FsResult<Vec<ChunkRef>> {
+        let mut chunks = vec![];
+        for chunk in FastCDC::new(data, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            chunks.push(
+                self.store_chunk(&data[chunk.offset..chunk.offset + chunk.length])
+                    .await?,
+            );
+        }
+        Ok(chunks)
+    }
+
+    async fn read_chunk(&self, chunk: &ChunkRef) -> FsResult<Vec<u8>> {
+        let ino = self
+            .state()
+            .chunks
+            .get(&chunk.hash)
+            .copied()
+            .ok_or(FsError::Other("chunk is missing"))?;
+        let fh = self.inner.open(ino, true, false).await?;
+        let res = read_exact(&*self.inner, ino, fh, u64::from(chunk.len)).await;
+        self.inner.release(fh).await?;
+        res
+    }
+
+    /// Read the chunk list saved in the inner file.
+    async fn load_manifest(&self, ino: u64) -> FsResult<Vec<ChunkRef>> {
+        let size = self.inner.get_attr(ino).await?.size;
+        if size == 0 {
+            return Ok(vec![]);
+        }
+        let fh = self.inner.open(ino, true, false).await?;
+        let res = read_exact(&*self.inner, ino, fh, size).await;
+        self.inner.release(fh).await?;
+        Ok(bincode::deserialize(&res?)?)
+    }
+
+    async fn ensure_list(&self, ino: u64) -> FsResult<()> {
+        if self.state().lists.contains_key(&ino) {
+            return Ok(());
+        }
+        let chunks = self.load_manifest(ino).await?;
+        self.state_mut()
+            .lists
+            .entry(ino)
+            .or_insert_with(|| ChunkList::new(chunks));
+        Ok(())
+    }
+
+    /// Save the chunk list in the inner file if it changed, with the tail cut in chunks. The caller
+    /// holds the write lock of `ino`.
+    ///
+    /// The list is written over the old one in place, the file cannot be replaced because its
+    /// inode has to stay the same. So it's first written in a journal, which is removed after and
+    /// written over the list on mount if we crashed in between.
+    async fn save_list(&self, ino: u64, fh: u64) -> FsResult<()> {
+        self.cut_tail(ino, true).await?;
+        let data = match self.state().lists.get(&ino) {
+            Some(list) if list.dirty => bincode::serialize(&list.chunks)?,
+            _ => return Ok(()),
+        };
+        let name = journal_name(ino);
+        self.write_renamed(self.chunks_dir, &name, &data).await?;
+        write_list(&*self.inner, ino, fh, &data).await?;
+        self.inner.remove_file(self.chunks_dir, &name).await?;
+        if let Some(list) = self.state_mut().lists.get_mut(&ino) {
+            list.dirty = false;
+        }
+        Ok(())
+    }
+
+    async fn size(&self, ino: u64) -> FsResult<u64> {
+        self.ensure_list(ino).await?;
+        self.state()
+            .lists
+            .get(&ino)
+            .map(ChunkList::size)
+            .ok_or(FsError::InodeNotFound)
+    }
+
+    async fn plain_attr(&self, mut attr: FileAttr) -> FsResult<FileAttr> {
+        if attr.kind == FileType::RegularFile {
+            attr.size = self.size(attr.ino).await?;
+            attr.blocks = attr.size.div_ceil(512);
+        } else if attr.ino == ROOT_INODE {
+            // CHUNKS_DIR is hidden, so it's not one of the subdirectories
+            attr.nlink -= 1;
+        }
+        Ok(attr)
+    }
+
+    fn splice_list(&self, ino: u64, range: Range<usize>, chunks: Vec<ChunkRef>) -> FsResult<()> {
+        let mut state = self.state_mut();
+        let removed = state
+            .lists
+            .get_mut(&ino)
+            .ok_or(FsError::InodeNotFound)?
+            .splice(range, chunks);
+        state.dropped += removed as u64;
+        Ok(())
+    }
+
+    /// Cut the tail in chunks and store them. Unless `all`, the last one stays in the tail, more
+    /// data could still move its end, and only when the tail is big enough to have more.
+    async fn cut_tail(&self, ino: u64, all: bool) -> FsResult<()> {
+        let tail = {
+            let state = self.state();
+            let list = state.lists.get(&ino).ok_or(FsError::InodeNotFound)?;
+            if list.tail.is_empty() || (!all && list.tail.len() < 2 * MAX_CHUNK_SIZE as usize) {
+                return Ok(());
+            }
+            list.tail.clone()
+        };
+        let mut cuts: Vec<_> =
+            FastCDC::new(&tail, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE).collect();
+        if !all {
+            cuts.pop();
+        }
+        let mut chunks = vec![];
+        let mut len = 0;
+        for cut in cuts {
+            chunks.push(
+                self.store_chunk(&tail[cut.offset..cut.offset + cut.length])
+                    .await?,
+            );
+            len += cut.length;
+        }
+        let mut state = self.state_mut();
+        let list = state.lists.get_mut(&ino).ok_or(FsError::InodeNotFound)?;
+        list.tail.drain(..len);
+        let count = list.chunks.len();
+        list.splice(count..count, chunks);
+        Ok(())
+    }
+
+    /// Extend the file with zeros up to `new_size`. The zeros are stored in chunks of the max
+    /// size, so a large gap only costs one chunk.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn fill_zeros(&self, ino: u64, new_size: u64) -> FsResult<()> {
+        if new_size <= self.size(ino).await? {
+            return Ok(());
+        }
+        self.cut_tail(ino, true).await?;
+        let (size, count) = {
+            let state = self.state();
+            let list = state.lists.get(&ino).ok_or(FsError::InodeNotFound)?;
+            (list.size(), list.chunks.len())
+        };
+        let gap = new_size - size;
+        let max = u64::from(MAX_CHUNK_SIZE);
+        let zeros = vec![0; min(gap, max) as usize];
+        let mut chunks = vec![];
+        if gap >= max {
+            let chunk = self.store_chunk(&zeros).await?;
+            chunks.extend((0..gap / max).map(|_| chunk));
+        }
+        if !gap.is_multiple_of(max) {
+            chunks.push(self.store_chunk(&zeros[..(gap % max) as usize]).await?);
+        }
+        self.splice_list(ino, count..count, chunks)
+    }
+
+    /// Replace `offset..offset + buf.len()` with `buf`. Writes after the chunks go to the tail,
+    /// the others are written in the chunks.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn write_chunks(&self, ino: u64, offset: u64, buf: &[u8]) -> FsResult<()> {
+        let end = offset + buf.len() as u64;
+        let (stored, size) = {
+            let state = self.state();
+            let list = state.lists.get(&ino).ok_or(FsError::InodeNotFound)?;
+            (list.stored(), list.size())
+        };
+        if offset < stored {
+            if end > stored {
+                // the chunks are cut again up to the end of the write, the tail with them
+                self.cut_tail(ino, true).await?;
+            }
+            return self.patch_chunks(ino, offset, buf).await;
+        }
+        if offset > size + u64::from(MAX_CHUNK_SIZE) {
+            // a large gap is stored in chunks of zeros instead of in the tail
+            self.fill_zeros(ino, offset).await?;
+        }
+        {
+            let mut state = self.state_mut();
+            let list = state.lists.get_mut(&ino).ok_or(FsError::InodeNotFound)?;
+            let from = (offset - list.stored()) as usize;
+            if list.tail.len() < from + buf.len() {
+                list.tail.resize(from + buf.len(), 0);
+            }
+            list.tail[from..from + buf.len()].copy_from_slice(buf);
+        }
+        self.cut_tail(ino, false).await
+    }
+
+    /// Write in the chunks, starting before their end. The chunks the write touches are read,
+    /// patched and cut again. Chunks after the change are kept as they are, so we don't need to
+    /// read the rest of the file.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn patch_chunks(&self, ino: u64, offset: u64, buf: &[u8]) -> FsResult<()> {
+        let (range, start, old) = {
+            let state = self.state();
+            let list = state.lists.get(&ino).ok_or(FsError::InodeNotFound)?;
+            let stored = list.stored();
+            let end = offset + buf.len() as u64;
+            let first = list.find(offset);
+            let last = if end < stored {
+                list.find(end - 1) + 1
+            } else {
+                list.chunks.len()
+            };
+            (
+                first..last,
+                list.starts[first],
+                list.chunks[first..last].to_vec(),
+            )
+        };
+        let mut data = Vec::new();
+        for chunk in &old {
+            data.extend(self.read_chunk(chunk).await?);
+        }
+        let from = (offset - start) as usize;
+        if data.len() < from + buf.len() {
+            data.resize(from + buf.len(), 0);
+        }
+        data[from..from + buf.len()].copy_from_slice(buf);
+        let chunks = self.store_chunks(&data).await?;
+        self.splice_list(ino, range, chunks)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn resize(&self, ino: u64, new_size: u64) -> FsResult<()> {
+        self.ensure_list(ino).await?;
+        self.cut_tail(ino, true).await?;
+        self.fill_zeros(ino, new_size).await?;
+        let (index, start, chunk, count) = {
+            let state = self.state();
+            let list = state.lists.get(&ino).ok_or(FsError::InodeNotFound)?;
+            if new_size >= list.size() {
+                return Ok(());
+            }
+            let index = list.find(new_size);
+            (
+                index,
+                list.starts[index],
+                list.chunks[index],
+                list.chunks.len(),
+            )
+        };
+        let mut tail = vec![];
+        if start < new_size {
+            let mut data = self.read_chunk(&chunk).await?;
+            data.truncate((new_size - start) as usize);
+            tail.push(self.store_chunk(&data).await?);
+        }
+        self.splice_list(ino, index..count, tail)
+    }
+
+    /// Copy by only copying the references to the chunks of the source. It's possible when the
+    /// range starts and ends on chunk boundaries in both files, it returns `None` otherwise.
+    fn splice(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: u64,
+    ) -> FsResult<Option<u64>> {
+        let mut state = self.state_mut();
+        let src = state.lists.get(&src_ino).ok_or(FsError::InodeNotFound)?;
+        let len = min(size, src.size().saturating_sub(src_offset));
+        if len == 0 {
+            return Ok(Some(0));
+        }
+        let (Some(first), Some(last)) = (src.boundary(src_offset), src.boundary(src_offset + len))
+        else {
+            return Ok(None);
+        };
+        let chunks = src.chunks[first..last].to_vec();
+        let dest = state
+            .lists
+            .get_mut(&dest_ino)
+            .ok_or(FsError::InodeNotFound)?;
+        let Some(dest_first) = dest.boundary(dest_offset) else {
+            return Ok(None);
+        };
+        let dest_last = if dest_offset + len >= dest.size() {
+            dest.chunks.len()
+        } else {
+            match dest.boundary(dest_offset + len) {
+                Some(index) => index,
+                None => return Ok(None),
+            }
+        };
+        debug!(chunks = chunks.len(), "copying chunk references");
+        let removed = dest.splice(dest_first..dest_last, chunks);
+        state.dropped += removed as u64;
+        Ok(Some(len))
+    }
+
+    /// Forget the chunk list of a file which is not there anymore, its chunks may be garbage now.
+    /// `attr` is how it was in the inner filesystem before.
+    fn forget(&self, attr: &FileAttr) {
+        if attr.kind != FileType::RegularFile || self.inner.exists(attr.ino) {
+            // there are other links to it, it's still opened or the kernel has references to it
+            return;
+        }
+        let mut state = self.state_mut();
+        let dropped = match state.lists.remove(&attr.ino) {
+            Some(list) => list.chunks.len() as u64,
+            // the list was not loaded, it's about right
+            None => attr.size / CHUNK_REF_LEN,
+        };
+        state.dropped += dropped;
+    }
+
+    async fn maybe_collect_garbage(&self) -> FsResult<()> {
+        if self.state().dropped < GC_THRESHOLD {
+            return Ok(());
+        }
+        if let Err(err) = self.collect_garbage().await {
+            // tried again after the next dropped reference
+            warn!(%err, "cannot collect garbage");
+        }
+        Ok(())
+    }
+
+    /// Remove the chunks which are not in any chunk list. The lists are read from all the files,
+    /// the ones changed and not saved yet are also taken from memory. If any list cannot be read
+    /// nothing is removed.
+    pub(crate) async fn collect_garbage(&self) -> FsResult<()> {
+        let _gc = self.gc.write().await;
+        let (mut used, saved) = {
+            let state = self.state();
+            let used: HashSet<Hash> = state
+                .lists
+                .values()
+                .flat_map(|list| list.chunks.iter().map(|chunk| chunk.hash))
+                .collect();
+            let saved: HashSet<u64> = state
+                .lists
+                .iter()
+                .filter(|(_, list)| !list.dirty)
+                .map(|(ino, _)| *ino)
+                .collect();
+            (used, saved)
+        };
+        let mut dirs = vec![ROOT_INODE];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = self.inner.clone().read_dir(dir, 0).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                if entry.name == "." || entry.name == ".." || is_reserved(dir, &entry.name) {
+                    continue;
+                }
+                match entry.kind {
+                    FileType::Directory => dirs.push(entry.ino),
+                    FileType::RegularFile if !saved.contains(&entry.ino) => {
+                        match self.load_manifest(entry.ino).await {
+                            Ok(chunks) => used.extend(chunks.iter().map(|chunk| chunk.hash)),
+                            // removed since the directory was read
+                            Err(FsError::InodeNotFound | FsError::NotFound(_)) => {}
+                            Err(FsError::Io { source })
+                                if source.kind() == io::ErrorKind::NotFound => {}
+                            // without its chunks we would remove ones which are used
+                            Err(err) => {
+                                warn!(ino = entry.ino, %err, "cannot read chunk list");
+                                return Err(err);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let garbage: Vec<Hash> = self
+            .state()
+            .chunks
+            .keys()
+            .filter(|hash| !used.contains(*hash))
+            .copied()
+            .collect();
+        for hash in &garbage {
+            let dir = self
+                .state()
+                .chunk_dirs
+                .get(&hash[0])
+                .copied()
+                .ok_or(FsError::Other("chunk dir is missing"))?;
+            self.inner.remove_file(dir, &hex::encode(hash)).await?;
+            self.state_mut().chunks.remove(hash);
+        }
+        self.state_mut().dropped = 0;
+        if !garbage.is_empty() {
+            info!(chunks = garbage.len(), "removed unused chunks");
+        }
+        Ok(())
+    }
+}
+
+/// [`CHUNKS_DIR`] is reserved in the root.
+fn is_reserved(parent: u64, name: &str) -> bool {
+    parent == ROOT_INODE && name == CHUNKS_DIR
+}
+
+fn parse_prefix(name: &str) -> Option<u8> {
+    if name.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(name, 16).ok()
+}
+
+fn parse_hash(name: &str) -> Option<Hash> {
+    hex::decode(name).ok()?.try_into().ok()
+}
+
+fn journal_name(ino: u64) -> String {
+    format!("{ino}{JOURNAL_SUFFIX}")
+}
+
+fn parse_journal(name: &str) -> Option<u64> {
+    name.strip_suffix(JOURNAL_SUFFIX)?.parse().ok()
+}
+
+fn create_attr(kind: FileType) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: if kind == FileType::Directory {
+            0o700
+        } else {
+            0o600
+        },
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+async fn read_exact(inner: &dyn Filesystem, ino: u64, fh: u64, len: u64) -> FsResult<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+    let mut read = 0;
+    while read < buf.len() {
+        let len = inner.read(ino, read as u64, &mut buf[read..], fh).await?;
+        if len == 0 {
+            return Err(FsError::Other(
+                "chunk or chunk list is shorter than expected",
+            ));
+        }
+        read += len;
+    }
+    Ok(buf)
+}
+
+async fn write_all(inner: &dyn Filesystem, ino: u64, fh: u64, data: &[u8]) -> FsResult<()> {
+    let mut written = 0;
+    while written < data.len() {
+        written += inner
+            .write(ino, written as u64, &data[written..], fh)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Write a chunk list over the one in the inner file.
+async fn write_list(inner: &dyn Filesystem, ino: u64, fh: u64, data: &[u8]) -> FsResult<()> {
+    write_all(inner, ino, fh, data).await?;
+    inner.set_len(ino, data.len() as u64).await?;
+    inner.flush(fh).await
+}
+
+#[async_trait]
+impl Filesystem for DedupFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.inner.exists(ino)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.inner.is_dir(ino)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.inner.is_file(ino)
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        if is_reserved(parent, name) {
+            return Err(FsError::InvalidInput("name is reserved for deduplication"));
+        }
+        let (fh, attr) = self
+            .inner
+            .create(parent, name, create_attr, read || write, write)
+            .await?;
+        let mut state = self.state_mut();
+        if attr.kind == FileType::RegularFile {
+            state.lists.insert(attr.ino, ChunkList::new(vec![]));
+        }
+        if read || write {
+            state.handles.insert(
+                fh,
+                Handle {
+                    ino: attr.ino,
+                    read,
+                    write,
+                },
+            );
+        }
+        Ok((fh, attr))
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        if is_reserved(parent, name) {
+            return Err(FsError::InvalidInput("name is reserved for deduplication"));
+        }
+        self.inner.symlink(parent, name, target, create_attr).await
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        self.inner.read_link(ino).await
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        if is_reserved(new_parent, new_name) {
+            return Err(FsError::InvalidInput("name is reserved for deduplication"));
+        }
+        let attr = self.inner.link(ino, new_parent, new_name).await?;
+        self.plain_attr(attr).await
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        if is_reserved(parent, name) {
+            return Ok(None);
+        }
+        match self.inner.find_by_name(parent, name).await? {
+            Some(attr) => Ok(Some(self.plain_attr(attr).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        let len = self.inner.len(ino).await?;
+        if ino == ROOT_INODE && self.inner.exists_by_name(ino, CHUNKS_DIR).await? {
+            return Ok(len - 1);
+        }
+        Ok(len)
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        self.inner.remove_dir(parent, name).await
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        let attr = self.inner.find_by_name(parent, name).await?;
+        self.inner.remove_file(parent, name).await?;
+        if let Some(attr) = attr {
+            self.forget(&attr);
+        }
+        self.maybe_collect_garbage().await
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        if is_reserved(parent, name) {
+            return Ok(false);
+        }
+        self.inner.exists_by_name(parent, name).await
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        let stream = self.inner.clone().read_dir(ino, cookie).await?;
+        if ino != ROOT_INODE {
+            return Ok(stream);
+        }
+        Ok(stream
+            .filter(|entry| {
+                future::ready(
+                    entry
+                        .as_ref()
+                        .map_or(true, |entry| entry.name != CHUNKS_DIR),
+                )
+            })
+            .boxed())
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        let stream = self.inner.clone().read_dir_plus(ino, cookie).await?;
+        Ok(stream
+            .filter_map(move |entry| {
+                let fs = self.clone();
+                async move {
+                    match entry {
+                        Ok(entry) if is_reserved(ino, &entry.name) => None,
+                        Ok(entry) => Some(
+                            fs.plain_attr(entry.attr)
+                                .await
+                                .map(|attr| DirectoryEntryPlus { attr, ..entry }),
+                        ),
+                        Err(err) => Some(Err(err)),
+                    }
+                }
+            })
+            .boxed())
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        self.plain_attr(self.inner.get_attr(ino).await?).await
+    }
+
+    async fn set_attr(&self, ino: u64, mut set_attr: SetFileAttr) -> FsResult<()> {
+        if let Some(size) = set_attr.size.take() {
+            self.set_len(ino, size).await?;
+        }
+        self.inner.set_attr(ino, set_attr).await
+    }
+
+    #[instrument(skip(self, buf))]
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        self.check_handle(handle, ino, false)?;
+        let _guard = self.locks.get(ino).read().await;
+        self.ensure_list(ino).await?;
+        let (end, mut pos, chunks, tail) = {
+            let state = self.state();
+            let list = state.lists.get(&ino).ok_or(FsError::InodeNotFound)?;
+            let end = min(list.size(), offset + buf.len() as u64);
+            if offset >= end {
+                return Ok(0);
+            }
+            let stored = list.stored();
+            let (pos, chunks) = if offset < stored {
+                let first = list.find(offset);
+                let last = list.find(min(end, stored) - 1);
+                (list.starts[first], list.chunks[first..=last].to_vec())
+            } else {
+                (stored, vec![])
+            };
+            let tail = if end > stored {
+                let from = offset.saturating_sub(stored) as usize;
+                list.tail[from..(end - stored) as usize].to_vec()
+            } else {
+                vec![]
+            };
+            (end, pos, chunks, tail)
+        };
+        let mut len = 0;
+        for chunk in chunks {
+            let data = self.read_chunk(&chunk).await?;
+            let from = offset.saturating_sub(pos) as usize;
+            let to = min(u64::from(chunk.len), end - pos) as usize;
+            buf[len..len + to - from].copy_from_slice(&data[from..to]);
+            len += to - from;
+            pos += u64::from(chunk.len);
+        }
+        buf[len..len + tail.len()].copy_from_slice(&tail);
+        Ok(len + tail.len())
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        let Handle { ino, write, .. } = self
+            .state_mut()
+            .handles
+            .remove(&handle)
+            .ok_or(FsError::InvalidFileHandle)?;
+        let res = if write {
+            let _gc = self.gc.read().await;
+            let _guard = self.locks.get(ino).write().await;
+            self.save_list(ino, handle).await
+        } else {
+            Ok(())
+        };
+        let attr = self.inner.get_attr(ino).await;
+        self.inner.release(handle).await?;
+        res?;
+        if let Ok(attr) = attr {
+            // if it was removed while opened
+            self.forget(&attr);
+        }
+        self.maybe_collect_garbage().await
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.state().handles.get(&fh).is_some_and(|h| h.read)
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        self.state().handles.get(&fh).is_some_and(|h| h.write)
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        self.check_handle(handle, ino, true)?;
+        if buf.is_empty() {
+            // no-op
+            return Ok(0);
+        }
+        let _gc = self.gc.read().await;
+        let _guard = self.locks.get(ino).write().await;
+        self.ensure_list(ino).await?;
+        self.write_chunks(ino, offset, buf).await?;
+        Ok(buf.len())
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        let (ino, write) = self
+            .state()
+            .handles
+            .get(&handle)
+            .map(|handle| (handle.ino, handle.write))
+            .ok_or(FsError::InvalidFileHandle)?;
+        if write {
+            let _gc = self.gc.read().await;
+            let _guard = self.locks.get(ino).write().await;
+            self.save_list(ino, handle).await?;
+        }
+        self.inner.flush(handle).await?;
+        self.maybe_collect_garbage().await
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        self.check_handle(src_fh, src_ino, false)?;
+        self.check_handle(dest_fh, dest_ino, true)?;
+        if src_ino != dest_ino {
+            let spliced = {
+                let _gc = self.gc.read().await;
+                let _guards = self.locks.write_both(src_ino, dest_ino).await;
+                self.ensure_list(src_ino).await?;
+                self.ensure_list(dest_ino).await?;
+                // the references can only be copied from and to the chunks
+                self.cut_tail(src_ino, true).await?;
+                self.cut_tail(dest_ino, true).await?;
+                self.splice(src_ino, src_offset, dest_ino, dest_offset, size as u64)?
+            };
+            if let Some(len) = spliced {
+                #[allow(clippy::cast_possible_truncation)]
+                return Ok(len as usize);
+            }
+        }
+        copy_with_writes(
+            self,
+            src_ino,
+            src_offset,
+            dest_ino,
+            dest_offset,
+            size,
+            src_fh,
+            dest_fh,
+        )
+        .await
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if !read && !write {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        let fh = self.inner.open(ino, true, write).await?;
+        self.state_mut()
+            .handles
+            .insert(fh, Handle { ino, read, write });
+        Ok(fh)
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        {
+            let _gc = self.gc.read().await;
+            let fh = self.inner.open(ino, true, true).await?;
+            let res = {
+                let _guard = self.locks.get(ino).write().await;
+                match self.resize(ino, size).await {
+                    Ok(()) => self.save_list(ino, fh).await,
+                    Err(err) => Err(err),
+                }
+            };
+            self.inner.release(fh).await?;
+            res?;
+        }
+        self.maybe_collect_garbage().await
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        // the content is split in chunks, so the zeros are too
+        fallocate_with_writes(self, ino, offset, len, mode, handle).await
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        if is_reserved(new_parent, new_name) {
+            return Err(FsError::InvalidInput("name is reserved for deduplication"));
+        }
+        let replaced = self.inner.find_by_name(new_parent, new_name).await?;
+        self.inner
+            .rename(parent, name, new_parent, new_name)
+            .await?;
+        if let Some(replaced) = replaced {
+            self.forget(&replaced);
+        }
+        self.maybe_collect_garbage().await
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        self.inner.get_xattr(ino, name).await
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        self.inner.set_xattr(ino, name, value, mode).await
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        self.inner.list_xattr(ino).await
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        self.inner.remove_xattr(ino, name).await
+    }
+
+    /// The chunks are in the inner filesystem, so that's where the space is taken.
+    async fn statfs(&self) -> FsResult<StatFs> {
+        self.inner.statfs().await
+    }
+
+    fn max_name_len(&self) -> usize {
+        self.inner.max_name_len()
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        self.inner.referenced(ino)
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        let attr = self.inner.get_attr(ino).await;
+        self.inner.forgotten(ino).await;
+        if let Ok(attr) = attr {
+            // if it was removed while the kernel had references to it
+            self.forget(&attr);
+        }
+    }
+
+    fn opened_dir(&self, ino: u64) {
+        self.inner.opened_dir(ino);
+    }
+
+    fn released_dir(&self, ino: u64) {
+        self.inner.released_dir(ino);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryFilesystem;
+    use crate::fs::testing::{create, noise, read_all};
+
+    #[tokio::test]
+    async fn chunks_are_shared() {
+        let inner: Arc<dyn Filesystem> = MemoryFilesystem::new();
+        let fs = DedupFilesystem::new(inner.clone()).await.unwrap();
+        let data = noise(1024 * 1024);
+        let a = create(&*fs, "a", &data).await;
+        let chunks = fs.state().chunks.len();
+        assert!(chunks > 1);
+        let b = create(&*fs, "b", &data).await;
+        assert_eq!(fs.state().chunks.len(), chunks);
+        // an insert changes only the chunks around it
+        let mut changed = data.clone();
+        changed.splice(500_000..500_000, *b"inserted");
+        let c = create(&*fs, "c", &changed).await;
+        let added = fs.state().chunks.len() - chunks;
+        assert!((1..=2).contains(&added), "{added} chunks added");
+        assert_eq!(read_all(&*fs, a).await, data);
+        assert_eq!(read_all(&*fs, b).await, data);
+        assert_eq!(read_all(&*fs, c).await, changed);
+
+        // the chunk lists are read back from the inner files
+        let fs = DedupFilesystem::new(inner).await.unwrap();
+        assert_eq!(read_all(&*fs, c).await, changed);
+    }
+
+    #[tokio::test]
+    async fn collect_garbage() {
+        let fs = DedupFilesystem::new(MemoryFilesystem::new()).await.unwrap();
+        let data = noise(512 * 1024);
+        create(&*fs, "a", &data).await;
+        let mut changed = data.clone();
+        changed.splice(200_000..200_000, *b"inserted");
+        create(&*fs, "b", &changed).await;
+        let chunks = fs.state().chunks.len();
+
+        fs.remove_file(ROOT_INODE, "b").await.unwrap();
+        fs.collect_garbage().await.unwrap();
+        // only the ones which were just in the removed file
+        let left = fs.state().chunks.len();
+        assert!(left < chunks && left > 1);
+        fs.remove_file(ROOT_INODE, "a").await.unwrap();
+        fs.collect_garbage().await.unwrap();
+        assert!(fs.state().chunks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn collect_garbage_keeps_chunks_of_unreadable_lists() {
+        let inner: Arc<dyn Filesystem> = MemoryFilesystem::new();
+        let fs = DedupFilesystem::new(inner.clone()).await.unwrap();
+        let data = noise(512 * 1024);
+        let a = create(&*fs, "a", &data).await;
+        let mut other = noise(300 * 1024);
+        other.reverse();
+        create(&*fs, "b", &other).await;
+        fs.remove_file(ROOT_INODE, "b").await.unwrap();
+        let chunks = fs.state().chunks.len();
+        let fh = inner.open(a, false, true).await.unwrap();
+        inner.write(a, 0, &[0xff; 8], fh).await.unwrap();
+        inner.release(fh).await.unwrap();
+
+        // the list of `a` is not loaded, it cannot be read so nothing is removed
+        let fs = DedupFilesystem::new(inner).await.unwrap();
+        assert!(fs.collect_garbage().await.is_err());
+        assert_eq!(fs.state().chunks.len(), chunks);
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Cookies of "." and "..", the other entries come after them.
+pub(crate) const DOT_COOKIE: u64 = 1;
+pub(crate) const DOT_DOT_COOKIE: u64 = 2;
+
+struct Entry {
+    ino: u64,
+    cookie: u64,
+}
+
+/// Entries of a directory by name, excluding "." and "..", for the filesystems which keep them in
+/// memory. Each entry gets the next cookie when it's added, so listing by cookie goes on after the
+/// last entry listed even if entries were added or removed in between.
+pub(crate) struct Directory {
+    entries: BTreeMap<String, Entry>,
+    names: BTreeMap<u64, String>,
+    next_cookie: u64,
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            names: BTreeMap::new(),
+            next_cookie: DOT_DOT_COOKIE + 1,
+        }
+    }
+}
+
+impl Directory {
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.entries.get(name).map(|entry| entry.ino)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add the entry `name`, or point it to `ino` if it exists, then it keeps its cookie. It
+    /// returns the inode it pointed to before.
+    pub fn insert(&mut self, name: String, ino: u64) -> Option<u64> {
+        if let Some(entry) = self.entries.get_mut(&name) {
+            return Some(std::mem::replace(&mut entry.ino, ino));
+        }
+        let cookie = self.next_cookie;
+        self.next_cookie += 1;
+        self.names.insert(cookie, name.clone());
+        self.entries.insert(name, Entry { ino, cookie });
+        None
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<u64> {
+        let entry = self.entries.remove(name)?;
+        self.names.remove(&entry.cookie);
+        Some(entry.ino)
+    }
+
+    /// Entries by name, with their inode.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.entries
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry.ino))
+    }
+
+    /// The entries after `cookie` with their inode and cookie, "." and ".." first, pointing to
+    /// `ino` and `parent`.
+    pub fn after(
+        &self,
+        cookie: u64,
+        ino: u64,
+        parent: u64,
+    ) -> impl Iterator<Item = (&str, u64, u64)> {
+        [(".", ino, DOT_COOKIE), ("..", parent, DOT_DOT_COOKIE)]
+            .into_iter()
+            .filter(move |(_, _, dot_cookie)| *dot_cookie > cookie)
+            .chain(
+                self.names
+                    .range((Bound::Excluded(cookie), Bound::Unbounded))
+                    .map(|(cookie, name)| (name.as_str(), self.entries[name].ino, *cookie)),
+            )
+    }
+}
@@ -0,0 +1,1173 @@
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::{future, StreamExt};
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use crate::crypto::{
+    self, Cipher, Encryptor, NameEncryptor, PasswordProvider, KEY_LEN, NONCE_LEN, SALT_LEN, TAG_LEN,
+};
+use crate::fs::{copy_with_writes, fallocate_with_writes, Filesystem, StripedLocks, ROOT_INODE};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, DirectoryEntryPlusStream,
+    DirectoryEntryStream, FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    SetXattrMode, StatFs,
+};
+
+/// Size of the plaintext chunk, each one is encrypted separately so we can read and write at any
+/// offset without touching the rest of the file.
+pub(crate) const CHUNK_SIZE: u64 = 4096;
+const CHUNK_OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+const ENCRYPTED_CHUNK_SIZE: u64 = CHUNK_SIZE + CHUNK_OVERHEAD;
+/// Random id of a file, stored in front of its chunks.
+const FILE_ID_LEN: usize = 16;
+const HEADER_LEN: u64 = FILE_ID_LEN as u64;
+/// The file id, the chunk index and whether it's the last chunk.
+const CHUNK_AAD_LEN: usize = FILE_ID_LEN + 8 + 1;
+/// How many chunks we encrypt at once when filling a gap with zeros.
+const ZERO_FILL_CHUNKS: u64 = 256;
+
+/// Stored in the root of the inner filesystem, hidden from the user. Encrypted names never start
+/// with `.` so it cannot collide with an entry.
+const KEY_FILE_NAME: &str = ".encryption-key";
+const KEY_FILE_VERSION: u32 = 3;
+/// Stored in each directory of the inner filesystem, hidden from the user too. It holds the
+/// random id of the directory, which the names of its entries are encrypted with.
+const DIR_ID_FILE_NAME: &str = ".directory-id";
+const DIR_ID_LEN: usize = 16;
+const KEY_AAD: &[u8] = b"encryption-key";
+const SYMLINK_AAD: &[u8] = b"symlink";
+const XATTR_AAD: &[u8] = b"xattr";
+/// Encrypted names of extended attributes are stored in this namespace, whatever their own is.
+const XATTR_NAMESPACE: &str = "user.";
+/// The longest name of an extended attribute, with its namespace, `XATTR_NAME_MAX` on Linux.
+const XATTR_NAME_MAX: usize = 255;
+
+type FileId = [u8; FILE_ID_LEN];
+type DirId = [u8; DIR_ID_LEN];
+
+/// The key used to encrypt the content, itself encrypted with a key derived from the password.
+/// This way changing the password doesn't need to re-encrypt everything.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    cipher: Cipher,
+    salt: [u8; SALT_LEN],
+    key: Vec<u8>,
+}
+
+struct Handle {
+    read: bool,
+    write: bool,
+}
+
+/// Encrypts the content of the files of another [`Filesystem`].
+///
+/// Files are split into chunks of [`CHUNK_SIZE`] bytes, each stored as `nonce | ciphertext | tag`
+/// in the inner filesystem, after a random id of the file. The id, the chunk index and whether
+/// it's the last chunk are authenticated too, so chunks cannot be swapped, moved to another file
+/// or cut from the end. Sizes reported to the user are the plaintext ones.
+///
+/// When a file grows, the gap is filled with encrypted chunks of zeros. The inner file has no
+/// holes, so any chunk which doesn't decrypt has been tampered with.
+///
+/// Names of the entries are encrypted too, with [`NameEncryptor`] and the id of their directory,
+/// so the same name in two directories is encrypted differently. Targets of symbolic links are
+/// encrypted with a random nonce and stored as URL safe base64.
+pub(crate) struct EncryptedFilesystem {
+    inner: Arc<dyn Filesystem>,
+    encryptor: Encryptor,
+    name_encryptor: NameEncryptor,
+    /// The inner handles are always opened for read, as we need to read partially written chunks.
+    /// Here we keep what the user asked for.
+    handles: RwLock<HashMap<u64, Handle>>,
+    /// Ids of the directories we've read, by inode.
+    dir_ids: RwLock<HashMap<u64, DirId>>,
+    /// Operations on the same inode are serialized with these, picked by `ino`.
+    locks: StripedLocks,
+}
+
+impl EncryptedFilesystem {
+    /// Wraps `inner`. On first use it generates a key, after that it checks the password against
+    /// it and fails with [`FsError::InvalidPassword`] if it's not the right one.
+    pub async fn new(
+        inner: Arc<dyn Filesystem>,
+        cipher: Cipher,
+        password_provider: &dyn PasswordProvider,
+    ) -> FsResult<Arc<Self>> {
+        let password = password_provider
+            .get_password()
+            .ok_or(FsError::InvalidPassword)?;
+        let key = if let Some(attr) = inner.find_by_name(ROOT_INODE, KEY_FILE_NAME).await? {
+            load_key(&*inner, attr, cipher, &password).await?
+        } else {
+            if inner.len(ROOT_INODE).await? > 0 {
+                return Err(FsError::Encryption(
+                    "cannot enable encryption over existing unencrypted content",
+                ));
+            }
+            let key = create_key(&*inner, cipher, &password).await?;
+            write_dir_id(
+                &*inner,
+                ROOT_INODE,
+                unsafe { libc::getuid() },
+                unsafe { libc::getgid() },
+                &crypto::random_bytes(),
+            )
+            .await?;
+            key
+        };
+        let encryptor = Encryptor::new(cipher, key.expose_secret())?;
+        let name_encryptor = NameEncryptor::new(cipher, key.expose_secret())?;
+
+        Ok(Arc::new(Self {
+            inner,
+            encryptor,
+            name_encryptor,
+            handles: RwLock::new(HashMap::new()),
+            dir_ids: RwLock::new(HashMap::new()),
+            locks: StripedLocks::new(),
+        }))
+    }
+
+    fn handles(&self) -> RwLockReadGuard<'_, HashMap<u64, Handle>> {
+        self.handles.read().expect("handles lock poisoned")
+    }
+
+    fn handles_mut(&self) -> RwLockWriteGuard<'_, HashMap<u64, Handle>> {
+        self.handles.write().expect("handles lock poisoned")
+    }
+
+    fn dir_ids(&self) -> RwLockReadGuard<'_, HashMap<u64, DirId>> {
+        self.dir_ids.read().expect("dir_ids lock poisoned")
+    }
+
+    fn dir_ids_mut(&self) -> RwLockWriteGuard<'_, HashMap<u64, DirId>> {
+        self.dir_ids.write().expect("dir_ids lock poisoned")
+    }
+
+    fn check_handle(&self, fh: u64, write: bool) -> FsResult<()> {
+        match self.handles().get(&fh) {
+            Some(handle) if (write && handle.write) || (!write && handle.read) => Ok(()),
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+
+    async fn encrypt_name(&self, parent: u64, name: &str) -> FsResult<String> {
+        if name.len() > self.max_name_len() {
+            return Err(FsError::NameTooLong);
+        }
+        self.name_encryptor
+            .encrypt(&self.dir_id(parent).await?, name)
+    }
+
+    /// The id of the directory `ino`, from the file with it in the directory.
+    async fn dir_id(&self, ino: u64) -> FsResult<DirId> {
+        if let Some(id) = self.dir_ids().get(&ino) {
+            return Ok(*id);
+        }
+        let attr = self
+            .inner
+            .find_by_name(ino, DIR_ID_FILE_NAME)
+            .await?
+            .ok_or(FsError::Encryption("missing directory id"))?;
+        let mut id = [0; DIR_ID_LEN];
+        let fh = self.inner.open(attr.ino, true, false).await?;
+        let res = self.inner.read(attr.ino, 0, &mut id, fh).await;
+        self.inner.release(fh).await?;
+        if res? != DIR_ID_LEN {
+            return Err(FsError::Encryption("invalid directory id"));
+        }
+        self.dir_ids_mut().insert(ino, id);
+        Ok(id)
+    }
+
+    /// Remove the id of the empty directory `dir` before the inner filesystem removes or replaces
+    /// it, the directory is not empty for it otherwise. The id is returned to put it back if that
+    /// fails.
+    async fn take_dir_id(&self, dir: &FileAttr) -> FsResult<DirId> {
+        let id = self.dir_id(dir.ino).await?;
+        if self.len(dir.ino).await? > 0 {
+            return Err(FsError::NotEmpty);
+        }
+        self.inner.remove_file(dir.ino, DIR_ID_FILE_NAME).await?;
+        self.dir_ids_mut().remove(&dir.ino);
+        Ok(id)
+    }
+
+    async fn restore_dir_id(&self, dir: &FileAttr, id: &DirId) -> FsResult<()> {
+        write_dir_id(&*self.inner, dir.ino, dir.uid, dir.gid, id).await
+    }
+
+    fn encrypt_xattr_name(&self, name: &str) -> FsResult<String> {
+        if name.len() > crypto::max_plain_name_len(XATTR_NAME_MAX - XATTR_NAMESPACE.len()) {
+            return Err(FsError::NameTooLong);
+        }
+        Ok(format!(
+            "{XATTR_NAMESPACE}{}",
+            self.name_encryptor.encrypt(XATTR_AAD, name)?
+        ))
+    }
+
+    /// Decrypt the name of an entry of `parent`, which has the id `id`, from the inner filesystem.
+    /// "." and ".." are kept as they are. It returns `None` for entries we should hide, the key
+    /// file and what we cannot decrypt.
+    fn visible_name(&self, parent: u64, id: &DirId, name: String) -> Option<String> {
+        if name == "." || name == ".." {
+            return Some(name);
+        }
+        if name == DIR_ID_FILE_NAME || (parent == ROOT_INODE && name == KEY_FILE_NAME) {
+            return None;
+        }
+        self.name_encryptor
+            .decrypt(id, &name)
+            .inspect_err(|_| warn!(name, "cannot decrypt name, skipping entry"))
+            .ok()
+    }
+
+    async fn size(&self, ino: u64) -> FsResult<u64> {
+        Ok(plain_size(self.inner.get_attr(ino).await?.size))
+    }
+
+    /// The id of the file, from its header. With `create` a new one is written if the file
+    /// doesn't have one yet, otherwise it fails.
+    async fn file_id(&self, ino: u64, fh: u64, create: bool) -> FsResult<FileId> {
+        let mut id = [0; FILE_ID_LEN];
+        let len = self.inner.read(ino, 0, &mut id, fh).await?;
+        if len == FILE_ID_LEN {
+            return Ok(id);
+        }
+        if !create {
+            return Err(FsError::Encryption("missing file header"));
+        }
+        let id = crypto::random_bytes::<FILE_ID_LEN>();
+        let mut written = 0;
+        while written < id.len() {
+            written += self
+                .inner
+                .write(ino, written as u64, &id[written..], fh)
+                .await?;
+        }
+        Ok(id)
+    }
+
+    /// Read and decrypt `count` chunks starting with `first`, `size` is the plaintext size of the
+    /// file. Chunks after the end of the file are not returned.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read_chunks(
+        &self,
+        ino: u64,
+        fh: u64,
+        id: &FileId,
+        first: u64,
+        count: u64,
+        size: u64,
+    ) -> FsResult<Vec<Vec<u8>>> {
+        let mut buf = vec![0; (count * ENCRYPTED_CHUNK_SIZE) as usize];
+        let len = self
+            .inner
+            .read(ino, chunk_offset(first), &mut buf, fh)
+            .await?;
+        buf.truncate(len);
+        buf.chunks(ENCRYPTED_CHUNK_SIZE as usize)
+            .zip(first..)
+            .map(|(chunk, index)| self.encryptor.decrypt(chunk, &chunk_aad(id, index, size)))
+            .collect()
+    }
+
+    /// Write `data` to the inner file at `offset`.
+    async fn write_inner(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> FsResult<()> {
+        let mut written = 0;
+        while written < data.len() {
+            written += self
+                .inner
+                .write(ino, offset + written as u64, &data[written..], fh)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` at `offset`, `size` is the current plaintext size and `offset` must not be
+    /// after it. Chunks which are only partially overwritten are decrypted, patched and encrypted
+    /// again.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn write_chunks(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        buf: &[u8],
+        size: u64,
+    ) -> FsResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = offset + buf.len() as u64;
+        let new_size = max(size, end);
+        let id = self.file_id(ino, fh, true).await?;
+        let mut first = offset / CHUNK_SIZE;
+        let last = (end - 1) / CHUNK_SIZE;
+        let mut data = Vec::with_capacity(((last - first + 2) * ENCRYPTED_CHUNK_SIZE) as usize);
+        if size > 0 && (size - 1) / CHUNK_SIZE < first {
+            // the last chunk is full and not the last one anymore, encrypt it again
+            first -= 1;
+            let chunk = self
+                .read_chunks(ino, fh, &id, first, 1, size)
+                .await?
+                .pop()
+                .unwrap_or_default();
+            data.extend(
+                self.encryptor
+                    .encrypt(&chunk, &chunk_aad(&id, first, new_size))?,
+            );
+        }
+        for index in (offset / CHUNK_SIZE)..=last {
+            let chunk_start = index * CHUNK_SIZE;
+            let from = max(offset, chunk_start);
+            let to = min(end, chunk_start + CHUNK_SIZE);
+            let existing_end = min(size, chunk_start + CHUNK_SIZE);
+            let mut chunk = if chunk_start < size && (from > chunk_start || to < existing_end) {
+                self.read_chunks(ino, fh, &id, index, 1, size)
+                    .await?
+                    .pop()
+                    .unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            if (chunk.len() as u64) < to - chunk_start {
+                chunk.resize((to - chunk_start) as usize, 0);
+            }
+            chunk[(from - chunk_start) as usize..(to - chunk_start) as usize]
+                .copy_from_slice(&buf[(from - offset) as usize..(to - offset) as usize]);
+            data.extend(
+                self.encryptor
+                    .encrypt(&chunk, &chunk_aad(&id, index, new_size))?,
+            );
+        }
+        self.write_inner(ino, fh, chunk_offset(first), &data).await
+    }
+
+    /// Extend the file from `size` to `new_size` with zeros.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn fill_zeros(&self, ino: u64, fh: u64, mut size: u64, new_size: u64) -> FsResult<()> {
+        while size < new_size {
+            let end = min(
+                new_size,
+                (size / CHUNK_SIZE + ZERO_FILL_CHUNKS) * CHUNK_SIZE,
+            );
+            let zeros = vec![0; (end - size) as usize];
+            self.write_chunks(ino, fh, size, &zeros, size).await?;
+            size = end;
+        }
+        Ok(())
+    }
+
+    async fn resize(&self, ino: u64, fh: u64, new_size: u64) -> FsResult<()> {
+        let size = self.size(ino).await?;
+        if new_size > size {
+            return self.fill_zeros(ino, fh, size, new_size).await;
+        }
+        if new_size == size {
+            return Ok(());
+        }
+        if new_size > 0 {
+            // there is a new last chunk, maybe shorter, encrypt it again
+            let id = self.file_id(ino, fh, false).await?;
+            let index = (new_size - 1) / CHUNK_SIZE;
+            let mut chunk = self
+                .read_chunks(ino, fh, &id, index, 1, size)
+                .await?
+                .pop()
+                .unwrap_or_default();
+            #[allow(clippy::cast_possible_truncation)]
+            chunk.truncate((new_size - index * CHUNK_SIZE) as usize);
+            let data = self
+                .encryptor
+                .encrypt(&chunk, &chunk_aad(&id, index, new_size))?;
+            self.inner
+                .write(ino, chunk_offset(index), &data, fh)
+                .await?;
+        }
+        self.inner.set_len(ino, encrypted_size(new_size)).await
+    }
+}
+
+#[async_trait]
+impl Filesystem for EncryptedFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.inner.exists(ino)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.inner.is_dir(ino)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.inner.is_file(ino)
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let name = self.encrypt_name(parent, name).await?;
+        let (fh, attr) = self
+            .inner
+            .create(parent, &name, create_attr, read || write, write)
+            .await?;
+        if attr.kind == FileType::Directory {
+            let id = crypto::random_bytes();
+            if let Err(err) = write_dir_id(&*self.inner, attr.ino, attr.uid, attr.gid, &id).await {
+                let _ = self.inner.remove_dir(parent, &name).await;
+                return Err(err);
+            }
+            self.dir_ids_mut().insert(attr.ino, id);
+        }
+        if read || write {
+            self.handles_mut().insert(fh, Handle { read, write });
+        }
+        Ok((fh, plain_attr(attr)))
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        let target =
+            URL_SAFE_NO_PAD.encode(self.encryptor.encrypt(target.as_bytes(), SYMLINK_AAD)?);
+        let attr = self
+            .inner
+            .symlink(
+                parent,
+                &self.encrypt_name(parent, name).await?,
+                &target,
+                create_attr,
+            )
+            .await?;
+        Ok(plain_attr(attr))
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        let data = URL_SAFE_NO_PAD
+            .decode(self.inner.read_link(ino).await?)
+            .map_err(|_| FsError::Encryption("invalid encrypted target"))?;
+        String::from_utf8(self.encryptor.decrypt(&data, SYMLINK_AAD)?)
+            .map_err(|_| FsError::Encryption("invalid encrypted target"))
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        let attr = self
+            .inner
+            .link(
+                ino,
+                new_parent,
+                &self.encrypt_name(new_parent, new_name).await?,
+            )
+            .await?;
+        Ok(plain_attr(attr))
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        Ok(self
+            .inner
+            .find_by_name(parent, &self.encrypt_name(parent, name).await?)
+            .await?
+            .map(plain_attr))
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        let mut len = self.inner.len(ino).await?;
+        if self.inner.exists_by_name(ino, DIR_ID_FILE_NAME).await? {
+            len -= 1;
+        }
+        if ino == ROOT_INODE && self.inner.exists_by_name(ino, KEY_FILE_NAME).await? {
+            len -= 1;
+        }
+        Ok(len)
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        let name = self.encrypt_name(parent, name).await?;
+        let Some(attr) = self.inner.find_by_name(parent, &name).await? else {
+            return self.inner.remove_dir(parent, &name).await;
+        };
+        if attr.kind != FileType::Directory {
+            return self.inner.remove_dir(parent, &name).await;
+        }
+        let id = self.take_dir_id(&attr).await?;
+        let res = self.inner.remove_dir(parent, &name).await;
+        if res.is_err() {
+            self.restore_dir_id(&attr, &id).await?;
+        }
+        res
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        self.inner
+            .remove_file(parent, &self.encrypt_name(parent, name).await?)
+            .await
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        self.inner
+            .exists_by_name(parent, &self.encrypt_name(parent, name).await?)
+            .await
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        let id = self.dir_id(ino).await?;
+        let stream = self.inner.clone().read_dir(ino, cookie).await?;
+        Ok(stream
+            .filter_map(move |entry| {
+                future::ready(match entry {
+                    Ok(mut entry) => self
+                        .visible_name(ino, &id, mem::take(&mut entry.name))
+                        .map(|name| Ok(DirectoryEntry { name, ..entry })),
+                    Err(err) => Some(Err(err)),
+                })
+            })
+            .boxed())
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        let id = self.dir_id(ino).await?;
+        let stream = self.inner.clone().read_dir_plus(ino, cookie).await?;
+        Ok(stream
+            .filter_map(move |entry| {
+                future::ready(match entry {
+                    Ok(mut entry) => {
+                        entry.attr = plain_attr(entry.attr);
+                        self.visible_name(ino, &id, mem::take(&mut entry.name))
+                            .map(|name| Ok(DirectoryEntryPlus { name, ..entry }))
+                    }
+                    Err(err) => Some(Err(err)),
+                })
+            })
+            .boxed())
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        Ok(plain_attr(self.inner.get_attr(ino).await?))
+    }
+
+    async fn set_attr(&self, ino: u64, mut set_attr: SetFileAttr) -> FsResult<()> {
+        if let Some(size) = set_attr.size.take() {
+            self.set_len(ino, size).await?;
+        }
+        self.inner.set_attr(ino, set_attr).await
+    }
+
+    #[instrument(skip(self, buf))]
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        self.check_handle(handle, false)?;
+        let _guard = self.locks.get(ino).read().await;
+        let size = self.size(ino).await?;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let end = min(size, offset + buf.len() as u64);
+        let first = offset / CHUNK_SIZE;
+        let count = (end - 1) / CHUNK_SIZE - first + 1;
+        let id = self.file_id(ino, handle, false).await?;
+        let chunks = self
+            .read_chunks(ino, handle, &id, first, count, size)
+            .await?;
+        let mut pos = offset;
+        for (index, chunk) in (first..).zip(chunks) {
+            let chunk_start = index * CHUNK_SIZE;
+            let from = (pos - chunk_start) as usize;
+            let to = min(chunk.len() as u64, end - chunk_start) as usize;
+            if from >= to {
+                break;
+            }
+            let dest = (pos - offset) as usize;
+            buf[dest..dest + to - from].copy_from_slice(&chunk[from..to]);
+            pos += (to - from) as u64;
+        }
+        Ok((pos - offset) as usize)
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        self.handles_mut().remove(&handle);
+        self.inner.release(handle).await
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.handles().get(&fh).is_some_and(|h| h.read)
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        self.handles().get(&fh).is_some_and(|h| h.write)
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        self.check_handle(handle, true)?;
+        if buf.is_empty() {
+            // no-op
+            return Ok(0);
+        }
+        let _guard = self.locks.get(ino).write().await;
+        let size = self.size(ino).await?;
+        if offset > size {
+            self.fill_zeros(ino, handle, size, offset).await?;
+        }
+        self.write_chunks(ino, handle, offset, buf, max(size, offset))
+            .await?;
+        Ok(buf.len())
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        self.inner.flush(handle).await
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        copy_with_writes(
+            self,
+            src_ino,
+            src_offset,
+            dest_ino,
+            dest_offset,
+            size,
+            src_fh,
+            dest_fh,
+        )
+        .await
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if !read && !write {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        let fh = self.inner.open(ino, true, write).await?;
+        self.handles_mut().insert(fh, Handle { read, write });
+        Ok(fh)
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        let fh = self.inner.open(ino, true, true).await?;
+        let res = {
+            let _guard = self.locks.get(ino).write().await;
+            self.resize(ino, fh, size).await
+        };
+        self.inner.release(fh).await?;
+        res
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        // the content is encrypted, so the zeros are too
+        fallocate_with_writes(self, ino, offset, len, mode, handle).await
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        let name = self.encrypt_name(parent, name).await?;
+        let new_name = self.encrypt_name(new_parent, new_name).await?;
+        // an empty directory replaced by another one must lose its id first
+        let mut replaced = None;
+        if let Some(attr) = self.inner.find_by_name(new_parent, &new_name).await? {
+            if attr.kind == FileType::Directory
+                && self
+                    .inner
+                    .find_by_name(parent, &name)
+                    .await?
+                    .is_some_and(|src| src.kind == FileType::Directory && src.ino != attr.ino)
+            {
+                let id = self.take_dir_id(&attr).await?;
+                replaced = Some((attr, id));
+            }
+        }
+        let res = self
+            .inner
+            .rename(parent, &name, new_parent, &new_name)
+            .await;
+        if let (Err(_), Some((attr, id))) = (&res, replaced) {
+            self.restore_dir_id(&attr, &id).await?;
+        }
+        res
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        let value = self
+            .inner
+            .get_xattr(ino, &self.encrypt_xattr_name(name)?)
+            .await?;
+        self.encryptor.decrypt(&value, XATTR_AAD)
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        let value = self.encryptor.encrypt(value, XATTR_AAD)?;
+        self.inner
+            .set_xattr(ino, &self.encrypt_xattr_name(name)?, &value, mode)
+            .await
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        Ok(self
+            .inner
+            .list_xattr(ino)
+            .await?
+            .into_iter()
+            .filter_map(|name| {
+                let name = name.strip_prefix(XATTR_NAMESPACE)?;
+                self.name_encryptor
+                    .decrypt(XATTR_AAD, name)
+                    .inspect_err(|_| warn!(name, "cannot decrypt xattr name, skipping it"))
+                    .ok()
+            })
+            .collect())
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        self.inner
+            .remove_xattr(ino, &self.encrypt_xattr_name(name)?)
+            .await
+    }
+
+    async fn statfs(&self) -> FsResult<StatFs> {
+        self.inner.statfs().await
+    }
+
+    fn max_name_len(&self) -> usize {
+        crypto::max_plain_name_len(self.inner.max_name_len())
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        self.inner.referenced(ino)
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        self.dir_ids_mut().remove(&ino);
+        self.inner.forgotten(ino).await;
+    }
+
+    fn opened_dir(&self, ino: u64) {
+        self.inner.opened_dir(ino);
+    }
+
+    fn released_dir(&self, ino: u64) {
+        self.inner.released_dir(ino);
+    }
+}
+
+/// Plaintext size from the size of the encrypted file.
+const fn plain_size(size: u64) -> u64 {
+    let size = size.saturating_sub(HEADER_LEN);
+    let rem = size % ENCRYPTED_CHUNK_SIZE;
+    (size / ENCRYPTED_CHUNK_SIZE) * CHUNK_SIZE + rem.saturating_sub(CHUNK_OVERHEAD)
+}
+
+/// Size of the encrypted file from the plaintext size.
+const fn encrypted_size(size: u64) -> u64 {
+    let rem = size % CHUNK_SIZE;
+    HEADER_LEN
+        + (size / CHUNK_SIZE) * ENCRYPTED_CHUNK_SIZE
+        + if rem > 0 { rem + CHUNK_OVERHEAD } else { 0 }
+}
+
+/// Where the chunk with `index` starts in the encrypted file.
+const fn chunk_offset(index: u64) -> u64 {
+    HEADER_LEN + index * ENCRYPTED_CHUNK_SIZE
+}
+
+/// What's authenticated with the chunk with `index` of a file with the plaintext `size`.
+fn chunk_aad(id: &FileId, index: u64, size: u64) -> [u8; CHUNK_AAD_LEN] {
+    let mut aad = [0; CHUNK_AAD_LEN];
+    aad[..FILE_ID_LEN].copy_from_slice(id);
+    aad[FILE_ID_LEN..FILE_ID_LEN + 8].copy_from_slice(&index.to_le_bytes());
+    aad[FILE_ID_LEN + 8] = u8::from(size > 0 && index == (size - 1) / CHUNK_SIZE);
+    aad
+}
+
+fn plain_attr(mut attr: FileAttr) -> FileAttr {
+    match attr.kind {
+        FileType::RegularFile => attr.size = plain_size(attr.size),
+        // base64 encodes every 3 bytes in 4 chars
+        FileType::Symlink => {
+            attr.size = (attr.size * 3 / 4).saturating_sub(CHUNK_OVERHEAD);
+        }
+        FileType::Directory
+        | FileType::NamedPipe
+        | FileType::CharDevice
+        | FileType::BlockDevice
+        | FileType::Socket => {}
+    }
+    attr
+}
+
+async fn load_key(
+    inner: &dyn Filesystem,
+    attr: FileAttr,
+    cipher: Cipher,
+    password: &SecretString,
+) -> FsResult<SecretVec<u8>> {
+    #[allow(clippy::cast_possible_truncation)]
+    let mut buf = vec![0; attr.size as usize];
+    let fh = inner.open(attr.ino, true, false).await?;
+    let res = inner.read(attr.ino, 0, &mut buf, fh).await;
+    inner.release(fh).await?;
+    buf.truncate(res?);
+    let key_file: KeyFile = bincode::deserialize(&buf)?;
+    if key_file.version != KEY_FILE_VERSION {
+        return Err(FsError::Encryption("unsupported key file version"));
+    }
+    if key_file.cipher != cipher {
+        return Err(FsError::Encryption(
+            "cipher differs from the one the filesystem was created with",
+        ));
+    }
+    let derived = crypto::derive_key(password, &key_file.salt)?;
+    let key = Encryptor::new(cipher, derived.expose_secret())?
+        .decrypt(&key_file.key, KEY_AAD)
+        .map_err(|_| FsError::InvalidPassword)?;
+    Ok(SecretVec::new(key))
+}
+
+async fn create_key(
+    inner: &dyn Filesystem,
+    cipher: Cipher,
+    password: &SecretString,
+) -> FsResult<SecretVec<u8>> {
+    debug!("generating encryption key");
+    let key = SecretVec::new(crypto::random_bytes::<KEY_LEN>().to_vec());
+    let salt = crypto::random_bytes::<SALT_LEN>();
+    let derived = crypto::derive_key(password, &salt)?;
+    let key_file = KeyFile {
+        version: KEY_FILE_VERSION,
+        cipher,
+        salt,
+        key: Encryptor::new(cipher, derived.expose_secret())?
+            .encrypt(key.expose_secret(), KEY_AAD)?,
+    };
+    let data = bincode::serialize(&key_file)?;
+    write_new_file(
+        inner,
+        ROOT_INODE,
+        KEY_FILE_NAME,
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+        &data,
+    )
+    .await?;
+    Ok(key)
+}
+
+async fn write_dir_id(
+    inner: &dyn Filesystem,
+    ino: u64,
+    uid: u32,
+    gid: u32,
+    id: &DirId,
+) -> FsResult<()> {
+    write_new_file(inner, ino, DIR_ID_FILE_NAME, uid, gid, id).await
+}
+
+/// Create `name` in `parent` of the inner filesystem, readable only by its owner, with `data`.
+async fn write_new_file(
+    inner: &dyn Filesystem,
+    parent: u64,
+    name: &str,
+    uid: u32,
+    gid: u32,
+    data: &[u8],
+) -> FsResult<()> {
+    let (fh, attr) = inner
+        .create(
+            parent,
+            name,
+            CreateFileAttr {
+                kind: FileType::RegularFile,
+                perm: 0o600,
+                uid,
+                gid,
+                rdev: 0,
+                flags: 0,
+            },
+            false,
+            true,
+        )
+        .await?;
+    let res = inner.write(attr.ino, 0, data, fh).await;
+    let res = match res {
+        Ok(_) => inner.flush(fh).await,
+        Err(err) => Err(err),
+    };
+    inner.release(fh).await?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryFilesystem;
+    use crate::fs::testing::{dir_attr, file_attr, names};
+
+    struct Password(&'static str);
+
+    impl PasswordProvider for Password {
+        fn get_password(&self) -> Option<SecretString> {
+            Some(SecretString::new(self.0.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let inner: Arc<dyn Filesystem> = MemoryFilesystem::new();
+        let fs = EncryptedFilesystem::new(inner.clone(), Cipher::ChaCha20Poly1305, &Password("a"))
+            .await
+            .unwrap();
+        let (fh, attr) = fs
+            .create(ROOT_INODE, "secret", file_attr(0), true, true)
+            .await
+            .unwrap();
+        #[allow(clippy::cast_possible_truncation)]
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        fs.write(attr.ino, 0, &data[..5000], fh).await.unwrap();
+        fs.write(attr.ino, 5000, &data[5000..], fh).await.unwrap();
+        let mut buf = vec![0; data.len() + 10];
+        assert_eq!(
+            fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(),
+            data.len()
+        );
+        assert_eq!(&buf[..data.len()], data);
+        // across a chunk boundary
+        let mut buf = [0; 100];
+        fs.read(attr.ino, CHUNK_SIZE - 50, &mut buf, fh)
+            .await
+            .unwrap();
+        assert_eq!(
+            buf,
+            data[CHUNK_SIZE as usize - 50..CHUNK_SIZE as usize + 50]
+        );
+        fs.release(fh).await.unwrap();
+        fs.set_attr(attr.ino, SetFileAttr::default().with_size(CHUNK_SIZE + 10))
+            .await
+            .unwrap();
+        assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, CHUNK_SIZE + 10);
+
+        // neither the name nor the content are in the backend as they are
+        assert!(inner
+            .find_by_name(ROOT_INODE, "secret")
+            .await
+            .unwrap()
+            .is_none());
+        let raw = inner.get_attr(attr.ino).await.unwrap();
+        assert!(raw.size > CHUNK_SIZE + 10);
+        let fh = inner.open(attr.ino, true, false).await.unwrap();
+        let mut raw_data = vec![0; usize::try_from(raw.size).unwrap()];
+        inner.read(attr.ino, 0, &mut raw_data, fh).await.unwrap();
+        inner.release(fh).await.unwrap();
+        assert!(!raw_data.windows(100).any(|w| w == &data[..100]));
+
+        // only the right password opens it again
+        assert!(matches!(
+            EncryptedFilesystem::new(inner.clone(), Cipher::ChaCha20Poly1305, &Password("b")).await,
+            Err(FsError::InvalidPassword)
+        ));
+        let fs = EncryptedFilesystem::new(inner, Cipher::ChaCha20Poly1305, &Password("a"))
+            .await
+            .unwrap();
+        let attr = fs
+            .find_by_name(ROOT_INODE, "secret")
+            .await
+            .unwrap()
+            .unwrap();
+        let fh = fs.open(attr.ino, true, false).await.unwrap();
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(),
+            CHUNK_SIZE as usize + 10
+        );
+        assert_eq!(
+            buf[..CHUNK_SIZE as usize + 10],
+            data[..CHUNK_SIZE as usize + 10]
+        );
+    }
+
+    #[tokio::test]
+    async fn grow_fills_zeros() {
+        let inner: Arc<dyn Filesystem> = MemoryFilesystem::new();
+        let fs = EncryptedFilesystem::new(inner.clone(), Cipher::ChaCha20Poly1305, &Password("a"))
+            .await
+            .unwrap();
+        let (fh, attr) = fs
+            .create(ROOT_INODE, "grown", file_attr(0), true, true)
+            .await
+            .unwrap();
+        fs.write(attr.ino, 0, b"start", fh).await.unwrap();
+        let size = 300 * CHUNK_SIZE + 10;
+        fs.set_attr(attr.ino, SetFileAttr::default().with_size(size))
+            .await
+            .unwrap();
+        // past the end, the gap is filled too
+        let end = 600 * CHUNK_SIZE;
+        fs.write(attr.ino, end, b"end", fh).await.unwrap();
+        let raw = inner.get_attr(attr.ino).await.unwrap();
+        assert_eq!(raw.size, encrypted_size(end + 3));
+
+        let mut buf = vec![0xff; 3 * CHUNK_SIZE as usize];
+        fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+        assert_eq!(&buf[..5], b"start");
+        assert!(buf[5..].iter().all(|&b| b == 0));
+        let mut buf = vec![0xff; CHUNK_SIZE as usize];
+        fs.read(attr.ino, size - 10, &mut buf, fh).await.unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        let mut buf = vec![0xff; 2 * CHUNK_SIZE as usize];
+        assert_eq!(
+            fs.read(attr.ino, end - CHUNK_SIZE, &mut buf, fh)
+                .await
+                .unwrap(),
+            CHUNK_SIZE as usize + 3
+        );
+        assert!(buf[..CHUNK_SIZE as usize].iter().all(|&b| b == 0));
+        assert_eq!(&buf[CHUNK_SIZE as usize..CHUNK_SIZE as usize + 3], b"end");
+        fs.release(fh).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn zeroed_chunk_fails() {
+        let inner: Arc<dyn Filesystem> = MemoryFilesystem::new();
+        let fs = EncryptedFilesystem::new(inner.clone(), Cipher::ChaCha20Poly1305, &Password("a"))
+            .await
+            .unwrap();
+        let (fh, attr) = fs
+            .create(ROOT_INODE, "a", file_attr(0), true, true)
+            .await
+            .unwrap();
+        fs.set_attr(attr.ino, SetFileAttr::default().with_size(3 * CHUNK_SIZE))
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+
+        // someone with access to the inner files zeroes the middle chunk
+        let inner_fh = inner.open(attr.ino, false, true).await.unwrap();
+        #[allow(clippy::cast_possible_truncation)]
+        let zeros = vec![0; ENCRYPTED_CHUNK_SIZE as usize];
+        inner
+            .write(attr.ino, chunk_offset(1), &zeros, inner_fh)
+            .await
+            .unwrap();
+        inner.release(inner_fh).await.unwrap();
+
+        let fh = fs.open(attr.ino, true, false).await.unwrap();
+        let mut buf = vec![0; CHUNK_SIZE as usize];
+        assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), buf.len());
+        // replied with EIO
+        assert!(matches!(
+            fs.read(attr.ino, CHUNK_SIZE, &mut buf, fh).await,
+            Err(FsError::Encryption(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn names_depend_on_directory() {
+        let inner: Arc<dyn Filesystem> = MemoryFilesystem::new();
+        let fs = EncryptedFilesystem::new(inner.clone(), Cipher::ChaCha20Poly1305, &Password("a"))
+            .await
+            .unwrap();
+        let (_, a) = fs
+            .create(ROOT_INODE, "a", dir_attr(0), false, false)
+            .await
+            .unwrap();
+        let (_, b) = fs
+            .create(ROOT_INODE, "b", dir_attr(0), false, false)
+            .await
+            .unwrap();
+        for dir in [a.ino, b.ino] {
+            fs.create(dir, "same", file_attr(0), false, false)
+                .await
+                .unwrap();
+        }
+        let inner_names_a = names(inner.clone(), a.ino).await;
+        let inner_names_b = names(inner.clone(), b.ino).await;
+        assert_eq!(inner_names_a.len(), 2);
+        assert!(inner_names_a
+            .iter()
+            .all(|name| !inner_names_b.contains(name) || name == DIR_ID_FILE_NAME));
+        assert_eq!(names(fs.clone(), a.ino).await, ["same"]);
+
+        // moving the raw entry to the other directory doesn't give a valid name there
+        let raw = inner_names_a
+            .iter()
+            .find(|name| *name != DIR_ID_FILE_NAME)
+            .unwrap();
+        inner.rename(a.ino, raw, b.ino, raw).await.unwrap();
+        assert_eq!(names(fs.clone(), b.ino).await, ["same"]);
+        inner.rename(b.ino, raw, a.ino, raw).await.unwrap();
+
+        // the ids don't keep directories from being removed or replaced
+        fs.remove_file(a.ino, "same").await.unwrap();
+        assert_eq!(fs.len(a.ino).await.unwrap(), 0);
+        assert!(matches!(
+            fs.rename(ROOT_INODE, "a", ROOT_INODE, "b").await,
+            Err(FsError::NotEmpty)
+        ));
+        assert_eq!(names(fs.clone(), b.ino).await, ["same"]);
+        fs.rename(ROOT_INODE, "b", ROOT_INODE, "a").await.unwrap();
+        assert_eq!(names(fs.clone(), b.ino).await, ["same"]);
+        fs.remove_file(b.ino, "same").await.unwrap();
+        fs.remove_dir(ROOT_INODE, "a").await.unwrap();
+        assert!(names(fs.clone(), ROOT_INODE).await.is_empty());
+
+        // the names are still readable after mounting again
+        let (_, c) = fs
+            .create(ROOT_INODE, "c", dir_attr(0), false, false)
+            .await
+            .unwrap();
+        fs.create(c.ino, "inside", file_attr(0), false, false)
+            .await
+            .unwrap();
+        let fs = EncryptedFilesystem::new(inner, Cipher::ChaCha20Poly1305, &Password("a"))
+            .await
+            .unwrap();
+        assert_eq!(names(fs.clone(), c.ino).await, ["inside"]);
+    }
+}
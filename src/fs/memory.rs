@@ -0,0 +1,1010 @@
+use std::cmp::{max, min};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use num_format::{Locale, ToFormattedString};
+use tracing::{debug, instrument};
+
+use crate::fs::directory::Directory;
+use crate::fs::memory::sparse::SparseFile;
+use crate::fs::{
+    check_name, merge_attr, read_dir_in_batches, set_xattr_in, Filesystem, Xattrs, COPY_CHUNK_LEN,
+    ROOT_INODE,
+};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, DirectoryEntryPlusStream,
+    DirectoryEntryStream, FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    SetXattrMode, StatFs, Whence,
+};
+
+pub(crate) mod sparse;
+
+pub(crate) const BLOCK_SIZE: u64 = 4096;
+
+enum Data {
+    Directory(Directory),
+    File(SparseFile),
+    /// Target of a symbolic link.
+    Symlink(String),
+    /// FIFOs, sockets and devices, all we keep are the attributes.
+    Special,
+}
+
+struct Node {
+    attr: FileAttr,
+    /// Only meaningful for directories, it's where ".." points to.
+    parent: u64,
+    /// How many handles are opened for this node, we keep the node after the last link is removed
+    /// until this reaches zero.
+    open_handles: u32,
+    /// References the kernel has to the node, it's kept until they are forgotten too.
+    references: u64,
+    /// Bumped each time the inode number is reused.
+    generation: u64,
+    data: Data,
+    xattrs: Xattrs,
+}
+
+impl Node {
+    const fn is_dir(&self) -> bool {
+        matches!(self.data, Data::Directory(_))
+    }
+}
+
+struct Handle {
+    ino: u64,
+    read: bool,
+    write: bool,
+}
+
+#[derive(Default)]
+struct State {
+    nodes: HashMap<u64, Node>,
+    handles: HashMap<u64, Handle>,
+    /// Numbers of the dropped nodes with their generation, reused oldest first.
+    free: VecDeque<(u64, u64)>,
+}
+
+impl State {
+    fn node(&self, ino: u64) -> FsResult<&Node> {
+        self.nodes.get(&ino).ok_or(FsError::InodeNotFound)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> FsResult<&mut Node> {
+        self.nodes.get_mut(&ino).ok_or(FsError::InodeNotFound)
+    }
+
+    fn children(&self, ino: u64) -> FsResult<&Directory> {
+        match &self.node(ino)?.data {
+            Data::Directory(children) => Ok(children),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn children_mut(&mut self, ino: u64) -> FsResult<&mut Directory> {
+        match &mut self.node_mut(ino)?.data {
+            Data::Directory(children) => Ok(children),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// Up to `len` entries of a directory after `cookie`.
+    fn entries(&self, ino: u64, cookie: u64, len: usize) -> FsResult<Vec<DirectoryEntryPlus>> {
+        let parent = self.node(ino)?.parent;
+        self.children(ino)?
+            .after(cookie, ino, parent)
+            .take(len)
+            .map(|(name, child, cookie)| {
+                let attr = self.node(child)?.attr;
+                Ok(DirectoryEntryPlus {
+                    ino: child,
+                    name: name.to_string(),
+                    kind: attr.kind,
+                    attr,
+                    cookie,
+                })
+            })
+            .collect()
+    }
+
+    fn content(&self, ino: u64) -> FsResult<&SparseFile> {
+        match &self.node(ino)?.data {
+            Data::File(content) => Ok(content),
+            _ => Err(FsError::InvalidInodeType),
+        }
+    }
+
+    fn handle(&self, fh: u64, ino: u64) -> FsResult<&Handle> {
+        match self.handles.get(&fh) {
+            Some(handle) if handle.ino == ino => Ok(handle),
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+
+    fn touch(&mut self, ino: u64) -> FsResult<()> {
+        let now = SystemTime::now();
+        let attr = &mut self.node_mut(ino)?.attr;
+        attr.mtime = now;
+        attr.ctime = now;
+        Ok(())
+    }
+
+    /// Decrement the link count and drop the node if nothing else uses it.
+    fn unlink(&mut self, ino: u64) -> FsResult<()> {
+        let node = self.node_mut(ino)?;
+        node.attr.nlink = node.attr.nlink.saturating_sub(1);
+        node.attr.ctime = SystemTime::now();
+        self.drop_unused(ino);
+        Ok(())
+    }
+
+    /// Detach an empty directory from its parent and drop it if nothing else uses it.
+    fn remove_dir_node(&mut self, parent: u64, ino: u64) -> FsResult<()> {
+        if !self.children(ino)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        let node = self.node_mut(ino)?;
+        node.attr.nlink = 0;
+        node.attr.ctime = SystemTime::now();
+        self.drop_unused(ino);
+        let parent_attr = &mut self.node_mut(parent)?.attr;
+        parent_attr.nlink -= 1;
+        Ok(())
+    }
+
+    /// Drop the node if it has no links, opened handles or references from the kernel, its
+    /// number can be reused after.
+    fn drop_unused(&mut self, ino: u64) {
+        let Some(node) = self.nodes.get(&ino) else {
+            return;
+        };
+        if node.attr.nlink == 0 && node.open_handles == 0 && node.references == 0 {
+            debug!(ino, "node dropped");
+            self.free.push_back((ino, node.generation));
+            self.nodes.remove(&ino);
+        }
+    }
+
+    /// Check if `ino` is `ancestor` or is inside it.
+    fn is_descendant(&self, mut ino: u64, ancestor: u64) -> FsResult<bool> {
+        loop {
+            if ino == ancestor {
+                return Ok(true);
+            }
+            if ino == ROOT_INODE {
+                return Ok(false);
+            }
+            ino = self.node(ino)?.parent;
+        }
+    }
+
+    fn read(&self, ino: u64, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        Ok(self.content(ino)?.read(offset, buf))
+    }
+
+    fn write(&mut self, ino: u64, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let node = self.node_mut(ino)?;
+        let Data::File(content) = &mut node.data else {
+            return Err(FsError::InvalidInodeType);
+        };
+        // what is between the end and `offset` is left a hole
+        content.write(offset, buf);
+        set_file_size(&mut node.attr, content);
+        self.touch(ino)?;
+        Ok(buf.len())
+    }
+
+    fn set_len(&mut self, ino: u64, size: u64) -> FsResult<()> {
+        let node = self.node_mut(ino)?;
+        let Data::File(content) = &mut node.data else {
+            return Err(FsError::InvalidInodeType);
+        };
+        if size == content.len() {
+            // no-op
+            return Ok(());
+        }
+        debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
+        content.set_len(size);
+        set_file_size(&mut node.attr, content);
+        self.touch(ino)
+    }
+
+    fn fallocate(&mut self, ino: u64, offset: u64, len: u64, mode: FallocateMode) -> FsResult<()> {
+        let node = self.node_mut(ino)?;
+        let Data::File(content) = &mut node.data else {
+            return Err(FsError::NotARegularFile);
+        };
+        let size = content.len();
+        let zeroed = mode.zeroed(size, offset, len);
+        let new_size = mode.new_size(size, offset, len);
+        if zeroed.is_none() && new_size == size {
+            // no-op, we don't reserve memory ahead
+            return Ok(());
+        }
+        if let Some((start, end)) = zeroed {
+            content.punch_hole(start, end);
+        }
+        content.set_len(new_size);
+        set_file_size(&mut node.attr, content);
+        self.touch(ino)
+    }
+
+    fn seek(&self, ino: u64, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+        let content = self.content(ino)?;
+        Ok(match whence {
+            Whence::Data => content.seek_data(offset),
+            Whence::Hole => content.seek_hole(offset),
+        })
+    }
+}
+
+/// The RAM of the host, like `tmpfs` we can use at most this much.
+pub(crate) fn total_memory() -> u64 {
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    #[allow(clippy::cast_sign_loss)]
+    if pages > 0 && page_size > 0 {
+        pages as u64 * page_size as u64
+    } else {
+        u64::MAX
+    }
+}
+
+fn set_size(attr: &mut FileAttr, size: u64) {
+    attr.size = size;
+    attr.blocks = size.div_ceil(512);
+}
+
+/// The blocks of a file are the ones allocated, the holes are not counted.
+fn set_file_size(attr: &mut FileAttr, content: &SparseFile) {
+    attr.size = content.len();
+    attr.blocks = content.allocated() / 512;
+}
+
+/// In-memory filesystem, similar to `tmpfs`. Everything is lost on unmount.
+pub(crate) struct MemoryFilesystem {
+    state: RwLock<State>,
+    current_ino: AtomicU64,
+    current_handle: AtomicU64,
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Arc<Self> {
+        let fs = Self {
+            state: RwLock::new(State::default()),
+            current_ino: AtomicU64::new(ROOT_INODE),
+            current_handle: AtomicU64::new(0),
+        };
+        fs.ensure_root_exists();
+        Arc::new(fs)
+    }
+
+    fn ensure_root_exists(&self) {
+        let mut attr: FileAttr = CreateFileAttr {
+            kind: FileType::Directory,
+            perm: 0o755,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            flags: 0,
+        }
+        .into();
+        attr.ino = ROOT_INODE;
+        attr.blksize = BLOCK_SIZE as u32;
+        self.state_mut().nodes.insert(
+            ROOT_INODE,
+            Node {
+                attr,
+                parent: ROOT_INODE,
+                open_handles: 0,
+                references: 0,
+                generation: 0,
+                data: Data::Directory(Directory::default()),
+                xattrs: Xattrs::new(),
+            },
+        );
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().expect("state lock poisoned")
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().expect("state lock poisoned")
+    }
+
+    /// Add a new node to `parent`.
+    fn add_node(
+        &self,
+        state: &mut State,
+        parent: u64,
+        name: &str,
+        mut attr: FileAttr,
+        data: Data,
+    ) -> FsResult<FileAttr> {
+        check_name(name)?;
+        if state.children(parent)?.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let (ino, generation) = match state.free.pop_front() {
+            Some((ino, generation)) => (ino, generation + 1),
+            None => (self.current_ino.fetch_add(1, Ordering::SeqCst) + 1, 0),
+        };
+        attr.ino = ino;
+        attr.blksize = BLOCK_SIZE as u32;
+        state.nodes.insert(
+            attr.ino,
+            Node {
+                attr,
+                parent,
+                open_handles: 0,
+                references: 0,
+                generation,
+                data,
+                xattrs: Xattrs::new(),
+            },
+        );
+        state
+            .children_mut(parent)?
+            .insert(name.to_string(), attr.ino);
+        if attr.kind == FileType::Directory {
+            state.node_mut(parent)?.attr.nlink += 1;
+        }
+        state.touch(parent)?;
+        Ok(attr)
+    }
+
+    fn open_handle(&self, state: &mut State, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        let fh = self.current_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        state.node_mut(ino)?.open_handles += 1;
+        state.handles.insert(fh, Handle { ino, read, write });
+        Ok(fh)
+    }
+}
+
+#[async_trait]
+impl Filesystem for MemoryFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.state().nodes.contains_key(&ino)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.state().nodes.get(&ino).is_some_and(Node::is_dir)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.state()
+            .nodes
+            .get(&ino)
+            .is_some_and(|node| matches!(node.data, Data::File(_)))
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let data = match create_attr.kind {
+            FileType::Directory => Data::Directory(Directory::default()),
+            FileType::RegularFile => Data::File(SparseFile::default()),
+            FileType::Symlink => {
+                return Err(FsError::InvalidInput(
+                    "use symlink to create symbolic links",
+                ))
+            }
+            FileType::NamedPipe
+            | FileType::CharDevice
+            | FileType::BlockDevice
+            | FileType::Socket => Data::Special,
+        };
+        let mut state = self.state_mut();
+        let attr = self.add_node(&mut state, parent, name, create_attr.into(), data)?;
+
+        let fh = if read || write {
+            self.open_handle(&mut state, attr.ino, read, write)?
+        } else {
+            0
+        };
+        Ok((fh, attr))
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        let mut attr: FileAttr = CreateFileAttr {
+            kind: FileType::Symlink,
+            ..create_attr
+        }
+        .into();
+        set_size(&mut attr, target.len() as u64);
+        let mut state = self.state_mut();
+        self.add_node(
+            &mut state,
+            parent,
+            name,
+            attr,
+            Data::Symlink(target.to_string()),
+        )
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        match &self.state().node(ino)?.data {
+            Data::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidInodeType),
+        }
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        check_name(new_name)?;
+        let mut state = self.state_mut();
+        let node = state.node(ino)?;
+        if node.is_dir() {
+            return Err(FsError::DirectoryLink);
+        }
+        if node.attr.nlink == 0 {
+            // removed while it was opened
+            return Err(FsError::InodeNotFound);
+        }
+        if state.children(new_parent)?.contains_key(new_name) {
+            return Err(FsError::AlreadyExists);
+        }
+        state
+            .children_mut(new_parent)?
+            .insert(new_name.to_string(), ino);
+        let attr = &mut state.node_mut(ino)?.attr;
+        attr.nlink += 1;
+        attr.ctime = SystemTime::now();
+        let attr = *attr;
+        state.touch(new_parent)?;
+        Ok(attr)
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        let state = self.state();
+        let Some(ino) = state.children(parent)?.get(name) else {
+            return Ok(None);
+        };
+        Ok(Some(state.node(ino)?.attr))
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        Ok(self.state().children(ino)?.len())
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        let mut state = self.state_mut();
+        let ino = state
+            .children(parent)?
+            .get(name)
+            .ok_or(FsError::NotFound("name not found"))?;
+        if !state.node(ino)?.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        state.remove_dir_node(parent, ino)?;
+        state.children_mut(parent)?.remove(name);
+        state.touch(parent)
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        let mut state = self.state_mut();
+        let ino = state
+            .children(parent)?
+            .get(name)
+            .ok_or(FsError::NotFound("name not found"))?;
+        if state.node(ino)?.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        state.children_mut(parent)?.remove(name);
+        state.unlink(ino)?;
+        state.touch(parent)
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        Ok(self.state().children(parent)?.contains_key(name))
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        let stream = self.read_dir_plus(ino, cookie).await?;
+        Ok(stream.map(|entry| entry.map(DirectoryEntry::from)).boxed())
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        self.state().children(ino)?;
+        Ok(read_dir_in_batches(cookie, move |cookie, len| {
+            let entries = self.state().entries(ino, cookie, len)?;
+            let next = entries
+                .last()
+                .filter(|_| entries.len() == len)
+                .map(|entry| entry.cookie);
+            Ok((entries, next))
+        }))
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        Ok(self.state().node(ino)?.attr)
+    }
+
+    async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        let mut state = self.state_mut();
+        if let Some(size) = set_attr.size {
+            state.set_len(ino, size)?;
+        }
+        let attr = &mut state.node_mut(ino)?.attr;
+        merge_attr(attr, &set_attr);
+        Ok(())
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        let state = self.state();
+        if !state.handle(handle, ino)?.read {
+            return Err(FsError::InvalidFileHandle);
+        }
+        state.read(ino, offset, buf)
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        let mut state = self.state_mut();
+        let Some(Handle { ino, .. }) = state.handles.remove(&handle) else {
+            return Err(FsError::InvalidFileHandle);
+        };
+        state.node_mut(ino)?.open_handles -= 1;
+        state.drop_unused(ino);
+        Ok(())
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.state().handles.get(&fh).is_some_and(|h| h.read)
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        self.state().handles.get(&fh).is_some_and(|h| h.write)
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let mut state = self.state_mut();
+        if !state.handle(handle, ino)?.write {
+            return Err(FsError::InvalidFileHandle);
+        }
+        if buf.is_empty() {
+            // no-op
+            return Ok(0);
+        }
+        state.write(ino, offset, buf)
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        // nothing to flush, everything is already in memory
+        if !self.state().handles.contains_key(&handle) {
+            return Err(FsError::InvalidFileHandle);
+        }
+        Ok(())
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        let mut state = self.state_mut();
+        if !state.handle(src_fh, src_ino)?.read || !state.handle(dest_fh, dest_ino)?.write {
+            return Err(FsError::InvalidFileHandle);
+        }
+        // the kernel can ask for any size, we copy up to the end in chunks
+        let len = min(
+            size as u64,
+            state.content(src_ino)?.len().saturating_sub(src_offset),
+        );
+        #[allow(clippy::cast_possible_truncation)]
+        let mut buf = vec![0; min(len, COPY_CHUNK_LEN) as usize];
+        let mut copied = 0;
+        while copied < len {
+            #[allow(clippy::cast_possible_truncation)]
+            let buf_len = min(buf.len() as u64, len - copied) as usize;
+            state.read(src_ino, src_offset + copied, &mut buf[..buf_len])?;
+            state.write(dest_ino, dest_offset + copied, &buf[..buf_len])?;
+            copied += buf_len as u64;
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(copied as usize)
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if !read && !write {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        let mut state = self.state_mut();
+        if state.node(ino)?.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        self.open_handle(&mut state, ino, read, write)
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        self.state_mut().set_len(ino, size)
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        let mut state = self.state_mut();
+        if !state.handle(handle, ino)?.write {
+            return Err(FsError::InvalidFileHandle);
+        }
+        state.fallocate(ino, offset, len, mode)
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        check_name(new_name)?;
+        let mut state = self.state_mut();
+        let ino = state
+            .children(parent)?
+            .get(name)
+            .ok_or(FsError::NotFound("name not found"))?;
+        if parent == new_parent && name == new_name {
+            // no-op
+            return Ok(());
+        }
+        let is_dir = state.node(ino)?.is_dir();
+        if is_dir && state.is_descendant(new_parent, ino)? {
+            return Err(FsError::InvalidInput(
+                "cannot move a directory inside itself",
+            ));
+        }
+
+        if let Some(existing) = state.children(new_parent)?.get(new_name) {
+            if existing == ino {
+                // both names link to the same inode, nothing to do
+                return Ok(());
+            }
+            let existing_is_dir = state.node(existing)?.is_dir();
+            if is_dir != existing_is_dir {
+                return Err(if existing_is_dir {
+                    FsError::IsADirectory
+                } else {
+                    FsError::NotADirectory
+                });
+            }
+            if existing_is_dir {
+                state.remove_dir_node(new_parent, existing)?;
+            } else {
+                state.unlink(existing)?;
+            }
+            state.children_mut(new_parent)?.remove(new_name);
+        }
+
+        state.children_mut(parent)?.remove(name);
+        state
+            .children_mut(new_parent)?
+            .insert(new_name.to_string(), ino);
+        if is_dir && parent != new_parent {
+            state.node_mut(parent)?.attr.nlink -= 1;
+            state.node_mut(new_parent)?.attr.nlink += 1;
+            state.node_mut(ino)?.parent = new_parent;
+        }
+
+        state.node_mut(ino)?.attr.ctime = SystemTime::now();
+        state.touch(parent)?;
+        state.touch(new_parent)
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        self.state()
+            .node(ino)?
+            .xattrs
+            .get(name)
+            .cloned()
+            .ok_or(FsError::XattrNotFound)
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        let mut state = self.state_mut();
+        let node = state.node_mut(ino)?;
+        set_xattr_in(&mut node.xattrs, name, value, mode)?;
+        node.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        Ok(self.state().node(ino)?.xattrs.keys().cloned().collect())
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        let mut state = self.state_mut();
+        let node = state.node_mut(ino)?;
+        node.xattrs.remove(name).ok_or(FsError::XattrNotFound)?;
+        node.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+
+    async fn seek(&self, ino: u64, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+        self.state().seek(ino, offset, whence)
+    }
+
+    async fn statfs(&self) -> FsResult<StatFs> {
+        let state = self.state();
+        let used = state
+            .nodes
+            .values()
+            .map(|node| match &node.data {
+                Data::File(content) => content.allocated() / BLOCK_SIZE,
+                Data::Symlink(target) => (target.len() as u64).div_ceil(BLOCK_SIZE),
+                Data::Directory(_) | Data::Special => 0,
+            })
+            .sum();
+        let blocks = max(total_memory() / BLOCK_SIZE, used);
+        // as many inodes as blocks, so it's not the limit before the memory is
+        let files = max(blocks, state.nodes.len() as u64);
+        Ok(StatFs {
+            bsize: BLOCK_SIZE as u32,
+            blocks,
+            bfree: blocks - used,
+            bavail: blocks - used,
+            files,
+            ffree: files - state.nodes.len() as u64,
+        })
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        let mut state = self.state_mut();
+        state.nodes.get_mut(&ino).map_or(0, |node| {
+            node.references += 1;
+            node.generation
+        })
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        let mut state = self.state_mut();
+        if let Some(node) = state.nodes.get_mut(&ino) {
+            node.references = node.references.saturating_sub(1);
+            state.drop_unused(ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing::{dir_attr, file_attr, names};
+
+    #[tokio::test]
+    async fn create_read_write() {
+        let fs = MemoryFilesystem::new();
+        let (fh, attr) = fs
+            .create(ROOT_INODE, "a", file_attr(0), true, true)
+            .await
+            .unwrap();
+        assert!(matches!(
+            fs.create(ROOT_INODE, "a", file_attr(0), true, true).await,
+            Err(FsError::AlreadyExists)
+        ));
+        assert_eq!(fs.write(attr.ino, 0, b"hello", fh).await.unwrap(), 5);
+        assert_eq!(fs.write(attr.ino, 10, b"world", fh).await.unwrap(), 5);
+        let mut buf = [1; 20];
+        assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 15);
+        assert_eq!(&buf[..15], b"hello\0\0\0\0\0world");
+        assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 15);
+        fs.release(fh).await.unwrap();
+        assert_eq!(
+            fs.find_by_name(ROOT_INODE, "a").await.unwrap().unwrap().ino,
+            attr.ino
+        );
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let fs = MemoryFilesystem::new();
+        let dir = fs
+            .create(ROOT_INODE, "dir", dir_attr(0), false, false)
+            .await
+            .unwrap()
+            .1;
+        let (fh, a) = fs
+            .create(ROOT_INODE, "a", file_attr(0), false, true)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+        let (fh, b) = fs
+            .create(dir.ino, "b", file_attr(0), false, true)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+        // over an existing file, which is removed
+        fs.rename(ROOT_INODE, "a", dir.ino, "b").await.unwrap();
+        assert!(fs.find_by_name(ROOT_INODE, "a").await.unwrap().is_none());
+        assert_eq!(
+            fs.find_by_name(dir.ino, "b").await.unwrap().unwrap().ino,
+            a.ino
+        );
+        assert!(!fs.exists(b.ino));
+        assert_eq!(names(fs.clone(), dir.ino).await, ["b"]);
+        assert!(matches!(
+            fs.rename(ROOT_INODE, "dir", dir.ino, "dir").await,
+            Err(FsError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            fs.rename(dir.ino, "b", ROOT_INODE, "dir").await,
+            Err(FsError::IsADirectory)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unlink_open_file() {
+        let fs = MemoryFilesystem::new();
+        let (fh, attr) = fs
+            .create(ROOT_INODE, "a", file_attr(0), true, true)
+            .await
+            .unwrap();
+        fs.write(attr.ino, 0, b"data", fh).await.unwrap();
+        fs.remove_file(ROOT_INODE, "a").await.unwrap();
+        assert!(fs.find_by_name(ROOT_INODE, "a").await.unwrap().is_none());
+        // the content stays until it's closed
+        let mut buf = [0; 4];
+        assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 4);
+        assert_eq!(&buf, b"data");
+        fs.release(fh).await.unwrap();
+        assert!(!fs.exists(attr.ino));
+        assert!(matches!(
+            fs.remove_file(ROOT_INODE, "a").await,
+            Err(FsError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn nlink() {
+        let fs = MemoryFilesystem::new();
+        let nlink = |ino| {
+            let fs = fs.clone();
+            async move { fs.get_attr(ino).await.unwrap().nlink }
+        };
+        let (fh, file) = fs
+            .create(ROOT_INODE, "a", file_attr(0), false, true)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+        assert_eq!(fs.link(file.ino, ROOT_INODE, "b").await.unwrap().nlink, 2);
+        assert_eq!(nlink(file.ino).await, 2);
+        fs.remove_file(ROOT_INODE, "a").await.unwrap();
+        assert_eq!(nlink(file.ino).await, 1);
+
+        // each subdirectory links to its parent with ".."
+        let a = fs
+            .create(ROOT_INODE, "dir_a", dir_attr(0), false, false)
+            .await
+            .unwrap()
+            .1;
+        let b = fs
+            .create(ROOT_INODE, "dir_b", dir_attr(0), false, false)
+            .await
+            .unwrap()
+            .1;
+        assert_eq!((a.nlink, nlink(ROOT_INODE).await), (2, 4));
+        let sub = fs
+            .create(a.ino, "sub", dir_attr(0), false, false)
+            .await
+            .unwrap()
+            .1;
+        assert_eq!(nlink(a.ino).await, 3);
+        fs.rename(a.ino, "sub", b.ino, "sub").await.unwrap();
+        assert_eq!((nlink(a.ino).await, nlink(b.ino).await), (2, 3));
+        // over an empty directory, which is removed
+        fs.create(a.ino, "other", dir_attr(0), false, false)
+            .await
+            .unwrap();
+        fs.rename(a.ino, "other", b.ino, "sub").await.unwrap();
+        assert!(!fs.exists(sub.ino));
+        assert_eq!((nlink(a.ino).await, nlink(b.ino).await), (2, 3));
+        fs.remove_dir(b.ino, "sub").await.unwrap();
+        assert_eq!(nlink(b.ino).await, 2);
+        fs.remove_dir(ROOT_INODE, "dir_b").await.unwrap();
+        assert_eq!(nlink(ROOT_INODE).await, 3);
+
+        assert!(matches!(
+            fs.link(a.ino, ROOT_INODE, "c").await,
+            Err(FsError::DirectoryLink)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sparse() {
+        let fs = MemoryFilesystem::new();
+        let (fh, attr) = fs
+            .create(ROOT_INODE, "a", file_attr(0), true, true)
+            .await
+            .unwrap();
+        fs.write(attr.ino, 0, &[1; 3 * BLOCK_SIZE as usize], fh)
+            .await
+            .unwrap();
+        fs.fallocate(attr.ino, 0, 2 * BLOCK_SIZE, FallocateMode::PunchHole, fh)
+            .await
+            .unwrap();
+        let attr = fs.get_attr(attr.ino).await.unwrap();
+        assert_eq!(attr.size, 3 * BLOCK_SIZE);
+        assert_eq!(attr.blocks * 512, BLOCK_SIZE);
+        assert_eq!(
+            fs.seek(attr.ino, 0, Whence::Data).await.unwrap(),
+            Some(2 * BLOCK_SIZE)
+        );
+        assert_eq!(fs.seek(attr.ino, 0, Whence::Hole).await.unwrap(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn copy_file_range() {
+        let fs = MemoryFilesystem::new();
+        let (src_fh, src) = fs
+            .create(ROOT_INODE, "a", file_attr(0), true, true)
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..3 * COPY_CHUNK_LEN / 2)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs.write(src.ino, 0, &data, src_fh).await.unwrap();
+        let (dest_fh, dest) = fs
+            .create(ROOT_INODE, "b", file_attr(0), true, true)
+            .await
+            .unwrap();
+        // the kernel can ask for more than there is
+        assert_eq!(
+            fs.copy_file_range(src.ino, 10, dest.ino, 0, usize::MAX, src_fh, dest_fh)
+                .await
+                .unwrap(),
+            data.len() - 10
+        );
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            fs.read(dest.ino, 0, &mut buf, dest_fh).await.unwrap(),
+            data.len() - 10
+        );
+        assert_eq!(buf[..data.len() - 10], data[10..]);
+        assert_eq!(
+            fs.copy_file_range(src.ino, 1 << 40, dest.ino, 0, usize::MAX, src_fh, dest_fh)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+
+use crate::fs::memory::BLOCK_SIZE;
+
+/// Content of a file stored in blocks of [`BLOCK_SIZE`], a block is allocated on the first write
+/// of something other than zeros to it. The ranges without blocks are holes, they take no space
+/// and read as zeros.
+#[derive(Debug, Default)]
+pub(crate) struct SparseFile {
+    size: u64,
+    /// Allocated blocks by index.
+    blocks: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl SparseFile {
+    pub const fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes taken by the allocated blocks.
+    pub fn allocated(&self) -> u64 {
+        self.blocks.len() as u64 * BLOCK_SIZE
+    }
+
+    /// Read from `offset`, it returns how much was read, 0 at or after the end.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let len = min(buf.len() as u64, self.size - offset) as usize;
+        let mut pos = 0;
+        while pos < len {
+            let (index, start) = split(offset + pos as u64);
+            let n = min(BLOCK_SIZE as usize - start, len - pos);
+            match self.blocks.get(&index) {
+                Some(block) => buf[pos..pos + n].copy_from_slice(&block[start..start + n]),
+                None => buf[pos..pos + n].fill(0),
+            }
+            pos += n;
+        }
+        len
+    }
+
+    /// Write at `offset`, the file grows if it ends after the end. Zeros written over a hole
+    /// leave it a hole.
+    pub fn write(&mut self, offset: u64, buf: &[u8]) {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (index, start) = split(offset + pos as u64);
+            let n = min(BLOCK_SIZE as usize - start, buf.len() - pos);
+            let data = &buf[pos..pos + n];
+            if let Some(block) = self.blocks.get_mut(&index) {
+                block[start..start + n].copy_from_slice(data);
+            } else if data.iter().any(|b| *b != 0) {
+                #[allow(clippy::cast_possible_truncation)]
+                let mut block = vec![0; BLOCK_SIZE as usize].into_boxed_slice();
+                block[start..start + n].copy_from_slice(data);
+                self.blocks.insert(index, block);
+            }
+            pos += n;
+        }
+        self.size = max(self.size, offset + buf.len() as u64);
+    }
+
+    /// Truncate or extend the file, extending only adds a hole at the end.
+    pub fn set_len(&mut self, size: u64) {
+        if size < self.size {
+            self.punch_hole(size, self.size);
+        }
+        self.size = size;
+    }
+
+    /// Make a hole from `start` to `end`, the blocks all in it are freed and the parts of the
+    /// others are zeroed.
+    pub fn punch_hole(&mut self, start: u64, end: u64) {
+        let end = min(end, self.size);
+        if start >= end {
+            return;
+        }
+        let first = start.div_ceil(BLOCK_SIZE);
+        let last = end / BLOCK_SIZE;
+        if first < last {
+            let freed: Vec<u64> = self.blocks.range(first..last).map(|(i, _)| *i).collect();
+            for index in freed {
+                self.blocks.remove(&index);
+            }
+        }
+        // the blocks at the ends which are only partly in the range
+        let (index, from) = split(start);
+        #[allow(clippy::cast_possible_truncation)]
+        let to = min(BLOCK_SIZE, end - index * BLOCK_SIZE) as usize;
+        self.zero(index, from, to);
+        let (index, to) = split(end);
+        if index * BLOCK_SIZE > start {
+            self.zero(index, 0, to);
+        }
+    }
+
+    /// Zero part of a block, it's freed if it has only zeros after.
+    fn zero(&mut self, index: u64, from: usize, to: usize) {
+        let Some(block) = self.blocks.get_mut(&index) else {
+            return;
+        };
+        block[from..to].fill(0);
+        if block.iter().all(|b| *b == 0) {
+            self.blocks.remove(&index);
+        }
+    }
+
+    /// Start of the first byte of data at or after `offset`, `None` if there is only a hole after.
+    pub fn seek_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        self.blocks
+            .range(offset / BLOCK_SIZE..)
+            .next()
+            .map(|(index, _)| max(index * BLOCK_SIZE, offset))
+            .filter(|pos| *pos < self.size)
+    }
+
+    /// Start of the first hole at or after `offset`, there is always one at the end. `None` if
+    /// `offset` is at or after the end.
+    pub fn seek_hole(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        let mut next = offset / BLOCK_SIZE;
+        for (index, _) in self.blocks.range(next..) {
+            if *index != next {
+                break;
+            }
+            next += 1;
+        }
+        Some(min(max(next * BLOCK_SIZE, offset), self.size))
+    }
+}
+
+/// Index of the block with `offset` and where `offset` is in it.
+#[allow(clippy::cast_possible_truncation)]
+const fn split(offset: u64) -> (u64, usize) {
+    (offset / BLOCK_SIZE, (offset % BLOCK_SIZE) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BS: u64 = BLOCK_SIZE;
+
+    #[test]
+    fn zeros_leave_holes() {
+        let mut file = SparseFile::default();
+        file.write(0, &[0; 3 * BS as usize]);
+        assert_eq!(file.len(), 3 * BS);
+        assert_eq!(file.allocated(), 0);
+        file.write(BS + 1, b"a");
+        assert_eq!(file.allocated(), BS);
+        let mut buf = [1; 4];
+        assert_eq!(file.read(BS, &mut buf), 4);
+        assert_eq!(buf, [0, b'a', 0, 0]);
+        assert_eq!(file.read(3 * BS, &mut buf), 0);
+    }
+
+    #[test]
+    fn punch_hole() {
+        let mut file = SparseFile::default();
+        file.write(0, &[1; 4 * BS as usize]);
+        assert_eq!(file.allocated(), 4 * BS);
+        // the blocks all in the range are freed, the partial ones are zeroed
+        file.punch_hole(BS / 2, 3 * BS);
+        assert_eq!(file.allocated(), 2 * BS);
+        assert_eq!(file.len(), 4 * BS);
+        let mut buf = vec![0; 4 * BS as usize];
+        file.read(0, &mut buf);
+        assert!(buf[..BS as usize / 2].iter().all(|b| *b == 1));
+        assert!(buf[BS as usize / 2..3 * BS as usize]
+            .iter()
+            .all(|b| *b == 0));
+        assert!(buf[3 * BS as usize..].iter().all(|b| *b == 1));
+        // a partial block left with only zeros is freed
+        file.punch_hole(0, BS / 2);
+        assert_eq!(file.allocated(), BS);
+        // past the end does nothing
+        file.punch_hole(4 * BS, 8 * BS);
+        assert_eq!(file.len(), 4 * BS);
+    }
+
+    #[test]
+    fn seek() {
+        let mut file = SparseFile::default();
+        file.write(BS, &[1; BS as usize]);
+        file.write(3 * BS, &[1; 10]);
+        file.set_len(5 * BS);
+        assert_eq!(file.seek_data(0), Some(BS));
+        assert_eq!(file.seek_data(BS + 5), Some(BS + 5));
+        assert_eq!(file.seek_data(2 * BS), Some(3 * BS));
+        assert_eq!(file.seek_data(4 * BS), None);
+        assert_eq!(file.seek_hole(0), Some(0));
+        assert_eq!(file.seek_hole(BS), Some(2 * BS));
+        assert_eq!(file.seek_hole(3 * BS), Some(4 * BS));
+        assert_eq!(file.seek_hole(4 * BS + 1), Some(4 * BS + 1));
+        assert_eq!(file.seek_hole(5 * BS), None);
+        // there is always a hole at the end
+        file.set_len(3 * BS + 10);
+        assert_eq!(file.seek_hole(3 * BS), Some(3 * BS + 10));
+        assert_eq!(file.seek_data(3 * BS + 10), None);
+    }
+}
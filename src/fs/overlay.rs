@@ -0,0 +1,1328 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use async_trait::async_trait;
+use futures_util::{future, stream, StreamExt};
+use tracing::{debug, instrument};
+
+use crate::fs::{check_name, copy_with_writes, Filesystem, ROOT_INODE};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, DirectoryEntryPlusStream,
+    DirectoryEntryStream, FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    SetXattrMode, StatFs, Whence,
+};
+
+/// A file with this prefix in a layer hides the entry with the rest of the name from the layers
+/// below, like in AUFS. Such names are never shown and cannot be created.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// A directory containing this file doesn't show the entries of the same directory from the layers
+/// below.
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+const COPY_UP_BUF_SIZE: usize = 128 * 1024;
+/// Set in the cookies of the entries from the lower layers, see [`OverlayFilesystem::cookie`].
+const LOWER_COOKIE: u64 = 1 << 62;
+/// Set with [`LOWER_COOKIE`] in the cookies kept in the table of the opened directory.
+const TABLE_COOKIE: u64 = 1 << 61;
+/// A lower layer's cookie which fits in these bits goes in ours, with the layer above it.
+const LAYER_COOKIE_BITS: u32 = 56;
+/// The cookie we give when it doesn't fit and the directory is not opened, it can't be resumed.
+/// The last table number below `i64::MAX - 1`, see [`Filesystem::read_dir`].
+const UNKNOWN_COOKIE: u64 = LOWER_COOKIE | (LOWER_COOKIE - 3);
+
+/// An entry in one of the lower layers.
+#[derive(Debug, Clone, Copy)]
+struct Lower {
+    layer: usize,
+    ino: u64,
+}
+
+/// Where an entry is found in the layers.
+#[derive(Debug, Clone, Default)]
+struct Layers {
+    upper: Option<u64>,
+    /// For files only the topmost one. For directories all the ones which are merged, from top to
+    /// bottom, it stops at the first opaque one.
+    lowers: Vec<Lower>,
+}
+
+/// A layer of a directory, `None` for the upper one, with the inode there.
+type DirLayer = (Option<usize>, Arc<dyn Filesystem>, u64);
+
+#[derive(Debug, Clone)]
+struct Node {
+    parent: u64,
+    name: String,
+    kind: FileType,
+    layers: Layers,
+}
+
+#[derive(Clone, Copy)]
+enum LayerHandle {
+    Upper { ino: u64, fh: u64 },
+    Lower { lower: Lower, fh: u64 },
+}
+
+struct Handle {
+    ino: u64,
+    read: bool,
+    write: bool,
+    layer: LayerHandle,
+}
+
+#[derive(Default)]
+struct State {
+    nodes: HashMap<u64, Node>,
+    /// Our inode for each entry, so it stays the same when it's copied up. Sorted, so the entries
+    /// of a directory are together.
+    inodes: BTreeMap<(u64, String), u64>,
+    /// How many entries in `inodes` have each inode, more than one for hard links.
+    names: HashMap<u64, u32>,
+    /// Our inode for each file in the upper layer, so its hard links get the same one.
+    uppers: HashMap<u64, u64>,
+    handles: HashMap<u64, Handle>,
+    /// The opened directories, with the cookies of their layers which don't fit in ours.
+    opened_dirs: HashMap<u64, DirCookies>,
+    /// The inodes the kernel has references to.
+    referenced: HashSet<u64>,
+}
+
+/// The cookies of the layers by our number, see [`OverlayFilesystem::cookie`].
+#[derive(Default)]
+struct DirCookies {
+    opened: usize,
+    cookies: Vec<(Option<usize>, u64)>,
+    ids: HashMap<(Option<usize>, u64), u64>,
+}
+
+impl State {
+    fn in_use(&self, ino: u64) -> bool {
+        ino == ROOT_INODE
+            || self.referenced.contains(&ino)
+            || self.opened_dirs.contains_key(&ino)
+            || self.handles.values().any(|handle| handle.ino == ino)
+    }
+
+    /// Drop the node of `ino` if the kernel has no references to it and it's not opened. The
+    /// nodes of the entries of a directory which are not used either go with it, they were only
+    /// listed. It's registered again if it's looked up later.
+    fn drop_unused(&mut self, ino: u64) {
+        if self.in_use(ino) {
+            return;
+        }
+        let Some(node) = self.nodes.remove(&ino) else {
+            return;
+        };
+        if let Some(upper) = node.layers.upper {
+            self.uppers.remove(&upper);
+        }
+        let key = (node.parent, node.name);
+        if self.inodes.get(&key) == Some(&ino) {
+            self.inodes.remove(&key);
+            self.drop_name(ino);
+        }
+        if node.kind != FileType::Directory {
+            return;
+        }
+        let children: Vec<((u64, String), u64)> = self
+            .inodes
+            .range((ino, String::new())..)
+            .take_while(|((parent, _), _)| *parent == ino)
+            .map(|(key, child)| (key.clone(), *child))
+            .collect();
+        for (key, child) in children {
+            if self.in_use(child) {
+                continue;
+            }
+            // the node might be gone already, if it's a hard link of another entry
+            self.inodes.remove(&key);
+            self.drop_name(child);
+            self.drop_unused(child);
+        }
+    }
+
+    /// One of the entries in `inodes` with `ino` was removed.
+    fn drop_name(&mut self, ino: u64) {
+        if let Some(names) = self.names.get_mut(&ino) {
+            *names = names.saturating_sub(1);
+            if *names == 0 {
+                self.names.remove(&ino);
+            }
+        }
+    }
+}
+
+/// Stacks a writable upper filesystem over one or more read-only lower ones, like `overlayfs`.
+///
+/// Directories are merged from all layers. Files are taken from the topmost layer which has them
+/// and they are copied up to the upper layer on the first change. Removing an entry which exists in
+/// a lower layer leaves a whiteout in the upper one, a directory created over such an entry is
+/// marked opaque so the lower content doesn't show through.
+pub(crate) struct OverlayFilesystem {
+    upper: Arc<dyn Filesystem>,
+    /// From top to bottom.
+    lowers: Vec<Arc<dyn Filesystem>>,
+    state: RwLock<State>,
+    /// So we copy up an entry only once when more changes come at the same time.
+    copy_up_lock: tokio::sync::Mutex<()>,
+    current_ino: AtomicU64,
+    current_handle: AtomicU64,
+}
+
+impl OverlayFilesystem {
+    pub async fn new(
+        upper: Arc<dyn Filesystem>,
+        lowers: Vec<Arc<dyn Filesystem>>,
+    ) -> FsResult<Arc<Self>> {
+        let mut root = Node {
+            parent: ROOT_INODE,
+            name: String::new(),
+            kind: FileType::Directory,
+            layers: Layers {
+                upper: Some(ROOT_INODE),
+                lowers: vec![],
+            },
+        };
+        if !upper.exists_by_name(ROOT_INODE, OPAQUE_MARKER).await? {
+            for (layer, fs) in lowers.iter().enumerate() {
+                root.layers.lowers.push(Lower {
+                    layer,
+                    ino: ROOT_INODE,
+                });
+                if fs.exists_by_name(ROOT_INODE, OPAQUE_MARKER).await? {
+                    break;
+                }
+            }
+        }
+        let mut state = State::default();
+        state.nodes.insert(ROOT_INODE, root);
+        Ok(Arc::new(Self {
+            upper,
+            lowers,
+            state: RwLock::new(state),
+            copy_up_lock: tokio::sync::Mutex::new(()),
+            current_ino: AtomicU64::new(ROOT_INODE),
+            current_handle: AtomicU64::new(0),
+        }))
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().expect("state lock poisoned")
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().expect("state lock poisoned")
+    }
+
+    fn node(&self, ino: u64) -> FsResult<Node> {
+        self.state()
+            .nodes
+            .get(&ino)
+            .cloned()
+            .ok_or(FsError::InodeNotFound)
+    }
+
+    /// Find an entry in the layers, the same way the kernel `overlayfs` does it.
+    async fn resolve(&self, parent: u64, name: &str) -> FsResult<Option<(Layers, FileAttr)>> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Ok(None);
+        }
+        let dir = self.node(parent)?;
+        if dir.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let whiteout = whiteout_name(name);
+        let mut found: Option<(Layers, FileAttr)> = None;
+        if let Some(upper_dir) = dir.layers.upper {
+            if let Some(attr) = self.upper.find_by_name(upper_dir, name).await? {
+                let layers = Layers {
+                    upper: Some(attr.ino),
+                    lowers: vec![],
+                };
+                if attr.kind != FileType::Directory
+                    || self.upper.exists_by_name(attr.ino, OPAQUE_MARKER).await?
+                {
+                    return Ok(Some((layers, attr)));
+                }
+                found = Some((layers, attr));
+            } else if self.upper.exists_by_name(upper_dir, &whiteout).await? {
+                return Ok(None);
+            }
+        }
+        for lower_dir in &dir.layers.lowers {
+            let fs = &self.lowers[lower_dir.layer];
+            let Some(attr) = fs.find_by_name(lower_dir.ino, name).await? else {
+                if fs.exists_by_name(lower_dir.ino, &whiteout).await? {
+                    break;
+                }
+                continue;
+            };
+            let lower = Lower {
+                layer: lower_dir.layer,
+                ino: attr.ino,
+            };
+            match &mut found {
+                None => {
+                    let layers = Layers {
+                        upper: None,
+                        lowers: vec![lower],
+                    };
+                    if attr.kind != FileType::Directory {
+                        return Ok(Some((layers, attr)));
+                    }
+                    found = Some((layers, attr));
+                }
+                // a file hides the directories below it
+                Some(_) if attr.kind != FileType::Directory => break,
+                Some((layers, _)) => layers.lowers.push(lower),
+            }
+            if fs.exists_by_name(attr.ino, OPAQUE_MARKER).await? {
+                break;
+            }
+        }
+        Ok(found)
+    }
+
+    /// If any of the lower layers has this entry, ignoring the upper one.
+    async fn in_lower(&self, parent: u64, name: &str) -> FsResult<bool> {
+        let whiteout = whiteout_name(name);
+        for lower_dir in &self.node(parent)?.layers.lowers {
+            let fs = &self.lowers[lower_dir.layer];
+            if fs.find_by_name(lower_dir.ino, name).await?.is_some() {
+                return Ok(true);
+            }
+            if fs.exists_by_name(lower_dir.ino, &whiteout).await? {
+                return Ok(false);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Get our inode for an entry, allocating one if we see it for the first time.
+    fn register(&self, parent: u64, name: &str, kind: FileType, layers: Layers) -> u64 {
+        let mut state = self.state_mut();
+        let state = &mut *state;
+        let key = (parent, name.to_string());
+        let upper = layers.upper.filter(|_| kind != FileType::Directory);
+        let ino = match state.inodes.get(&key) {
+            Some(&ino) => ino,
+            None => {
+                // hard links of a file in the upper layer share our inode
+                let ino = upper
+                    .and_then(|upper| state.uppers.get(&upper).copied())
+                    .unwrap_or_else(|| self.current_ino.fetch_add(1, Ordering::SeqCst) + 1);
+                state.inodes.insert(key, ino);
+                *state.names.entry(ino).or_default() += 1;
+                ino
+            }
+        };
+        if let Some(upper) = upper {
+            state.uppers.insert(upper, ino);
+        }
+        state.nodes.insert(
+            ino,
+            Node {
+                parent,
+                name: name.to_string(),
+                kind,
+                layers,
+            },
+        );
+        ino
+    }
+
+    /// The entry was removed, its node is dropped if it's not used, otherwise when it's released
+    /// or forgotten.
+    fn forget(&self, parent: u64, name: &str) {
+        let mut state = self.state_mut();
+        let Some(ino) = state.inodes.remove(&(parent, name.to_string())) else {
+            return;
+        };
+        state.drop_name(ino);
+        if state.names.contains_key(&ino) {
+            // it has other hard links
+            return;
+        }
+        state.drop_unused(ino);
+    }
+
+    async fn lookup(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        let Some((layers, mut attr)) = self.resolve(parent, name).await? else {
+            return Ok(None);
+        };
+        attr.ino = self.register(parent, name, attr.kind, layers);
+        Ok(Some(attr))
+    }
+
+    /// The layers of a directory from top to bottom.
+    fn dir_layers(&self, ino: u64) -> FsResult<Vec<DirLayer>> {
+        let dir = self.node(ino)?;
+        if dir.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let mut layers = vec![];
+        if let Some(upper) = dir.layers.upper {
+            layers.push((None, self.upper.clone(), upper));
+        }
+        for lower in &dir.layers.lowers {
+            layers.push((
+                Some(lower.layer),
+                self.lowers[lower.layer].clone(),
+                lower.ino,
+            ));
+        }
+        Ok(layers)
+    }
+
+    /// Our cookie for an entry at `cookie` in `layer` of the directory `ino`, it must stay below
+    /// 2^63 as the kernel takes it as a signed offset. The ones of the upper layer are used as
+    /// they are and the ones of the lower layers get the layer in the bits above them, when they
+    /// fit. The others, like the hashes ext4 gives, are numbered in a table kept while the
+    /// directory is opened.
+    fn cookie(&self, ino: u64, layer: Option<usize>, cookie: u64) -> u64 {
+        match layer {
+            None if cookie < LOWER_COOKIE => return cookie,
+            Some(layer) if cookie >> LAYER_COOKIE_BITS == 0 => {
+                let layer = layer as u64;
+                if layer < TABLE_COOKIE >> LAYER_COOKIE_BITS {
+                    return LOWER_COOKIE | layer << LAYER_COOKIE_BITS | cookie;
+                }
+            }
+            _ => {}
+        }
+        let mut state = self.state_mut();
+        let Some(dir) = state.opened_dirs.get_mut(&ino) else {
+            return UNKNOWN_COOKIE;
+        };
+        let dir = &mut *dir;
+        let id = *dir.ids.entry((layer, cookie)).or_insert_with(|| {
+            dir.cookies.push((layer, cookie));
+            dir.cookies.len() as u64 - 1
+        });
+        LOWER_COOKIE | TABLE_COOKIE | id
+    }
+
+    /// The layer and its cookie where the listing of the directory `ino` goes on after our
+    /// `cookie`.
+    fn layer_cookie(&self, ino: u64, cookie: u64) -> FsResult<(Option<usize>, u64)> {
+        if cookie & LOWER_COOKIE == 0 {
+            return Ok((None, cookie));
+        }
+        if cookie & TABLE_COOKIE == 0 {
+            let layer = (cookie & !LOWER_COOKIE) >> LAYER_COOKIE_BITS;
+            #[allow(clippy::cast_possible_truncation)]
+            return Ok((
+                Some(layer as usize),
+                cookie & ((1 << LAYER_COOKIE_BITS) - 1),
+            ));
+        }
+        usize::try_from(cookie & !(LOWER_COOKIE | TABLE_COOKIE))
+            .ok()
+            .and_then(|id| self.state().opened_dirs.get(&ino)?.cookies.get(id).copied())
+            .ok_or(FsError::InvalidInput("unknown cookie"))
+    }
+
+    /// The merged entries of a directory after `cookie`. Each one is listed from the topmost layer
+    /// which has it, going through the layers from top to bottom, "." and ".." from the top one.
+    fn merged(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryPlusStream> {
+        let layers = self.dir_layers(ino)?;
+        let (start, layer_cookie) = if cookie == 0 {
+            (0, 0)
+        } else {
+            let (layer, layer_cookie) = self.layer_cookie(ino, cookie)?;
+            // the layer might not be merged anymore, after an opaque directory replaced it
+            let start = layers
+                .iter()
+                .position(|(merged, _, _)| *merged == layer)
+                .unwrap_or(layers.len());
+            (start, layer_cookie)
+        };
+        let parent = self.node(ino)?.parent;
+        Ok(stream::iter(layers.into_iter().enumerate().skip(start))
+            .then(move |(pos, (layer, fs, dir))| {
+                let cookie = if pos == start { layer_cookie } else { 0 };
+                async move {
+                    let entries = match fs.read_dir(dir, cookie).await {
+                        Ok(entries) => entries,
+                        Err(err) => stream::once(future::ready(Err(err))).boxed(),
+                    };
+                    entries.map(move |entry| (pos, layer, entry))
+                }
+            })
+            .flatten()
+            .filter_map(move |(pos, layer, entry)| {
+                let fs = self.clone();
+                async move {
+                    fs.merged_entry(ino, parent, pos, layer, entry)
+                        .await
+                        .transpose()
+                }
+            })
+            .boxed())
+    }
+
+    /// What we list for an entry of the layer at `pos` in the directory, `None` if it's hidden or
+    /// it's listed from another layer.
+    async fn merged_entry(
+        &self,
+        ino: u64,
+        parent: u64,
+        pos: usize,
+        layer: Option<usize>,
+        entry: FsResult<DirectoryEntry>,
+    ) -> FsResult<Option<DirectoryEntryPlus>> {
+        let entry = entry?;
+        let attr = match entry.name.as_str() {
+            "." | ".." if pos > 0 => return Ok(None),
+            "." => self.get_attr(ino).await?,
+            ".." => self.get_attr(parent).await?,
+            name if name.starts_with(WHITEOUT_PREFIX) => return Ok(None),
+            name => {
+                let Some((layers, attr)) = self.resolve(ino, name).await? else {
+                    return Ok(None);
+                };
+                let top = match layers.upper {
+                    Some(_) => None,
+                    None => layers.lowers.first().map(|lower| lower.layer),
+                };
+                if top != layer {
+                    return Ok(None);
+                }
+                let child = self.register(ino, name, attr.kind, layers);
+                FileAttr { ino: child, ..attr }
+            }
+        };
+        Ok(Some(DirectoryEntryPlus {
+            ino: attr.ino,
+            name: entry.name,
+            kind: attr.kind,
+            attr,
+            cookie: self.cookie(ino, layer, entry.cookie),
+        }))
+    }
+
+    /// The names of the merged entries of a directory, excluding "." and "..". They are read from
+    /// the layers, no node is registered for them.
+    async fn names(&self, ino: u64) -> FsResult<BTreeSet<String>> {
+        let dir = self.node(ino)?;
+        if dir.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let mut layers: Vec<(&Arc<dyn Filesystem>, u64)> = vec![];
+        if let Some(upper) = dir.layers.upper {
+            layers.push((&self.upper, upper));
+        }
+        for lower in &dir.layers.lowers {
+            layers.push((&self.lowers[lower.layer], lower.ino));
+        }
+        let mut names = BTreeSet::new();
+        let mut whiteouts = HashSet::new();
+        for (fs, dir_ino) in layers {
+            let mut entries = fs.clone().read_dir(dir_ino, 0).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                if entry.name == "." || entry.name == ".." || entry.name == OPAQUE_MARKER {
+                    continue;
+                }
+                if let Some(name) = entry.name.strip_prefix(WHITEOUT_PREFIX) {
+                    whiteouts.insert(name.to_string());
+                } else if !whiteouts.contains(&entry.name) {
+                    names.insert(entry.name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Create an empty file used as a whiteout or an opaque marker.
+    async fn create_marker(&self, upper_dir: u64, name: &str) -> FsResult<()> {
+        if self.upper.exists_by_name(upper_dir, name).await? {
+            return Ok(());
+        }
+        let dir_attr = self.upper.get_attr(upper_dir).await?;
+        self.upper
+            .create(
+                upper_dir,
+                name,
+                CreateFileAttr {
+                    kind: FileType::RegularFile,
+                    perm: 0,
+                    uid: dir_attr.uid,
+                    gid: dir_attr.gid,
+                    rdev: 0,
+                    flags: 0,
+                },
+                false,
+                false,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Check a new entry can be created and copy up its parent, it returns the parent in the upper
+    /// layer.
+    async fn prepare_create(&self, parent: u64, name: &str) -> FsResult<u64> {
+        check_name(name)?;
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(FsError::InvalidInput("name is reserved for whiteouts"));
+        }
+        if self.lookup(parent, name).await?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        self.copy_up(parent).await
+    }
+
+    /// Remove the whiteout of an entry which was just created in the upper layer, if any.
+    async fn remove_whiteout(&self, upper_parent: u64, name: &str) -> FsResult<()> {
+        let whiteout = whiteout_name(name);
+        if self.upper.exists_by_name(upper_parent, &whiteout).await? {
+            self.upper.remove_file(upper_parent, &whiteout).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the whiteouts and opaque marker from a directory in the upper layer, so it can be
+    /// removed.
+    async fn clear_markers(&self, upper_dir: u64) -> FsResult<()> {
+        let mut markers = vec![];
+        let mut entries = self.upper.clone().read_dir(upper_dir, 0).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            if entry.name.starts_with(WHITEOUT_PREFIX) {
+                markers.push(entry.name);
+            }
+        }
+        for name in markers {
+            self.upper.remove_file(upper_dir, &name).await?;
+        }
+        Ok(())
+    }
+
+    async fn copy_up(&self, ino: u64) -> FsResult<u64> {
+        self.copy_up_with_len(ino, u64::MAX).await
+    }
+
+    /// Copy the entry and its parents to the upper layer, if they are not there already. For files,
+    /// only the first `len` bytes are copied, useful when it's truncated right after.
+    async fn copy_up_with_len(&self, ino: u64, len: u64) -> FsResult<u64> {
+        let _guard = self.copy_up_lock.lock().await;
+        let mut chain = vec![];
+        let mut current = ino;
+        let mut upper_parent = loop {
+            let node = self.node(current)?;
+            if let Some(upper) = node.layers.upper {
+                break upper;
+            }
+            chain.push(current);
+            current = node.parent;
+        };
+        for ino in chain.into_iter().rev() {
+            let node = self.node(ino)?;
+            let lower = node.layers.lowers[0];
+            let fs = &self.lowers[lower.layer];
+            let attr = fs.get_attr(lower.ino).await?;
+            debug!(ino, name = node.name, "copy up");
+            let create_attr = CreateFileAttr {
+                kind: attr.kind,
+                perm: attr.perm,
+                uid: attr.uid,
+                gid: attr.gid,
+                rdev: attr.rdev,
+                flags: attr.flags,
+            };
+            let upper = match attr.kind {
+                FileType::Directory
+                | FileType::NamedPipe
+                | FileType::CharDevice
+                | FileType::BlockDevice
+                | FileType::Socket => {
+                    let (_, upper_attr) = self
+                        .upper
+                        .create(upper_parent, &node.name, create_attr, false, false)
+                        .await?;
+                    upper_attr.ino
+                }
+                FileType::Symlink => {
+                    let target = fs.read_link(lower.ino).await?;
+                    self.upper
+                        .symlink(upper_parent, &node.name, &target, create_attr)
+                        .await?
+                        .ino
+                }
+                FileType::RegularFile => {
+                    let (fh, upper_attr) = self
+                        .upper
+                        .create(upper_parent, &node.name, create_attr, false, true)
+                        .await?;
+                    let res = self
+                        .copy_data(fs, lower.ino, upper_attr.ino, min(len, attr.size), fh)
+                        .await;
+                    self.upper.release(fh).await?;
+                    res?;
+                    upper_attr.ino
+                }
+            };
+            for name in fs.list_xattr(lower.ino).await? {
+                let value = fs.get_xattr(lower.ino, &name).await?;
+                self.upper
+                    .set_xattr(upper, &name, &value, SetXattrMode::Any)
+                    .await?;
+            }
+            self.upper
+                .set_attr(
+                    upper,
+                    SetFileAttr::default()
+                        .with_atime(attr.atime)
+                        .with_mtime(attr.mtime)
+                        .with_ctime(attr.ctime)
+                        .with_crtime(attr.crtime),
+                )
+                .await?;
+            if let Some(node) = self.state_mut().nodes.get_mut(&ino) {
+                node.layers.upper = Some(upper);
+            }
+            upper_parent = upper;
+        }
+        Ok(upper_parent)
+    }
+
+    async fn copy_data(
+        &self,
+        fs: &Arc<dyn Filesystem>,
+        lower_ino: u64,
+        upper_ino: u64,
+        len: u64,
+        upper_fh: u64,
+    ) -> FsResult<()> {
+        let fh = fs.open(lower_ino, true, false).await?;
+        let mut buf = vec![0; COPY_UP_BUF_SIZE];
+        let mut offset = 0;
+        let res = async {
+            while offset < len {
+                #[allow(clippy::cast_possible_truncation)]
+                let chunk = min(buf.len() as u64, len - offset) as usize;
+                let read = fs.read(lower_ino, offset, &mut buf[..chunk], fh).await?;
+                if read == 0 {
+                    break;
+                }
+                self.upper
+                    .write(upper_ino, offset, &buf[..read], upper_fh)
+                    .await?;
+                offset += read as u64;
+            }
+            Ok(())
+        }
+        .await;
+        fs.release(fh).await?;
+        res
+    }
+
+    fn open_handle(&self, ino: u64, read: bool, write: bool, layer: LayerHandle) -> u64 {
+        let fh = self.current_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        self.state_mut().handles.insert(
+            fh,
+            Handle {
+                ino,
+                read,
+                write,
+                layer,
+            },
+        );
+        fh
+    }
+
+    fn layer_handle(&self, fh: u64, ino: u64) -> FsResult<(bool, bool, LayerHandle)> {
+        match self.state().handles.get(&fh) {
+            Some(handle) if handle.ino == ino => Ok((handle.read, handle.write, handle.layer)),
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+}
+
+fn whiteout_name(name: &str) -> String {
+    format!("{WHITEOUT_PREFIX}{name}")
+}
+
+#[async_trait]
+impl Filesystem for OverlayFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.state().nodes.contains_key(&ino)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.state()
+            .nodes
+            .get(&ino)
+            .is_some_and(|node| node.kind == FileType::Directory)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.state()
+            .nodes
+            .get(&ino)
+            .is_some_and(|node| node.kind == FileType::RegularFile)
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let upper_parent = self.prepare_create(parent, name).await?;
+        let kind = create_attr.kind;
+        let (upper_fh, mut attr) = self
+            .upper
+            .create(upper_parent, name, create_attr, read, write)
+            .await?;
+        self.remove_whiteout(upper_parent, name).await?;
+        if kind == FileType::Directory && self.in_lower(parent, name).await? {
+            self.create_marker(attr.ino, OPAQUE_MARKER).await?;
+        }
+        let layers = Layers {
+            upper: Some(attr.ino),
+            lowers: vec![],
+        };
+        let upper_ino = attr.ino;
+        attr.ino = self.register(parent, name, kind, layers);
+        let fh = if read || write {
+            self.open_handle(
+                attr.ino,
+                read,
+                write,
+                LayerHandle::Upper {
+                    ino: upper_ino,
+                    fh: upper_fh,
+                },
+            )
+        } else {
+            0
+        };
+        Ok((fh, attr))
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        let upper_parent = self.prepare_create(parent, name).await?;
+        let mut attr = self
+            .upper
+            .symlink(upper_parent, name, target, create_attr)
+            .await?;
+        self.remove_whiteout(upper_parent, name).await?;
+        let layers = Layers {
+            upper: Some(attr.ino),
+            lowers: vec![],
+        };
+        attr.ino = self.register(parent, name, FileType::Symlink, layers);
+        Ok(attr)
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        let node = self.node(ino)?;
+        match node.layers.upper {
+            Some(upper) => self.upper.read_link(upper).await,
+            None => {
+                let lower = node.layers.lowers[0];
+                self.lowers[lower.layer].read_link(lower.ino).await
+            }
+        }
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        if self.node(ino)?.kind == FileType::Directory {
+            return Err(FsError::DirectoryLink);
+        }
+        let upper_parent = self.prepare_create(new_parent, new_name).await?;
+        // links are made in the upper layer, so the file must be there
+        let upper = self.copy_up(ino).await?;
+        let mut attr = self.upper.link(upper, upper_parent, new_name).await?;
+        self.remove_whiteout(upper_parent, new_name).await?;
+        let mut state = self.state_mut();
+        state.uppers.insert(upper, ino);
+        if state
+            .inodes
+            .insert((new_parent, new_name.to_string()), ino)
+            .is_none()
+        {
+            *state.names.entry(ino).or_default() += 1;
+        }
+        attr.ino = ino;
+        Ok(attr)
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        self.lookup(parent, name).await
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        Ok(self.names(ino).await?.len())
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        let ino = self
+            .lookup(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?
+            .ino;
+        let node = self.node(ino)?;
+        if node.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        if !self.names(ino).await?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        let upper_parent = self.copy_up(parent).await?;
+        if let Some(upper) = node.layers.upper {
+            self.clear_markers(upper).await?;
+            self.upper.remove_dir(upper_parent, name).await?;
+        }
+        if self.in_lower(parent, name).await? {
+            self.create_marker(upper_parent, &whiteout_name(name))
+                .await?;
+        }
+        self.forget(parent, name);
+        Ok(())
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        let ino = self
+            .lookup(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?
+            .ino;
+        let node = self.node(ino)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let upper_parent = self.copy_up(parent).await?;
+        if node.layers.upper.is_some() {
+            self.upper.remove_file(upper_parent, name).await?;
+        }
+        if self.in_lower(parent, name).await? {
+            self.create_marker(upper_parent, &whiteout_name(name))
+                .await?;
+        }
+        self.forget(parent, name);
+        Ok(())
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        Ok(self.resolve(parent, name).await?.is_some())
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        Ok(self
+            .merged(ino, cookie)?
+            .map(|entry| entry.map(DirectoryEntry::from))
+            .boxed())
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        self.merged(ino, cookie)
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        let node = self.node(ino)?;
+        let mut attr = match node.layers.upper {
+            Some(upper) => self.upper.get_attr(upper).await?,
+            None => {
+                let lower = node.layers.lowers[0];
+                self.lowers[lower.layer].get_attr(lower.ino).await?
+            }
+        };
+        attr.ino = ino;
+        Ok(attr)
+    }
+
+    async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        let upper = self
+            .copy_up_with_len(ino, set_attr.size.unwrap_or(u64::MAX))
+            .await?;
+        self.upper.set_attr(upper, set_attr).await
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        let (read, _, layer) = self.layer_handle(handle, ino)?;
+        if !read {
+            return Err(FsError::InvalidFileHandle);
+        }
+        match layer {
+            LayerHandle::Upper { ino, fh } => self.upper.read(ino, offset, buf, fh).await,
+            LayerHandle::Lower { lower, fh } => {
+                let Some(upper) = self.state().nodes.get(&ino).and_then(|n| n.layers.upper) else {
+                    return self.lowers[lower.layer]
+                        .read(lower.ino, offset, buf, fh)
+                        .await;
+                };
+                // it was copied up since we opened it, continue with the new content
+                debug!(ino, "switching handle to upper layer");
+                let upper_fh = self.upper.open(upper, true, false).await?;
+                if let Some(handle) = self.state_mut().handles.get_mut(&handle) {
+                    handle.layer = LayerHandle::Upper {
+                        ino: upper,
+                        fh: upper_fh,
+                    };
+                }
+                self.lowers[lower.layer].release(fh).await?;
+                self.upper.read(upper, offset, buf, upper_fh).await
+            }
+        }
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        let handle = {
+            let mut state = self.state_mut();
+            let Some(handle) = state.handles.remove(&handle) else {
+                return Err(FsError::InvalidFileHandle);
+            };
+            state.drop_unused(handle.ino);
+            handle
+        };
+        match handle.layer {
+            LayerHandle::Upper { fh, .. } => self.upper.release(fh).await,
+            LayerHandle::Lower { lower, fh } => self.lowers[lower.layer].release(fh).await,
+        }
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.state().handles.get(&fh).is_some_and(|h| h.read)
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        self.state().handles.get(&fh).is_some_and(|h| h.write)
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        // handles opened for write are always on the upper layer
+        match self.layer_handle(handle, ino)? {
+            (_, true, LayerHandle::Upper { ino, fh }) => {
+                self.upper.write(ino, offset, buf, fh).await
+            }
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        let layer = self
+            .state()
+            .handles
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .layer;
+        match layer {
+            LayerHandle::Upper { fh, .. } => self.upper.flush(fh).await,
+            // nothing to flush, lower layers are read-only
+            LayerHandle::Lower { .. } => Ok(()),
+        }
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        copy_with_writes(
+            self,
+            src_ino,
+            src_offset,
+            dest_ino,
+            dest_offset,
+            size,
+            src_fh,
+            dest_fh,
+        )
+        .await
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if !read && !write {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        let node = self.node(ino)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let layer = match node.layers.upper {
+            _ if write => {
+                let upper = self.copy_up(ino).await?;
+                let fh = self.upper.open(upper, read, write).await?;
+                LayerHandle::Upper { ino: upper, fh }
+            }
+            Some(upper) => {
+                let fh = self.upper.open(upper, read, write).await?;
+                LayerHandle::Upper { ino: upper, fh }
+            }
+            None => {
+                let lower = node.layers.lowers[0];
+                let fh = self.lowers[lower.layer]
+                    .open(lower.ino, read, write)
+                    .await?;
+                LayerHandle::Lower { lower, fh }
+            }
+        };
+        Ok(self.open_handle(ino, read, write, layer))
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        let upper = self.copy_up_with_len(ino, size).await?;
+        self.upper.set_len(upper, size).await
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        // handles opened for write are always on the upper layer
+        match self.layer_handle(handle, ino)? {
+            (_, true, LayerHandle::Upper { ino, fh }) => {
+                self.upper.fallocate(ino, offset, len, mode, fh).await
+            }
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        check_name(new_name)?;
+        if new_name.starts_with(WHITEOUT_PREFIX) {
+            return Err(FsError::InvalidInput("name is reserved for whiteouts"));
+        }
+        let ino = self
+            .lookup(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?
+            .ino;
+        if parent == new_parent && name == new_name {
+            // no-op
+            return Ok(());
+        }
+        let node = self.node(ino)?;
+        if node.kind == FileType::Directory && !node.layers.lowers.is_empty() {
+            // like overlayfs without redirect_dir, tools like mv fall back to copy and delete
+            return Err(FsError::CrossDevice);
+        }
+        if let Some(existing) = self.lookup(new_parent, new_name).await? {
+            if existing.ino == ino {
+                // both names are hard links to the same file, nothing to do
+                return Ok(());
+            }
+            if (existing.kind == FileType::Directory) != (node.kind == FileType::Directory) {
+                return Err(if existing.kind == FileType::Directory {
+                    FsError::IsADirectory
+                } else {
+                    FsError::NotADirectory
+                });
+            }
+            if existing.kind == FileType::Directory {
+                if !self.names(existing.ino).await?.is_empty() {
+                    return Err(FsError::NotEmpty);
+                }
+                if let Some(upper) = self.node(existing.ino)?.layers.upper {
+                    self.clear_markers(upper).await?;
+                }
+            }
+        }
+
+        let upper = self.copy_up(ino).await?;
+        let upper_parent = self.copy_up(parent).await?;
+        let new_upper_parent = self.copy_up(new_parent).await?;
+        self.upper
+            .rename(upper_parent, name, new_upper_parent, new_name)
+            .await?;
+        let whiteout = whiteout_name(new_name);
+        if self
+            .upper
+            .exists_by_name(new_upper_parent, &whiteout)
+            .await?
+        {
+            self.upper.remove_file(new_upper_parent, &whiteout).await?;
+        }
+        if node.kind == FileType::Directory && self.in_lower(new_parent, new_name).await? {
+            self.create_marker(upper, OPAQUE_MARKER).await?;
+        }
+        if self.in_lower(parent, name).await? {
+            self.create_marker(upper_parent, &whiteout_name(name))
+                .await?;
+        }
+
+        self.forget(new_parent, new_name);
+        let mut state = self.state_mut();
+        state.inodes.remove(&(parent, name.to_string()));
+        state.inodes.insert((new_parent, new_name.to_string()), ino);
+        if let Some(node) = state.nodes.get_mut(&ino) {
+            node.parent = new_parent;
+            node.name = new_name.to_string();
+            node.layers = Layers {
+                upper: Some(upper),
+                lowers: vec![],
+            };
+        }
+        Ok(())
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        let node = self.node(ino)?;
+        match node.layers.upper {
+            Some(upper) => self.upper.get_xattr(upper, name).await,
+            None => {
+                let lower = node.layers.lowers[0];
+                self.lowers[lower.layer].get_xattr(lower.ino, name).await
+            }
+        }
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        let upper = self.copy_up(ino).await?;
+        self.upper.set_xattr(upper, name, value, mode).await
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        let node = self.node(ino)?;
+        match node.layers.upper {
+            Some(upper) => self.upper.list_xattr(upper).await,
+            None => {
+                let lower = node.layers.lowers[0];
+                self.lowers[lower.layer].list_xattr(lower.ino).await
+            }
+        }
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        let upper = self.copy_up(ino).await?;
+        self.upper.remove_xattr(upper, name).await
+    }
+
+    /// Only the upper layer can take more, like `overlayfs` we show its usage.
+    async fn seek(&self, ino: u64, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+        let node = self.node(ino)?;
+        match node.layers.upper {
+            Some(upper) => self.upper.seek(upper, offset, whence).await,
+            None => {
+                let lower = node.layers.lowers[0];
+                self.lowers[lower.layer]
+                    .seek(lower.ino, offset, whence)
+                    .await
+            }
+        }
+    }
+
+    async fn statfs(&self) -> FsResult<StatFs> {
+        self.upper.statfs().await
+    }
+
+    fn opened_dir(&self, ino: u64) {
+        self.state_mut().opened_dirs.entry(ino).or_default().opened += 1;
+    }
+
+    fn released_dir(&self, ino: u64) {
+        let mut state = self.state_mut();
+        if let Some(dir) = state.opened_dirs.get_mut(&ino) {
+            dir.opened -= 1;
+            if dir.opened == 0 {
+                state.opened_dirs.remove(&ino);
+            }
+        }
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        // inode numbers are not reused until the next mount
+        self.state_mut().referenced.insert(ino);
+        0
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        let mut state = self.state_mut();
+        state.referenced.remove(&ino);
+        state.drop_unused(ino);
+    }
+
+    fn max_name_len(&self) -> usize {
+        // whiteouts need room for the prefix
+        self.upper.max_name_len() - WHITEOUT_PREFIX.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::fs::memory::MemoryFilesystem;
+    use crate::fs::testing::create;
+
+    async fn list(fs: &Arc<OverlayFilesystem>, cookie: u64) -> Vec<(String, u64)> {
+        fs.clone()
+            .read_dir(ROOT_INODE, cookie)
+            .await
+            .unwrap()
+            .map_ok(|entry| (entry.name, entry.cookie))
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn read_dir_resumes() {
+        let upper = MemoryFilesystem::new();
+        let middle = MemoryFilesystem::new();
+        let lower = MemoryFilesystem::new();
+        for name in ["u1", "u2", "shadowed"] {
+            create(&*upper, name, b"").await;
+        }
+        for name in ["m1", "shadowed", "deleted"] {
+            create(&*middle, name, b"").await;
+        }
+        for name in ["l1", "l2", "m1"] {
+            create(&*lower, name, b"").await;
+        }
+        let fs = OverlayFilesystem::new(upper, vec![middle, lower])
+            .await
+            .unwrap();
+        fs.remove_file(ROOT_INODE, "deleted").await.unwrap();
+
+        let entries = list(&fs, 0).await;
+        let mut names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, [".", "..", "l1", "l2", "m1", "shadowed", "u1", "u2"]);
+        // from after each entry we get the ones after it, in any of the layers
+        for (i, (_, cookie)) in entries.iter().enumerate() {
+            assert_eq!(list(&fs, *cookie).await, entries[i + 1..]);
+        }
+    }
+}
@@ -0,0 +1,1360 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::fs::{File, Metadata, OpenOptions, Permissions};
+use std::io;
+use std::iter;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    lchown, symlink, DirBuilderExt, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt,
+    PermissionsExt,
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tracing::{debug, instrument, warn};
+
+use crate::fs::passthrough::watch::{Event, Watcher};
+use crate::fs::{
+    check_name, copy_with_writes, host_fallocate, host_read_dir, host_seek, host_statfs,
+    read_host_dir_in_batches, run_blocking, Batch, Filesystem, ROOT_INODE,
+};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, DirectoryEntryPlusStream,
+    DirectoryEntryStream, FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    SetXattrMode, StatFs, Whence,
+};
+use crate::mount::Invalidator;
+
+pub(crate) mod watch;
+
+/// Where an inode is located, relative to its parent.
+struct Node {
+    parent: u64,
+    name: String,
+    /// Other names of a file with more hard links, the ones we have seen. One of them takes the
+    /// place of the above when it's removed.
+    links: Vec<(u64, String)>,
+    /// The host `(dev, ino)`.
+    host: (u64, u64),
+}
+
+impl Node {
+    /// The directories with a name of the node.
+    fn parents(&self) -> impl Iterator<Item = u64> + '_ {
+        iter::once(self.parent).chain(self.links.iter().map(|(parent, _)| *parent))
+    }
+}
+
+struct Handle {
+    ino: u64,
+    file: Arc<File>,
+    read: bool,
+    write: bool,
+}
+
+#[derive(Default)]
+struct State {
+    nodes: HashMap<u64, Node>,
+    /// Maps host `(dev, ino)` to our inode, so we give the same inode for the same host file.
+    host_inodes: HashMap<(u64, u64), u64>,
+    handles: HashMap<u64, Handle>,
+    /// The nodes with a name in each directory.
+    children: HashMap<u64, HashSet<u64>>,
+    /// Inodes the kernel has references to.
+    referenced: HashSet<u64>,
+    /// Directories we watch for changes on the host, by their watch.
+    watches: HashMap<i32, u64>,
+    /// The watch of each directory in `watches`.
+    watched: HashMap<u64, i32>,
+}
+
+impl State {
+    fn in_use(&self, ino: u64) -> bool {
+        ino == ROOT_INODE
+            || self.referenced.contains(&ino)
+            || self.handles.values().any(|handle| handle.ino == ino)
+    }
+
+    fn insert_node(&mut self, ino: u64, node: Node) {
+        self.remove_node(ino);
+        for parent in node.parents() {
+            self.children.entry(parent).or_default().insert(ino);
+        }
+        self.nodes.insert(ino, node);
+    }
+
+    fn remove_node(&mut self, ino: u64) -> Option<Node> {
+        let node = self.nodes.remove(&ino)?;
+        for parent in node.parents() {
+            if let Some(children) = self.children.get_mut(&parent) {
+                children.remove(&ino);
+                if children.is_empty() {
+                    self.children.remove(&parent);
+                }
+            }
+        }
+        Some(node)
+    }
+
+    /// Change the names of a node, keeping `children` up to date.
+    fn update_node(&mut self, ino: u64, update: impl FnOnce(&mut Node)) {
+        if let Some(mut node) = self.remove_node(ino) {
+            update(&mut node);
+            self.insert_node(ino, node);
+        }
+    }
+}
+
+/// Mirrors a directory from the host.
+///
+/// We keep our own inode numbers and for each one only the parent and the name, so they are
+/// stable across renames, the host path is resolved by walking up to the root. The directories
+/// we have seen are watched, so the kernel drops what it cached when they are changed on the host.
+/// The inodes the kernel forgot are dropped, a host entry gets a new one when it's seen again.
+///
+/// The operations run on the threads for blocking work, with [`run_blocking`].
+pub(crate) struct PassthroughFilesystem {
+    source_dir: PathBuf,
+    state: RwLock<State>,
+    current_ino: AtomicU64,
+    current_handle: AtomicU64,
+    watcher: Option<Arc<Watcher>>,
+    /// The thread of [`PassthroughFilesystem::watch_changes`], joined when we are dropped.
+    watching: Mutex<Option<JoinHandle<()>>>,
+    invalidator: Invalidator,
+    this: Weak<Self>,
+}
+
+impl PassthroughFilesystem {
+    pub fn new(source_dir: &Path, invalidator: Invalidator) -> FsResult<Arc<Self>> {
+        let metadata = fs::metadata(source_dir)?;
+        if !metadata.is_dir() {
+            return Err(FsError::InvalidInput("source dir is not a directory"));
+        }
+        let mut state = State::default();
+        let host = (metadata.dev(), metadata.ino());
+        state.nodes.insert(
+            ROOT_INODE,
+            Node {
+                parent: ROOT_INODE,
+                name: String::new(),
+                links: vec![],
+                host,
+            },
+        );
+        state.host_inodes.insert(host, ROOT_INODE);
+        let watcher = match Watcher::new() {
+            Ok(watcher) => Some(Arc::new(watcher)),
+            Err(err) => {
+                warn!(%err, "cannot watch the source dir, changes on the host are seen later");
+                None
+            }
+        };
+        let fs = Arc::new_cyclic(|this| Self {
+            source_dir: source_dir.to_path_buf(),
+            state: RwLock::new(state),
+            current_ino: AtomicU64::new(ROOT_INODE),
+            current_handle: AtomicU64::new(0),
+            watcher: watcher.clone(),
+            watching: Mutex::new(None),
+            invalidator,
+            this: this.clone(),
+        });
+        if let Some(watcher) = watcher {
+            fs.watch(ROOT_INODE);
+            Self::watch_changes(&fs, watcher);
+        }
+        Ok(fs)
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().expect("state lock poisoned")
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().expect("state lock poisoned")
+    }
+
+    /// Resolve the host path of an inode by walking up to the root.
+    fn path(&self, ino: u64) -> FsResult<PathBuf> {
+        let state = self.state();
+        let mut names = vec![];
+        let mut current = ino;
+        while current != ROOT_INODE {
+            let node = state.nodes.get(&current).ok_or(FsError::InodeNotFound)?;
+            names.push(node.name.as_str());
+            current = node.parent;
+        }
+        let mut path = self.source_dir.clone();
+        path.extend(names.iter().rev());
+        Ok(path)
+    }
+
+    fn child_path(&self, parent: u64, name: &str) -> FsResult<PathBuf> {
+        Ok(self.path(parent)?.join(name))
+    }
+
+    /// Get our inode for a host entry, registering it if we see it for the first time.
+    fn register(&self, parent: u64, name: &str, metadata: &Metadata) -> u64 {
+        let mut state = self.state_mut();
+        let key = (metadata.dev(), metadata.ino());
+        let mut new = false;
+        let ino = *state.host_inodes.entry(key).or_insert_with(|| {
+            new = true;
+            self.current_ino.fetch_add(1, Ordering::SeqCst) + 1
+        });
+        if ino == ROOT_INODE {
+            return ino;
+        }
+        if state.nodes.contains_key(&ino) && !metadata.is_dir() && metadata.nlink() > 1 {
+            // another hard link, use the last seen location and keep the previous one
+            state.update_node(ino, |node| {
+                if node.parent != parent || node.name != name {
+                    node.links.retain(|(p, n)| *p != parent || n != name);
+                    let previous = (node.parent, std::mem::take(&mut node.name));
+                    node.links.push(previous);
+                    node.parent = parent;
+                    node.name = name.to_string();
+                }
+            });
+        } else {
+            // it might have been renamed from outside, use the last seen location
+            state.insert_node(
+                ino,
+                Node {
+                    parent,
+                    name: name.to_string(),
+                    links: vec![],
+                    host: key,
+                },
+            );
+        }
+        drop(state);
+        if new && metadata.is_dir() {
+            self.watch(ino);
+        }
+        ino
+    }
+
+    /// Watch a directory for changes made on the host.
+    fn watch(&self, ino: u64) {
+        let Some(watcher) = &self.watcher else {
+            return;
+        };
+        let Ok(path) = self.path(ino) else {
+            return;
+        };
+        match watcher.watch(&path) {
+            Ok(wd) => {
+                let mut state = self.state_mut();
+                if state.nodes.contains_key(&ino) {
+                    state.watches.insert(wd, ino);
+                    state.watched.insert(ino, wd);
+                } else {
+                    // dropped meanwhile
+                    self.unwatch(wd);
+                }
+            }
+            Err(err) => debug!(?path, %err, "cannot watch directory"),
+        }
+    }
+
+    fn unwatch(&self, wd: i32) {
+        if let Some(watcher) = &self.watcher {
+            if let Err(err) = watcher.unwatch(wd) {
+                debug!(%err, "cannot stop watching directory");
+            }
+        }
+    }
+
+    /// Tell the kernel about the changes made on the host, until the filesystem is dropped.
+    fn watch_changes(fs: &Arc<Self>, watcher: Arc<Watcher>) {
+        let this = Arc::downgrade(fs);
+        let watching = thread::spawn(move || loop {
+            let events = match watcher.read() {
+                Ok(Some(events)) => events,
+                Ok(None) => return,
+                Err(err) => {
+                    warn!(%err, "cannot read the changes of the source dir");
+                    return;
+                }
+            };
+            let Some(fs) = this.upgrade() else {
+                return;
+            };
+            for event in events {
+                fs.changed(&event);
+            }
+        });
+        *fs.watching.lock().expect("watching lock poisoned") = Some(watching);
+    }
+
+    /// Something changed on the host in a watched directory, the kernel drops what it cached about
+    /// it.
+    fn changed(&self, event: &Event) {
+        let Some(&parent) = self.state().watches.get(&event.wd) else {
+            return;
+        };
+        if event.unwatched() {
+            let mut state = self.state_mut();
+            state.watches.remove(&event.wd);
+            if state.watched.get(&parent) == Some(&event.wd) {
+                state.watched.remove(&parent);
+            }
+            return;
+        }
+        let res = match &event.name {
+            // the listing, mtime and link count of the directory changed too
+            Some(name) if event.entry_changed() => self
+                .invalidator
+                .entry(parent, name)
+                .and_then(|()| self.invalidator.inode(parent)),
+            Some(name) => match self.known(parent, name) {
+                Some(ino) => self.invalidator.inode(ino),
+                None => Ok(()),
+            },
+            None => self.invalidator.inode(parent),
+        };
+        if let Err(err) = res {
+            debug!(%err, "cannot invalidate the kernel cache");
+        }
+    }
+
+    /// Our inode for a host entry, if we have seen it.
+    fn known(&self, parent: u64, name: &str) -> Option<u64> {
+        let metadata = fs::symlink_metadata(self.child_path(parent, name).ok()?).ok()?;
+        self.state()
+            .host_inodes
+            .get(&(metadata.dev(), metadata.ino()))
+            .copied()
+    }
+
+    /// Update our inodes after a host entry was removed from `parent`.
+    fn removed(&self, metadata: &Metadata, parent: u64, name: &str) {
+        if metadata.is_dir() || metadata.nlink() <= 1 {
+            self.forget(metadata);
+            return;
+        }
+        // the file has other hard links
+        self.drop_name(metadata, parent, name);
+    }
+
+    /// Drop one of the names of a host entry, another known name becomes its location.
+    fn drop_name(&self, metadata: &Metadata, parent: u64, name: &str) {
+        let mut state = self.state_mut();
+        let Some(&ino) = state.host_inodes.get(&(metadata.dev(), metadata.ino())) else {
+            return;
+        };
+        state.update_node(ino, |node| {
+            node.links.retain(|(p, n)| *p != parent || n != name);
+            if node.parent == parent && node.name == name {
+                if let Some((p, n)) = node.links.pop() {
+                    node.parent = p;
+                    node.name = n;
+                }
+            }
+        });
+    }
+
+    /// Drop our inode for a host entry which was removed, with the entries listed in it.
+    fn forget(&self, metadata: &Metadata) {
+        let mut state = self.state_mut();
+        if let Some(ino) = state.host_inodes.remove(&(metadata.dev(), metadata.ino())) {
+            state.remove_node(ino);
+            let children = state.children.get(&ino).cloned().unwrap_or_default();
+            for child in children {
+                self.drop_tree(&mut state, child);
+            }
+        }
+    }
+
+    /// Drop our inode for `ino` if the kernel has no references to it and it's not opened, it
+    /// gets a new one if it's seen again. Its parents which were only kept for it go too.
+    fn drop_unused(&self, state: &mut State, ino: u64) {
+        if let Some(node) = self.drop_tree(state, ino) {
+            for parent in node.parents() {
+                self.drop_unused(state, parent);
+            }
+        }
+    }
+
+    /// Drop the node of `ino` if it's not used, with the entries of a directory which were only
+    /// listed. A directory is kept while one of its entries is used.
+    fn drop_tree(&self, state: &mut State, ino: u64) -> Option<Node> {
+        if state.in_use(ino) {
+            return None;
+        }
+        let children = state.children.get(&ino).cloned().unwrap_or_default();
+        for child in children {
+            self.drop_tree(state, child);
+        }
+        if state.children.contains_key(&ino) {
+            return None;
+        }
+        let node = state.remove_node(ino)?;
+        if state.host_inodes.get(&node.host) == Some(&ino) {
+            state.host_inodes.remove(&node.host);
+        }
+        if let Some(wd) = state.watched.remove(&ino) {
+            state.watches.remove(&wd);
+            self.unwatch(wd);
+        }
+        Some(node)
+    }
+
+    fn lookup(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        let path = self.child_path(parent, name)?;
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if file_type(&metadata).is_none() {
+            debug!(?path, "unsupported file type");
+            return Ok(None);
+        }
+        let ino = self.register(parent, name, &metadata);
+        attr_from_metadata(ino, &metadata).map(Some)
+    }
+
+    fn check_dir(&self, ino: u64) -> FsResult<()> {
+        if !fs::symlink_metadata(self.path(ino)?)?.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(())
+    }
+
+    /// Up to `len` entries of a directory after `cookie`, with the cookies the host gives them.
+    fn entries(
+        &self,
+        ino: u64,
+        cookie: u64,
+        len: usize,
+    ) -> FsResult<Batch<(String, FileAttr, u64)>> {
+        let path = self.path(ino)?;
+        let host_entries = host_read_dir(&path, cookie, len)?;
+        let next = host_entries
+            .last()
+            .filter(|_| host_entries.len() == len)
+            .map(|(_, cookie)| *cookie);
+        let mut entries = Vec::with_capacity(host_entries.len());
+        for (name, cookie) in host_entries {
+            let Some(name) = name.to_str().map(str::to_string) else {
+                warn!(?path, ?name, "skipping name which is not UTF-8");
+                continue;
+            };
+            let attr = match name.as_str() {
+                "." => attr_from_metadata(ino, &fs::symlink_metadata(&path)?)?,
+                ".." => {
+                    let parent = self
+                        .state()
+                        .nodes
+                        .get(&ino)
+                        .ok_or(FsError::InodeNotFound)?
+                        .parent;
+                    attr_from_metadata(parent, &fs::symlink_metadata(self.path(parent)?)?)?
+                }
+                _ => {
+                    let metadata = match fs::symlink_metadata(path.join(&name)) {
+                        Ok(metadata) => metadata,
+                        // removed since it was read
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                        Err(err) => return Err(err.into()),
+                    };
+                    if file_type(&metadata).is_none() {
+                        debug!(name, "unsupported file type");
+                        continue;
+                    }
+                    let child = self.register(ino, &name, &metadata);
+                    attr_from_metadata(child, &metadata)?
+                }
+            };
+            entries.push((name, attr, cookie));
+        }
+        Ok((entries, next))
+    }
+
+    fn handle(&self, fh: u64, ino: u64) -> FsResult<(Arc<File>, bool, bool)> {
+        match self.state().handles.get(&fh) {
+            Some(handle) if handle.ino == ino => {
+                Ok((handle.file.clone(), handle.read, handle.write))
+            }
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+
+    /// Any of the opened files of an inode.
+    fn opened_file(&self, ino: u64) -> Option<Arc<File>> {
+        self.state()
+            .handles
+            .values()
+            .find(|handle| handle.ino == ino)
+            .map(|handle| handle.file.clone())
+    }
+
+    fn open_handle(&self, ino: u64, file: File, read: bool, write: bool) -> u64 {
+        let fh = self.current_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        self.state_mut().handles.insert(
+            fh,
+            Handle {
+                ino,
+                file: Arc::new(file),
+                read,
+                write,
+            },
+        );
+        fh
+    }
+
+    /// Create an entry, `name` is checked by the caller.
+    fn create_entry(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        if !self.is_dir(parent) {
+            return Err(FsError::NotADirectory);
+        }
+        let path = self.child_path(parent, name)?;
+        let mode = u32::from(create_attr.perm);
+        let file = match create_attr.kind {
+            FileType::Directory => {
+                fs::DirBuilder::new()
+                    .mode(mode)
+                    .create(&path)
+                    .map_err(map_exists)?;
+                None
+            }
+            FileType::RegularFile => Some(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .mode(mode)
+                    .open(&path)
+                    .map_err(map_exists)?,
+            ),
+            FileType::Symlink => {
+                return Err(FsError::InvalidInput(
+                    "use symlink to create symbolic links",
+                ))
+            }
+            FileType::NamedPipe => {
+                mknod(&path, libc::S_IFIFO | mode, 0).map_err(map_exists)?;
+                None
+            }
+            FileType::CharDevice => {
+                mknod(&path, libc::S_IFCHR | mode, create_attr.rdev).map_err(map_exists)?;
+                None
+            }
+            FileType::BlockDevice => {
+                mknod(&path, libc::S_IFBLK | mode, create_attr.rdev).map_err(map_exists)?;
+                None
+            }
+            FileType::Socket => {
+                mknod(&path, libc::S_IFSOCK | mode, 0).map_err(map_exists)?;
+                None
+            }
+        };
+        // the mode given on creation is subject to the process umask
+        fs::set_permissions(&path, Permissions::from_mode(mode))?;
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.uid() != create_attr.uid || metadata.gid() != create_attr.gid {
+            // only works if we have the rights, otherwise the owner will be the user running the fs
+            if let Err(err) = lchown(&path, Some(create_attr.uid), Some(create_attr.gid)) {
+                debug!(err = %err, "cannot change owner");
+            }
+        }
+        let metadata = fs::symlink_metadata(&path)?;
+        let ino = self.register(parent, name, &metadata);
+        let attr = attr_from_metadata(ino, &metadata)?;
+
+        let fh = match file {
+            Some(file) if read || write => self.open_handle(ino, file, read, write),
+            _ => 0,
+        };
+        Ok((fh, attr))
+    }
+
+    /// Move an entry, `new_name` is checked by the caller.
+    fn rename_entry(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        let from = self.child_path(parent, name)?;
+        let to = self.child_path(new_parent, new_name)?;
+        let metadata = fs::symlink_metadata(&from).map_err(map_not_found)?;
+        if parent == new_parent && name == new_name {
+            // no-op
+            return Ok(());
+        }
+        let replaced = fs::symlink_metadata(&to).ok();
+        if let Some(replaced) = &replaced {
+            if metadata.is_dir() != replaced.is_dir() {
+                return Err(if replaced.is_dir() {
+                    FsError::IsADirectory
+                } else {
+                    FsError::NotADirectory
+                });
+            }
+        }
+        fs::rename(&from, &to).map_err(|err| match err.raw_os_error() {
+            Some(libc::ENOTEMPTY | libc::EEXIST) => FsError::NotEmpty,
+            Some(libc::EINVAL) => FsError::InvalidInput("cannot move a directory inside itself"),
+            _ => err.into(),
+        })?;
+        if let Some(replaced) = replaced {
+            if replaced.dev() != metadata.dev() || replaced.ino() != metadata.ino() {
+                self.removed(&replaced, new_parent, new_name);
+            }
+        }
+        self.drop_name(&metadata, parent, name);
+        self.register(new_parent, new_name, &metadata);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Filesystem for PassthroughFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.path(ino)
+            .is_ok_and(|path| fs::symlink_metadata(path).is_ok())
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.path(ino)
+            .is_ok_and(|path| fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()))
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.path(ino)
+            .is_ok_and(|path| fs::symlink_metadata(path).is_ok_and(|m| m.is_file()))
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        check_name(name)?;
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            fs.create_entry(parent, &name, create_attr, read, write)
+        })
+        .await
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        check_name(name)?;
+        let (name, target) = (name.to_string(), target.to_string());
+        run_blocking(&self.this, move |fs| {
+            if !fs.is_dir(parent) {
+                return Err(FsError::NotADirectory);
+            }
+            let path = fs.child_path(parent, &name)?;
+            symlink(&target, &path).map_err(map_exists)?;
+            // the permissions of a symbolic link are not used, only the owner
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.uid() != create_attr.uid || metadata.gid() != create_attr.gid {
+                if let Err(err) = lchown(&path, Some(create_attr.uid), Some(create_attr.gid)) {
+                    debug!(err = %err, "cannot change owner");
+                }
+            }
+            let metadata = fs::symlink_metadata(&path)?;
+            let ino = fs.register(parent, &name, &metadata);
+            attr_from_metadata(ino, &metadata)
+        })
+        .await
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        run_blocking(&self.this, move |fs| {
+            let target = fs::read_link(fs.path(ino)?).map_err(|err| {
+                if err.raw_os_error() == Some(libc::EINVAL) {
+                    FsError::InvalidInodeType
+                } else {
+                    err.into()
+                }
+            })?;
+            target.into_os_string().into_string().map_err(|target| {
+                warn!(?target, "symbolic link target is not UTF-8");
+                FsError::InvalidInput("target is not valid UTF-8")
+            })
+        })
+        .await
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        check_name(new_name)?;
+        let new_name = new_name.to_string();
+        run_blocking(&self.this, move |fs| {
+            if !fs.is_dir(new_parent) {
+                return Err(FsError::NotADirectory);
+            }
+            let path = fs.path(ino)?;
+            if fs::symlink_metadata(&path).map_err(map_not_found)?.is_dir() {
+                return Err(FsError::DirectoryLink);
+            }
+            let new_path = fs.child_path(new_parent, &new_name)?;
+            fs::hard_link(&path, &new_path).map_err(map_exists)?;
+            let metadata = fs::symlink_metadata(&new_path)?;
+            let ino = fs.register(new_parent, &new_name, &metadata);
+            attr_from_metadata(ino, &metadata)
+        })
+        .await
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            if !fs.is_dir(parent) {
+                return Err(FsError::NotADirectory);
+            }
+            fs.lookup(parent, &name)
+        })
+        .await
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        run_blocking(&self.this, move |fs| {
+            let path = fs.path(ino)?;
+            if !fs::symlink_metadata(&path)?.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            Ok(fs::read_dir(path)?.count())
+        })
+        .await
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            let path = fs.child_path(parent, &name)?;
+            let metadata = fs::symlink_metadata(&path).map_err(map_not_found)?;
+            if !metadata.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            fs::remove_dir(&path).map_err(|err| {
+                if err.raw_os_error() == Some(libc::ENOTEMPTY) {
+                    FsError::NotEmpty
+                } else {
+                    err.into()
+                }
+            })?;
+            fs.forget(&metadata);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            let path = fs.child_path(parent, &name)?;
+            let metadata = fs::symlink_metadata(&path).map_err(map_not_found)?;
+            if metadata.is_dir() {
+                return Err(FsError::IsADirectory);
+            }
+            fs::remove_file(&path)?;
+            fs.removed(&metadata, parent, &name);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            if !fs.is_dir(parent) {
+                return Err(FsError::NotADirectory);
+            }
+            Ok(fs::symlink_metadata(fs.child_path(parent, &name)?).is_ok())
+        })
+        .await
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        run_blocking(&self.this, move |fs| fs.check_dir(ino)).await?;
+        Ok(read_host_dir_in_batches(cookie, move |cookie, len| {
+            let (entries, next) = self.entries(ino, cookie, len)?;
+            let entries = entries
+                .into_iter()
+                .map(|(name, attr, cookie)| DirectoryEntry {
+                    ino: attr.ino,
+                    name,
+                    kind: attr.kind,
+                    cookie,
+                })
+                .collect();
+            Ok((entries, next))
+        }))
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        run_blocking(&self.this, move |fs| fs.check_dir(ino)).await?;
+        Ok(read_host_dir_in_batches(cookie, move |cookie, len| {
+            let (entries, next) = self.entries(ino, cookie, len)?;
+            let entries = entries
+                .into_iter()
+                .map(|(name, attr, cookie)| DirectoryEntryPlus {
+                    ino: attr.ino,
+                    name,
+                    kind: attr.kind,
+                    attr,
+                    cookie,
+                })
+                .collect();
+            Ok((entries, next))
+        }))
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        run_blocking(&self.this, move |fs| {
+            let path = match fs.path(ino) {
+                Ok(path) => path,
+                Err(FsError::InodeNotFound) => {
+                    // all its names were removed but it's still opened
+                    let file = fs.opened_file(ino).ok_or(FsError::InodeNotFound)?;
+                    return attr_from_metadata(ino, &file.metadata()?);
+                }
+                Err(err) => return Err(err),
+            };
+            let metadata = fs::symlink_metadata(path).map_err(|err| {
+                if err.kind() == io::ErrorKind::NotFound {
+                    FsError::InodeNotFound
+                } else {
+                    err.into()
+                }
+            })?;
+            attr_from_metadata(ino, &metadata)
+        })
+        .await
+    }
+
+    async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        run_blocking(&self.this, move |fs| {
+            let path = fs.path(ino)?;
+            if let Some(size) = set_attr.size {
+                OpenOptions::new().write(true).open(&path)?.set_len(size)?;
+            }
+            // the permissions of a symbolic link cannot be changed, `chmod` would change the
+            // target
+            if let Some(perm) = set_attr.perm {
+                if !fs::symlink_metadata(&path)?.is_symlink() {
+                    fs::set_permissions(&path, Permissions::from_mode(u32::from(perm)))?;
+                }
+            }
+            if set_attr.uid.is_some() || set_attr.gid.is_some() {
+                lchown(&path, set_attr.uid, set_attr.gid)?;
+            }
+            if set_attr.atime.is_some() || set_attr.mtime.is_some() {
+                set_times(&path, set_attr.atime, set_attr.mtime)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        let (file, read, _) = self.handle(handle, ino)?;
+        if !read {
+            return Err(FsError::InvalidFileHandle);
+        }
+        let size = buf.len();
+        let data = tokio::task::spawn_blocking(move || -> FsResult<Vec<u8>> {
+            let mut buf = vec![0; size];
+            let mut len = 0;
+            while len < buf.len() {
+                let read = file.read_at(&mut buf[len..], offset + len as u64)?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            buf.truncate(len);
+            Ok(buf)
+        })
+        .await??;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        let mut state = self.state_mut();
+        let Some(handle) = state.handles.remove(&handle) else {
+            return Err(FsError::InvalidFileHandle);
+        };
+        self.drop_unused(&mut state, handle.ino);
+        Ok(())
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.state().handles.get(&fh).is_some_and(|h| h.read)
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        self.state().handles.get(&fh).is_some_and(|h| h.write)
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let (file, _, write) = self.handle(handle, ino)?;
+        if !write {
+            return Err(FsError::InvalidFileHandle);
+        }
+        let buf = buf.to_vec();
+        tokio::task::spawn_blocking(move || {
+            file.write_all_at(&buf, offset)?;
+            Ok(buf.len())
+        })
+        .await?
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        // we write directly to the host file, nothing is buffered
+        if !self.state().handles.contains_key(&handle) {
+            return Err(FsError::InvalidFileHandle);
+        }
+        Ok(())
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        copy_with_writes(
+            self,
+            src_ino,
+            src_offset,
+            dest_ino,
+            dest_offset,
+            size,
+            src_fh,
+            dest_fh,
+        )
+        .await
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if !read && !write {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        run_blocking(&self.this, move |fs| {
+            let path = fs.path(ino)?;
+            if fs::symlink_metadata(&path)?.is_dir() {
+                return Err(FsError::IsADirectory);
+            }
+            let file = OpenOptions::new().read(read).write(write).open(path)?;
+            Ok(fs.open_handle(ino, file, read, write))
+        })
+        .await
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        run_blocking(&self.this, move |fs| {
+            let path = fs.path(ino)?;
+            if fs::symlink_metadata(&path)?.is_dir() {
+                return Err(FsError::IsADirectory);
+            }
+            OpenOptions::new().write(true).open(path)?.set_len(size)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        let (file, _, write) = self.handle(handle, ino)?;
+        if !write {
+            return Err(FsError::InvalidFileHandle);
+        }
+        tokio::task::spawn_blocking(move || host_fallocate(&file, offset, len, mode)).await?
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        check_name(new_name)?;
+        let (name, new_name) = (name.to_string(), new_name.to_string());
+        run_blocking(&self.this, move |fs| {
+            fs.rename_entry(parent, &name, new_parent, &new_name)
+        })
+        .await
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            get_xattr(&fs.path(ino)?, &name).map_err(map_xattr)
+        })
+        .await
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        let flags = match mode {
+            SetXattrMode::Any => 0,
+            SetXattrMode::Create => libc::XATTR_CREATE,
+            SetXattrMode::Replace => libc::XATTR_REPLACE,
+        };
+        let (name, value) = (name.to_string(), value.to_vec());
+        run_blocking(&self.this, move |fs| {
+            set_xattr(&fs.path(ino)?, &name, &value, flags).map_err(map_xattr)
+        })
+        .await
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        let names = run_blocking(&self.this, move |fs| {
+            list_xattr(&fs.path(ino)?).map_err(map_xattr)
+        })
+        .await?;
+        // each name ends with a NUL
+        Ok(names
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| String::from_utf8(name.to_vec()).ok())
+            .collect())
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            remove_xattr(&fs.path(ino)?, &name).map_err(map_xattr)
+        })
+        .await
+    }
+
+    async fn seek(&self, ino: u64, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+        run_blocking(&self.this, move |fs| {
+            host_seek(&fs.path(ino)?, offset, whence)
+        })
+        .await
+    }
+
+    async fn statfs(&self) -> FsResult<StatFs> {
+        run_blocking(&self.this, |fs| host_statfs(&fs.source_dir)).await
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        // inode numbers are not reused until the next mount
+        self.state_mut().referenced.insert(ino);
+        0
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        let mut state = self.state_mut();
+        state.referenced.remove(&ino);
+        self.drop_unused(&mut state, ino);
+    }
+}
+
+impl Drop for PassthroughFilesystem {
+    fn drop(&mut self) {
+        let Some(watcher) = &self.watcher else {
+            return;
+        };
+        if let Err(err) = watcher.stop() {
+            warn!(%err, "cannot stop watching the source dir");
+            return;
+        }
+        let watching = self
+            .watching
+            .get_mut()
+            .expect("watching lock poisoned")
+            .take();
+        // the thread drops us when it had the last reference while handling the changes
+        if let Some(watching) = watching.filter(|w| w.thread().id() != thread::current().id()) {
+            if watching.join().is_err() {
+                warn!("the thread watching the source dir panicked");
+            }
+        }
+    }
+}
+
+fn file_type(metadata: &Metadata) -> Option<FileType> {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        Some(FileType::Directory)
+    } else if file_type.is_file() {
+        Some(FileType::RegularFile)
+    } else if file_type.is_symlink() {
+        Some(FileType::Symlink)
+    } else if file_type.is_fifo() {
+        Some(FileType::NamedPipe)
+    } else if file_type.is_char_device() {
+        Some(FileType::CharDevice)
+    } else if file_type.is_block_device() {
+        Some(FileType::BlockDevice)
+    } else if file_type.is_socket() {
+        Some(FileType::Socket)
+    } else {
+        None
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn attr_from_metadata(ino: u64, metadata: &Metadata) -> FsResult<FileAttr> {
+    let kind = file_type(metadata).ok_or(FsError::InvalidInodeType)?;
+    let ctime = system_time(metadata.ctime(), metadata.ctime_nsec());
+    Ok(FileAttr {
+        ino,
+        size: metadata.size(),
+        blocks: metadata.blocks(),
+        atime: system_time(metadata.atime(), metadata.atime_nsec()),
+        mtime: system_time(metadata.mtime(), metadata.mtime_nsec()),
+        ctime,
+        crtime: metadata.created().unwrap_or(ctime),
+        kind,
+        perm: (metadata.mode() & 0o7777) as u16,
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        blksize: metadata.blksize() as u32,
+        flags: 0,
+    })
+}
+
+#[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+fn system_time(secs: i64, nsecs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsecs as u32)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + Duration::from_nanos(nsecs as u64)
+    }
+}
+
+/// Set access and modification times, without following symlinks. `None` leaves the time unchanged.
+fn set_times(path: &Path, atime: Option<SystemTime>, mtime: Option<SystemTime>) -> io::Result<()> {
+    fn timespec(time: Option<SystemTime>) -> libc::timespec {
+        match time.map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default()) {
+            #[allow(clippy::cast_possible_wrap)]
+            Some(d) => libc::timespec {
+                tv_sec: d.as_secs() as libc::time_t,
+                tv_nsec: libc::c_long::from(d.subsec_nanos()),
+            },
+            None => libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+        }
+    }
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [timespec(atime), timespec(mtime)];
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Create a special file, `mode` includes the file type. Devices need the rights to do so.
+fn mknod(path: &Path, mode: u32, rdev: u32) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mknod(path.as_ptr(), mode, libc::dev_t::from(rdev)) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn map_exists(err: io::Error) -> FsError {
+    if err.kind() == io::ErrorKind::AlreadyExists {
+        FsError::AlreadyExists
+    } else {
+        err.into()
+    }
+}
+
+/// The xattr functions below don't follow symbolic links.
+fn get_xattr(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    read_sized(|buf, len| unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, len) })
+}
+
+fn set_xattr(path: &Path, name: &str, value: &[u8], flags: libc::c_int) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    let res = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            flags,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The names, each one followed by a NUL.
+fn list_xattr(path: &Path) -> io::Result<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    read_sized(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), len) })
+}
+
+fn remove_xattr(path: &Path, name: &str) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    if unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Read something which can have any size. `read` is called first with an empty buffer to get the
+/// size, like the xattr syscalls allow.
+fn read_sized(read: impl Fn(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let len = read(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(clippy::cast_sign_loss)]
+        let mut buf = vec![0_u8; len as usize];
+        let len = read(buf.as_mut_ptr().cast(), buf.len());
+        if len >= 0 {
+            #[allow(clippy::cast_sign_loss)]
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        // it got bigger since we asked for the size
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+fn map_xattr(err: io::Error) -> FsError {
+    match err.raw_os_error() {
+        Some(libc::ENODATA) => FsError::XattrNotFound,
+        Some(libc::EEXIST) => FsError::AlreadyExists,
+        Some(libc::ENOENT) => FsError::InodeNotFound,
+        _ => err.into(),
+    }
+}
+
+fn map_not_found(err: io::Error) -> FsError {
+    if err.kind() == io::ErrorKind::NotFound {
+        FsError::NotFound("name not found")
+    } else {
+        err.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing::{names, TempDir};
+
+    #[tokio::test]
+    async fn forgotten_inodes_are_dropped() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/b"), b"b").unwrap();
+        let passthrough = PassthroughFilesystem::new(dir.path(), Invalidator::default()).unwrap();
+        let a = passthrough
+            .find_by_name(ROOT_INODE, "a")
+            .await
+            .unwrap()
+            .unwrap()
+            .ino;
+        passthrough.referenced(a);
+        assert_eq!(names(passthrough.clone(), a).await, ["b"]);
+        let b = passthrough.find_by_name(a, "b").await.unwrap().unwrap().ino;
+        let fh = passthrough.open(b, true, false).await.unwrap();
+
+        // the opened file keeps its directory
+        passthrough.forgotten(a).await;
+        assert!(passthrough.exists(a));
+        passthrough.release(fh).await.unwrap();
+        {
+            let state = passthrough.state();
+            assert_eq!(state.nodes.len(), 1);
+            assert_eq!(state.host_inodes.len(), 1);
+            assert!(state.children.is_empty());
+            assert_eq!(state.watches.len(), 1);
+        }
+        // seen again with a new inode
+        let again = passthrough
+            .find_by_name(ROOT_INODE, "a")
+            .await
+            .unwrap()
+            .unwrap()
+            .ino;
+        assert_ne!(again, a);
+
+        // the watcher thread ends with the filesystem, it might be the one dropping it while it
+        // handles the last changes
+        let watcher = passthrough.watcher.clone().unwrap();
+        drop(passthrough);
+        for _ in 0..100 {
+            if Arc::strong_count(&watcher) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Arc::strong_count(&watcher), 1);
+    }
+}
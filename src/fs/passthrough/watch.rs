@@ -0,0 +1,151 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str;
+
+/// What we watch for in a directory, the entries which are added, removed or renamed and the files
+/// which are written or have their attributes changed.
+const MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_ATTRIB
+    | libc::IN_CLOSE_WRITE;
+
+const ENTRY_CHANGES: u32 =
+    libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO;
+
+/// Watches directories of the host with inotify.
+pub(crate) struct Watcher {
+    inotify: File,
+    /// An eventfd which wakes up [`Watcher::read`] when we stop.
+    stop: File,
+}
+
+/// A change in a watched directory.
+pub(crate) struct Event {
+    /// The watch of the directory.
+    pub wd: i32,
+    mask: u32,
+    /// The entry which changed, `None` for the directory itself or a name which is not UTF-8.
+    pub name: Option<String>,
+}
+
+impl Event {
+    /// An entry was added, removed or renamed.
+    pub const fn entry_changed(&self) -> bool {
+        self.mask & ENTRY_CHANGES != 0
+    }
+
+    /// The directory is not watched anymore, it was removed.
+    pub const fn unwatched(&self) -> bool {
+        self.mask & libc::IN_IGNORED != 0
+    }
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            inotify,
+            stop: File::from(unsafe { OwnedFd::from_raw_fd(fd) }),
+        })
+    }
+
+    /// Watch the directory at `path`, it returns the watch, the same one if it's already watched.
+    pub fn watch(&self, path: &Path) -> io::Result<i32> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                self.inotify.as_raw_fd(),
+                path.as_ptr(),
+                MASK | libc::IN_ONLYDIR,
+            )
+        };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(wd)
+    }
+
+    /// Stop watching the directory, we get an event for which [`Event::unwatched`] is true.
+    pub fn unwatch(&self, wd: i32) -> io::Result<()> {
+        if unsafe { libc::inotify_rm_watch(self.inotify.as_raw_fd(), wd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wake up [`Watcher::read`], it returns `None` from now on.
+    pub fn stop(&self) -> io::Result<()> {
+        (&self.stop).write_all(&1u64.to_ne_bytes())
+    }
+
+    /// Wait for the next changes, `None` once we are stopped.
+    pub fn read(&self) -> io::Result<Option<Vec<Event>>> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.inotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.stop.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } >= 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        if fds[1].revents != 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0; 64 * 1024];
+        let len = (&self.inotify).read(&mut buf)?;
+        let mut events = vec![];
+        let mut pos = 0;
+        while pos + size_of::<libc::inotify_event>() <= len {
+            let event: libc::inotify_event = unsafe {
+                buf.as_ptr()
+                    .add(pos)
+                    .cast::<libc::inotify_event>()
+                    .read_unaligned()
+            };
+            pos += size_of::<libc::inotify_event>();
+            // the name is padded with NULs
+            let name = &buf[pos..pos + event.len as usize];
+            let name = name.split(|b| *b == 0).next().unwrap_or_default();
+            pos += event.len as usize;
+            events.push(Event {
+                wd: event.wd,
+                mask: event.mask,
+                name: str::from_utf8(name)
+                    .ok()
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string),
+            });
+        }
+        Ok(Some(events))
+    }
+}
@@ -0,0 +1,1493 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use rand::thread_rng;
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, instrument, warn};
+
+use crate::fs::{
+    check_name, copy_with_writes, host_fallocate, host_read_dir, host_seek, host_statfs,
+    merge_attr, read_host_dir_in_batches, run_blocking, set_xattr_in, Batch, Filesystem, Xattrs,
+    ROOT_INODE,
+};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, DirectoryEntryPlusStream,
+    DirectoryEntryStream, FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    SetXattrMode, StatFs, Whence,
+};
+
+/// Version of the structure of the data directory, increment it on incompatible changes.
+const LAYOUT_VERSION: u32 = 1;
+
+const VERSION_FILE: &str = "version";
+const INODES_DIR: &str = "inodes";
+const CONTENTS_DIR: &str = "contents";
+const XATTRS_DIR: &str = "xattrs";
+const TMP_DIR: &str = "tmp";
+/// Inside a directory's content dir, the file with the inode of its parent.
+const PARENT_FILE: &str = "parent";
+/// Inside a directory's content dir, the dir with an entry file for each child.
+const ENTRIES_DIR: &str = "entries";
+
+pub(crate) const BLOCK_SIZE: u64 = 4096;
+
+/// How many locks the inodes are spread over.
+const INODE_LOCKS: u64 = 64;
+
+/// What is stored in the file of an entry.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct EntryData {
+    ino: u64,
+    kind: FileType,
+}
+
+struct Handle {
+    ino: u64,
+    file: Arc<File>,
+    read: bool,
+    write: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// Attributes changed by writes which are not saved yet.
+    dirty_attrs: HashMap<u64, FileAttr>,
+    handles: HashMap<u64, Handle>,
+    /// How many handles are opened for an inode, we keep it after the last link is removed until
+    /// this reaches zero.
+    open_handles: HashMap<u64, u32>,
+    /// References the kernel has to an inode, it's kept until they are forgotten too.
+    references: HashMap<u64, u64>,
+}
+
+impl State {
+    /// Whether the inode has opened handles or references from the kernel.
+    fn in_use(&self, ino: u64) -> bool {
+        self.open_handles.contains_key(&ino) || self.references.contains_key(&ino)
+    }
+}
+
+/// Stores everything in a data directory with this structure:
+///
+/// ```text
+/// <data_dir>/
+///   version                         LAYOUT_VERSION
+///   inodes/<ino>                    bincode serialized FileAttr
+///   contents/<ino>                  content of a file or target of a symbolic link
+///   contents/<ino>/parent           bincode serialized inode of the parent of a directory
+///   contents/<ino>/entries/<name>   bincode serialized inode and kind of a child of a directory
+///   xattrs/<ino>                    bincode serialized extended attributes, if it has any
+///   tmp/                            used to write files and then atomically move them in place
+/// ```
+///
+/// Metadata files are never written in place, we write a new one in `tmp`, sync it and rename it
+/// over the old one, so they are always consistent even if we crash.
+///
+/// The operations run on the threads for blocking work, with [`run_blocking`]. The ones changing
+/// metadata lock the inodes they change with [`PersistentFilesystem::lock`], so only operations on
+/// the same inodes wait for each other's writes to the disk.
+pub(crate) struct PersistentFilesystem {
+    data_dir: PathBuf,
+    /// Only held shortly, never while we wait for the disk.
+    state: Mutex<State>,
+    /// Serialize the changes of the metadata, inode `ino` uses the one at `ino % INODE_LOCKS`.
+    inode_locks: Vec<Mutex<()>>,
+    /// Taken by renames to another directory, so no other directory moves while we check we don't
+    /// move one inside itself.
+    rename_lock: Mutex<()>,
+    current_ino: AtomicU64,
+    current_handle: AtomicU64,
+    this: Weak<Self>,
+}
+
+impl PersistentFilesystem {
+    pub fn new(data_dir: &Path) -> FsResult<Arc<Self>> {
+        let fs = Arc::new_cyclic(|this| Self {
+            data_dir: data_dir.to_path_buf(),
+            state: Mutex::new(State::default()),
+            inode_locks: (0..INODE_LOCKS).map(|_| Mutex::new(())).collect(),
+            rename_lock: Mutex::new(()),
+            current_ino: AtomicU64::new(ROOT_INODE),
+            current_handle: AtomicU64::new(0),
+            this: this.clone(),
+        });
+        fs.ensure_structure()?;
+        fs.recover()?;
+        Ok(fs)
+    }
+
+    /// Create the structure if the data dir is empty, otherwise check it's one of ours.
+    fn ensure_structure(&self) -> FsResult<()> {
+        fs::create_dir_all(&self.data_dir)?;
+        let version_path = self.data_dir.join(VERSION_FILE);
+        if fs::read_dir(&self.data_dir)?.next().is_none() {
+            info!(data_dir = ?self.data_dir, "initializing data dir");
+            fs::create_dir(self.data_dir.join(INODES_DIR))?;
+            fs::create_dir(self.data_dir.join(CONTENTS_DIR))?;
+            fs::create_dir(self.data_dir.join(XATTRS_DIR))?;
+            fs::create_dir(self.data_dir.join(TMP_DIR))?;
+            let mut attr: FileAttr = CreateFileAttr {
+                kind: FileType::Directory,
+                perm: 0o755,
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
+                rdev: 0,
+                flags: 0,
+            }
+            .into();
+            attr.ino = ROOT_INODE;
+            attr.blksize = BLOCK_SIZE as u32;
+            self.create_content(ROOT_INODE, FileType::Directory, ROOT_INODE)?;
+            self.write_attr(&attr)?;
+            // written last, it marks the structure as complete
+            self.write_atomic(&version_path, LAYOUT_VERSION.to_string().as_bytes())?;
+            return Ok(());
+        }
+
+        let version = fs::read_to_string(&version_path)
+            .map_err(|_| FsError::InvalidDataDirStructure)?
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| FsError::InvalidDataDirStructure)?;
+        if version != LAYOUT_VERSION {
+            warn!(version, "unsupported version of data dir");
+            return Err(FsError::InvalidDataDirStructure);
+        }
+        for dir in [INODES_DIR, CONTENTS_DIR, TMP_DIR] {
+            if !self.data_dir.join(dir).is_dir() {
+                return Err(FsError::InvalidDataDirStructure);
+            }
+        }
+        if !self.inode_path(ROOT_INODE).is_file() {
+            return Err(FsError::InvalidDataDirStructure);
+        }
+        // added later, older data dirs don't have it
+        fs::create_dir_all(self.data_dir.join(XATTRS_DIR))?;
+        Ok(())
+    }
+
+    /// Find the last used inode and clean up what was left behind if we crashed: inodes which
+    /// were removed while they were opened or which were not linked yet, and link counts of files
+    /// which are off.
+    fn recover(&self) -> FsResult<()> {
+        for entry in fs::read_dir(self.data_dir.join(TMP_DIR))? {
+            fs::remove_file(entry?.path())?;
+        }
+        // the entries linking to each inode, from the root down
+        let mut links = HashMap::from([(ROOT_INODE, 1)]);
+        let mut dirs = vec![ROOT_INODE];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(self.entries_path(dir))? {
+                let name = entry?
+                    .file_name()
+                    .into_string()
+                    .map_err(|_| FsError::InvalidDataDirStructure)?;
+                let Some(entry) = self.read_entry(dir, &name)? else {
+                    continue;
+                };
+                let count = links.entry(entry.ino).or_default();
+                *count += 1;
+                if entry.kind == FileType::Directory && *count == 1 {
+                    dirs.push(entry.ino);
+                }
+            }
+        }
+        let mut max_ino = ROOT_INODE;
+        for entry in fs::read_dir(self.data_dir.join(INODES_DIR))? {
+            let ino = parse_ino(&entry?.file_name())?;
+            let mut attr = self.read_attr(ino)?;
+            let Some(&count) = links.get(&ino) else {
+                debug!(ino, "removing unlinked inode");
+                self.delete_inode(ino, attr.kind)?;
+                continue;
+            };
+            if attr.kind != FileType::Directory && attr.nlink != count {
+                debug!(ino, attr.nlink, count, "fixing link count");
+                attr.nlink = count;
+                self.write_attr(&attr)?;
+            }
+            max_ino = max_ino.max(ino);
+        }
+        // written before their inode
+        for dir in [CONTENTS_DIR, XATTRS_DIR] {
+            for entry in fs::read_dir(self.data_dir.join(dir))? {
+                let entry = entry?;
+                if links.contains_key(&parse_ino(&entry.file_name())?) {
+                    continue;
+                }
+                debug!(path = ?entry.path(), "removing content without inode");
+                if entry.file_type()?.is_dir() {
+                    fs::remove_dir_all(entry.path())?;
+                } else {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        self.current_ino.store(max_ino, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("state lock poisoned")
+    }
+
+    /// Lock the metadata of `inos`, always in the same order, so operations locking more of them
+    /// don't wait for each other. The helpers changing metadata expect the caller to hold them.
+    fn lock(&self, inos: &[u64]) -> Vec<MutexGuard<'_, ()>> {
+        let mut locks: Vec<u64> = inos.iter().map(|ino| ino % INODE_LOCKS).collect();
+        locks.sort_unstable();
+        locks.dedup();
+        locks
+            .into_iter()
+            .map(|i| {
+                self.inode_locks[i as usize]
+                    .lock()
+                    .expect("inode lock poisoned")
+            })
+            .collect()
+    }
+
+    /// Lock `parents` and the inode `name` links to in `parent`, and return its entry. The entry
+    /// is read again once locked, in case it changed meanwhile.
+    fn lock_entry(
+        &self,
+        parents: &[u64],
+        parent: u64,
+        name: &str,
+    ) -> FsResult<(Vec<MutexGuard<'_, ()>>, Option<EntryData>)> {
+        loop {
+            let entry = self.read_entry(parent, name)?;
+            let mut inos = parents.to_vec();
+            inos.extend(entry.map(|entry| entry.ino));
+            let locks = self.lock(&inos);
+            let current = self.read_entry(parent, name)?;
+            if current.map(|entry| entry.ino) == entry.map(|entry| entry.ino) {
+                return Ok((locks, current));
+            }
+        }
+    }
+
+    fn inode_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(INODES_DIR).join(ino.to_string())
+    }
+
+    fn contents_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
+    }
+
+    fn xattrs_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(XATTRS_DIR).join(ino.to_string())
+    }
+
+    fn entries_path(&self, ino: u64) -> PathBuf {
+        self.contents_path(ino).join(ENTRIES_DIR)
+    }
+
+    fn entry_path(&self, parent: u64, name: &str) -> PathBuf {
+        self.entries_path(parent).join(name)
+    }
+
+    /// Write to a temporary file and then rename it over `path`, so the change is atomic. Both are
+    /// synced, so after a crash `path` has the old or the new content, not an empty file.
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> FsResult<()> {
+        let tmp = self
+            .data_dir
+            .join(TMP_DIR)
+            .join(thread_rng().next_u64().to_string());
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn read_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        let data = fs::read(self.inode_path(ino)).map_err(|err| {
+            if err.kind() == io::ErrorKind::NotFound {
+                FsError::InodeNotFound
+            } else {
+                err.into()
+            }
+        })?;
+        let mut attr: FileAttr = bincode::deserialize(&data)?;
+        if attr.kind == FileType::RegularFile {
+            // content is the source of truth for the size, the attr might not be saved after the last write
+            set_size(&mut attr, fs::metadata(self.contents_path(ino))?.len());
+        }
+        Ok(attr)
+    }
+
+    fn read_xattrs(&self, ino: u64) -> FsResult<Xattrs> {
+        match fs::read(self.xattrs_path(ino)) {
+            Ok(data) => Ok(bincode::deserialize(&data)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Xattrs::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Save the extended attributes and update ctime. The file is removed when there are none left.
+    fn write_xattrs(&self, ino: u64, xattrs: &Xattrs) -> FsResult<()> {
+        if xattrs.is_empty() {
+            fs::remove_file(self.xattrs_path(ino))?;
+        } else {
+            self.write_atomic(&self.xattrs_path(ino), &bincode::serialize(xattrs)?)?;
+        }
+        let mut attr = self.attr(ino)?;
+        attr.ctime = SystemTime::now();
+        self.save_attr(attr)
+    }
+
+    fn write_attr(&self, attr: &FileAttr) -> FsResult<()> {
+        self.write_atomic(&self.inode_path(attr.ino), &bincode::serialize(attr)?)
+    }
+
+    fn attr(&self, ino: u64) -> FsResult<FileAttr> {
+        let dirty = self.state().dirty_attrs.get(&ino).copied();
+        match dirty {
+            Some(attr) => Ok(attr),
+            None => self.read_attr(ino),
+        }
+    }
+
+    fn save_attr(&self, attr: FileAttr) -> FsResult<()> {
+        self.write_attr(&attr)?;
+        self.state().dirty_attrs.remove(&attr.ino);
+        Ok(())
+    }
+
+    /// Save the attr if it has changes from writes which were not saved yet.
+    fn save_if_dirty(&self, ino: u64) -> FsResult<()> {
+        let dirty = self.state().dirty_attrs.get(&ino).copied();
+        match dirty {
+            Some(attr) => self.save_attr(attr),
+            None => Ok(()),
+        }
+    }
+
+    fn touch(&self, ino: u64) -> FsResult<()> {
+        let mut attr = self.attr(ino)?;
+        let now = SystemTime::now();
+        attr.mtime = now;
+        attr.ctime = now;
+        self.save_attr(attr)
+    }
+
+    fn dir_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        let attr = self.attr(ino)?;
+        if attr.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(attr)
+    }
+
+    fn create_content(&self, ino: u64, kind: FileType, parent: u64) -> FsResult<()> {
+        match kind {
+            FileType::Directory => {
+                fs::create_dir_all(self.entries_path(ino))?;
+                self.write_atomic(
+                    &self.contents_path(ino).join(PARENT_FILE),
+                    &bincode::serialize(&parent)?,
+                )?;
+            }
+            FileType::RegularFile => {
+                File::create(self.contents_path(ino))?;
+            }
+            FileType::Symlink => {
+                return Err(FsError::InvalidInput(
+                    "symbolic links are created with a target",
+                ))
+            }
+            // everything about special files is in the inode
+            FileType::NamedPipe
+            | FileType::CharDevice
+            | FileType::BlockDevice
+            | FileType::Socket => {}
+        }
+        Ok(())
+    }
+
+    fn delete_inode(&self, ino: u64, kind: FileType) -> FsResult<()> {
+        match kind {
+            FileType::Directory => fs::remove_dir_all(self.contents_path(ino))?,
+            FileType::RegularFile | FileType::Symlink => {
+                fs::remove_file(self.contents_path(ino))?;
+            }
+            FileType::NamedPipe
+            | FileType::CharDevice
+            | FileType::BlockDevice
+            | FileType::Socket => {}
+        }
+        if let Err(err) = fs::remove_file(self.xattrs_path(ino)) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
+        fs::remove_file(self.inode_path(ino))?;
+        Ok(())
+    }
+
+    /// Add a new inode to `parent`, `target` is only for symbolic links.
+    fn add_inode(
+        &self,
+        parent: u64,
+        name: &str,
+        mut attr: FileAttr,
+        target: Option<&str>,
+    ) -> FsResult<FileAttr> {
+        check_name(name)?;
+        let mut parent_attr = self.dir_attr(parent)?;
+        if self.entry_path(parent, name).exists() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        attr.ino = self.current_ino.fetch_add(1, Ordering::SeqCst) + 1;
+        attr.blksize = BLOCK_SIZE as u32;
+        match target {
+            Some(target) => {
+                self.write_atomic(&self.contents_path(attr.ino), target.as_bytes())?;
+                set_size(&mut attr, target.len() as u64);
+            }
+            None => self.create_content(attr.ino, attr.kind, parent)?,
+        }
+        self.save_attr(attr)?;
+        // the entry is written last, until then the inode is not reachable
+        self.write_entry(
+            parent,
+            name,
+            EntryData {
+                ino: attr.ino,
+                kind: attr.kind,
+            },
+        )?;
+
+        if attr.kind == FileType::Directory {
+            parent_attr.nlink += 1;
+        }
+        let now = SystemTime::now();
+        parent_attr.mtime = now;
+        parent_attr.ctime = now;
+        self.save_attr(parent_attr)?;
+        Ok(attr)
+    }
+
+    fn read_entry(&self, parent: u64, name: &str) -> FsResult<Option<EntryData>> {
+        match fs::read(self.entry_path(parent, name)) {
+            Ok(data) => Ok(Some(bincode::deserialize(&data)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write_entry(&self, parent: u64, name: &str, entry: EntryData) -> FsResult<()> {
+        self.write_atomic(&self.entry_path(parent, name), &bincode::serialize(&entry)?)
+    }
+
+    fn parent(&self, ino: u64) -> FsResult<u64> {
+        Ok(bincode::deserialize(&fs::read(
+            self.contents_path(ino).join(PARENT_FILE),
+        )?)?)
+    }
+
+    /// Check if `ino` is `ancestor` or is inside it.
+    fn is_descendant(&self, mut ino: u64, ancestor: u64) -> FsResult<bool> {
+        loop {
+            if ino == ancestor {
+                return Ok(true);
+            }
+            if ino == ROOT_INODE {
+                return Ok(false);
+            }
+            ino = self.parent(ino)?;
+        }
+    }
+
+    fn is_empty_dir(&self, ino: u64) -> FsResult<bool> {
+        Ok(fs::read_dir(self.entries_path(ino))?.next().is_none())
+    }
+
+    /// Delete the inode if it has no links and it's not used.
+    fn delete_if_unused(&self, ino: u64, attr: &FileAttr) -> FsResult<bool> {
+        {
+            let mut state = self.state();
+            if attr.nlink > 0 || state.in_use(ino) {
+                return Ok(false);
+            }
+            state.dirty_attrs.remove(&ino);
+        }
+        self.delete_inode(ino, attr.kind)?;
+        Ok(true)
+    }
+
+    /// Decrement the link count and delete the inode if there are no more links and it's not used.
+    fn unlink(&self, ino: u64) -> FsResult<()> {
+        let mut attr = self.attr(ino)?;
+        attr.nlink = attr.nlink.saturating_sub(1);
+        attr.ctime = SystemTime::now();
+        if self.delete_if_unused(ino, &attr)? {
+            return Ok(());
+        }
+        self.save_attr(attr)
+    }
+
+    /// Detach an empty directory from its parent and delete it, or only mark it removed while the
+    /// kernel has references to it.
+    fn remove_dir_inode(&self, parent: u64, ino: u64) -> FsResult<()> {
+        if !self.is_empty_dir(ino)? {
+            return Err(FsError::NotEmpty);
+        }
+        let mut attr = self.attr(ino)?;
+        attr.nlink = 0;
+        attr.ctime = SystemTime::now();
+        if !self.delete_if_unused(ino, &attr)? {
+            self.save_attr(attr)?;
+        }
+        let mut parent_attr = self.attr(parent)?;
+        parent_attr.nlink -= 1;
+        self.save_attr(parent_attr)
+    }
+
+    /// Up to `len` entries of a directory after `cookie`, with their cookie, which is the one the
+    /// host gives to the entry file.
+    fn entries(
+        &self,
+        ino: u64,
+        cookie: u64,
+        len: usize,
+    ) -> FsResult<Batch<(String, EntryData, u64)>> {
+        let host_entries = host_read_dir(&self.entries_path(ino), cookie, len)?;
+        let next = host_entries
+            .last()
+            .filter(|_| host_entries.len() == len)
+            .map(|(_, cookie)| *cookie);
+        let mut entries = Vec::with_capacity(host_entries.len());
+        for (name, cookie) in host_entries {
+            let name = name
+                .into_string()
+                .map_err(|_| FsError::InvalidDataDirStructure)?;
+            let data = match name.as_str() {
+                "." => EntryData {
+                    ino,
+                    kind: FileType::Directory,
+                },
+                ".." => EntryData {
+                    ino: self.parent(ino)?,
+                    kind: FileType::Directory,
+                },
+                _ => match self.read_entry(ino, &name)? {
+                    Some(data) => data,
+                    // removed since it was read
+                    None => continue,
+                },
+            };
+            entries.push((name, data, cookie));
+        }
+        Ok((entries, next))
+    }
+
+    fn handle_ino(&self, fh: u64) -> FsResult<u64> {
+        self.state()
+            .handles
+            .get(&fh)
+            .map(|handle| handle.ino)
+            .ok_or(FsError::InvalidFileHandle)
+    }
+
+    fn handle(&self, fh: u64, ino: u64) -> FsResult<(Arc<File>, bool, bool)> {
+        match self.state().handles.get(&fh) {
+            Some(handle) if handle.ino == ino => {
+                Ok((handle.file.clone(), handle.read, handle.write))
+            }
+            _ => Err(FsError::InvalidFileHandle),
+        }
+    }
+
+    fn open_handle(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        let file = OpenOptions::new()
+            .read(read)
+            .write(write)
+            .open(self.contents_path(ino))?;
+        let fh = self.current_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut state = self.state();
+        state.handles.insert(
+            fh,
+            Handle {
+                ino,
+                file: Arc::new(file),
+                read,
+                write,
+            },
+        );
+        *state.open_handles.entry(ino).or_default() += 1;
+        Ok(fh)
+    }
+
+    fn write_content(&self, ino: u64, offset: u64, buf: &[u8], file: &File) -> FsResult<usize> {
+        file.write_all_at(buf, offset)?;
+        self.content_changed(ino, file)?;
+        Ok(buf.len())
+    }
+
+    /// Update the size and times after the content was changed through `file`. They are saved on
+    /// flush or release, the size is recovered from the content anyway.
+    fn content_changed(&self, ino: u64, file: &File) -> FsResult<()> {
+        let _locks = self.lock(&[ino]);
+        let size = file.metadata()?.len();
+        let mut attr = self.attr(ino)?;
+        set_size(&mut attr, size);
+        let now = SystemTime::now();
+        attr.mtime = now;
+        attr.ctime = now;
+        self.state().dirty_attrs.insert(ino, attr);
+        Ok(())
+    }
+
+    /// Move an entry, `new_name` is checked by the caller.
+    fn rename_entry(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        let _rename_lock =
+            (parent != new_parent).then(|| self.rename_lock.lock().expect("rename lock poisoned"));
+        let (_locks, entry, existing) = loop {
+            let entry = self.read_entry(parent, name)?;
+            let existing = self.read_entry(new_parent, new_name)?;
+            let mut inos = vec![parent, new_parent];
+            inos.extend(entry.iter().chain(&existing).map(|entry| entry.ino));
+            let locks = self.lock(&inos);
+            let current = self.read_entry(parent, name)?;
+            let current_existing = self.read_entry(new_parent, new_name)?;
+            if current.map(|entry| entry.ino) == entry.map(|entry| entry.ino)
+                && current_existing.map(|entry| entry.ino) == existing.map(|entry| entry.ino)
+            {
+                break (locks, current, current_existing);
+            }
+        };
+        self.dir_attr(parent)?;
+        self.dir_attr(new_parent)?;
+        let entry = entry.ok_or(FsError::NotFound("name not found"))?;
+        if parent == new_parent && name == new_name {
+            // no-op
+            return Ok(());
+        }
+        let is_dir = entry.kind == FileType::Directory;
+        if is_dir && self.is_descendant(new_parent, entry.ino)? {
+            return Err(FsError::InvalidInput(
+                "cannot move a directory inside itself",
+            ));
+        }
+
+        if let Some(existing) = existing {
+            if existing.ino == entry.ino {
+                // both names link to the same inode, nothing to do
+                return Ok(());
+            }
+            let existing_is_dir = existing.kind == FileType::Directory;
+            if is_dir != existing_is_dir {
+                return Err(if existing_is_dir {
+                    FsError::IsADirectory
+                } else {
+                    FsError::NotADirectory
+                });
+            }
+            if existing_is_dir && !self.is_empty_dir(existing.ino)? {
+                return Err(FsError::NotEmpty);
+            }
+        }
+
+        // atomically replaces the existing entry, if any
+        fs::rename(
+            self.entry_path(parent, name),
+            self.entry_path(new_parent, new_name),
+        )?;
+        if let Some(existing) = existing {
+            if existing.kind == FileType::Directory {
+                self.remove_dir_inode(new_parent, existing.ino)?;
+            } else {
+                self.unlink(existing.ino)?;
+            }
+        }
+        if is_dir && parent != new_parent {
+            self.write_atomic(
+                &self.contents_path(entry.ino).join(PARENT_FILE),
+                &bincode::serialize(&new_parent)?,
+            )?;
+            let mut parent_attr = self.attr(parent)?;
+            parent_attr.nlink -= 1;
+            self.save_attr(parent_attr)?;
+            let mut new_parent_attr = self.attr(new_parent)?;
+            new_parent_attr.nlink += 1;
+            self.save_attr(new_parent_attr)?;
+        }
+
+        let mut attr = self.attr(entry.ino)?;
+        attr.ctime = SystemTime::now();
+        self.save_attr(attr)?;
+        self.touch(parent)?;
+        self.touch(new_parent)
+    }
+
+    /// The kernel has one reference less to `ino`, it's deleted after the last one if it was
+    /// removed.
+    fn forget(&self, ino: u64) {
+        let _locks = self.lock(&[ino]);
+        {
+            let mut state = self.state();
+            let Some(references) = state.references.get_mut(&ino) else {
+                return;
+            };
+            *references -= 1;
+            if *references > 0 {
+                return;
+            }
+            state.references.remove(&ino);
+        }
+        let Ok(attr) = self.attr(ino) else {
+            return;
+        };
+        match self.delete_if_unused(ino, &attr) {
+            Ok(true) => debug!(ino, "unlinked inode forgotten"),
+            Ok(false) => {}
+            Err(err) => warn!(ino, %err, "cannot delete inode"),
+        }
+    }
+}
+
+fn parse_ino(name: &OsStr) -> FsResult<u64> {
+    Ok(name
+        .to_str()
+        .ok_or(FsError::InvalidDataDirStructure)?
+        .parse::<u64>()?)
+}
+
+fn set_size(attr: &mut FileAttr, size: u64) {
+    attr.size = size;
+    attr.blocks = size.div_ceil(512);
+}
+
+#[async_trait]
+impl Filesystem for PersistentFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.inode_path(ino).is_file()
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.attr(ino)
+            .is_ok_and(|attr| attr.kind == FileType::Directory)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.attr(ino)
+            .is_ok_and(|attr| attr.kind == FileType::RegularFile)
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[parent]);
+            let attr = fs.add_inode(parent, &name, create_attr.into(), None)?;
+
+            let fh = if read || write {
+                fs.open_handle(attr.ino, read, write)?
+            } else {
+                0
+            };
+            Ok((fh, attr))
+        })
+        .await
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        let attr = CreateFileAttr {
+            kind: FileType::Symlink,
+            ..create_attr
+        };
+        let (name, target) = (name.to_string(), target.to_string());
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[parent]);
+            fs.add_inode(parent, &name, attr.into(), Some(&target))
+        })
+        .await
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        run_blocking(&self.this, move |fs| {
+            if fs.attr(ino)?.kind != FileType::Symlink {
+                return Err(FsError::InvalidInodeType);
+            }
+            Ok(fs::read_to_string(fs.contents_path(ino))?)
+        })
+        .await
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        check_name(new_name)?;
+        let new_name = new_name.to_string();
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[new_parent, ino]);
+            let mut parent_attr = fs.dir_attr(new_parent)?;
+            let mut attr = fs.attr(ino)?;
+            if attr.kind == FileType::Directory {
+                return Err(FsError::DirectoryLink);
+            }
+            if attr.nlink == 0 {
+                // removed while it was opened
+                return Err(FsError::InodeNotFound);
+            }
+            if fs.entry_path(new_parent, &new_name).exists() {
+                return Err(FsError::AlreadyExists);
+            }
+
+            let now = SystemTime::now();
+            attr.nlink += 1;
+            attr.ctime = now;
+            // saved before the entry, if we crash in between the count is too high, which is
+            // fixed when we recover, while a count too low would delete it with a name still
+            // pointing to it
+            fs.save_attr(attr)?;
+            fs.write_entry(
+                new_parent,
+                &new_name,
+                EntryData {
+                    ino,
+                    kind: attr.kind,
+                },
+            )?;
+
+            parent_attr.mtime = now;
+            parent_attr.ctime = now;
+            fs.save_attr(parent_attr)?;
+            Ok(attr)
+        })
+        .await
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[parent]);
+            fs.dir_attr(parent)?;
+            let Some(entry) = fs.read_entry(parent, &name)? else {
+                return Ok(None);
+            };
+            Ok(Some(fs.attr(entry.ino)?))
+        })
+        .await
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        run_blocking(&self.this, move |fs| {
+            fs.dir_attr(ino)?;
+            Ok(fs::read_dir(fs.entries_path(ino))?.count())
+        })
+        .await
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            let (_locks, entry) = fs.lock_entry(&[parent], parent, &name)?;
+            fs.dir_attr(parent)?;
+            let entry = entry.ok_or(FsError::NotFound("name not found"))?;
+            if entry.kind != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+            if !fs.is_empty_dir(entry.ino)? {
+                return Err(FsError::NotEmpty);
+            }
+            fs::remove_file(fs.entry_path(parent, &name))?;
+            fs.remove_dir_inode(parent, entry.ino)?;
+            fs.touch(parent)
+        })
+        .await
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            let (_locks, entry) = fs.lock_entry(&[parent], parent, &name)?;
+            fs.dir_attr(parent)?;
+            let entry = entry.ok_or(FsError::NotFound("name not found"))?;
+            if entry.kind == FileType::Directory {
+                return Err(FsError::IsADirectory);
+            }
+            fs::remove_file(fs.entry_path(parent, &name))?;
+            fs.unlink(entry.ino)?;
+            fs.touch(parent)
+        })
+        .await
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            fs.dir_attr(parent)?;
+            Ok(fs.entry_path(parent, &name).exists())
+        })
+        .await
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        run_blocking(&self.this, move |fs| fs.dir_attr(ino)).await?;
+        Ok(read_host_dir_in_batches(cookie, move |cookie, len| {
+            let (entries, next) = self.entries(ino, cookie, len)?;
+            let entries = entries
+                .into_iter()
+                .map(|(name, entry, cookie)| DirectoryEntry {
+                    ino: entry.ino,
+                    name,
+                    kind: entry.kind,
+                    cookie,
+                })
+                .collect();
+            Ok((entries, next))
+        }))
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        run_blocking(&self.this, move |fs| fs.dir_attr(ino)).await?;
+        Ok(read_host_dir_in_batches(cookie, move |cookie, len| {
+            let (entries, next) = self.entries(ino, cookie, len)?;
+            let mut entries_plus = Vec::with_capacity(entries.len());
+            for (name, entry, cookie) in entries {
+                let attr = match self.attr(entry.ino) {
+                    Ok(attr) => attr,
+                    // unlinked since its entry was read
+                    Err(FsError::InodeNotFound) => continue,
+                    Err(FsError::Io { source }) if source.kind() == io::ErrorKind::NotFound => {
+                        continue
+                    }
+                    Err(err) => return Err(err),
+                };
+                entries_plus.push(DirectoryEntryPlus {
+                    ino: entry.ino,
+                    name,
+                    kind: entry.kind,
+                    attr,
+                    cookie,
+                });
+            }
+            Ok((entries_plus, next))
+        }))
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        run_blocking(&self.this, move |fs| fs.attr(ino)).await
+    }
+
+    async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[ino]);
+            let mut attr = fs.attr(ino)?;
+            if let Some(size) = set_attr.size {
+                if attr.kind != FileType::RegularFile {
+                    return Err(FsError::InvalidInodeType);
+                }
+                OpenOptions::new()
+                    .write(true)
+                    .open(fs.contents_path(ino))?
+                    .set_len(size)?;
+            }
+            merge_attr(&mut attr, &set_attr);
+            fs.save_attr(attr)
+        })
+        .await
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        let size = buf.len();
+        let data = run_blocking(&self.this, move |fs| {
+            let (file, read, _) = fs.handle(handle, ino)?;
+            if !read {
+                return Err(FsError::InvalidFileHandle);
+            }
+            let mut buf = vec![0; size];
+            let mut len = 0;
+            while len < buf.len() {
+                let read = file.read_at(&mut buf[len..], offset + len as u64)?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            buf.truncate(len);
+            Ok(buf)
+        })
+        .await?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        run_blocking(&self.this, move |fs| {
+            let ino = fs.handle_ino(handle)?;
+            let _locks = fs.lock(&[ino]);
+            {
+                let mut state = fs.state();
+                let Handle { ino, .. } = state
+                    .handles
+                    .remove(&handle)
+                    .ok_or(FsError::InvalidFileHandle)?;
+                let open_handles = state.open_handles.entry(ino).or_default();
+                *open_handles -= 1;
+                if *open_handles == 0 {
+                    state.open_handles.remove(&ino);
+                }
+            }
+            if fs.delete_if_unused(ino, &fs.attr(ino)?)? {
+                debug!(ino, "last handle of unlinked file released");
+                return Ok(());
+            }
+            fs.save_if_dirty(ino)
+        })
+        .await
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        run_blocking(&self.this, move |fs| {
+            Ok(fs.state().handles.get(&fh).is_some_and(|h| h.read))
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        run_blocking(&self.this, move |fs| {
+            Ok(fs.state().handles.get(&fh).is_some_and(|h| h.write))
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let buf = buf.to_vec();
+        run_blocking(&self.this, move |fs| {
+            let (file, _, write) = fs.handle(handle, ino)?;
+            if !write {
+                return Err(FsError::InvalidFileHandle);
+            }
+            if buf.is_empty() {
+                // no-op
+                return Ok(0);
+            }
+            fs.write_content(ino, offset, &buf, &file)
+        })
+        .await
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        run_blocking(&self.this, move |fs| {
+            let ino = fs.handle_ino(handle)?;
+            let _locks = fs.lock(&[ino]);
+            fs.save_if_dirty(ino)
+        })
+        .await
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        copy_with_writes(
+            self,
+            src_ino,
+            src_offset,
+            dest_ino,
+            dest_offset,
+            size,
+            src_fh,
+            dest_fh,
+        )
+        .await
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if !read && !write {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[ino]);
+            if fs.attr(ino)?.kind == FileType::Directory {
+                return Err(FsError::IsADirectory);
+            }
+            fs.open_handle(ino, read, write)
+        })
+        .await
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[ino]);
+            let mut attr = fs.attr(ino)?;
+            if attr.kind != FileType::RegularFile {
+                return Err(FsError::InvalidInodeType);
+            }
+            if size == attr.size {
+                // no-op
+                return Ok(());
+            }
+            OpenOptions::new()
+                .write(true)
+                .open(fs.contents_path(ino))?
+                .set_len(size)?;
+            set_size(&mut attr, size);
+            let now = SystemTime::now();
+            attr.mtime = now;
+            attr.ctime = now;
+            fs.save_attr(attr)
+        })
+        .await
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        run_blocking(&self.this, move |fs| {
+            let (file, _, write) = fs.handle(handle, ino)?;
+            if !write {
+                return Err(FsError::InvalidFileHandle);
+            }
+            host_fallocate(&file, offset, len, mode)?;
+            fs.content_changed(ino, &file)
+        })
+        .await
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        check_name(new_name)?;
+        let (name, new_name) = (name.to_string(), new_name.to_string());
+        run_blocking(&self.this, move |fs| {
+            fs.rename_entry(parent, &name, new_parent, &new_name)
+        })
+        .await
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            fs.attr(ino)?;
+            fs.read_xattrs(ino)?
+                .remove(&name)
+                .ok_or(FsError::XattrNotFound)
+        })
+        .await
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        let (name, value) = (name.to_string(), value.to_vec());
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[ino]);
+            fs.attr(ino)?;
+            let mut xattrs = fs.read_xattrs(ino)?;
+            set_xattr_in(&mut xattrs, &name, &value, mode)?;
+            fs.write_xattrs(ino, &xattrs)
+        })
+        .await
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        run_blocking(&self.this, move |fs| {
+            fs.attr(ino)?;
+            Ok(fs.read_xattrs(ino)?.into_keys().collect())
+        })
+        .await
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        let name = name.to_string();
+        run_blocking(&self.this, move |fs| {
+            let _locks = fs.lock(&[ino]);
+            fs.attr(ino)?;
+            let mut xattrs = fs.read_xattrs(ino)?;
+            xattrs.remove(&name).ok_or(FsError::XattrNotFound)?;
+            fs.write_xattrs(ino, &xattrs)
+        })
+        .await
+    }
+
+    async fn seek(&self, ino: u64, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+        // the content is a sparse file on the host
+        run_blocking(&self.this, move |fs| {
+            host_seek(&fs.contents_path(ino), offset, whence)
+        })
+        .await
+    }
+
+    async fn statfs(&self) -> FsResult<StatFs> {
+        // we take as much space as there is on the host
+        run_blocking(&self.this, |fs| host_statfs(&fs.data_dir)).await
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        // inode numbers are not reused until the next mount
+        *self.state().references.entry(ino).or_default() += 1;
+        0
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        let res = run_blocking(&self.this, move |fs| {
+            fs.forget(ino);
+            Ok(())
+        })
+        .await;
+        if let Err(err) = res {
+            warn!(ino, %err, "cannot forget inode");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::fs::testing::{create, dir_attr, file_attr, names, read_all, TempDir};
+
+    #[tokio::test]
+    async fn reopen() {
+        let dir = TempDir::new();
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        let a = create(&*fs, "a", b"hello").await;
+        let (_, sub) = fs
+            .create(ROOT_INODE, "sub", dir_attr(0), false, false)
+            .await
+            .unwrap();
+        fs.rename(ROOT_INODE, "a", sub.ino, "b").await.unwrap();
+        drop(fs);
+
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        assert_eq!(names(fs.clone(), ROOT_INODE).await, ["sub"]);
+        let b = fs.find_by_name(sub.ino, "b").await.unwrap().unwrap();
+        assert_eq!(b.ino, a);
+        assert_eq!(fs.get_attr(sub.ino).await.unwrap().nlink, 2);
+        assert_eq!(read_all(&*fs, a).await, b"hello");
+        // new inodes come after the ones we have
+        assert!(create(&*fs, "c", b"").await > sub.ino);
+    }
+
+    #[tokio::test]
+    async fn special_files() {
+        let dir = TempDir::new();
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        let rdev = u32::try_from(libc::makedev(8, 1)).unwrap();
+        let mut nodes = vec![];
+        for kind in [
+            FileType::NamedPipe,
+            FileType::Socket,
+            FileType::CharDevice,
+            FileType::BlockDevice,
+        ] {
+            let attr = CreateFileAttr {
+                kind,
+                rdev,
+                ..file_attr(0)
+            };
+            let name = format!("{kind:?}");
+            let (_, attr) = fs
+                .create(ROOT_INODE, &name, attr, false, false)
+                .await
+                .unwrap();
+            nodes.push((name, attr.ino, kind));
+        }
+        drop(fs);
+
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        for (name, ino, kind) in nodes {
+            let attr = fs.find_by_name(ROOT_INODE, &name).await.unwrap().unwrap();
+            assert_eq!((attr.ino, attr.kind, attr.rdev), (ino, kind, rdev));
+        }
+    }
+
+    #[tokio::test]
+    async fn xattrs() {
+        let dir = TempDir::new();
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        let ino = create(&*fs, "a", b"").await;
+        assert!(matches!(
+            fs.set_xattr(ino, "user.a", b"a", SetXattrMode::Replace)
+                .await,
+            Err(FsError::XattrNotFound)
+        ));
+        fs.set_xattr(ino, "user.a", b"a", SetXattrMode::Create)
+            .await
+            .unwrap();
+        assert!(matches!(
+            fs.set_xattr(ino, "user.a", b"b", SetXattrMode::Create)
+                .await,
+            Err(FsError::AlreadyExists)
+        ));
+        fs.set_xattr(ino, "user.b", b"b", SetXattrMode::Any)
+            .await
+            .unwrap();
+        fs.remove_xattr(ino, "user.b").await.unwrap();
+        drop(fs);
+
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        assert_eq!(fs.list_xattr(ino).await.unwrap(), ["user.a"]);
+        assert_eq!(fs.get_xattr(ino, "user.a").await.unwrap(), b"a");
+        assert!(matches!(
+            fs.get_xattr(ino, "user.b").await,
+            Err(FsError::XattrNotFound)
+        ));
+    }
+
+    #[test]
+    fn corrupt_version() {
+        let dir = TempDir::new();
+        drop(PersistentFilesystem::new(dir.path()).unwrap());
+        fs::write(dir.path().join(VERSION_FILE), b"garbage").unwrap();
+
+        assert!(matches!(
+            PersistentFilesystem::new(dir.path()),
+            Err(FsError::InvalidDataDirStructure)
+        ));
+    }
+
+    #[tokio::test]
+    async fn recover() {
+        let dir = TempDir::new();
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        // removed while it was opened
+        let (_, opened) = fs
+            .create(ROOT_INODE, "opened", file_attr(0), true, true)
+            .await
+            .unwrap();
+        fs.remove_file(ROOT_INODE, "opened").await.unwrap();
+        assert!(fs.exists(opened.ino));
+        // we crashed before its entry was written
+        let unlinked = create(&*fs, "unlinked", b"").await;
+        fs::remove_file(fs.entry_path(ROOT_INODE, "unlinked")).unwrap();
+        // or before the link count was decremented
+        let linked = create(&*fs, "linked", b"data").await;
+        fs.link(linked, ROOT_INODE, "other").await.unwrap();
+        fs::remove_file(fs.entry_path(ROOT_INODE, "other")).unwrap();
+        // or before the inode was written
+        fs::write(fs.contents_path(100), b"").unwrap();
+        drop(fs);
+
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        assert!(!fs.exists(opened.ino));
+        assert!(!fs.exists(unlinked));
+        assert!(!fs.contents_path(unlinked).exists());
+        assert!(!fs.contents_path(100).exists());
+        assert_eq!(fs.get_attr(linked).await.unwrap().nlink, 1);
+        assert_eq!(read_all(&*fs, linked).await, b"data");
+        assert_eq!(names(fs.clone(), ROOT_INODE).await, ["linked"]);
+    }
+
+    #[tokio::test]
+    async fn read_dir_with_unlinks() {
+        let dir = TempDir::new();
+        let fs = PersistentFilesystem::new(dir.path()).unwrap();
+        for i in 0..100 {
+            create(&*fs, &format!("f{i}"), b"").await;
+        }
+
+        let mut stream = fs.clone().read_dir_plus(ROOT_INODE, 0).await.unwrap();
+        let mut seen = vec![];
+        while seen.len() < 10 {
+            seen.push(stream.try_next().await.unwrap().unwrap().name);
+        }
+        // the others are listed once, those removed might be if they were read already
+        let mut removed = vec![];
+        for i in 0..100 {
+            let name = format!("f{i}");
+            if i % 3 == 0 && !seen.contains(&name) {
+                fs.remove_file(ROOT_INODE, &name).await.unwrap();
+                removed.push(name);
+            }
+        }
+        while let Some(entry) = stream.try_next().await.unwrap() {
+            seen.push(entry.name);
+        }
+        seen.sort();
+        assert!(seen.windows(2).all(|pair| pair[0] != pair[1]));
+        seen.retain(|name| name != "." && name != ".." && !removed.contains(name));
+        let mut expected: Vec<String> = (0..100)
+            .map(|i| format!("f{i}"))
+            .filter(|name| !removed.contains(name))
+            .collect();
+        expected.sort();
+        assert_eq!(seen, expected);
+
+        // the entry is read but the inode is gone by the time we get its attributes
+        let ino = fs
+            .find_by_name(ROOT_INODE, "f1")
+            .await
+            .unwrap()
+            .unwrap()
+            .ino;
+        fs::remove_file(fs.inode_path(ino)).unwrap();
+        let names: Vec<String> = fs
+            .clone()
+            .read_dir_plus(ROOT_INODE, 0)
+            .await
+            .unwrap()
+            .map_ok(|entry| entry.name)
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(!names.contains(&"f1".to_string()));
+        assert!(names.contains(&"f2".to_string()));
+    }
+}
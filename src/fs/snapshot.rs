@@ -0,0 +1,1288 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures_util::{future, StreamExt};
+use tracing::{debug, info, instrument};
+
+use crate::fs::capacity::CapacityFilesystem;
+use crate::fs::directory::Directory;
+use crate::fs::memory::total_memory;
+use crate::fs::{check_name, read_dir_in_batches, Filesystem, StripedLocks, Xattrs, ROOT_INODE};
+use crate::fs_model::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, DirectoryEntryPlusStream,
+    DirectoryEntryStream, FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    SetXattrMode, StatFs, Whence,
+};
+
+/// Reserved name in the root, where the snapshots are shown.
+pub(crate) const SNAPSHOTS_DIR: &str = ".snapshots";
+/// Inodes of the snapshots have this bit set, so they never clash with the ones of the filesystem.
+const SNAPSHOT_INODE_FLAG: u64 = 1 << 63;
+/// The `/.snapshots` directory.
+pub(crate) const SNAPSHOTS_INODE: u64 = SNAPSHOT_INODE_FLAG | 1;
+/// When a file shared with a snapshot is changed we keep the original content in blocks of this
+/// size.
+const COW_BLOCK_SIZE: u64 = 64 * 1024;
+
+pub(crate) const fn is_snapshot_inode(ino: u64) -> bool {
+    ino & SNAPSHOT_INODE_FLAG != 0
+}
+
+/// The content of a file in a snapshot. The blocks which are not in `blocks` are the same as in
+/// the live file.
+struct Content {
+    /// Inode of the live file, `None` after it's removed, then all blocks are in `blocks`.
+    live: Option<u64>,
+    size: u64,
+    blocks: HashMap<u64, Arc<Block>>,
+}
+
+/// How much the kept blocks take. They are in memory, so like `tmpfs` they can take at most half
+/// of it, even if the capacity is not limited.
+struct Usage {
+    kept: AtomicU64,
+    max: u64,
+    capacity: Option<Arc<CapacityFilesystem>>,
+}
+
+impl Usage {
+    fn reserve(&self, len: u64) -> FsResult<()> {
+        if self.kept.fetch_add(len, Ordering::SeqCst) + len > self.max {
+            self.kept.fetch_sub(len, Ordering::SeqCst);
+            return Err(FsError::NoSpace);
+        }
+        if let Some(capacity) = &self.capacity {
+            capacity.reserve(len).inspect_err(|_| {
+                self.kept.fetch_sub(len, Ordering::SeqCst);
+            })?;
+        }
+        Ok(())
+    }
+
+    fn unreserve(&self, len: u64) {
+        self.kept.fetch_sub(len, Ordering::SeqCst);
+        if let Some(capacity) = &self.capacity {
+            capacity.unreserve(len);
+        }
+    }
+}
+
+/// A block of a live file kept for the snapshots, counted in [`Usage`] until it's dropped.
+struct Block {
+    data: Vec<u8>,
+    usage: Arc<Usage>,
+}
+
+impl Block {
+    fn new(data: Vec<u8>, usage: Arc<Usage>) -> FsResult<Self> {
+        usage.reserve(data.len() as u64)?;
+        Ok(Self { data, usage })
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        self.usage.unreserve(self.data.len() as u64);
+    }
+}
+
+struct Node {
+    attr: FileAttr,
+    parent: u64,
+    /// Entries of directories, for `/.snapshots` the roots of the snapshots.
+    children: Directory,
+    /// Only for files.
+    content: Option<Content>,
+    /// Only for symbolic links.
+    target: Option<String>,
+    xattrs: Xattrs,
+}
+
+struct Handle {
+    ino: u64,
+    /// Opened on the live file, for the blocks still shared with it.
+    live_fh: Option<(u64, u64)>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Root inode of the snapshots, by name.
+    snapshots: BTreeMap<String, u64>,
+    nodes: HashMap<u64, Node>,
+    /// Files in the snapshots which still share content with a live file, by live inode.
+    shared: HashMap<u64, Vec<u64>>,
+    handles: HashMap<u64, Handle>,
+}
+
+impl State {
+    fn node(&self, ino: u64) -> FsResult<&Node> {
+        self.nodes.get(&ino).ok_or(FsError::InodeNotFound)
+    }
+
+    fn child(&self, parent: u64, name: &str) -> FsResult<Option<u64>> {
+        Ok(self.children(parent)?.get(name))
+    }
+
+    /// The file `ino` of a snapshot doesn't share the content of `live` anymore.
+    fn unshare(&mut self, ino: u64, live: u64) {
+        if let Some(files) = self.shared.get_mut(&live) {
+            files.retain(|file| *file != ino);
+            if files.is_empty() {
+                self.shared.remove(&live);
+            }
+        }
+    }
+
+    fn children(&self, ino: u64) -> FsResult<&Directory> {
+        let node = self.node(ino)?;
+        if node.attr.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(&node.children)
+    }
+
+    /// Up to `len` entries of a directory after `cookie`, with their attributes and cookie.
+    /// `root_attr` is the one of the live root, the parent of `/.snapshots`.
+    fn entries(
+        &self,
+        ino: u64,
+        cookie: u64,
+        len: usize,
+        root_attr: FileAttr,
+    ) -> FsResult<Vec<(String, FileAttr, u64)>> {
+        let parent = self.node(ino)?.parent;
+        self.children(ino)?
+            .after(cookie, ino, parent)
+            .take(len)
+            .map(|(name, child, cookie)| {
+                let attr = if child == ROOT_INODE {
+                    root_attr
+                } else {
+                    self.node(child)?.attr
+                };
+                Ok((name.to_string(), attr, cookie))
+            })
+            .collect()
+    }
+}
+
+/// Point-in-time, read-only copies of a filesystem, shown under `/.snapshots/<name>`.
+///
+/// When a snapshot is created we only copy the tree and the attributes, the content of the files
+/// is shared with the live ones. Before a shared file is changed by [`SnapshotFilesystem`], the
+/// blocks about to change are copied to the snapshots which still share them. Snapshots are kept in
+/// memory, they don't survive a restart. The copied blocks are charged to the capacity, if it's
+/// limited, and can take at most half of the memory.
+///
+/// This is the filesystem of the snapshot inodes, `Fuse3` sends to it the requests for them.
+pub(crate) struct Snapshots {
+    fs: Arc<dyn Filesystem>,
+    usage: Arc<Usage>,
+    state: RwLock<State>,
+    /// Changes take it for read, taking a snapshot takes it for write so it sees the tree at one
+    /// point in time.
+    changes: tokio::sync::RwLock<()>,
+    /// Striped by live inode, so we don't read shared blocks while they are changed.
+    locks: StripedLocks,
+    current_ino: AtomicU64,
+    current_handle: AtomicU64,
+}
+
+impl Snapshots {
+    pub async fn new(
+        fs: Arc<dyn Filesystem>,
+        capacity: Option<Arc<CapacityFilesystem>>,
+    ) -> FsResult<Arc<Self>> {
+        let root_attr = fs.get_attr(ROOT_INODE).await?;
+        let mut attr: FileAttr = CreateFileAttr {
+            kind: FileType::Directory,
+            perm: 0o755,
+            uid: root_attr.uid,
+            gid: root_attr.gid,
+            rdev: 0,
+            flags: 0,
+        }
+        .into();
+        attr.ino = SNAPSHOTS_INODE;
+        attr.blksize = root_attr.blksize;
+        let mut state = State::default();
+        state.nodes.insert(
+            SNAPSHOTS_INODE,
+            Node {
+                attr,
+                parent: ROOT_INODE,
+                children: Directory::default(),
+                content: None,
+                target: None,
+                xattrs: Xattrs::new(),
+            },
+        );
+        Ok(Arc::new(Self {
+            fs,
+            usage: Arc::new(Usage {
+                kept: AtomicU64::new(0),
+                max: total_memory() / 2,
+                capacity,
+            }),
+            state: RwLock::new(state),
+            changes: tokio::sync::RwLock::new(()),
+            locks: StripedLocks::new(),
+            current_ino: AtomicU64::new(SNAPSHOTS_INODE),
+            current_handle: AtomicU64::new(0),
+        }))
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().expect("state lock poisoned")
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().expect("state lock poisoned")
+    }
+
+    fn next_ino(&self) -> u64 {
+        self.current_ino.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Take a snapshot of the whole filesystem. Changes wait while the tree is copied, so it has
+    /// the files as they were at one point in time.
+    #[instrument(skip(self))]
+    pub async fn take(&self, name: &str) -> FsResult<FileAttr> {
+        check_name(name)?;
+        if self.state().snapshots.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let _changes = self.changes.write().await;
+        let created = SystemTime::now();
+        let mut files = vec![];
+        let res = match self.copy_tree(created, &mut files).await {
+            Ok(nodes) => self.show(name, created, nodes),
+            Err(err) => Err(err),
+        };
+        if res.is_err() {
+            let mut state = self.state_mut();
+            for file in files {
+                if let Some(Node {
+                    content:
+                        Some(Content {
+                            live: Some(live), ..
+                        }),
+                    ..
+                }) = state.nodes.remove(&file)
+                {
+                    state.unshare(file, live);
+                }
+            }
+        }
+        res
+    }
+
+    /// Copy the tree, the root is the first node. The regular files are added to the state right
+    /// away, in `files`, so they share the content of the live ones. The caller holds `changes` for
+    /// write.
+    async fn copy_tree(
+        &self,
+        created: SystemTime,
+        files: &mut Vec<u64>,
+    ) -> FsResult<Vec<(u64, Node)>> {
+        let mut root_attr = self.fs.get_attr(ROOT_INODE).await?;
+        let root = self.next_ino();
+        root_attr.ino = root;
+        root_attr.crtime = created;
+        let mut nodes = vec![(
+            root,
+            Node {
+                attr: root_attr,
+                parent: SNAPSHOTS_INODE,
+                children: Directory::default(),
+                content: None,
+                target: None,
+                xattrs: self.live_xattrs(ROOT_INODE).await?,
+            },
+        )];
+        let mut dirs = vec![(ROOT_INODE, 0)];
+        // the files with more hard links we've seen, so their names share the node
+        let mut links = HashMap::new();
+        while let Some((live_dir, index)) = dirs.pop() {
+            let dir = nodes[index].0;
+            let mut entries = self.fs.clone().read_dir_plus(live_dir, 0).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                if live_dir == ROOT_INODE && entry.name == SNAPSHOTS_DIR {
+                    continue;
+                }
+                if let Some(&ino) = links.get(&entry.ino) {
+                    nodes[index].1.children.insert(entry.name, ino);
+                    continue;
+                }
+                let ino = self.next_ino();
+                if entry.attr.kind == FileType::RegularFile {
+                    let mut attr = entry.attr;
+                    attr.ino = ino;
+                    if attr.nlink > 1 {
+                        links.insert(entry.ino, ino);
+                    }
+                    let node = Node {
+                        attr,
+                        parent: dir,
+                        children: Directory::default(),
+                        content: Some(Content {
+                            live: Some(entry.ino),
+                            size: attr.size,
+                            blocks: HashMap::new(),
+                        }),
+                        target: None,
+                        xattrs: self.live_xattrs(entry.ino).await?,
+                    };
+                    let mut state = self.state_mut();
+                    state.shared.entry(entry.ino).or_default().push(ino);
+                    state.nodes.insert(ino, node);
+                    files.push(ino);
+                    drop(state);
+                    nodes[index].1.children.insert(entry.name, ino);
+                    continue;
+                }
+                let mut attr = entry.attr;
+                attr.ino = ino;
+                if attr.kind != FileType::Directory && attr.nlink > 1 {
+                    links.insert(entry.ino, ino);
+                }
+                nodes[index].1.children.insert(entry.name, ino);
+                let target = match attr.kind {
+                    FileType::Directory => {
+                        dirs.push((entry.ino, nodes.len()));
+                        None
+                    }
+                    FileType::Symlink => Some(self.fs.read_link(entry.ino).await?),
+                    // special files have no data, `rdev` is in the attributes
+                    FileType::NamedPipe
+                    | FileType::CharDevice
+                    | FileType::BlockDevice
+                    | FileType::Socket
+                    | FileType::RegularFile => None,
+                };
+                nodes.push((
+                    ino,
+                    Node {
+                        attr,
+                        parent: dir,
+                        children: Directory::default(),
+                        content: None,
+                        target,
+                        xattrs: self.live_xattrs(entry.ino).await?,
+                    },
+                ));
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// Add the rest of the nodes of a snapshot and show it in `/.snapshots`.
+    fn show(&self, name: &str, created: SystemTime, nodes: Vec<(u64, Node)>) -> FsResult<FileAttr> {
+        let mut state = self.state_mut();
+        if state.snapshots.contains_key(name) {
+            // taken by someone else while we waited for the changes
+            return Err(FsError::AlreadyExists);
+        }
+        let (root, root_attr) = (nodes[0].0, nodes[0].1.attr);
+        info!(name, entries = nodes.len(), "snapshot created");
+        state.nodes.extend(nodes);
+        state.snapshots.insert(name.to_string(), root);
+        let snapshots_dir = state.nodes.get_mut(&SNAPSHOTS_INODE).unwrap();
+        snapshots_dir.children.insert(name.to_string(), root);
+        let attr = &mut snapshots_dir.attr;
+        attr.nlink += 1;
+        attr.mtime = created;
+        attr.ctime = created;
+        Ok(root_attr)
+    }
+
+    async fn live_xattrs(&self, live_ino: u64) -> FsResult<Xattrs> {
+        let mut xattrs = Xattrs::new();
+        for name in self.fs.list_xattr(live_ino).await? {
+            let value = self.fs.get_xattr(live_ino, &name).await?;
+            xattrs.insert(name, value);
+        }
+        Ok(xattrs)
+    }
+
+    #[instrument(skip(self))]
+    pub fn delete(&self, name: &str) -> FsResult<()> {
+        let mut state = self.state_mut();
+        let root = state
+            .snapshots
+            .remove(name)
+            .ok_or(FsError::NotFound("snapshot not found"))?;
+        let mut inodes = vec![root];
+        while let Some(ino) = inodes.pop() {
+            let Some(node) = state.nodes.remove(&ino) else {
+                continue;
+            };
+            inodes.extend(node.children.iter().map(|(_, ino)| ino));
+            if let Some(Content {
+                live: Some(live), ..
+            }) = node.content
+            {
+                state.unshare(ino, live);
+            }
+        }
+        let snapshots_dir = state.nodes.get_mut(&SNAPSHOTS_INODE).unwrap();
+        snapshots_dir.children.remove(name);
+        let attr = &mut snapshots_dir.attr;
+        attr.nlink -= 1;
+        attr.mtime = SystemTime::now();
+        attr.ctime = attr.mtime;
+        Ok(())
+    }
+
+    /// Copy to the snapshots the blocks of a live file in this range, if they still share them.
+    /// Must be called with the lock of the inode taken for write.
+    async fn preserve(&self, live_ino: u64, start: u64, end: u64) -> FsResult<()> {
+        if start >= end {
+            return Ok(());
+        }
+        let needed: BTreeSet<u64> = {
+            let state = self.state();
+            let Some(files) = state.shared.get(&live_ino) else {
+                return Ok(());
+            };
+            let mut needed = BTreeSet::new();
+            for file in files {
+                let Some(content) = state.node(*file)?.content.as_ref() else {
+                    continue;
+                };
+                let end = min(end, content.size);
+                if start >= end {
+                    continue;
+                }
+                needed.extend(
+                    (start / COW_BLOCK_SIZE..end.div_ceil(COW_BLOCK_SIZE))
+                        .filter(|block| !content.blocks.contains_key(block)),
+                );
+            }
+            needed
+        };
+        if needed.is_empty() {
+            return Ok(());
+        }
+        debug!(
+            live_ino,
+            blocks = needed.len(),
+            "preserving blocks for snapshots"
+        );
+
+        let fh = self.fs.open(live_ino, true, false).await?;
+        let mut blocks = Vec::with_capacity(needed.len());
+        let res = async {
+            for block in needed {
+                #[allow(clippy::cast_possible_truncation)]
+                let mut buf = vec![0; COW_BLOCK_SIZE as usize];
+                let mut len = 0;
+                while len < buf.len() {
+                    let offset = block * COW_BLOCK_SIZE + len as u64;
+                    let read = self.fs.read(live_ino, offset, &mut buf[len..], fh).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    len += read;
+                }
+                buf.truncate(len);
+                blocks.push((block, Arc::new(Block::new(buf, self.usage.clone())?)));
+            }
+            Ok::<_, FsError>(())
+        }
+        .await;
+        self.fs.release(fh).await?;
+        res?;
+
+        let mut state = self.state_mut();
+        let files = state.shared.get(&live_ino).cloned().unwrap_or_default();
+        for file in files {
+            let Some(content) = state
+                .nodes
+                .get_mut(&file)
+                .and_then(|node| node.content.as_mut())
+            else {
+                continue;
+            };
+            for (block, data) in &blocks {
+                if block * COW_BLOCK_SIZE < content.size {
+                    content.blocks.entry(*block).or_insert_with(|| data.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A name of the live file is about to be removed, if it's the last one copy everything it
+    /// still shares. Must be called with the lock of the inode taken for write, until it's removed.
+    async fn detach_last_link(&self, live_ino: u64) -> FsResult<()> {
+        if self.fs.get_attr(live_ino).await?.nlink > 1 {
+            // the content stays with the other names
+            return Ok(());
+        }
+        self.preserve(live_ino, 0, u64::MAX).await?;
+        let mut state = self.state_mut();
+        for file in state.shared.remove(&live_ino).unwrap_or_default() {
+            if let Some(content) = state
+                .nodes
+                .get_mut(&file)
+                .and_then(|node| node.content.as_mut())
+            {
+                content.live = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        Ok(self.state().node(ino)?.attr)
+    }
+}
+
+#[async_trait]
+impl Filesystem for Snapshots {
+    fn exists(&self, ino: u64) -> bool {
+        self.state().nodes.contains_key(&ino)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.state()
+            .nodes
+            .get(&ino)
+            .is_some_and(|node| node.attr.kind == FileType::Directory)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.state()
+            .nodes
+            .get(&ino)
+            .is_some_and(|node| node.attr.kind == FileType::RegularFile)
+    }
+
+    /// Creating a directory in `/.snapshots` takes a snapshot, everything else is read-only.
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        _read: bool,
+        _write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        if parent != SNAPSHOTS_INODE || create_attr.kind != FileType::Directory {
+            return Err(FsError::ReadOnly);
+        }
+        Ok((0, self.take(name).await?))
+    }
+
+    async fn symlink(
+        &self,
+        _parent: u64,
+        _name: &str,
+        _target: &str,
+        _create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        self.state()
+            .node(ino)?
+            .target
+            .clone()
+            .ok_or(FsError::InvalidInodeType)
+    }
+
+    async fn link(&self, _ino: u64, _new_parent: u64, _new_name: &str) -> FsResult<FileAttr> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        let state = self.state();
+        state
+            .child(parent, name)?
+            .map(|ino| state.node(ino).map(|node| node.attr))
+            .transpose()
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        Ok(self.state().children(ino)?.len())
+    }
+
+    /// Removing a directory from `/.snapshots` deletes the snapshot.
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        if parent != SNAPSHOTS_INODE {
+            return Err(FsError::ReadOnly);
+        }
+        self.delete(name)
+    }
+
+    async fn remove_file(&self, _parent: u64, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        Ok(self.state().child(parent, name)?.is_some())
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        let stream = self.read_dir_plus(ino, cookie).await?;
+        Ok(stream.map(|entry| entry.map(DirectoryEntry::from)).boxed())
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        self.state().children(ino)?;
+        let root_attr = self.fs.get_attr(ROOT_INODE).await?;
+        Ok(read_dir_in_batches(cookie, move |cookie, len| {
+            let entries: Vec<_> = self
+                .state()
+                .entries(ino, cookie, len, root_attr)?
+                .into_iter()
+                .map(|(name, attr, cookie)| DirectoryEntryPlus {
+                    ino: attr.ino,
+                    name,
+                    kind: attr.kind,
+                    attr,
+                    cookie,
+                })
+                .collect();
+            let next = entries
+                .last()
+                .filter(|_| entries.len() == len)
+                .map(|entry| entry.cookie);
+            Ok((entries, next))
+        }))
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        self.entry_attr(ino)
+    }
+
+    async fn set_attr(&self, _ino: u64, _set_attr: SetFileAttr) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    #[instrument(skip(self, buf))]
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        let live_fh = match self.state().handles.get(&handle) {
+            Some(handle) if handle.ino == ino => handle.live_fh,
+            _ => return Err(FsError::InvalidFileHandle),
+        };
+        let live = {
+            let state = self.state();
+            let content = state
+                .node(ino)?
+                .content
+                .as_ref()
+                .ok_or(FsError::InvalidInodeType)?;
+            content.live
+        };
+        let _lock = match live {
+            Some(live) => Some(self.locks.get(live).read().await),
+            None => None,
+        };
+        // take the blocks we have now, with the lock no more can be added until we finish
+        let (size, plan) = {
+            let state = self.state();
+            let content = state.node(ino)?.content.as_ref().unwrap();
+            if offset >= content.size {
+                return Ok(0);
+            }
+            let end = min(offset + buf.len() as u64, content.size);
+            let mut plan = vec![];
+            let mut pos = offset;
+            while pos < end {
+                let block = pos / COW_BLOCK_SIZE;
+                let len = min(end, (block + 1) * COW_BLOCK_SIZE) - pos;
+                plan.push((pos, len, content.blocks.get(&block).cloned()));
+                pos += len;
+            }
+            (end - offset, plan)
+        };
+        for (pos, len, data) in plan {
+            #[allow(clippy::cast_possible_truncation)]
+            let dest = &mut buf[(pos - offset) as usize..(pos - offset + len) as usize];
+            if let Some(data) = data {
+                #[allow(clippy::cast_possible_truncation)]
+                let data = &data.data;
+                let start = min((pos % COW_BLOCK_SIZE) as usize, data.len());
+                let available = min(dest.len(), data.len() - start);
+                dest[..available].copy_from_slice(&data[start..start + available]);
+                dest[available..].fill(0);
+                continue;
+            }
+            let (live_ino, fh) = live_fh.ok_or(FsError::InvalidFileHandle)?;
+            let mut read = 0;
+            while read < dest.len() {
+                let len = self
+                    .fs
+                    .read(live_ino, pos + read as u64, &mut dest[read..], fh)
+                    .await?;
+                if len == 0 {
+                    break;
+                }
+                read += len;
+            }
+            dest[read..].fill(0);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(size as usize)
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        let Some(handle) = self.state_mut().handles.remove(&handle) else {
+            return Err(FsError::InvalidFileHandle);
+        };
+        if let Some((_, fh)) = handle.live_fh {
+            self.fs.release(fh).await?;
+        }
+        Ok(())
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.state().handles.contains_key(&fh)
+    }
+
+    async fn is_write_handle(&self, _fh: u64) -> bool {
+        false
+    }
+
+    async fn write(&self, _ino: u64, _offset: u64, _buf: &[u8], _handle: u64) -> FsResult<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        if !self.state().handles.contains_key(&handle) {
+            return Err(FsError::InvalidFileHandle);
+        }
+        Ok(())
+    }
+
+    async fn copy_file_range(
+        &self,
+        _src_ino: u64,
+        _src_offset: u64,
+        _dest_ino: u64,
+        _dest_offset: u64,
+        _size: usize,
+        _src_fh: u64,
+        _dest_fh: u64,
+    ) -> FsResult<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        if write {
+            return Err(FsError::ReadOnly);
+        }
+        if !read {
+            return Err(FsError::InvalidInput(
+                "read and write cannot be false at the same time",
+            ));
+        }
+        let live = {
+            let state = self.state();
+            let content = state
+                .node(ino)?
+                .content
+                .as_ref()
+                .ok_or(FsError::InvalidInodeType)?;
+            content.live
+        };
+        let live_fh = match live {
+            Some(live) => Some((live, self.fs.open(live, true, false).await?)),
+            None => None,
+        };
+        let fh = self.current_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        self.state_mut().handles.insert(fh, Handle { ino, live_fh });
+        Ok(fh)
+    }
+
+    async fn set_len(&self, _ino: u64, _size: u64) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn fallocate(
+        &self,
+        _ino: u64,
+        _offset: u64,
+        _len: u64,
+        _mode: FallocateMode,
+        _handle: u64,
+    ) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn rename(
+        &self,
+        _parent: u64,
+        _name: &str,
+        _new_parent: u64,
+        _new_name: &str,
+    ) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        self.state()
+            .node(ino)?
+            .xattrs
+            .get(name)
+            .cloned()
+            .ok_or(FsError::XattrNotFound)
+    }
+
+    async fn set_xattr(
+        &self,
+        _ino: u64,
+        _name: &str,
+        _value: &[u8],
+        _mode: SetXattrMode,
+    ) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        Ok(self.state().node(ino)?.xattrs.keys().cloned().collect())
+    }
+
+    async fn remove_xattr(&self, _ino: u64, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// The snapshots are on the live filesystem.
+    async fn statfs(&self) -> FsResult<StatFs> {
+        self.fs.statfs().await
+    }
+
+    fn max_name_len(&self) -> usize {
+        self.fs.max_name_len()
+    }
+}
+
+/// The live filesystem, it keeps the content shared with the [`Snapshots`] before changing it.
+pub(crate) struct SnapshotFilesystem {
+    snapshots: Arc<Snapshots>,
+}
+
+impl SnapshotFilesystem {
+    pub fn new(snapshots: Arc<Snapshots>) -> Arc<Self> {
+        Arc::new(Self { snapshots })
+    }
+
+    fn inner(&self) -> &Arc<dyn Filesystem> {
+        &self.snapshots.fs
+    }
+}
+
+/// [`SNAPSHOTS_DIR`] is reserved in the root.
+fn is_reserved(parent: u64, name: &str) -> bool {
+    parent == ROOT_INODE && name == SNAPSHOTS_DIR
+}
+
+#[async_trait]
+impl Filesystem for SnapshotFilesystem {
+    fn exists(&self, ino: u64) -> bool {
+        self.inner().exists(ino)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.inner().is_dir(ino)
+    }
+
+    fn is_file(&self, ino: u64) -> bool {
+        self.inner().is_file(ino)
+    }
+
+    async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        if is_reserved(parent, name) {
+            return Err(FsError::InvalidInput("name is reserved for snapshots"));
+        }
+        let _changes = self.snapshots.changes.read().await;
+        self.inner()
+            .create(parent, name, create_attr, read, write)
+            .await
+    }
+
+    async fn symlink(
+        &self,
+        parent: u64,
+        name: &str,
+        target: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        if is_reserved(parent, name) {
+            return Err(FsError::InvalidInput("name is reserved for snapshots"));
+        }
+        let _changes = self.snapshots.changes.read().await;
+        self.inner()
+            .symlink(parent, name, target, create_attr)
+            .await
+    }
+
+    async fn read_link(&self, ino: u64) -> FsResult<String> {
+        self.inner().read_link(ino).await
+    }
+
+    async fn link(&self, ino: u64, new_parent: u64, new_name: &str) -> FsResult<FileAttr> {
+        if is_reserved(new_parent, new_name) {
+            return Err(FsError::InvalidInput("name is reserved for snapshots"));
+        }
+        let _changes = self.snapshots.changes.read().await;
+        self.inner().link(ino, new_parent, new_name).await
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &str) -> FsResult<Option<FileAttr>> {
+        if is_reserved(parent, name) {
+            return Ok(None);
+        }
+        self.inner().find_by_name(parent, name).await
+    }
+
+    async fn len(&self, ino: u64) -> FsResult<usize> {
+        let len = self.inner().len(ino).await?;
+        if ino == ROOT_INODE && self.inner().exists_by_name(ino, SNAPSHOTS_DIR).await? {
+            return Ok(len - 1);
+        }
+        Ok(len)
+    }
+
+    async fn remove_dir(&self, parent: u64, name: &str) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        let _changes = self.snapshots.changes.read().await;
+        self.inner().remove_dir(parent, name).await
+    }
+
+    async fn remove_file(&self, parent: u64, name: &str) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        let _changes = self.snapshots.changes.read().await;
+        let Some(attr) = self.inner().find_by_name(parent, name).await? else {
+            return self.inner().remove_file(parent, name).await;
+        };
+        let _lock = self.snapshots.locks.get(attr.ino).write().await;
+        self.snapshots.detach_last_link(attr.ino).await?;
+        self.inner().remove_file(parent, name).await
+    }
+
+    async fn exists_by_name(&self, parent: u64, name: &str) -> FsResult<bool> {
+        if is_reserved(parent, name) {
+            return Ok(false);
+        }
+        self.inner().exists_by_name(parent, name).await
+    }
+
+    async fn read_dir(self: Arc<Self>, ino: u64, cookie: u64) -> FsResult<DirectoryEntryStream> {
+        let stream = self.inner().clone().read_dir(ino, cookie).await?;
+        if ino != ROOT_INODE {
+            return Ok(stream);
+        }
+        Ok(stream
+            .filter(|entry| {
+                future::ready(
+                    entry
+                        .as_ref()
+                        .map_or(true, |entry| entry.name != SNAPSHOTS_DIR),
+                )
+            })
+            .boxed())
+    }
+
+    async fn read_dir_plus(
+        self: Arc<Self>,
+        ino: u64,
+        cookie: u64,
+    ) -> FsResult<DirectoryEntryPlusStream> {
+        let stream = self.inner().clone().read_dir_plus(ino, cookie).await?;
+        if ino != ROOT_INODE {
+            return Ok(stream);
+        }
+        Ok(stream
+            .filter(|entry| {
+                future::ready(
+                    entry
+                        .as_ref()
+                        .map_or(true, |entry| entry.name != SNAPSHOTS_DIR),
+                )
+            })
+            .boxed())
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        self.inner().get_attr(ino).await
+    }
+
+    async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        let _changes = self.snapshots.changes.read().await;
+        let _lock = self.snapshots.locks.get(ino).write().await;
+        if let Some(size) = set_attr.size {
+            self.snapshots.preserve(ino, size, u64::MAX).await?;
+        }
+        self.inner().set_attr(ino, set_attr).await
+    }
+
+    async fn read(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        self.inner().read(ino, offset, buf, handle).await
+    }
+
+    async fn release(&self, handle: u64) -> FsResult<()> {
+        self.inner().release(handle).await
+    }
+
+    async fn is_read_handle(&self, fh: u64) -> bool {
+        self.inner().is_read_handle(fh).await
+    }
+
+    async fn is_write_handle(&self, fh: u64) -> bool {
+        self.inner().is_write_handle(fh).await
+    }
+
+    async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let _changes = self.snapshots.changes.read().await;
+        let _lock = self.snapshots.locks.get(ino).write().await;
+        self.snapshots
+            .preserve(ino, offset, offset + buf.len() as u64)
+            .await?;
+        self.inner().write(ino, offset, buf, handle).await
+    }
+
+    async fn flush(&self, handle: u64) -> FsResult<()> {
+        self.inner().flush(handle).await
+    }
+
+    async fn copy_file_range(
+        &self,
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        size: usize,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> FsResult<usize> {
+        let _changes = self.snapshots.changes.read().await;
+        let _lock = self.snapshots.locks.get(dest_ino).write().await;
+        self.snapshots
+            .preserve(dest_ino, dest_offset, dest_offset + size as u64)
+            .await?;
+        self.inner()
+            .copy_file_range(
+                src_ino,
+                src_offset,
+                dest_ino,
+                dest_offset,
+                size,
+                src_fh,
+                dest_fh,
+            )
+            .await
+    }
+
+    async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        self.inner().open(ino, read, write).await
+    }
+
+    async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        let _changes = self.snapshots.changes.read().await;
+        let _lock = self.snapshots.locks.get(ino).write().await;
+        self.snapshots.preserve(ino, size, u64::MAX).await?;
+        self.inner().set_len(ino, size).await
+    }
+
+    async fn fallocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: FallocateMode,
+        handle: u64,
+    ) -> FsResult<()> {
+        let _changes = self.snapshots.changes.read().await;
+        let _lock = self.snapshots.locks.get(ino).write().await;
+        if !matches!(mode, FallocateMode::Allocate { .. }) {
+            self.snapshots.preserve(ino, offset, offset + len).await?;
+        }
+        self.inner().fallocate(ino, offset, len, mode, handle).await
+    }
+
+    async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> FsResult<()> {
+        if is_reserved(parent, name) {
+            return Err(FsError::NotFound("name not found"));
+        }
+        if is_reserved(new_parent, new_name) {
+            return Err(FsError::InvalidInput("name is reserved for snapshots"));
+        }
+        let _changes = self.snapshots.changes.read().await;
+        let mut _lock = None;
+        if let Some(attr) = self.inner().find_by_name(new_parent, new_name).await? {
+            // it's replaced, unless it's the same entry
+            let same = self
+                .inner()
+                .find_by_name(parent, name)
+                .await?
+                .is_some_and(|src| src.ino == attr.ino);
+            if attr.kind != FileType::Directory && !same {
+                _lock = Some(self.snapshots.locks.get(attr.ino).write().await);
+                self.snapshots.detach_last_link(attr.ino).await?;
+            }
+        }
+        self.inner()
+            .rename(parent, name, new_parent, new_name)
+            .await
+    }
+
+    async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        self.inner().get_xattr(ino, name).await
+    }
+
+    async fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        mode: SetXattrMode,
+    ) -> FsResult<()> {
+        let _changes = self.snapshots.changes.read().await;
+        self.inner().set_xattr(ino, name, value, mode).await
+    }
+
+    async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        self.inner().list_xattr(ino).await
+    }
+
+    async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        let _changes = self.snapshots.changes.read().await;
+        self.inner().remove_xattr(ino, name).await
+    }
+
+    async fn seek(&self, ino: u64, offset: u64, whence: Whence) -> FsResult<Option<u64>> {
+        self.inner().seek(ino, offset, whence).await
+    }
+
+    async fn statfs(&self) -> FsResult<StatFs> {
+        self.inner().statfs().await
+    }
+
+    fn max_name_len(&self) -> usize {
+        self.inner().max_name_len()
+    }
+
+    fn referenced(&self, ino: u64) -> u64 {
+        self.inner().referenced(ino)
+    }
+
+    async fn forgotten(&self, ino: u64) {
+        self.inner().forgotten(ino).await;
+    }
+
+    fn opened_dir(&self, ino: u64) {
+        self.inner().opened_dir(ino);
+    }
+
+    fn released_dir(&self, ino: u64) {
+        self.inner().released_dir(ino);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryFilesystem;
+    use crate::fs::testing::{create, noise, read_all};
+
+    #[allow(clippy::cast_possible_truncation)]
+    const BLOCK: usize = COW_BLOCK_SIZE as usize;
+
+    async fn overwrite(fs: &dyn Filesystem, ino: u64, offset: u64) -> FsResult<usize> {
+        let fh = fs.open(ino, false, true).await?;
+        let res = fs.write(ino, offset, &[1; 10], fh).await;
+        fs.release(fh).await?;
+        res
+    }
+
+    #[tokio::test]
+    async fn keeps_changed_blocks() {
+        let snapshots = Snapshots::new(MemoryFilesystem::new(), None).await.unwrap();
+        let live = SnapshotFilesystem::new(snapshots.clone());
+        let data = noise(3 * BLOCK);
+        let ino = create(&*live, "file", &data).await;
+        let root = snapshots.take("before").await.unwrap();
+        let copy = snapshots
+            .find_by_name(root.ino, "file")
+            .await
+            .unwrap()
+            .unwrap();
+
+        // only the changed block is copied
+        overwrite(&*live, ino, COW_BLOCK_SIZE).await.unwrap();
+        assert_eq!(snapshots.usage.kept.load(Ordering::SeqCst), COW_BLOCK_SIZE);
+        assert_eq!(read_all(&*snapshots, copy.ino).await, data);
+        assert_ne!(read_all(&*live, ino).await, data);
+
+        // the rest when the file is removed
+        live.remove_file(ROOT_INODE, "file").await.unwrap();
+        assert_eq!(read_all(&*snapshots, copy.ino).await, data);
+        assert_eq!(
+            snapshots.usage.kept.load(Ordering::SeqCst),
+            3 * COW_BLOCK_SIZE
+        );
+
+        snapshots.delete("before").unwrap();
+        assert_eq!(snapshots.usage.kept.load(Ordering::SeqCst), 0);
+        assert!(snapshots
+            .find_by_name(SNAPSHOTS_INODE, "before")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn kept_blocks_are_limited() {
+        let mut snapshots = Snapshots::new(MemoryFilesystem::new(), None).await.unwrap();
+        Arc::get_mut(&mut Arc::get_mut(&mut snapshots).unwrap().usage)
+            .unwrap()
+            .max = COW_BLOCK_SIZE;
+        let live = SnapshotFilesystem::new(snapshots.clone());
+        let data = noise(2 * BLOCK);
+        let ino = create(&*live, "file", &data).await;
+        snapshots.take("before").await.unwrap();
+
+        overwrite(&*live, ino, 0).await.unwrap();
+        assert!(matches!(
+            overwrite(&*live, ino, COW_BLOCK_SIZE).await,
+            Err(FsError::NoSpace)
+        ));
+        assert_eq!(read_all(&*live, ino).await[BLOCK..], data[BLOCK..]);
+
+        // deleting the snapshot gives the space back
+        snapshots.delete("before").unwrap();
+        overwrite(&*live, ino, COW_BLOCK_SIZE).await.unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tar::EntryType;
+use tracing::{debug, info, warn};
+
+use crate::fs::archive::inflate::{self, GzIndex, GzIndexer, InflateStream};
+use crate::fs::archive::{Archive, ArchiveFilesystem, ArchiveTree, BLOCK_SIZE};
+use crate::fs_model::{CreateFileAttr, FileAttr, FileType, FsResult};
+
+/// Where the content of a file is in the uncompressed tar stream.
+pub(crate) struct TarEntry {
+    offset: u64,
+}
+
+pub(crate) struct TarArchive {
+    file: Arc<File>,
+    /// Set for `.tar.gz`, offsets are in the uncompressed data.
+    gzip: Option<GzIndex>,
+}
+
+impl Archive for TarArchive {
+    type Entry = TarEntry;
+    /// Where the previous read stopped, for `.tar.gz`.
+    type Reader = Option<InflateStream>;
+
+    fn reader(&self, _entry: &Self::Entry) -> FsResult<Self::Reader> {
+        Ok(None)
+    }
+
+    fn read(
+        &self,
+        entry: &Self::Entry,
+        reader: &mut Self::Reader,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> FsResult<usize> {
+        let offset = entry.offset + offset;
+        let Some(gzip) = &self.gzip else {
+            let mut len = 0;
+            while len < buf.len() {
+                let read = self.file.read_at(&mut buf[len..], offset + len as u64)?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            return Ok(len);
+        };
+        let mut stream = gzip.seek(reader.take(), offset)?;
+        let len = stream.read(buf)?;
+        debug!(
+            position = stream.position(),
+            "keeping gzip stream for next read"
+        );
+        *reader = Some(stream);
+        Ok(len)
+    }
+}
+
+/// Read-only view of a `.tar` or `.tar.gz` archive. The archive is indexed once, when we create
+/// it, after that we read directly from the offsets of the files.
+pub(crate) type TarFilesystem = ArchiveFilesystem<TarArchive>;
+
+impl TarFilesystem {
+    pub fn new(path: &Path) -> FsResult<Arc<Self>> {
+        let file = Arc::new(File::open(path)?);
+        let mut tree = ArchiveTree::new(&file.metadata()?);
+        let gzip = if inflate::is_gzip(&file)? {
+            info!("indexing gzip compressed tar archive");
+            let mut indexer = GzIndexer::new(file.clone())?;
+            index(&mut indexer, &mut tree)?;
+            Some(indexer.finish()?)
+        } else {
+            info!("indexing tar archive");
+            index(BufReader::new(&*file), &mut tree)?;
+            None
+        };
+        Ok(Self::from_archive(TarArchive { file, gzip }, tree))
+    }
+}
+
+fn index<R: Read>(reader: R, tree: &mut ArchiveTree<TarEntry>) -> FsResult<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let path = entry.path()?;
+        let kind = match header.entry_type() {
+            EntryType::Directory => FileType::Directory,
+            EntryType::Regular | EntryType::Continuous => FileType::RegularFile,
+            EntryType::Symlink => FileType::Symlink,
+            EntryType::Fifo => FileType::NamedPipe,
+            EntryType::Char => FileType::CharDevice,
+            EntryType::Block => FileType::BlockDevice,
+            EntryType::Link => {
+                let Some(target) = entry.link_name()? else {
+                    warn!(path = %path.display(), "hard link without target, skipping");
+                    continue;
+                };
+                tree.insert_link(&path, &target)?;
+                continue;
+            }
+            other => {
+                warn!(path = %path.display(), kind = ?other, "unsupported entry type, skipping");
+                continue;
+            }
+        };
+        // the device numbers are not filled in for other kinds of entries
+        let rdev = match kind {
+            FileType::CharDevice | FileType::BlockDevice => {
+                let major = header.device_major()?.unwrap_or_default();
+                let minor = header.device_minor()?.unwrap_or_default();
+                #[allow(clippy::cast_possible_truncation)]
+                {
+                    libc::makedev(major, minor) as u32
+                }
+            }
+            _ => 0,
+        };
+        let mut attr: FileAttr = CreateFileAttr {
+            kind,
+            #[allow(clippy::cast_possible_truncation)]
+            perm: (header.mode()? & 0o7777) as u16,
+            #[allow(clippy::cast_possible_truncation)]
+            uid: header.uid()? as u32,
+            #[allow(clippy::cast_possible_truncation)]
+            gid: header.gid()? as u32,
+            rdev,
+            flags: 0,
+        }
+        .into();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?);
+        attr.atime = mtime;
+        attr.mtime = mtime;
+        attr.ctime = mtime;
+        attr.crtime = mtime;
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            attr.blksize = BLOCK_SIZE as u32;
+        }
+        if kind == FileType::Symlink {
+            let Some(target) = entry.link_name()? else {
+                warn!(path = %path.display(), "symbolic link without target, skipping");
+                continue;
+            };
+            let Some(target) = target.to_str().map(str::to_string) else {
+                warn!(path = %path.display(), "target is not valid UTF-8, skipping");
+                continue;
+            };
+            tree.insert_symlink(&path, attr, target)?;
+            continue;
+        }
+        let entry_data = if kind == FileType::RegularFile {
+            let size = entry.size();
+            attr.size = size;
+            attr.blocks = size.div_ceil(512);
+            Some(TarEntry {
+                offset: entry.raw_file_position(),
+            })
+        } else {
+            None
+        };
+        tree.insert(&path, attr, entry_data)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tar::{Builder, Header};
+
+    use super::*;
+    use crate::fs::testing::{file_attr, gzip, names, noise, read_all, TempDir};
+    use crate::fs::{Filesystem, ROOT_INODE};
+    use crate::fs_model::{FallocateMode, FsError, SetFileAttr, SetXattrMode};
+
+    fn header(kind: EntryType, mode: u32, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_mtime(1_000_000);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        header
+    }
+
+    /// A directory with a file, a symbolic link to it and a hard link to it.
+    fn archive(data: &[u8]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        let mut dir = header(EntryType::Directory, 0o750, 0);
+        builder.append_data(&mut dir, "dir", &[][..]).unwrap();
+        let mut file = header(EntryType::Regular, 0o640, data.len() as u64);
+        builder.append_data(&mut file, "dir/file", data).unwrap();
+        let mut symlink = header(EntryType::Symlink, 0o777, 0);
+        builder
+            .append_link(&mut symlink, "symlink", "dir/file")
+            .unwrap();
+        let mut link = header(EntryType::Link, 0o640, 0);
+        builder.append_link(&mut link, "link", "dir/file").unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    async fn check(fs: Arc<TarFilesystem>, data: &[u8]) {
+        assert_eq!(
+            names(fs.clone(), ROOT_INODE).await,
+            ["dir", "link", "symlink"]
+        );
+        let dir = fs.find_by_name(ROOT_INODE, "dir").await.unwrap().unwrap();
+        assert_eq!(dir.perm, 0o750);
+        let file = fs.find_by_name(dir.ino, "file").await.unwrap().unwrap();
+        assert_eq!((file.perm, file.uid, file.gid), (0o640, 1000, 1000));
+        assert_eq!(file.size, data.len() as u64);
+        assert_eq!(
+            file.mtime,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)
+        );
+        assert_eq!(read_all(&*fs, file.ino).await, data);
+
+        // from an offset, and again before it
+        let fh = fs.open(file.ino, true, false).await.unwrap();
+        let mut buf = vec![0; 1000];
+        for offset in [300_000, 12_345, 0] {
+            assert_eq!(fs.read(file.ino, offset, &mut buf, fh).await.unwrap(), 1000);
+            assert_eq!(buf, data[offset as usize..offset as usize + 1000]);
+        }
+        fs.release(fh).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_tar() {
+        let dir = TempDir::new();
+        let path = dir.path().join("archive.tar");
+        let data = noise(500_000);
+        fs::write(&path, archive(&data)).unwrap();
+
+        check(TarFilesystem::new(&path).unwrap(), &data).await;
+    }
+
+    #[tokio::test]
+    async fn reads_tar_gz() {
+        let dir = TempDir::new();
+        let path = dir.path().join("archive.tar.gz");
+        let data = noise(500_000);
+        fs::write(&path, gzip(&archive(&data))).unwrap();
+
+        check(TarFilesystem::new(&path).unwrap(), &data).await;
+    }
+
+    #[tokio::test]
+    async fn links() {
+        let dir = TempDir::new();
+        let path = dir.path().join("archive.tar");
+        fs::write(&path, archive(b"data")).unwrap();
+        let fs = TarFilesystem::new(&path).unwrap();
+
+        let symlink = fs
+            .find_by_name(ROOT_INODE, "symlink")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(symlink.kind, FileType::Symlink);
+        assert_eq!(symlink.size, "dir/file".len() as u64);
+        assert_eq!(fs.read_link(symlink.ino).await.unwrap(), "dir/file");
+
+        let link = fs.find_by_name(ROOT_INODE, "link").await.unwrap().unwrap();
+        let dir = fs.find_by_name(ROOT_INODE, "dir").await.unwrap().unwrap();
+        let file = fs.find_by_name(dir.ino, "file").await.unwrap().unwrap();
+        assert_eq!(link.ino, file.ino);
+        assert_eq!(link.nlink, 2);
+        assert_eq!(read_all(&*fs, link.ino).await, b"data");
+    }
+
+    #[tokio::test]
+    async fn read_only() {
+        let dir = TempDir::new();
+        let path = dir.path().join("archive.tar");
+        fs::write(&path, archive(b"data")).unwrap();
+        let fs = TarFilesystem::new(&path).unwrap();
+        let dir = fs.find_by_name(ROOT_INODE, "dir").await.unwrap().unwrap();
+        let file = fs.find_by_name(dir.ino, "file").await.unwrap().unwrap();
+        let fh = fs.open(file.ino, true, false).await.unwrap();
+
+        let results = [
+            fs.create(ROOT_INODE, "new", file_attr(0), true, true)
+                .await
+                .map(|_| ()),
+            fs.symlink(ROOT_INODE, "new", "dir", file_attr(0))
+                .await
+                .map(|_| ()),
+            fs.link(file.ino, ROOT_INODE, "new").await.map(|_| ()),
+            fs.remove_dir(ROOT_INODE, "dir").await,
+            fs.remove_file(dir.ino, "file").await,
+            fs.set_attr(file.ino, SetFileAttr::default().with_perm(0o600))
+                .await,
+            fs.write(file.ino, 0, b"new", fh).await.map(|_| ()),
+            fs.copy_file_range(file.ino, 0, file.ino, 2, 2, fh, fh)
+                .await
+                .map(|_| ()),
+            fs.open(file.ino, false, true).await.map(|_| ()),
+            fs.set_len(file.ino, 0).await,
+            fs.fallocate(file.ino, 0, 10, FallocateMode::PunchHole, fh)
+                .await,
+            fs.rename(ROOT_INODE, "dir", ROOT_INODE, "new").await,
+            fs.set_xattr(file.ino, "user.a", b"a", SetXattrMode::Any)
+                .await,
+            fs.remove_xattr(file.ino, "user.a").await,
+        ];
+        for res in results {
+            assert!(matches!(res, Err(FsError::ReadOnly)), "{res:?}");
+        }
+        assert_eq!(read_all(&*fs, file.ino).await, b"data");
+    }
+}
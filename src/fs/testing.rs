@@ -0,0 +1,121 @@
+//! Helpers for the tests of the filesystems.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use futures_util::TryStreamExt;
+
+use crate::fs::{Filesystem, ROOT_INODE};
+use crate::fs_model::{CreateFileAttr, FileType};
+
+/// A regular file owned by `uid`, with the group of the same number.
+pub(crate) const fn file_attr(uid: u32) -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        uid,
+        gid: uid,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+/// A directory owned by `uid`, with the group of the same number.
+pub(crate) const fn dir_attr(uid: u32) -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::Directory,
+        perm: 0o755,
+        ..file_attr(uid)
+    }
+}
+
+/// Create a file in the root with `data`, written in pieces smaller than the blocks and chunks
+/// of the layers, so their boundaries don't depend on the writes.
+pub(crate) async fn create(fs: &dyn Filesystem, name: &str, data: &[u8]) -> u64 {
+    let (fh, attr) = fs
+        .create(ROOT_INODE, name, file_attr(0), false, true)
+        .await
+        .unwrap();
+    for (i, piece) in data.chunks(10_000).enumerate() {
+        fs.write(attr.ino, i as u64 * 10_000, piece, fh)
+            .await
+            .unwrap();
+    }
+    fs.release(fh).await.unwrap();
+    attr.ino
+}
+
+pub(crate) async fn read_all(fs: &dyn Filesystem, ino: u64) -> Vec<u8> {
+    let size = fs.get_attr(ino).await.unwrap().size;
+    let fh = fs.open(ino, true, false).await.unwrap();
+    let mut buf = vec![0; usize::try_from(size).unwrap()];
+    assert_eq!(fs.read(ino, 0, &mut buf, fh).await.unwrap(), buf.len());
+    fs.release(fh).await.unwrap();
+    buf
+}
+
+/// Bytes which don't repeat and don't compress, the same ones each time.
+pub(crate) fn noise(len: usize) -> Vec<u8> {
+    let mut x = 0x9e37_79b9_7f4a_7c15_u64;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x.to_le_bytes()[0]
+        })
+        .collect()
+}
+
+/// `data` as a gzip file. The trailer is not checked when we read, so its CRC is left 0.
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    gzip.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+    gzip.extend_from_slice(&[0; 4]);
+    gzip.extend_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+    gzip
+}
+
+/// The names in a directory, without `.` and `..`.
+pub(crate) async fn names(fs: Arc<dyn Filesystem>, ino: u64) -> Vec<String> {
+    let mut names: Vec<String> = fs
+        .read_dir(ino, 0)
+        .await
+        .unwrap()
+        .map_ok(|entry| entry.name)
+        .try_collect()
+        .await
+        .unwrap();
+    names.retain(|name| name != "." && name != "..");
+    names.sort();
+    names
+}
+
+/// A directory on the host which is removed when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "fuse3-template-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
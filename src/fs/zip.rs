@@ -0,0 +1,499 @@
+use std::cmp::min;
+use std::fs::File;
+use std::io::BufReader;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+use zip::extra_fields::ExtraField;
+use zip::CompressionMethod;
+
+use crate::fs::archive::inflate::InflateStream;
+use crate::fs::archive::{Archive, ArchiveFilesystem, ArchiveTree, BLOCK_SIZE};
+use crate::fs_model::{CreateFileAttr, FileAttr, FileType, FsError, FsResult};
+
+/// How much of the decompressed data we keep for each handle, so reads which are a bit out of
+/// order, like the ones from the kernel read-ahead, don't need to decompress from the start.
+const CACHE_SIZE: usize = 128 * 1024;
+const LOCAL_HEADER_LEN: u64 = 30;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+
+enum Method {
+    Stored,
+    Deflated,
+}
+
+/// Where the content of a file is in the zip.
+pub(crate) struct ZipEntry {
+    data_start: u64,
+    size: u64,
+    compressed_size: u64,
+    method: Method,
+}
+
+/// For deflate entries, the stream positioned where the previous read stopped and the last bytes
+/// we read.
+pub(crate) struct ZipReader {
+    stream: Option<InflateStream>,
+    cache: Vec<u8>,
+    cache_offset: u64,
+}
+
+impl ZipReader {
+    fn read_cached(&self, offset: u64, buf: &mut [u8]) -> bool {
+        let end = self.cache_offset + self.cache.len() as u64;
+        if offset < self.cache_offset || offset + buf.len() as u64 > end {
+            return false;
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let start = (offset - self.cache_offset) as usize;
+        buf.copy_from_slice(&self.cache[start..start + buf.len()]);
+        true
+    }
+
+    fn add_to_cache(&mut self, offset: u64, data: &[u8]) {
+        if offset != self.cache_offset + self.cache.len() as u64 {
+            self.cache.clear();
+            self.cache_offset = offset;
+        }
+        self.cache.extend_from_slice(data);
+        if self.cache.len() > CACHE_SIZE {
+            let drop = self.cache.len() - CACHE_SIZE;
+            self.cache.drain(..drop);
+            self.cache_offset += drop as u64;
+        }
+    }
+}
+
+pub(crate) struct ZipArchive {
+    file: Arc<File>,
+}
+
+impl Archive for ZipArchive {
+    type Entry = ZipEntry;
+    type Reader = ZipReader;
+
+    fn reader(&self, _entry: &Self::Entry) -> FsResult<Self::Reader> {
+        Ok(ZipReader {
+            stream: None,
+            cache: vec![],
+            cache_offset: 0,
+        })
+    }
+
+    fn read(
+        &self,
+        entry: &Self::Entry,
+        reader: &mut Self::Reader,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> FsResult<usize> {
+        match entry.method {
+            Method::Stored => {
+                // the central directory could claim more than is stored
+                let stored = min(entry.size, entry.compressed_size);
+                let len = min(buf.len() as u64, stored.saturating_sub(offset));
+                #[allow(clippy::cast_possible_truncation)]
+                let buf = &mut buf[..len as usize];
+                self.file.read_exact_at(buf, entry.data_start + offset)?;
+                Ok(buf.len())
+            }
+            Method::Deflated => {
+                if reader.read_cached(offset, buf) {
+                    return Ok(buf.len());
+                }
+                let mut stream = match reader.stream.take() {
+                    Some(stream) if stream.position() <= offset => stream,
+                    _ => InflateStream::raw(
+                        self.file.clone(),
+                        entry.data_start,
+                        entry.compressed_size,
+                    ),
+                };
+                stream.skip(offset - stream.position())?;
+                let len = stream.read(buf)?;
+                reader.add_to_cache(offset, &buf[..len]);
+                reader.stream = Some(stream);
+                Ok(len)
+            }
+        }
+    }
+}
+
+/// Read-only view of a zip archive, indexed from its central directory when we create it.
+pub(crate) type ZipFilesystem = ArchiveFilesystem<ZipArchive>;
+
+impl ZipFilesystem {
+    pub fn new(path: &Path) -> FsResult<Arc<Self>> {
+        let file = Arc::new(File::open(path)?);
+        let metadata = file.metadata()?;
+        let mut tree = ArchiveTree::new(&metadata);
+
+        info!("indexing zip archive");
+        let mut archive = zip::ZipArchive::new(BufReader::new(&*file)).map_err(map_zip_err)?;
+        for i in 0..archive.len() {
+            let entry = archive.by_index_raw(i).map_err(map_zip_err)?;
+            let Some(path) = entry.enclosed_name() else {
+                warn!(
+                    name = entry.name(),
+                    "skipping entry outside of archive root"
+                );
+                continue;
+            };
+            let kind = if entry.is_dir() {
+                FileType::Directory
+            } else if entry.is_symlink() {
+                FileType::Symlink
+            } else if entry.is_file() {
+                FileType::RegularFile
+            } else {
+                warn!(path = %path.display(), "unsupported entry type, skipping");
+                continue;
+            };
+            let mut attr: FileAttr = CreateFileAttr {
+                kind,
+                #[allow(clippy::cast_possible_truncation)]
+                perm: entry.unix_mode().map_or_else(
+                    || {
+                        if kind == FileType::Directory {
+                            0o755
+                        } else {
+                            0o644
+                        }
+                    },
+                    |mode| (mode & 0o7777) as u16,
+                ),
+                uid: std::os::unix::fs::MetadataExt::uid(&metadata),
+                gid: std::os::unix::fs::MetadataExt::gid(&metadata),
+                rdev: 0,
+                flags: 0,
+            }
+            .into();
+            let mtime = entry
+                .extra_data_fields()
+                .find_map(|field| match field {
+                    ExtraField::ExtendedTimestamp(ts) => ts.mod_time(),
+                    ExtraField::Ntfs(_) => None,
+                })
+                .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from(secs)))
+                .or_else(|| entry.last_modified().map(dos_time))
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            attr.atime = mtime;
+            attr.mtime = mtime;
+            attr.ctime = mtime;
+            attr.crtime = mtime;
+            #[allow(clippy::cast_possible_truncation)]
+            {
+                attr.blksize = BLOCK_SIZE as u32;
+            }
+
+            let entry_data = if kind == FileType::Directory {
+                None
+            } else {
+                if entry.encrypted() {
+                    warn!(path = %path.display(), "encrypted entries are not supported, skipping");
+                    continue;
+                }
+                let method = match entry.compression() {
+                    CompressionMethod::Stored => Method::Stored,
+                    method if method == CompressionMethod::DEFLATE => Method::Deflated,
+                    other => {
+                        warn!(path = %path.display(), method = %other, "unsupported compression, skipping");
+                        continue;
+                    }
+                };
+                attr.size = entry.size();
+                attr.blocks = entry.compressed_size().div_ceil(512);
+                let data_start = match entry.data_start() {
+                    Some(data_start) => data_start,
+                    None => data_start(&file, entry.header_start())?,
+                };
+                Some(ZipEntry {
+                    data_start,
+                    size: entry.size(),
+                    compressed_size: entry.compressed_size(),
+                    method,
+                })
+            };
+            if let (FileType::Symlink, Some(entry_data)) = (kind, &entry_data) {
+                // the target is the content of the entry
+                if attr.size > libc::PATH_MAX as u64 {
+                    warn!(path = %path.display(), size = attr.size, "target is too long, skipping");
+                    continue;
+                }
+                let Some(target) = read_target(&file, entry_data, attr.size)? else {
+                    warn!(path = %path.display(), "target is not valid UTF-8, skipping");
+                    continue;
+                };
+                tree.insert_symlink(&path, attr, target)?;
+                continue;
+            }
+            tree.insert(&path, attr, entry_data)?;
+        }
+        drop(archive);
+
+        Ok(Self::from_archive(ZipArchive { file }, tree))
+    }
+}
+
+fn read_target(file: &Arc<File>, entry: &ZipEntry, size: u64) -> FsResult<Option<String>> {
+    let archive = ZipArchive { file: file.clone() };
+    let mut reader = archive.reader(entry)?;
+    #[allow(clippy::cast_possible_truncation)]
+    let mut buf = vec![0; size as usize];
+    let mut len = 0;
+    while len < buf.len() {
+        let read = archive.read(entry, &mut reader, len as u64, &mut buf[len..])?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+    buf.truncate(len);
+    Ok(String::from_utf8(buf).ok())
+}
+
+/// Where the data starts, after the local header of the entry.
+fn data_start(file: &File, header_start: u64) -> FsResult<u64> {
+    let mut header = [0; LOCAL_HEADER_LEN as usize];
+    file.read_exact_at(&mut header, header_start)?;
+    if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != LOCAL_HEADER_SIGNATURE {
+        return Err(FsError::Other("invalid zip local header"));
+    }
+    let name_len = u16::from_le_bytes([header[26], header[27]]);
+    let extra_len = u16::from_le_bytes([header[28], header[29]]);
+    Ok(header_start + LOCAL_HEADER_LEN + u64::from(name_len) + u64::from(extra_len))
+}
+
+/// Zip stores the time in local time without a timezone, we take it as UTC.
+fn dos_time(time: zip::DateTime) -> SystemTime {
+    // days from 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let (month, day) = (i64::from(time.month()), i64::from(time.day()));
+    let year = i64::from(time.year()) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).unwrap_or(0))
+}
+
+fn map_zip_err(err: zip::result::ZipError) -> FsError {
+    match err {
+        zip::result::ZipError::Io(err) => err.into(),
+        err => {
+            warn!(err = %err, "invalid zip archive");
+            FsError::Other("invalid zip archive")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::fs::testing::{names, noise, read_all, TempDir};
+    use crate::fs::{Filesystem, ROOT_INODE};
+
+    const MTIME: u32 = 1_000_000;
+
+    struct Entry<'a> {
+        name: &'a str,
+        data: &'a [u8],
+        deflate: bool,
+        mode: u32,
+    }
+
+    /// A zip with the entries, made by unix so the modes are kept, and with an extended timestamp
+    /// of [`MTIME`]. The CRCs are not checked when we read, so they are left 0.
+    fn zip(entries: &[Entry]) -> Vec<u8> {
+        // the extended timestamp with only the modification time
+        let mut extra = vec![0x55, 0x54, 5, 0, 1];
+        extra.extend_from_slice(&MTIME.to_le_bytes());
+        let mut zip = vec![];
+        let mut central = vec![];
+        for entry in entries {
+            let (method, data) = if entry.deflate {
+                (8u16, miniz_oxide::deflate::compress_to_vec(entry.data, 6))
+            } else {
+                (0, entry.data.to_vec())
+            };
+            let mut common = vec![];
+            common.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            common.extend_from_slice(&0u16.to_le_bytes()); // flags
+            common.extend_from_slice(&method.to_le_bytes());
+            common.extend_from_slice(&0u16.to_le_bytes()); // time
+            common.extend_from_slice(&0x1421u16.to_le_bytes()); // date, 1990-01-01
+            common.extend_from_slice(&0u32.to_le_bytes()); // crc
+            common.extend_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+            common.extend_from_slice(&u32::try_from(entry.data.len()).unwrap().to_le_bytes());
+            common.extend_from_slice(&u16::try_from(entry.name.len()).unwrap().to_le_bytes());
+            common.extend_from_slice(&u16::try_from(extra.len()).unwrap().to_le_bytes());
+
+            central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            central.extend_from_slice(&(3u16 << 8 | 20).to_le_bytes()); // made by unix
+            central.extend_from_slice(&common);
+            central.extend_from_slice(&[0; 6]); // comment, disk and internal attributes
+            central.extend_from_slice(&(entry.mode << 16).to_le_bytes());
+            central.extend_from_slice(&u32::try_from(zip.len()).unwrap().to_le_bytes());
+            central.extend_from_slice(entry.name.as_bytes());
+            central.extend_from_slice(&extra);
+
+            zip.extend_from_slice(&LOCAL_HEADER_SIGNATURE.to_le_bytes());
+            zip.extend_from_slice(&common);
+            zip.extend_from_slice(entry.name.as_bytes());
+            zip.extend_from_slice(&extra);
+            zip.extend_from_slice(&data);
+        }
+        let count = u16::try_from(entries.len()).unwrap().to_le_bytes();
+        let central_start = u32::try_from(zip.len()).unwrap();
+        zip.extend_from_slice(&central);
+        zip.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&[0; 4]); // disks
+        zip.extend_from_slice(&count);
+        zip.extend_from_slice(&count);
+        zip.extend_from_slice(&u32::try_from(central.len()).unwrap().to_le_bytes());
+        zip.extend_from_slice(&central_start.to_le_bytes());
+        zip.extend_from_slice(&[0; 2]); // comment
+        zip
+    }
+
+    #[tokio::test]
+    async fn reads_stored_and_deflated() {
+        let dir = TempDir::new();
+        let path = dir.path().join("archive.zip");
+        let data = noise(500_000);
+        let entries = [
+            Entry {
+                name: "dir/",
+                data: b"",
+                deflate: false,
+                mode: 0o040_750,
+            },
+            Entry {
+                name: "dir/stored",
+                data: b"stored data",
+                deflate: false,
+                mode: 0o100_640,
+            },
+            Entry {
+                name: "deflated",
+                data: &data,
+                deflate: true,
+                mode: 0o100_600,
+            },
+            Entry {
+                name: "symlink",
+                data: b"dir/stored",
+                deflate: false,
+                mode: 0o120_777,
+            },
+        ];
+        fs::write(&path, zip(&entries)).unwrap();
+        let fs = ZipFilesystem::new(&path).unwrap();
+
+        assert_eq!(
+            names(fs.clone(), ROOT_INODE).await,
+            ["deflated", "dir", "symlink"]
+        );
+        let dir = fs.find_by_name(ROOT_INODE, "dir").await.unwrap().unwrap();
+        assert_eq!((dir.kind, dir.perm), (FileType::Directory, 0o750));
+        let stored = fs.find_by_name(dir.ino, "stored").await.unwrap().unwrap();
+        assert_eq!((stored.perm, stored.size), (0o640, 11));
+        assert_eq!(read_all(&*fs, stored.ino).await, b"stored data");
+        let deflated = fs
+            .find_by_name(ROOT_INODE, "deflated")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((deflated.perm, deflated.size), (0o600, 500_000));
+        assert_eq!(read_all(&*fs, deflated.ino).await, data);
+        let symlink = fs
+            .find_by_name(ROOT_INODE, "symlink")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(symlink.kind, FileType::Symlink);
+        assert_eq!(fs.read_link(symlink.ino).await.unwrap(), "dir/stored");
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from(MTIME));
+        for attr in [dir, stored, deflated] {
+            assert_eq!(attr.mtime, mtime);
+        }
+    }
+
+    #[test]
+    fn reads_out_of_order_from_cache() {
+        let dir = TempDir::new();
+        let path = dir.path().join("archive.zip");
+        let data = noise(500_000);
+        let entry = Entry {
+            name: "file",
+            data: &data,
+            deflate: true,
+            mode: 0o100_644,
+        };
+        fs::write(&path, zip(&[entry])).unwrap();
+        let file = Arc::new(File::open(&path).unwrap());
+        let compressed_size = miniz_oxide::deflate::compress_to_vec(&data, 6).len() as u64;
+        let entry = ZipEntry {
+            data_start: data_start(&file, 0).unwrap(),
+            size: data.len() as u64,
+            compressed_size,
+            method: Method::Deflated,
+        };
+        let archive = ZipArchive { file };
+        let mut reader = archive.reader(&entry).unwrap();
+        let mut buf = vec![0; 1000];
+
+        let mut read = |offset: u64, reader: &mut ZipReader| {
+            assert_eq!(
+                archive.read(&entry, reader, offset, &mut buf).unwrap(),
+                1000
+            );
+            #[allow(clippy::cast_possible_truncation)]
+            let offset = offset as usize;
+            assert_eq!(buf, data[offset..offset + 1000]);
+            reader.stream.as_ref().unwrap().position()
+        };
+        assert_eq!(read(200_000, &mut reader), 201_000);
+        assert_eq!(read(201_000, &mut reader), 202_000);
+        // behind the stream but still in the cache, so the stream stays where it is
+        assert_eq!(read(200_000, &mut reader), 202_000);
+        assert_eq!(read(200_500, &mut reader), 202_000);
+        // out of the cache, the stream starts again
+        assert_eq!(read(10_000, &mut reader), 11_000);
+    }
+
+    #[tokio::test]
+    async fn stored_shorter_than_claimed() {
+        let dir = TempDir::new();
+        let path = dir.path().join("archive.zip");
+        let entry = Entry {
+            name: "file",
+            data: b"data",
+            deflate: false,
+            mode: 0o100_644,
+        };
+        let mut zip = zip(&[entry]);
+        // the size in the central directory claims more than is stored
+        let central_start = zip.len() - 22 - 46 - "file".len() - 9;
+        zip[central_start + 24..central_start + 28].copy_from_slice(&1000u32.to_le_bytes());
+        fs::write(&path, zip).unwrap();
+        let fs = ZipFilesystem::new(&path).unwrap();
+
+        let file = fs.find_by_name(ROOT_INODE, "file").await.unwrap().unwrap();
+        assert_eq!(file.size, 1000);
+        let fh = fs.open(file.ino, true, false).await.unwrap();
+        let mut buf = vec![0; 100];
+        assert_eq!(fs.read(file.ino, 2, &mut buf, fh).await.unwrap(), 2);
+        assert_eq!(fs.read(file.ino, 10, &mut buf, fh).await.unwrap(), 0);
+    }
+}
@@ -1,11 +1,10 @@
+use futures_util::stream::BoxStream;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
 use std::io;
 use std::num::ParseIntError;
 use std::time::SystemTime;
 use thiserror::Error;
 use tokio::task::JoinError;
-use tracing::instrument;
 
 /// File attributes.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -43,22 +42,24 @@ pub struct FileAttr {
 }
 
 /// File types.
+///
+/// The index of the variant is stored in the data dir, so new ones are added at the end.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum FileType {
-    // /// Named pipe (S_IFIFO)
-    // NamedPipe,
-    // /// Character device (S_IFCHR)
-    // CharDevice,
-    // /// Block device (S_IFBLK)
-    // BlockDevice,
     /// Directory (`S_IFDIR`)
     Directory,
     /// Regular file (`S_IFREG`)
     RegularFile,
-    // /// Symbolic link (S_IFLNK)
-    // Symlink,
-    // /// Unix domain socket (S_IFSOCK)
-    // Socket,
+    /// Symbolic link (`S_IFLNK`)
+    Symlink,
+    /// Named pipe (`S_IFIFO`)
+    NamedPipe,
+    /// Character device (`S_IFCHR`)
+    CharDevice,
+    /// Block device (`S_IFBLK`)
+    BlockDevice,
+    /// Unix domain socket (`S_IFSOCK`)
+    Socket,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -135,14 +136,16 @@ impl SetFileAttr {
     }
 
     #[must_use]
+    #[allow(dead_code)]
     pub const fn with_rdev(mut self, rdev: u32) -> Self {
         self.rdev = Some(rdev);
         self
     }
 
     #[must_use]
+    #[allow(dead_code)]
     pub const fn with_flags(mut self, flags: u32) -> Self {
-        self.rdev = Some(flags);
+        self.flags = Some(flags);
         self
     }
 }
@@ -240,11 +243,91 @@ impl From<TimeAndSizeFileAttr> for SetFileAttr {
     }
 }
 
+/// What to do when setting an extended attribute, depending on whether it exists already.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SetXattrMode {
+    /// Create it or replace its value.
+    #[default]
+    Any,
+    /// Fail with [`FsError::AlreadyExists`] if it exists (`XATTR_CREATE`).
+    Create,
+    /// Fail with [`FsError::XattrNotFound`] if it doesn't exist (`XATTR_REPLACE`).
+    Replace,
+}
+
+/// What `fallocate` does with a range of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallocateMode {
+    /// Reserve the space, the file grows to cover the range unless `keep_size`
+    /// (`FALLOC_FL_KEEP_SIZE`).
+    Allocate { keep_size: bool },
+    /// Make the range read as zeros and free its space, the size doesn't change
+    /// (`FALLOC_FL_PUNCH_HOLE`).
+    PunchHole,
+    /// Make the range read as zeros, the file grows to cover it unless `keep_size`
+    /// (`FALLOC_FL_ZERO_RANGE`).
+    ZeroRange { keep_size: bool },
+}
+
+impl FallocateMode {
+    /// Size of a file of `size` bytes after the range is applied to it.
+    #[must_use]
+    pub fn new_size(self, size: u64, offset: u64, len: u64) -> u64 {
+        match self {
+            Self::Allocate { keep_size: false } | Self::ZeroRange { keep_size: false } => {
+                size.max(offset + len)
+            }
+            _ => size,
+        }
+    }
+
+    /// The part of the range which has to be zeroed in a file of `size` bytes, what is past the
+    /// end reads as zeros anyway.
+    #[must_use]
+    pub fn zeroed(self, size: u64, offset: u64, len: u64) -> Option<(u64, u64)> {
+        match self {
+            Self::Allocate { .. } => None,
+            Self::PunchHole | Self::ZeroRange { .. } => {
+                let end = size.min(offset + len);
+                (end > offset).then_some((offset, end))
+            }
+        }
+    }
+}
+
+/// What `lseek` looks for in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    /// The next byte which is not in a hole (`SEEK_DATA`).
+    Data,
+    /// The next hole, there is always one at the end of the file (`SEEK_HOLE`).
+    Hole,
+}
+
+/// Usage of a filesystem, the sizes are in blocks of `bsize` bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatFs {
+    /// Block size
+    pub bsize: u32,
+    /// Total blocks
+    pub blocks: u64,
+    /// Free blocks
+    pub bfree: u64,
+    /// Free blocks for users which are not root
+    pub bavail: u64,
+    /// Total inodes
+    pub files: u64,
+    /// Free inodes
+    pub ffree: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct DirectoryEntry {
     pub ino: u64,
     pub name: String,
     pub kind: FileType,
+    /// Where the listing goes on after this entry, see [`crate::fs::Filesystem::read_dir`].
+    pub cookie: u64,
 }
 
 impl PartialEq for DirectoryEntry {
@@ -260,6 +343,7 @@ pub struct DirectoryEntryPlus {
     pub name: String,
     pub kind: FileType,
     pub attr: FileAttr,
+    pub cookie: u64,
 }
 
 impl PartialEq for DirectoryEntryPlus {
@@ -271,26 +355,21 @@ impl PartialEq for DirectoryEntryPlus {
     }
 }
 
-pub struct DirectoryEntryIterator(pub VecDeque<FsResult<DirectoryEntry>>);
-
-impl Iterator for DirectoryEntryIterator {
-    type Item = FsResult<DirectoryEntry>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop_front()
+impl From<DirectoryEntryPlus> for DirectoryEntry {
+    fn from(entry: DirectoryEntryPlus) -> Self {
+        Self {
+            ino: entry.ino,
+            name: entry.name,
+            kind: entry.kind,
+            cookie: entry.cookie,
+        }
     }
 }
 
-pub struct DirectoryEntryPlusIterator(pub VecDeque<FsResult<DirectoryEntryPlus>>);
+/// The entries of a directory, read as they are asked for.
+pub type DirectoryEntryStream = BoxStream<'static, FsResult<DirectoryEntry>>;
 
-impl Iterator for DirectoryEntryPlusIterator {
-    type Item = FsResult<DirectoryEntryPlus>;
-
-    #[instrument(name = "DirectoryEntryPlusIterator::next", skip(self))]
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop_front()
-    }
-}
+pub type DirectoryEntryPlusStream = BoxStream<'static, FsResult<DirectoryEntryPlus>>;
 
 pub type FsResult<T> = Result<T, FsError>;
 
@@ -298,7 +377,6 @@ pub type FsResult<T> = Result<T, FsError>;
 pub enum FsError {
     #[error("IO error: {source}")]
     Io {
-        #[from]
         source: io::Error,
         // backtrace: Backtrace,
     },
@@ -322,6 +400,12 @@ pub enum FsError {
     #[error("invalid node type")]
     InvalidInodeType,
 
+    #[error("directories cannot be linked")]
+    DirectoryLink,
+
+    #[error("not a regular file")]
+    NotARegularFile,
+
     #[error("invalid file handle")]
     InvalidFileHandle,
 
@@ -340,6 +424,12 @@ pub enum FsError {
     #[error("invalid password")]
     InvalidPassword,
 
+    #[error("encryption error: {0}")]
+    Encryption(&'static str),
+
+    #[error("compression error: {0}")]
+    Compression(&'static str),
+
     #[error("invalid structure of data directory")]
     InvalidDataDirStructure,
 
@@ -359,4 +449,51 @@ pub enum FsError {
 
     #[error("max filesize exceeded, max allowed {0}")]
     MaxFilesizeExceeded(usize),
+
+    #[error("read-only filesystem")]
+    ReadOnly,
+
+    #[error("cross-device link")]
+    CrossDevice,
+
+    #[error("extended attribute not found")]
+    XattrNotFound,
+
+    #[error("no space left")]
+    NoSpace,
+
+    #[error("quota exceeded")]
+    QuotaExceeded,
+
+    #[error("permission denied")]
+    PermissionDenied,
+
+    #[error("name too long")]
+    NameTooLong,
+
+    #[error("not a directory")]
+    NotADirectory,
+
+    #[error("is a directory")]
+    IsADirectory,
+
+    #[error("lock is held by another owner")]
+    WouldBlock,
+
+    #[error("waiting for the lock would deadlock")]
+    Deadlock,
+
+    #[error("interrupted")]
+    Interrupted,
+}
+
+impl From<io::Error> for FsError {
+    /// The host denying access is a `PermissionDenied` like ours, the other errors are kept.
+    fn from(source: io::Error) -> Self {
+        if source.raw_os_error() == Some(libc::EACCES) {
+            Self::PermissionDenied
+        } else {
+            Self::Io { source }
+        }
+    }
 }
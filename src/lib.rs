@@ -1,7 +1,9 @@
 pub(crate) mod fs_model;
 pub(crate) mod fs;
-pub(crate) mod stream_util;
 pub mod mount;
+pub mod crypto;
+pub mod compression;
+pub mod quota;
 
 #[allow(unreachable_code)]
 #[must_use]
@@ -1,10 +1,14 @@
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
-use std::{io, panic, process};
+use std::time::{Duration, SystemTime};
+use std::{env, io, panic, process};
 
 use clap::{crate_authors, crate_name, crate_version, Arg, ArgAction, ArgMatches, Command};
 use ctrlc::set_handler;
+use rpassword::read_password;
+use secrecy::{ExposeSecret, SecretString};
 use thiserror::Error;
 use tokio::sync::Mutex;
 use tokio::task;
@@ -13,8 +17,13 @@ use tracing::{error, info, warn, Level};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::EnvFilter;
 
-use fuse_template::mount::MountPoint;
-use fuse_template::{is_debug, mount};
+use fuse3_template::compression::Compression;
+use fuse3_template::crypto::{Cipher, PasswordProvider};
+use fuse3_template::mount::{Backend, Capacity, MountPoint, Ttl};
+use fuse3_template::quota::{Owner, QuotaStatus, Quotas};
+use fuse3_template::{is_debug, mount, quota};
+
+const PASSWORD_ENV: &str = "FUSE3_TEMPLATE_PASSWORD";
 
 #[derive(Debug, Error)]
 enum ExitStatusError {
@@ -97,6 +106,7 @@ fn get_cli_args() -> ArgMatches {
         .version(crate_version!())
         .author(crate_authors!())
         .arg_required_else_help(true)
+        .subcommand_negates_reqs(true)
         .arg(
             Arg::new("log-level")
                 .long("log-level")
@@ -113,6 +123,101 @@ fn get_cli_args() -> ArgMatches {
                 .value_name("MOUNT_POINT")
                 .help("Act as a client, and mount FUSE at given path"),
         )
+        .arg(
+            Arg::new("source-dir")
+                .long("source-dir")
+                .short('d')
+                .value_name("SOURCE_DIR")
+                .conflicts_with("data-dir")
+                .help("Mirror this directory from the host. If not specified, it will use an in-memory filesystem"),
+        )
+        .arg(
+            Arg::new("data-dir")
+                .long("data-dir")
+                .value_name("DATA_DIR")
+                .conflicts_with("tar")
+                .help("Where to store the files, so they survive restarts. If not specified, it will use an in-memory filesystem"),
+        )
+        .arg(
+            Arg::new("tar")
+                .long("tar")
+                .value_name("ARCHIVE")
+                .conflicts_with("source-dir")
+                .help("Expose the content of a .tar or .tar.gz archive, read-only"),
+        )
+        .arg(
+            Arg::new("zip")
+                .long("zip")
+                .value_name("ARCHIVE")
+                .conflicts_with_all(["source-dir", "data-dir", "tar"])
+                .help("Expose the content of a .zip archive, read-only"),
+        )
+        .arg(
+            Arg::new("lower")
+                .long("lower")
+                .value_name("LOWER")
+                .action(ArgAction::Append)
+                .conflicts_with_all(["tar", "zip"])
+                .help("Use this directory or archive (.tar, .tar.gz, .tgz, .zip) as a read-only layer under the filesystem, changes are only made to the filesystem on top. Can be given more times, the first one is the topmost"),
+        )
+        .arg(
+            Arg::new("encrypt")
+                .long("encrypt")
+                .short('e')
+                .action(ArgAction::SetTrue)
+                .help(format!("Encrypt the content of the files with a key derived from a password. The password is read from {PASSWORD_ENV} env var or from stdin")),
+        )
+        .arg(
+            Arg::new("cipher")
+                .long("cipher")
+                .short('c')
+                .value_name("cipher")
+                .default_value("ChaCha20Poly1305")
+                .requires("encrypt")
+                .help("Cipher used for encryption, possible values: ChaCha20Poly1305, Aes256Gcm"),
+        )
+        .arg(
+            Arg::new("compression")
+                .long("compression")
+                .value_name("compression")
+                .conflicts_with_all(["tar", "zip"])
+                .help("Compress the content of the files, possible values: zstd, lz4. Blocks which don't compress are stored as they are"),
+        )
+        .arg(
+            Arg::new("dedup")
+                .long("dedup")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["tar", "zip"])
+                .help("Split the content of the files in chunks and store each one once, so identical files and the parts they have in common take space only once"),
+        )
+        .arg(
+            Arg::new("snapshots")
+                .long("snapshots")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["tar", "zip", "data-dir", "source-dir"])
+                .help("Allow taking snapshots. They are under /.snapshots, mkdir there takes one, rmdir deletes it. They are kept in memory until unmount, so they cannot be used with --data-dir. The files must only be changed through the mount, so they cannot be used with --source-dir"),
+        )
+        .arg(
+            Arg::new("max-size")
+                .long("max-size")
+                .value_name("SIZE")
+                .conflicts_with_all(["tar", "zip"])
+                .help("The most the files can take in total, like 512M or 10G (K, M, G and T are powers of 1024). Over it writes fail with no space left, df shows it as the size"),
+        )
+        .arg(
+            Arg::new("max-inodes")
+                .long("max-inodes")
+                .value_name("COUNT")
+                .conflicts_with_all(["tar", "zip"])
+                .help("The most files, directories and other nodes there can be"),
+        )
+        .arg(
+            Arg::new("quota-config")
+                .long("quota-config")
+                .value_name("FILE")
+                .conflicts_with_all(["tar", "zip"])
+                .help("TOML file with the quotas of the users and groups. Over a hard limit writes fail with disk quota exceeded, a soft one can be exceeded for the grace period"),
+        )
         .arg(
             Arg::new("umount-on-start")
                 .long("umount-on-start")
@@ -142,6 +247,24 @@ fn get_cli_args() -> ArgMatches {
                 .requires("mount-point")
                 .help("Use direct I/O (bypass page cache for an open file)"),
         )
+        .arg(
+            Arg::new("attr-ttl")
+                .long("attr-ttl")
+                .value_name("SECONDS")
+                .help("How long the kernel caches the attributes of files and directories, 1 by default"),
+        )
+        .arg(
+            Arg::new("entry-ttl")
+                .long("entry-ttl")
+                .value_name("SECONDS")
+                .help("How long the kernel caches the names in directories, 1 by default"),
+        )
+        .arg(
+            Arg::new("negative-ttl")
+                .long("negative-ttl")
+                .value_name("SECONDS")
+                .help("How long the kernel caches names which are not found, by default they are not cached"),
+        )
         .arg(
             Arg::new("suid")
                 .long("suid")
@@ -149,6 +272,15 @@ fn get_cli_args() -> ArgMatches {
                 .action(ArgAction::SetTrue)
                 .help("If it should allow setting SUID and SGID when files are created. Default is false and it will unset those flags when creating files"),
         )
+        .subcommand(
+            Command::new("quota")
+                .about("Show how much each user and group uses on a mount, their limits and grace periods, as the mount enforces them")
+                .arg(
+                    Arg::new("mount-point")
+                        .required(true)
+                        .value_name("MOUNT_POINT"),
+                ),
+        )
         .get_matches()
 }
 
@@ -179,10 +311,84 @@ pub fn log_init(level: Level) -> WorkerGuard {
 
 async fn async_main() -> anyhow::Result<()> {
     let matches = get_cli_args();
-    run_mount(&matches).await?;
+    match matches.subcommand() {
+        Some(("quota", matches)) => run_quota(matches)?,
+        _ => run_mount(&matches).await?,
+    }
     Ok(())
 }
 
+fn run_quota(matches: &ArgMatches) -> anyhow::Result<()> {
+    let path = Path::new(matches.get_one::<String>("mount-point").unwrap());
+    let status = quota::status(path).map_err(|err| {
+        if err.raw_os_error() == Some(libc::ENODATA) {
+            error!(path = %path.display(), "the mount has no limits");
+        } else {
+            error!(%err, path = %path.display(), "cannot read quotas");
+        }
+        ExitStatusError::Failure(1)
+    })?;
+    let (users, groups): (Vec<_>, Vec<_>) = status
+        .into_iter()
+        .partition(|status| matches!(status.owner, Owner::User(_)));
+    print_status("User", &users);
+    println!();
+    print_status("Group", &groups);
+    Ok(())
+}
+
+/// A line for each owner, `*` marks what is over a limit, the grace is what is left of it.
+fn print_status(title: &str, status: &[QuotaStatus]) {
+    println!(
+        "{title:<10} {:>14} {:>14} {:>14} {:>8}   {:>10} {:>10} {:>10} {:>8}",
+        "bytes", "soft", "hard", "grace", "inodes", "soft", "hard", "grace"
+    );
+    let limit = |limit: Option<u64>| limit.map_or_else(|| "-".to_string(), |l| l.to_string());
+    let over = |used: u64, soft: Option<u64>, hard: Option<u64>| {
+        if soft.is_some_and(|soft| used > soft) || hard.is_some_and(|hard| used > hard) {
+            "*"
+        } else {
+            " "
+        }
+    };
+    let now = SystemTime::now();
+    let grace = |end: Option<SystemTime>| match end.map(|end| end.duration_since(now)) {
+        None => "-".to_string(),
+        Some(Ok(left)) => {
+            let secs = left.as_secs();
+            format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600)
+        }
+        Some(Err(_)) => "none".to_string(),
+    };
+    for status in status {
+        let (Owner::User(id) | Owner::Group(id)) = status.owner;
+        let (used, limits) = (status.usage, status.limits);
+        println!(
+            "{id:<10} {:>14}{} {:>14} {:>14} {:>8}  {:>10}{} {:>10} {:>10} {:>8}",
+            used.bytes,
+            over(used.bytes, limits.soft_bytes, limits.hard_bytes),
+            limit(limits.soft_bytes),
+            limit(limits.hard_bytes),
+            grace(status.bytes_grace),
+            used.inodes,
+            over(used.inodes, limits.soft_inodes, limits.hard_inodes),
+            limit(limits.soft_inodes),
+            limit(limits.hard_inodes),
+            grace(status.inodes_grace),
+        );
+    }
+}
+
+fn load_quotas(matches: &ArgMatches) -> anyhow::Result<Quotas> {
+    let Some(config) = matches.get_one::<String>("quota-config") else {
+        return Ok(Quotas::default());
+    };
+    Ok(Quotas::load(Path::new(config)).map_err(|err| {
+        error!(%err, "cannot load quota config");
+        ExitStatusError::Failure(1)
+    })?)
+}
+
 async fn run_mount(matches: &ArgMatches) -> anyhow::Result<()> {
     let mountpoint: String = matches
         .get_one::<String>("mount-point")
@@ -196,8 +402,90 @@ async fn run_mount(matches: &ArgMatches) -> anyhow::Result<()> {
         });
     }
 
+    let backend = if let Some(source_dir) = matches.get_one::<String>("source-dir") {
+        Backend::Passthrough {
+            source_dir: PathBuf::from(source_dir),
+        }
+    } else if let Some(data_dir) = matches.get_one::<String>("data-dir") {
+        Backend::Persistent {
+            data_dir: PathBuf::from(data_dir),
+        }
+    } else if let Some(archive) = matches.get_one::<String>("tar") {
+        Backend::Tar {
+            archive: PathBuf::from(archive),
+        }
+    } else if let Some(archive) = matches.get_one::<String>("zip") {
+        Backend::Zip {
+            archive: PathBuf::from(archive),
+        }
+    } else {
+        Backend::Memory
+    };
+    let backend = match matches.get_many::<String>("lower") {
+        Some(lowers) => Backend::Overlay {
+            upper: Box::new(backend),
+            lowers: lowers.map(String::as_str).map(lower_backend).collect(),
+        },
+        None => backend,
+    };
+
+    let cipher: Cipher = matches
+        .get_one::<String>("cipher")
+        .unwrap()
+        .parse()
+        .map_err(|err: String| {
+            error!(err);
+            ExitStatusError::Failure(1)
+        })?;
+    let compression: Option<Compression> = matches
+        .get_one::<String>("compression")
+        .map(|compression| compression.parse())
+        .transpose()
+        .map_err(|err: String| {
+            error!(err);
+            ExitStatusError::Failure(1)
+        })?;
+    let capacity = Capacity {
+        max_size: matches
+            .get_one::<String>("max-size")
+            .map(|size| parse_size(size))
+            .transpose()
+            .map_err(|err| {
+                error!(err);
+                ExitStatusError::Failure(1)
+            })?,
+        max_inodes: matches
+            .get_one::<String>("max-inodes")
+            .map(|count| count.parse())
+            .transpose()
+            .map_err(|err| {
+                error!(%err, "invalid max inodes");
+                ExitStatusError::Failure(1)
+            })?,
+        quotas: load_quotas(matches)?,
+    };
+    let default_ttl = Ttl::default();
+    let ttl = Ttl {
+        attr: parse_ttl(matches, "attr-ttl")?.unwrap_or(default_ttl.attr),
+        entry: parse_ttl(matches, "entry-ttl")?.unwrap_or(default_ttl.entry),
+        negative: parse_ttl(matches, "negative-ttl")?.unwrap_or(default_ttl.negative),
+    };
+    let password_provider: Option<Box<dyn PasswordProvider>> = if matches.get_flag("encrypt") {
+        Some(Box::new(PasswordProviderImpl {}))
+    } else {
+        None
+    };
+
     let mount_point = mount::create_mount_point(
         Path::new(&mountpoint),
+        backend,
+        password_provider,
+        cipher,
+        compression,
+        matches.get_flag("dedup"),
+        matches.get_flag("snapshots"),
+        capacity,
+        ttl,
         matches.get_flag("allow-root"),
         matches.get_flag("allow-other"),
         matches.get_flag("direct-io"),
@@ -257,6 +545,70 @@ async fn run_mount(matches: &ArgMatches) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// A size in bytes, with an optional `K`, `M`, `G` or `T` suffix for powers of 1024.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (number, shift) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 10),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('G') => (&size[..size.len() - 1], 30),
+        Some('T') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size {size}"))
+}
+
+/// A TTL in seconds, fractions are allowed.
+fn parse_ttl(matches: &ArgMatches, name: &str) -> anyhow::Result<Option<Duration>> {
+    let Some(ttl) = matches.get_one::<String>(name) else {
+        return Ok(None);
+    };
+    let ttl = ttl
+        .parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| {
+            error!(ttl, "invalid {name}");
+            ExitStatusError::Failure(1)
+        })?;
+    Ok(Some(ttl))
+}
+
+/// Archives are recognized by the extension, anything else is a directory.
+fn lower_backend(path: &str) -> Backend {
+    let lower = PathBuf::from(path);
+    if path.ends_with(".zip") {
+        Backend::Zip { archive: lower }
+    } else if [".tar", ".tar.gz", ".tgz"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+    {
+        Backend::Tar { archive: lower }
+    } else {
+        Backend::Passthrough { source_dir: lower }
+    }
+}
+
+struct PasswordProviderImpl {}
+
+impl PasswordProvider for PasswordProviderImpl {
+    fn get_password(&self) -> Option<SecretString> {
+        if let Ok(password) = env::var(PASSWORD_ENV) {
+            return Some(SecretString::new(password));
+        }
+        print!("Enter password: ");
+        io::stdout().flush().ok()?;
+        let password = SecretString::new(read_password().ok()?);
+        if password.expose_secret().is_empty() {
+            return None;
+        }
+        Some(password)
+    }
+}
+
 fn umount(mountpoint: &str) -> io::Result<()> {
     // try normal umount
     if process::Command::new("umount")
@@ -287,10 +639,6 @@ fn umount(mountpoint: &str) -> io::Result<()> {
     {
         Ok(())
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("cannot umount {}", mountpoint),
-        )
-        .into())
+        Err(io::Error::other(format!("cannot umount {mountpoint}")))
     }
 }
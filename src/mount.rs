@@ -2,21 +2,199 @@ use std::future::Future;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use async_trait::async_trait;
 use futures_util::FutureExt;
+use tracing::info;
+use crate::compression::Compression;
+use crate::crypto::{Cipher, PasswordProvider};
+use crate::fs::compressed::CompressedFilesystem;
+use crate::fs::dedup::DedupFilesystem;
+use crate::fs::encrypted::EncryptedFilesystem;
+use crate::fs::memory::MemoryFilesystem;
+use crate::fs::overlay::OverlayFilesystem;
+use crate::fs::passthrough::PassthroughFilesystem;
+use crate::fs::persistent::PersistentFilesystem;
+use crate::fs::tar::TarFilesystem;
+use crate::fs::zip::ZipFilesystem;
+use crate::fs::Filesystem;
 use crate::fs_model::FsResult;
 use crate::mount::fuse3::{MountHandleInnerImpl, MountPointImpl};
+use crate::quota::Quotas;
 
+mod acl;
 mod fuse3;
+mod locks;
+mod lookups;
+mod notify;
+mod requests;
+
+pub(crate) use notify::Invalidator;
+
+/// The implementation of the filesystem which is mounted.
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// Everything is kept in memory and lost on unmount
+    Memory,
+    /// Mirror a directory from the host
+    Passthrough {
+        /// The directory to mirror
+        source_dir: PathBuf,
+    },
+    /// Persist everything in a data directory, it survives restarts
+    Persistent {
+        /// The directory where the files and metadata are stored
+        data_dir: PathBuf,
+    },
+    /// Expose the content of a `.tar` or `.tar.gz` archive, read-only
+    Tar {
+        /// The archive file
+        archive: PathBuf,
+    },
+    /// Expose the content of a `.zip` archive, read-only
+    Zip {
+        /// The archive file
+        archive: PathBuf,
+    },
+    /// Stack a writable backend over read-only ones, changes only go to the upper one
+    Overlay {
+        /// Where the changes are made
+        upper: Box<Backend>,
+        /// Only read from these, from top to bottom
+        lowers: Vec<Backend>,
+    },
+}
+
+impl Backend {
+    /// Create the filesystem, encrypted if `encryption` is set, compressed if `compression` is
+    /// set and deduplicated if `dedup` is set. For [`Backend::Overlay`] only the upper layer gets
+    /// these, the lower ones are used as they are. Backends which can be changed from outside use
+    /// `invalidator` to tell the kernel.
+    pub(crate) async fn create_fs(
+        &self,
+        encryption: Option<(&dyn PasswordProvider, Cipher)>,
+        compression: Option<Compression>,
+        dedup: bool,
+        invalidator: &Invalidator,
+    ) -> FsResult<Arc<dyn Filesystem>> {
+        let fs: Arc<dyn Filesystem> = match self {
+            Self::Memory => MemoryFilesystem::new(),
+            Self::Passthrough { source_dir } => {
+                PassthroughFilesystem::new(source_dir, invalidator.clone())?
+            }
+            Self::Persistent { data_dir } => PersistentFilesystem::new(data_dir)?,
+            Self::Tar { archive } => TarFilesystem::new(archive)?,
+            Self::Zip { archive } => ZipFilesystem::new(archive)?,
+            Self::Overlay { upper, lowers } => {
+                // the overlay has its own inode numbers, the layers cannot invalidate them
+                let unconnected = Invalidator::default();
+                let upper =
+                    Box::pin(upper.create_fs(encryption, compression, dedup, &unconnected)).await?;
+                let mut lower_fs = Vec::with_capacity(lowers.len());
+                for lower in lowers {
+                    lower_fs
+                        .push(Box::pin(lower.create_fs(None, None, false, &unconnected)).await?);
+                }
+                return Ok(OverlayFilesystem::new(upper, lower_fs).await?);
+            }
+        };
+        let fs: Arc<dyn Filesystem> = match encryption {
+            Some((password_provider, cipher)) => {
+                info!("Checking password");
+                EncryptedFilesystem::new(fs, cipher, password_provider).await?
+            }
+            None => fs,
+        };
+        // compress before encrypting, encrypted data doesn't compress
+        let fs: Arc<dyn Filesystem> = match compression {
+            Some(compression) => CompressedFilesystem::new(fs, compression).await?,
+            None => fs,
+        };
+        // the chunks are files in the layers below, so each one is compressed and encrypted
+        Ok(if dedup {
+            DedupFilesystem::new(fs).await?
+        } else {
+            fs
+        })
+    }
+
+    /// If its files can be changed from outside, without going through the mount. For
+    /// [`Backend::Overlay`] that's the upper layer, the lower ones are not changed by us.
+    #[must_use]
+    pub fn is_changed_outside(&self) -> bool {
+        match self {
+            Self::Passthrough { .. } => true,
+            Self::Overlay { upper, .. } => upper.is_changed_outside(),
+            _ => false,
+        }
+    }
+
+    /// If we can only read from it, the filesystem is mounted read-only then.
+    #[must_use]
+    pub const fn is_read_only(&self) -> bool {
+        matches!(self, Self::Tar { .. } | Self::Zip { .. })
+    }
+}
+
+/// How much can be stored, over it changes fail with `ENOSPC`. `statfs` shows these as the size of
+/// the filesystem. Without them we are limited only by the backend.
+#[derive(Debug, Clone, Default)]
+pub struct Capacity {
+    /// Total bytes of the files
+    pub max_size: Option<u64>,
+    /// Number of files, directories and the other nodes
+    pub max_inodes: Option<u64>,
+    /// Limits of each user and group, over them changes fail with `EDQUOT`
+    pub quotas: Quotas,
+}
+
+impl Capacity {
+    #[must_use]
+    pub fn is_limited(&self) -> bool {
+        self.max_size.is_some() || self.max_inodes.is_some() || !self.quotas.is_empty()
+    }
+}
+
+/// How long the kernel keeps what we reply with before asking again. Longer is faster, but changes
+/// made to the backend from outside are seen later, unless it invalidates them.
+#[derive(Debug, Clone, Copy)]
+pub struct Ttl {
+    /// Attributes of the files and directories
+    pub attr: Duration,
+    /// Entries of the directories, from a name to the inode
+    pub entry: Duration,
+    /// Names which are not found, zero to not keep them
+    pub negative: Duration,
+}
+
+impl Default for Ttl {
+    fn default() -> Self {
+        Self {
+            attr: Duration::from_secs(1),
+            entry: Duration::from_secs(1),
+            negative: Duration::ZERO,
+        }
+    }
+}
 
 #[async_trait]
 #[allow(clippy::module_name_repetitions)]
 #[allow(clippy::struct_excessive_bools)]
 pub trait MountPoint {
     #[allow(clippy::fn_params_excessive_bools)]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         mountpoint: PathBuf,
+        backend: Backend,
+        password_provider: Option<Box<dyn PasswordProvider>>,
+        cipher: Cipher,
+        compression: Option<Compression>,
+        dedup: bool,
+        snapshots: bool,
+        capacity: Capacity,
+        ttl: Ttl,
         allow_root: bool,
         allow_other: bool,
         direct_io: bool,
@@ -51,10 +229,18 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 }
 
 /// **`mountpoint`** where it wil mount the filesystem
-/// **`data_dir`** the directory where the encrypted files will be stored
-/// **`password_provider`** the password provider
+/// **`backend`** the implementation of the filesystem, see [`Backend`]
+/// **`password_provider`** the password provider, if set the content of the files is encrypted
+/// with a key derived from the password
 /// **`cipher`** The encryption algorithm to use.
 /// Currently, it supports these ciphers [`Cipher`]
+/// **`compression`** if set the content of the files is compressed with this algorithm, see
+/// [`Compression`]
+/// **`dedup`** if the content of the files is split in chunks which are stored once, so identical
+/// content is stored once
+/// **`snapshots`** if we can take snapshots, they are created, listed and deleted under `/.snapshots`
+/// **`capacity`** limits of what can be stored, see [`Capacity`]
+/// **`ttl`** how long the kernel caches attributes and entries, see [`Ttl`]
 /// **`allow_root`** allow root to access the file system
 /// **`allow_other`** allow other users to access the file system
 /// **`direct_io`** use direct I/O (bypass page cache for open files)
@@ -62,8 +248,17 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 ///
 #[must_use]
 #[allow(clippy::fn_params_excessive_bools)]
+#[allow(clippy::too_many_arguments)]
 pub fn create_mount_point(
     mountpoint: &Path,
+    backend: Backend,
+    password_provider: Option<Box<dyn PasswordProvider>>,
+    cipher: Cipher,
+    compression: Option<Compression>,
+    dedup: bool,
+    snapshots: bool,
+    capacity: Capacity,
+    ttl: Ttl,
     allow_root: bool,
     allow_other: bool,
     direct_io: bool,
@@ -71,6 +266,14 @@ pub fn create_mount_point(
 ) -> impl MountPoint {
     MountPointImpl::new(
         mountpoint.to_path_buf(),
+        backend,
+        password_provider,
+        cipher,
+        compression,
+        dedup,
+        snapshots,
+        capacity,
+        ttl,
         allow_root,
         allow_other,
         direct_io,
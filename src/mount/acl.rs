@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::fs_model::{FsError, FsResult};
+
+/// Extended attribute with the ACL used for access checks.
+pub(crate) const ACCESS_XATTR: &str = "system.posix_acl_access";
+/// Extended attribute with the ACL a directory gives to the entries created in it.
+pub(crate) const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// Version in the header of the xattr format, same as Linux.
+const VERSION: u32 = 2;
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 8;
+
+const TAG_USER_OBJ: u16 = 0x01;
+const TAG_USER: u16 = 0x02;
+const TAG_GROUP_OBJ: u16 = 0x04;
+const TAG_GROUP: u16 = 0x08;
+const TAG_MASK: u16 = 0x10;
+const TAG_OTHER: u16 = 0x20;
+/// Id of the entries which are not for a specific user or group.
+const UNDEFINED_ID: u32 = u32::MAX;
+
+/// The order is the one the entries must have, it's also the order they are checked in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Tag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tag: Tag,
+    /// `rwx` bits.
+    perm: u16,
+}
+
+/// A POSIX.1e access control list.
+///
+/// The owner, group and other entries are kept in sync with the mode bits, with the mask entry
+/// taking the place of the group bits when there is one.
+#[derive(Debug, Clone)]
+pub(crate) struct Acl {
+    entries: Vec<Entry>,
+}
+
+impl Acl {
+    /// Parse the value of one of the ACL extended attributes, in the format Linux uses.
+    pub fn parse(data: &[u8]) -> FsResult<Self> {
+        if data.len() < HEADER_LEN || !(data.len() - HEADER_LEN).is_multiple_of(ENTRY_LEN) {
+            return Err(FsError::InvalidInput("invalid ACL size"));
+        }
+        if u32::from_le_bytes(data[..HEADER_LEN].try_into().unwrap()) != VERSION {
+            return Err(FsError::InvalidInput("unsupported ACL version"));
+        }
+        let mut entries = vec![];
+        for entry in data[HEADER_LEN..].chunks(ENTRY_LEN) {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            if perm & !0o7 != 0 {
+                return Err(FsError::InvalidInput("invalid ACL permissions"));
+            }
+            let tag = match tag {
+                TAG_USER_OBJ => Tag::UserObj,
+                TAG_USER => Tag::User(id),
+                TAG_GROUP_OBJ => Tag::GroupObj,
+                TAG_GROUP => Tag::Group(id),
+                TAG_MASK => Tag::Mask,
+                TAG_OTHER => Tag::Other,
+                _ => return Err(FsError::InvalidInput("invalid ACL tag")),
+            };
+            entries.push(Entry { tag, perm });
+        }
+        entries.sort_by_key(|entry| entry.tag);
+        let acl = Self { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    /// Exactly one owner, group and other entry, no user or group twice and a mask if there are
+    /// entries for specific users or groups.
+    fn validate(&self) -> FsResult<()> {
+        let count = |f: fn(&Tag) -> bool| self.entries.iter().filter(|e| f(&e.tag)).count();
+        if count(|tag| *tag == Tag::UserObj) != 1
+            || count(|tag| *tag == Tag::GroupObj) != 1
+            || count(|tag| *tag == Tag::Other) != 1
+            || count(|tag| *tag == Tag::Mask) > 1
+        {
+            return Err(FsError::InvalidInput("invalid ACL entries"));
+        }
+        if self.entries.windows(2).any(|w| w[0].tag == w[1].tag) {
+            return Err(FsError::InvalidInput("duplicate ACL entries"));
+        }
+        let named = count(|tag| matches!(tag, Tag::User(_) | Tag::Group(_)));
+        if named > 0 && self.mask().is_none() {
+            return Err(FsError::InvalidInput("ACL needs a mask entry"));
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN + self.entries.len() * ENTRY_LEN);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        for entry in &self.entries {
+            let (tag, id) = match entry.tag {
+                Tag::UserObj => (TAG_USER_OBJ, UNDEFINED_ID),
+                Tag::User(id) => (TAG_USER, id),
+                Tag::GroupObj => (TAG_GROUP_OBJ, UNDEFINED_ID),
+                Tag::Group(id) => (TAG_GROUP, id),
+                Tag::Mask => (TAG_MASK, UNDEFINED_ID),
+                Tag::Other => (TAG_OTHER, UNDEFINED_ID),
+            };
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&entry.perm.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        data
+    }
+
+    /// It has only the entries the mode bits have, so it's not needed.
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    fn mask(&self) -> Option<u16> {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == Tag::Mask)
+            .map(|entry| entry.perm)
+    }
+
+    fn perm_mut(&mut self, tag: Tag) -> Option<&mut u16> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| &mut entry.perm)
+    }
+
+    /// The entry which takes the place of the group bits of the mode.
+    fn group_class_tag(&self) -> Tag {
+        if self.mask().is_some() {
+            Tag::Mask
+        } else {
+            Tag::GroupObj
+        }
+    }
+
+    fn perm(&self, tag: Tag) -> u16 {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map_or(0, |entry| entry.perm)
+    }
+
+    /// `mode` with the permission bits from the ACL, the others like setuid are kept.
+    pub fn mode(&self, mode: u16) -> u16 {
+        (mode & !0o777)
+            | self.perm(Tag::UserObj) << 6
+            | self.perm(self.group_class_tag()) << 3
+            | self.perm(Tag::Other)
+    }
+
+    /// Follow a change of the mode bits, the mask is changed instead of the owning group if there
+    /// is one.
+    pub fn chmod(&mut self, mode: u16) {
+        let group_class = self.group_class_tag();
+        for (tag, perm) in [
+            (Tag::UserObj, mode >> 6),
+            (group_class, mode >> 3),
+            (Tag::Other, mode),
+        ] {
+            if let Some(entry) = self.perm_mut(tag) {
+                *entry = perm & 0o7;
+            }
+        }
+    }
+
+    /// Make this default ACL of the parent the access ACL of a new entry created with `mode`. The
+    /// ACL and the mode are both restricted to what the other allows.
+    pub fn create_masq(&mut self, mode: &mut u16) {
+        let group_class = self.group_class_tag();
+        for (tag, shift) in [(Tag::UserObj, 6), (group_class, 3), (Tag::Other, 0)] {
+            if let Some(entry) = self.perm_mut(tag) {
+                *entry &= (*mode >> shift) & 0o7;
+            }
+        }
+        *mode = self.mode(*mode);
+    }
+
+    /// Check the access a user which is not root has, `mask` has the `R_OK`, `W_OK` and `X_OK`
+    /// bits, the same as the ones of the entries. `in_group` tells if the user is in a group.
+    pub fn check(
+        &self,
+        owner: u32,
+        group: u32,
+        uid: u32,
+        in_group: impl Fn(u32) -> bool,
+        mask: u16,
+    ) -> bool {
+        let allows = |perm: u16| perm & mask == mask;
+        let masked = |perm: u16| self.mask().map_or(perm, |m| perm & m);
+        let mut in_group_class = false;
+        for entry in &self.entries {
+            match entry.tag {
+                Tag::UserObj if uid == owner => return allows(entry.perm),
+                Tag::User(id) if id == uid => return allows(masked(entry.perm)),
+                Tag::GroupObj if in_group(group) => {
+                    if allows(masked(entry.perm)) {
+                        return true;
+                    }
+                    in_group_class = true;
+                }
+                Tag::Group(id) if in_group(id) => {
+                    if allows(masked(entry.perm)) {
+                        return true;
+                    }
+                    in_group_class = true;
+                }
+                // a matching group which doesn't allow it doesn't fall back to other
+                Tag::Other => return !in_group_class && allows(entry.perm),
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+struct Cached {
+    /// `None` if the node has no such ACL.
+    acl: Option<Acl>,
+    added: Instant,
+}
+
+#[derive(Default)]
+struct CacheState {
+    acls: HashMap<(u64, &'static str), Cached>,
+    /// Incremented on each invalidation, an ACL read before it is not cached.
+    generation: u64,
+}
+
+/// The ACLs parsed from the extended attributes of the nodes, so the access checks don't read
+/// them each time. They are invalidated when we change the attributes, and expire after `ttl` so
+/// the changes made to the backend from outside are seen.
+pub(crate) struct AclCache {
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl AclCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Mutex::default(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().expect("ACL cache lock poisoned")
+    }
+
+    /// The ACL `name` of `ino` if it's cached, as [`Self::insert`] got it.
+    pub fn get(&self, ino: u64, name: &'static str) -> Option<Option<Acl>> {
+        self.state()
+            .acls
+            .get(&(ino, name))
+            .filter(|cached| cached.added.elapsed() < self.ttl)
+            .map(|cached| cached.acl.clone())
+    }
+
+    /// Taken before reading an ACL, to give to [`Self::insert`] after.
+    pub fn generation(&self) -> u64 {
+        self.state().generation
+    }
+
+    /// Cache what was read, unless it was invalidated since `generation`.
+    pub fn insert(&self, ino: u64, name: &'static str, acl: Option<Acl>, generation: u64) {
+        let mut state = self.state();
+        if state.generation == generation {
+            state.acls.insert(
+                (ino, name),
+                Cached {
+                    acl,
+                    added: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Drop the ACLs of `ino`, after they changed or the inode is not used anymore.
+    pub fn invalidate(&self, ino: u64) {
+        let mut state = self.state();
+        state.generation += 1;
+        for name in [ACCESS_XATTR, DEFAULT_XATTR] {
+            state.acls.remove(&(ino, name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R: u16 = 4;
+    const W: u16 = 2;
+    const X: u16 = 1;
+
+    fn raw(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut data = VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&perm.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        data
+    }
+
+    /// Owner 1000 and group 100 with `rw-`, user 2000 with `rwx`, group 200 with `r--`, mask `r-x`
+    /// and other `---`.
+    fn extended() -> Acl {
+        Acl::parse(&raw(&[
+            (TAG_OTHER, 0, UNDEFINED_ID),
+            (TAG_USER_OBJ, R | W, UNDEFINED_ID),
+            (TAG_USER, R | W | X, 2000),
+            (TAG_GROUP_OBJ, R | W, UNDEFINED_ID),
+            (TAG_GROUP, R, 200),
+            (TAG_MASK, R | X, UNDEFINED_ID),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let acl = extended();
+        assert!(!acl.is_minimal());
+        // the entries are sorted
+        assert_eq!(
+            Acl::parse(&acl.to_bytes()).unwrap().to_bytes(),
+            acl.to_bytes()
+        );
+        assert_eq!(acl.mode(0o100_000), 0o100_650);
+        // named entries need a mask
+        assert!(Acl::parse(&raw(&[
+            (TAG_USER_OBJ, R, UNDEFINED_ID),
+            (TAG_USER, R, 2000),
+            (TAG_GROUP_OBJ, R, UNDEFINED_ID),
+            (TAG_OTHER, R, UNDEFINED_ID),
+        ]))
+        .is_err());
+        assert!(Acl::parse(&raw(&[(TAG_USER_OBJ, R, UNDEFINED_ID)])).is_err());
+        assert!(Acl::parse(&[1, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn check() {
+        let acl = extended();
+        let none = |_| false;
+        // the owner isn't limited by the mask
+        assert!(acl.check(1000, 100, 1000, none, R | W));
+        assert!(!acl.check(1000, 100, 1000, none, X));
+        // named users and groups are
+        assert!(acl.check(1000, 100, 2000, none, R | X));
+        assert!(!acl.check(1000, 100, 2000, none, W));
+        assert!(acl.check(1000, 100, 3000, |gid| gid == 200, R));
+        assert!(!acl.check(1000, 100, 3000, |gid| gid == 100, W));
+        // any matching group can allow it
+        assert!(acl.check(1000, 100, 3000, |gid| gid == 100 || gid == 200, R));
+        // a matching group which doesn't allow it doesn't fall back to other
+        let mut open = acl.clone();
+        open.chmod(0o657);
+        assert!(open.check(1000, 100, 3000, none, R | W | X));
+        assert!(!open.check(1000, 100, 3000, |gid| gid == 200, W));
+    }
+
+    #[test]
+    fn chmod_and_create() {
+        let mut acl = extended();
+        // the mask takes the place of the group bits
+        acl.chmod(0o741);
+        assert_eq!(acl.mode(0), 0o741);
+        assert!(acl.check(1000, 100, 2000, |_| false, R));
+        assert!(!acl.check(1000, 100, 2000, |_| false, X));
+
+        let mut default = extended();
+        let mut mode = 0o100_644;
+        default.create_masq(&mut mode);
+        assert_eq!(mode, 0o100_640);
+        assert_eq!(default.mode(0), 0o640);
+    }
+
+    #[test]
+    fn cache() {
+        let cache = AclCache::new(Duration::from_secs(60));
+        assert!(cache.get(1, ACCESS_XATTR).is_none());
+        let generation = cache.generation();
+        cache.insert(1, ACCESS_XATTR, Some(extended()), generation);
+        cache.insert(1, DEFAULT_XATTR, None, generation);
+        assert!(cache.get(1, ACCESS_XATTR).unwrap().is_some());
+        assert!(cache.get(1, DEFAULT_XATTR).unwrap().is_none());
+        // what was read before an invalidation is not kept
+        let generation = cache.generation();
+        cache.invalidate(1);
+        assert!(cache.get(1, ACCESS_XATTR).is_none());
+        cache.insert(1, ACCESS_XATTR, Some(extended()), generation);
+        assert!(cache.get(1, ACCESS_XATTR).is_none());
+        // they expire
+        let cache = AclCache::new(Duration::ZERO);
+        cache.insert(1, ACCESS_XATTR, None, cache.generation());
+        assert!(cache.get(1, ACCESS_XATTR).is_none());
+    }
+}
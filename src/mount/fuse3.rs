@@ -1,10 +1,12 @@
+use std::cell::OnceCell;
+use std::cmp::min;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::future::Future;
 use std::io;
 use std::io::{BufRead, BufReader};
-use std::iter::Skip;
 use std::num::NonZeroU32;
+use std::os::fd::AsFd;
 use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::pin::Pin;
@@ -16,134 +18,226 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use fuse3::raw::prelude::{
     DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCopyFileRange, ReplyCreated, ReplyData,
-    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs, ReplyWrite,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyLSeek, ReplyLock, ReplyOpen,
+    ReplyStatFs, ReplyWrite, ReplyXAttr,
 };
 use fuse3::raw::{Filesystem, MountHandle, Request, Session};
 use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
-use futures_util::stream::Iter;
-use futures_util::{stream, FutureExt};
-use libc::{EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use futures_util::stream::BoxStream;
+use futures_util::{future, ready, stream, FutureExt, Stream, StreamExt};
+use libc::{
+    EACCES, EAGAIN, EBADF, EBUSY, EDEADLK, EDQUOT, EEXIST, EFBIG, EINTR, EINVAL, EIO, EISDIR,
+    ENAMETOOLONG, ENODATA, ENODEV, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENXIO, EOPNOTSUPP, EPERM,
+    ERANGE, EROFS, EXDEV,
+};
 use tracing::{debug, error, instrument, trace, warn};
 use tracing::{info, Level};
 
-use crate::fs_model::{CreateFileAttr, FileAttr, FileType, FsError, FsResult, SetFileAttr};
-use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
-
-const TTL: Duration = Duration::from_secs(1);
-const STATFS: ReplyStatFs = ReplyStatFs {
-    blocks: 1,
-    bfree: 0,
-    bavail: 0,
-    files: 1,
-    ffree: 0,
-    bsize: 4096,
-    namelen: u32::MAX,
-    frsize: 0,
+use crate::compression::Compression;
+use crate::crypto::{Cipher, PasswordProvider};
+use crate::fs::capacity::CapacityFilesystem;
+use crate::fs::snapshot::{
+    is_snapshot_inode, SnapshotFilesystem, Snapshots, SNAPSHOTS_DIR, SNAPSHOTS_INODE,
 };
+use crate::fs::ROOT_INODE;
+use crate::fs_model::{
+    CreateFileAttr, FallocateMode, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+    SetXattrMode, Whence,
+};
+use crate::mount;
+use crate::mount::acl::{Acl, AclCache, ACCESS_XATTR, DEFAULT_XATTR};
+use crate::mount::locks::{Lock, LockKind, LockManager};
+use crate::mount::lookups::Lookups;
+use crate::mount::requests::Requests;
+use crate::mount::{Backend, Capacity, Invalidator, MountHandleInner, MountPoint, Ttl};
+use crate::quota::QUOTA_XATTR;
 
 const FMODE_EXEC: i32 = 0x20;
 
-// const MAX_NAME_LENGTH: u32 = 255 - ENCRYPT_FILENAME_OVERHEAD_CHARS as u32;
+/// Namespaces of extended attributes we support.
+const XATTR_USER: &str = "user.";
+const XATTR_TRUSTED: &str = "trusted.";
+const XATTR_SECURITY: &str = "security.";
+
+/// How long we wait before asking the kernel to send again an interrupt for a request we are not
+/// handling yet, so it doesn't send them in a loop.
+const INTERRUPT_RETRY: Duration = Duration::from_millis(10);
 
 // Flags returned by the open request
 const FOPEN_DIRECT_IO: u32 = 1 << 0; // bypass page cache for this open file
 
-pub struct DirectoryEntryIterator(crate::fs_model::DirectoryEntryIterator, u64);
-
-impl Iterator for DirectoryEntryIterator {
-    type Item = Result<DirectoryEntry>;
-
-    #[instrument(name = "DirectoryEntryIterator::next", skip(self))]
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
-            Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
-                self.1 += 1;
-                Some(Ok(DirectoryEntry {
-                    inode: entry.ino,
-                    kind,
-                    name: OsString::from(entry.name),
-                    #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
-                }))
-            }
-            Some(Err(FsError::Io { source, .. })) => {
-                error!(err = %source);
-                Some(Err(source.into()))
-            }
-            Some(Err(err)) => {
-                error!(err = %err);
-                Some(Err(EIO.into()))
-            }
-            None => None,
+/// `/.snapshots` is listed after the entries of the root with this offset, one the backends
+/// don't use, ext4 gives the one after to the end of a directory.
+const SNAPSHOTS_OFFSET: u64 = i64::MAX as u64 - 1;
+
+fn dir_entry(entry: FsResult<crate::fs_model::DirectoryEntry>) -> Result<DirectoryEntry> {
+    match entry {
+        Ok(entry) => Ok(DirectoryEntry {
+            inode: entry.ino,
+            kind: entry.kind.into(),
+            name: OsString::from(entry.name),
+            #[allow(clippy::cast_possible_wrap)]
+            offset: entry.cookie as i64,
+        }),
+        Err(err) => {
+            error!(err = %err);
+            Err(errno(&err).into())
         }
     }
 }
 
-pub struct DirectoryEntryPlusIterator(crate::fs_model::DirectoryEntryPlusIterator, u64);
+/// The kernel takes a reference to each entry of `readdirplus`, but "." and "..", we count it as
+/// a lookup.
+pub struct DirectoryEntryPlusStream<'a> {
+    entries: crate::fs_model::DirectoryEntryPlusStream,
+    fuse: &'a Fuse3,
+    /// Inode of the last entry returned. The reply stops at the first entry which doesn't fit, so
+    /// we only know it was sent when the next one is asked for, if we are dropped before its
+    /// lookup is undone.
+    pending: Option<u64>,
+}
 
-impl Iterator for DirectoryEntryPlusIterator {
+impl Stream for DirectoryEntryPlusStream<'_> {
     type Item = Result<DirectoryEntryPlus>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        this.pending = None;
+        Poll::Ready(match ready!(this.entries.poll_next_unpin(cx)) {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
+                let generation = if entry.name == "." || entry.name == ".." {
+                    0
                 } else {
-                    fuse3::raw::prelude::FileType::RegularFile
+                    this.pending = Some(entry.ino);
+                    this.fuse.add_lookup(entry.ino)
                 };
-                self.1 += 1;
                 Some(Ok(DirectoryEntryPlus {
                     inode: entry.ino,
-                    generation: 0,
-                    kind,
+                    generation,
+                    kind: entry.kind.into(),
                     name: OsString::from(entry.name),
                     #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
+                    offset: entry.cookie as i64,
                     attr: entry.attr.into(),
-                    entry_ttl: TTL,
-                    attr_ttl: TTL,
+                    entry_ttl: this.fuse.ttl.entry,
+                    attr_ttl: this.fuse.ttl.attr,
                 }))
             }
-            Some(Err(FsError::Io { source, .. })) => {
-                error!(err = %source);
-                Some(Err(source.into()))
-            }
             Some(Err(err)) => {
                 error!(err = %err);
-                Some(Err(EIO.into()))
+                Some(Err(errno(&err).into()))
             }
             None => None,
+        })
+    }
+}
+
+impl Drop for DirectoryEntryPlusStream<'_> {
+    fn drop(&mut self) {
+        if let Some(ino) = self.pending.take() {
+            if self.fuse.lookups.forget(ino, 1) {
+                let fs = self.fuse.get_fs(ino);
+                tokio::spawn(async move { fs.forgotten(ino).await });
+            }
         }
     }
 }
 
 pub struct Fuse3 {
     fs: Arc<dyn crate::fs::Filesystem>,
+    snapshots: Option<Arc<Snapshots>>,
+    /// Enforces the limits, its quotas are shown in [`QUOTA_XATTR`] of the root.
+    capacity: Option<Arc<CapacityFilesystem>>,
+    ttl: Ttl,
     direct_io: bool,
     suid_support: bool,
+    locks: LockManager,
+    /// The `flock` locks, they are on the whole file and don't conflict with the POSIX ones.
+    flocks: LockManager,
+    lookups: Lookups,
+    requests: Requests,
+    acls: AclCache,
 }
 
 impl Fuse3 {
-    pub async fn new(direct_io: bool, suid_support: bool) -> FsResult<Self> {
-        Ok(Self {
-            fs: crate::fs::FilesystemImpl::new(direct_io, suid_support).await?,
+    pub fn new(
+        fs: Arc<dyn crate::fs::Filesystem>,
+        snapshots: Option<Arc<Snapshots>>,
+        capacity: Option<Arc<CapacityFilesystem>>,
+        ttl: Ttl,
+        direct_io: bool,
+        suid_support: bool,
+    ) -> Self {
+        Self {
+            fs,
+            snapshots,
+            capacity,
+            ttl,
             direct_io,
             suid_support,
-        })
+            locks: LockManager::default(),
+            flocks: LockManager::default(),
+            lookups: Lookups::default(),
+            requests: Requests::default(),
+            acls: AclCache::new(ttl.attr),
+        }
+    }
+
+    /// Count the reference the kernel takes to an inode with an entry we reply with, it returns
+    /// the generation of the inode.
+    fn add_lookup(&self, ino: u64) -> u64 {
+        self.lookups.add(ino, || self.get_fs(ino).referenced(ino))
+    }
+
+    /// fuse3 replies to lookups and new entries with one TTL for the entry and its attributes, we
+    /// use the shorter one so neither is kept longer than asked.
+    fn entry_ttl(&self) -> Duration {
+        min(self.ttl.entry, self.ttl.attr)
+    }
+
+    /// The kernel dropped `nlookup` references, the filesystem is told when there are none left.
+    async fn forget_lookups(&self, ino: u64, nlookup: u64) {
+        if self.lookups.forget(ino, nlookup) {
+            self.acls.invalidate(ino);
+            self.get_fs(ino).forgotten(ino).await;
+        }
+    }
+
+    /// The filesystem which has this inode, the snapshots have their own.
+    fn get_fs(&self, ino: u64) -> Arc<dyn crate::fs::Filesystem> {
+        match &self.snapshots {
+            Some(snapshots) if is_snapshot_inode(ino) => snapshots.clone(),
+            _ => self.fs.clone(),
+        }
+    }
+
+    /// The attributes we show for an inode, the root counts `/.snapshots` as a subdirectory.
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        let mut attr = self.get_fs(ino).get_attr(ino).await?;
+        if ino == ROOT_INODE && self.snapshots.is_some() {
+            attr.nlink += 1;
+        }
+        Ok(attr)
     }
 
-    fn get_fs(&self) -> Arc<dyn crate::fs::Filesystem> {
-        self.fs.clone()
+    /// `/.snapshots` is not in the filesystem, we resolve it here.
+    fn is_snapshots_dir(&self, parent: u64, name: &OsStr) -> bool {
+        self.snapshots.is_some() && parent == ROOT_INODE && name == SNAPSHOTS_DIR
     }
 
-    const fn creation_mode(&self, mode: u32) -> u16 {
+    /// Check the name fits in what the filesystem supports, with encryption this is less than
+    /// what the backend supports.
+    fn check_name_len(&self, name: &OsStr) -> std::result::Result<(), c_int> {
+        if name.len() > self.fs.max_name_len() {
+            warn!(name = %name.to_string_lossy(), "name too long");
+            return Err(ENAMETOOLONG);
+        }
+        Ok(())
+    }
+
+    const fn creation_mode(&self, mut mode: u32) -> u16 {
+        // the type is kept apart, in the kind
+        mode &= !libc::S_IFMT;
         if self.suid_support {
             mode as u16
         } else {
@@ -151,6 +245,222 @@ impl Fuse3 {
         }
     }
 
+    /// An ACL stored in an extended attribute, if the node has one.
+    async fn get_acl(&self, ino: u64, name: &'static str) -> FsResult<Option<Acl>> {
+        if let Some(acl) = self.acls.get(ino, name) {
+            return Ok(acl);
+        }
+        let generation = self.acls.generation();
+        let acl = match self.get_fs(ino).get_xattr(ino, name).await {
+            Ok(value) => Some(Acl::parse(&value)?),
+            Err(FsError::XattrNotFound) => None,
+            Err(err) => return Err(err),
+        };
+        self.acls.insert(ino, name, acl.clone(), generation);
+        Ok(acl)
+    }
+
+    /// Check the caller has the access in `mask`, with the access ACL of the node if it has one.
+    async fn has_access(&self, attr: &FileAttr, req: &Request, mask: i32) -> bool {
+        // root doesn't care about ACLs, only about the X bits in the mode
+        if req.uid == 0 || mask == libc::F_OK {
+            return check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask);
+        }
+        match self.get_acl(attr.ino, ACCESS_XATTR).await {
+            Ok(Some(acl)) => {
+                let groups = OnceCell::new();
+                let in_group = |gid| {
+                    gid == req.gid || groups.get_or_init(|| get_groups(req.pid)).contains(&gid)
+                };
+                #[allow(clippy::cast_possible_truncation)]
+                #[allow(clippy::cast_sign_loss)]
+                acl.check(attr.uid, attr.gid, req.uid, in_group, mask as u16)
+            }
+            Ok(None) => check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask),
+            Err(err) => {
+                error!(err = %err, "cannot read ACL");
+                false
+            }
+        }
+    }
+
+    /// Check the caller can read or change (`write`) an extended attribute, with the rules Linux
+    /// has for each namespace.
+    async fn check_xattr_access(
+        &self,
+        name: &str,
+        attr: &FileAttr,
+        req: &Request,
+        write: bool,
+    ) -> std::result::Result<(), Errno> {
+        // what cannot be read looks like it doesn't exist
+        let denied = if write { EPERM } else { ENODATA };
+        if name.starts_with(XATTR_USER) {
+            // the permissions of the other kinds are not about their content
+            if !matches!(attr.kind, FileType::RegularFile | FileType::Directory) {
+                return Err(denied.into());
+            }
+            // in sticky directories only the owner can change them
+            #[allow(clippy::cast_possible_truncation)]
+            if write
+                && attr.kind == FileType::Directory
+                && attr.perm & libc::S_ISVTX as u16 != 0
+                && req.uid != 0
+                && req.uid != attr.uid
+            {
+                return Err(EPERM.into());
+            }
+            let mask = if write { libc::W_OK } else { libc::R_OK };
+            if !self.has_access(attr, req, mask).await {
+                return Err(EACCES.into());
+            }
+            Ok(())
+        } else if name.starts_with(XATTR_TRUSTED) {
+            if req.uid != 0 {
+                return Err(denied.into());
+            }
+            Ok(())
+        } else if name.starts_with(XATTR_SECURITY) {
+            if write && req.uid != 0 {
+                return Err(EPERM.into());
+            }
+            Ok(())
+        } else {
+            Err(EOPNOTSUPP.into())
+        }
+    }
+
+    /// Set one of the ACLs, only the owner can do it. Setting the access ACL changes the mode
+    /// bits too, an ACL which has only what the mode bits have is not kept.
+    async fn set_acl(
+        &self,
+        attr: &FileAttr,
+        req: &Request,
+        name: &str,
+        value: &[u8],
+    ) -> std::result::Result<(), c_int> {
+        if req.uid != 0 && req.uid != attr.uid {
+            return Err(EPERM);
+        }
+        if name == DEFAULT_XATTR && attr.kind != FileType::Directory {
+            return Err(EACCES);
+        }
+        // an empty default ACL is how it's removed
+        if name == DEFAULT_XATTR && value.is_empty() {
+            return self.remove_acl(attr, req, name).await;
+        }
+        let acl = Acl::parse(value).map_err(|err| {
+            warn!(err = %err);
+            EINVAL
+        })?;
+        let fs = self.get_fs(attr.ino);
+
+        if name == ACCESS_XATTR {
+            let mut perm = acl.mode(attr.perm);
+            #[allow(clippy::cast_possible_truncation)]
+            if req.uid != 0 && req.gid != attr.gid && !get_groups(req.pid).contains(&attr.gid) {
+                perm &= !libc::S_ISGID as u16;
+            }
+            fs.set_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_perm(perm)
+                    .with_ctime(SystemTime::now()),
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                errno(&err)
+            })?;
+            if acl.is_minimal() {
+                return match fs.remove_xattr(attr.ino, name).await {
+                    Ok(()) | Err(FsError::XattrNotFound) => Ok(()),
+                    Err(err) => {
+                        error!(err = %err);
+                        Err(errno(&err))
+                    }
+                };
+            }
+        }
+
+        fs.set_xattr(attr.ino, name, &acl.to_bytes(), SetXattrMode::Any)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                errno(&err)
+            })
+    }
+
+    /// Remove one of the ACLs, only the owner can do it. The mode bits are not changed.
+    async fn remove_acl(
+        &self,
+        attr: &FileAttr,
+        req: &Request,
+        name: &str,
+    ) -> std::result::Result<(), c_int> {
+        if req.uid != 0 && req.uid != attr.uid {
+            return Err(EPERM);
+        }
+        match self.get_fs(attr.ino).remove_xattr(attr.ino, name).await {
+            Ok(()) | Err(FsError::XattrNotFound) => Ok(()),
+            Err(err) => {
+                error!(err = %err);
+                Err(errno(&err))
+            }
+        }
+    }
+
+    /// The ACLs a new node in `parent` gets from its default ACL, as access and default ACL. The
+    /// mode is restricted by them like the umask would do, which is used only when the parent
+    /// has no default ACL.
+    async fn inherit_acls(
+        &self,
+        parent: u64,
+        kind: FileType,
+        perm: &mut u16,
+        umask: u32,
+    ) -> std::result::Result<(Option<Acl>, Option<Acl>), c_int> {
+        let default = self.get_acl(parent, DEFAULT_XATTR).await.map_err(|err| {
+            error!(err = %err, "cannot read default ACL");
+            errno(&err)
+        })?;
+        let Some(default) = default else {
+            #[allow(clippy::cast_possible_truncation)]
+            {
+                *perm &= !(umask & 0o777) as u16;
+            }
+            return Ok((None, None));
+        };
+        let mut access = default.clone();
+        access.create_masq(perm);
+        let access = (!access.is_minimal()).then_some(access);
+        let default = (kind == FileType::Directory).then_some(default);
+        Ok((access, default))
+    }
+
+    /// Store the ACLs a new node got from [`Self::inherit_acls`].
+    async fn set_inherited_acls(
+        &self,
+        ino: u64,
+        (access, default): (Option<Acl>, Option<Acl>),
+    ) -> std::result::Result<(), c_int> {
+        let fs = self.get_fs(ino);
+        for (name, acl) in [(ACCESS_XATTR, access), (DEFAULT_XATTR, default)] {
+            if let Some(acl) = acl {
+                let res = fs
+                    .set_xattr(ino, name, &acl.to_bytes(), SetXattrMode::Any)
+                    .await;
+                self.acls.invalidate(ino);
+                res.map_err(|err| {
+                    error!(err = %err, "cannot set inherited ACL");
+                    errno(&err)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     #[instrument(
         skip(self, name),
         fields(name = name.to_str().unwrap()),
@@ -161,27 +471,23 @@ impl Fuse3 {
         &self,
         parent: u64,
         mut mode: u32,
+        rdev: u32,
         req: &Request,
         name: &OsStr,
         read: bool,
         write: bool,
     ) -> std::result::Result<(u64, FileAttr), c_int> {
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        self.check_name_len(name)?;
+
+        let parent_attr = match self.get_fs(parent).get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
-                return Err(ENOENT);
+                return Err(errno(&err));
             }
             Ok(parent_attr) => parent_attr,
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, req, libc::W_OK).await {
             return Err(EACCES);
         }
 
@@ -190,33 +496,34 @@ impl Fuse3 {
         }
 
         let kind = as_file_kind(mode);
-        let mut attr = if kind == FileType::Directory {
-            dir_attr()
-        } else {
-            file_attr()
+        let mut attr = match kind {
+            FileType::Directory => dir_attr(),
+            FileType::RegularFile => file_attr(),
+            _ => CreateFileAttr {
+                kind,
+                ..file_attr()
+            },
         };
+        if matches!(kind, FileType::CharDevice | FileType::BlockDevice) {
+            attr.rdev = rdev;
+        }
         attr.perm = self.creation_mode(mode);
         attr.uid = req.uid;
         attr.gid = creation_gid(&parent_attr, req.gid);
+        // only mkdir gets the umask, the kernel doesn't apply it as we handle default ACLs
+        let acls = self
+            .inherit_acls(parent, kind, &mut attr.perm, get_umask(req.pid))
+            .await?;
 
         let (fh, attr) = self
-            .get_fs()
+            .get_fs(parent)
             .create(parent, name.to_str().unwrap(), attr, read, write)
             .await
             .map_err(|err| {
                 error!(err = %err);
-                match err {
-                    FsError::AlreadyExists => EEXIST,
-                    FsError::Io { source, .. } => {
-                        if source.to_string().to_lowercase().contains("too long") {
-                            ENAMETOOLONG
-                        } else {
-                            EIO
-                        }
-                    }
-                    _ => EIO,
-                }
+                errno(&err)
             })?;
+        self.set_inherited_acls(attr.ino, acls).await?;
         Ok((fh, attr))
     }
 }
@@ -229,6 +536,20 @@ const fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
     gid
 }
 
+impl From<FileType> for fuse3::raw::prelude::FileType {
+    fn from(from: FileType) -> Self {
+        match from {
+            FileType::Directory => Self::Directory,
+            FileType::RegularFile => Self::RegularFile,
+            FileType::Symlink => Self::Symlink,
+            FileType::NamedPipe => Self::NamedPipe,
+            FileType::CharDevice => Self::CharDevice,
+            FileType::BlockDevice => Self::BlockDevice,
+            FileType::Socket => Self::Socket,
+        }
+    }
+}
+
 impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
     fn from(from: FileAttr) -> Self {
         Self {
@@ -238,11 +559,7 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
             atime: from.atime.into(),
             mtime: from.mtime.into(),
             ctime: from.ctime.into(),
-            kind: if from.kind == FileType::Directory {
-                fuse3::raw::prelude::FileType::Directory
-            } else {
-                fuse3::raw::prelude::FileType::RegularFile
-            },
+            kind: from.kind.into(),
             perm: from.perm,
             nlink: from.nlink,
             uid: from.uid,
@@ -277,55 +594,72 @@ impl Filesystem for Fuse3 {
     async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
         trace!("");
 
-        // if name.len() > MAX_NAME_LENGTH as usize {
-        //     warn!(name = %name.to_str().unwrap(), "name too long");
-        //     return Err(ENAMETOOLONG.into());
-        // }
+        self.check_name_len(name)?;
 
-        match self.get_fs().get_attr(parent).await {
+        let parent_attr = match self.get_fs(parent).get_attr(parent).await {
             Err(err) => {
                 error!(parent, err = %err, "not found");
-                return Err(ENOENT.into());
-            }
-            Ok(parent_attr) => {
-                if !check_access(
-                    parent_attr.uid,
-                    parent_attr.gid,
-                    parent_attr.perm,
-                    req.uid,
-                    req.gid,
-                    libc::X_OK,
-                ) {
-                    return Err(EACCES.into());
-                }
+                return Err(errno(&err).into());
             }
+            Ok(parent_attr) => parent_attr,
+        };
+        if !self.has_access(&parent_attr, &req, libc::X_OK).await {
+            return Err(EACCES.into());
         }
 
-        let attr = match self
-            .get_fs()
-            .find_by_name(parent, name.to_str().unwrap())
-            .await
-        {
+        let res = if self.is_snapshots_dir(parent, name) {
+            self.get_fs(SNAPSHOTS_INODE)
+                .get_attr(SNAPSHOTS_INODE)
+                .await
+                .map(Some)
+        } else {
+            self.get_fs(parent)
+                .find_by_name(parent, name.to_str().unwrap())
+                .await
+        };
+        let attr = match res {
             Ok(Some(attr)) => attr,
             Err(err) => {
                 error!(err = %err);
-                return Err(ENOENT.into());
+                return Err(errno(&err).into());
             }
-            _ => {
+            Ok(None) if !self.ttl.negative.is_zero() => {
+                // inode 0 is a name which doesn't exist, the kernel keeps it for the TTL, the
+                // attributes are not used
+                return Ok(ReplyEntry {
+                    ttl: self.ttl.negative,
+                    attr: FileAttr {
+                        ino: 0,
+                        ..parent_attr
+                    }
+                    .into(),
+                    generation: 0,
+                });
+            }
+            Ok(None) => {
                 return Err(ENOENT.into());
             }
         };
 
         Ok(ReplyEntry {
-            ttl: TTL,
+            ttl: self.entry_ttl(),
             attr: attr.into(),
-            generation: 0,
+            generation: self.add_lookup(attr.ino),
         })
     }
 
     #[instrument(skip(self))]
     async fn forget(&self, req: Request, inode: Inode, nlookup: u64) {
         trace!("");
+        self.forget_lookups(inode, nlookup).await;
+    }
+
+    #[instrument(skip(self))]
+    async fn batch_forget(&self, req: Request, inodes: &[(Inode, u64)]) {
+        trace!("");
+        for &(inode, nlookup) in inodes {
+            self.forget_lookups(inode, nlookup).await;
+        }
     }
 
     #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
@@ -338,13 +672,13 @@ impl Filesystem for Fuse3 {
     ) -> Result<ReplyAttr> {
         trace!("");
 
-        match self.get_fs().get_attr(inode).await {
+        match self.get_attr(inode).await {
             Err(err) => {
                 error!(err = %err);
-                return Err(ENOENT.into());
+                return Err(errno(&err).into());
             }
             Ok(attr) => Ok(ReplyAttr {
-                ttl: TTL,
+                ttl: self.ttl.attr,
                 attr: attr.into(),
             }),
         }
@@ -362,15 +696,16 @@ impl Filesystem for Fuse3 {
         trace!("");
         debug!("{set_attr:#?}");
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+        let attr = self.get_fs(inode).get_attr(inode).await.map_err(|err| {
             error!(err = %err);
-            Errno::from(ENOENT)
+            Errno::from(errno(&err))
         })?;
 
         let mut set_attr2 = SetFileAttr::default();
 
         if let Some(mode) = set_attr.mode {
             debug!("chmod mode={mode:o}");
+            let mode = mode & !libc::S_IFMT;
             let mut set_attr2 = SetFileAttr::default();
             if req.uid != 0 && req.uid != attr.uid {
                 return Err(EPERM.into());
@@ -383,20 +718,36 @@ impl Filesystem for Fuse3 {
                 set_attr2 = set_attr2.with_perm(mode as u16);
             }
             set_attr2 = set_attr2.with_atime(SystemTime::now());
-            self.get_fs()
+            self.get_fs(inode)
                 .set_attr(inode, set_attr2)
                 .await
                 .map_err(|err| {
                     error!(err = %err);
-                    Errno::from(EIO)
+                    Errno::from(errno(&err))
+                })?;
+            // the ACL follows the new mode bits
+            let acl = self.get_acl(inode, ACCESS_XATTR).await.map_err(|err| {
+                error!(err = %err);
+                Errno::from(errno(&err))
+            })?;
+            if let Some(mut acl) = acl {
+                acl.chmod(mode as u16);
+                let res = self
+                    .get_fs(inode)
+                    .set_xattr(inode, ACCESS_XATTR, &acl.to_bytes(), SetXattrMode::Any)
+                    .await;
+                self.acls.invalidate(inode);
+                res.map_err(|err| {
+                    error!(err = %err);
+                    Errno::from(errno(&err))
                 })?;
+            }
             return Ok(ReplyAttr {
-                ttl: TTL,
+                ttl: self.ttl.attr,
                 attr: self
-                    .get_fs()
                     .get_attr(inode)
                     .await
-                    .map_err(|_err| Errno::from(ENOENT))?
+                    .map_err(|err| Errno::from(errno(&err)))?
                     .into(),
             });
         }
@@ -404,13 +755,13 @@ impl Filesystem for Fuse3 {
         if set_attr.uid.is_some() || set_attr.gid.is_some() {
             debug!(?set_attr.uid, ?set_attr.gid, "chown");
             let mut set_attr2 = SetFileAttr::default();
-            if let Some(gid) = set_attr2.gid {
+            if let Some(gid) = set_attr.gid {
                 // Non-root users can only change gid to a group they're in
                 if req.uid != 0 && !get_groups(req.pid).contains(&gid) {
                     return Err(EPERM.into());
                 }
             }
-            if let Some(uid) = set_attr2.uid {
+            if let Some(uid) = set_attr.uid {
                 if req.uid != 0
                     // but no-op changes by the owner are not an error
                     && !(uid == attr.uid && req.uid == attr.uid)
@@ -419,7 +770,7 @@ impl Filesystem for Fuse3 {
                 }
             }
             // Only owner may change the group
-            if set_attr2.gid.is_some() && req.uid != 0 && req.uid != attr.uid {
+            if set_attr.gid.is_some() && req.uid != 0 && req.uid != attr.uid {
                 return Err(EPERM.into());
             }
 
@@ -429,13 +780,13 @@ impl Filesystem for Fuse3 {
                 set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
             }
 
-            if let Some(uid) = set_attr2.uid {
+            if let Some(uid) = set_attr.uid {
                 set_attr2 = set_attr2.with_uid(uid);
                 // Clear SETUID on owner change
                 let perm = *set_attr2.perm.as_ref().unwrap();
                 set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISUID as u16));
             }
-            if let Some(gid) = set_attr2.gid {
+            if let Some(gid) = set_attr.gid {
                 set_attr2 = set_attr2.with_gid(gid);
                 // Clear SETGID unless user is root
                 if req.uid != 0 {
@@ -444,20 +795,19 @@ impl Filesystem for Fuse3 {
                 }
             }
             set_attr2 = set_attr2.with_atime(SystemTime::now());
-            self.get_fs()
+            self.get_fs(inode)
                 .set_attr(inode, set_attr2)
                 .await
                 .map_err(|err| {
                     error!(err = %err);
-                    Errno::from(EIO)
+                    Errno::from(errno(&err))
                 })?;
             return Ok(ReplyAttr {
-                ttl: TTL,
+                ttl: self.ttl.attr,
                 attr: self
-                    .get_fs()
                     .get_attr(inode)
                     .await
-                    .map_err(|_err| Errno::from(ENOENT))?
+                    .map_err(|err| Errno::from(errno(&err)))?
                     .into(),
             });
         }
@@ -465,10 +815,13 @@ impl Filesystem for Fuse3 {
         if let Some(size) = set_attr.size {
             debug!(size, "truncate");
 
-            self.get_fs().set_len(inode, size).await.map_err(|err| {
-                error!(err = %err);
-                Errno::from(EIO)
-            })?;
+            self.get_fs(inode)
+                .set_len(inode, size)
+                .await
+                .map_err(|err| {
+                    error!(err = %err);
+                    Errno::from(errno(&err))
+                })?;
             set_attr2 = set_attr2.with_size(size);
 
             // Clear SETUID & SETGID on truncate
@@ -478,9 +831,7 @@ impl Filesystem for Fuse3 {
         if let Some(atime) = set_attr.atime {
             debug!(?atime, "utimens");
 
-            if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-            {
+            if attr.uid != req.uid && !self.has_access(&attr, &req, libc::W_OK).await {
                 return Err(EACCES.into());
             }
 
@@ -491,9 +842,7 @@ impl Filesystem for Fuse3 {
         if let Some(mtime) = set_attr.mtime {
             debug!(?mtime, "utimens");
 
-            if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-            {
+            if attr.uid != req.uid && !self.has_access(&attr, &req, libc::W_OK).await {
                 return Err(EACCES.into());
             }
 
@@ -501,25 +850,92 @@ impl Filesystem for Fuse3 {
             set_attr2 = set_attr2.with_ctime(SystemTime::now());
         }
 
-        self.get_fs()
+        self.get_fs(inode)
             .set_attr(inode, set_attr2)
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(EIO)
+                Errno::from(errno(&err))
             })?;
 
         Ok(ReplyAttr {
-            ttl: TTL,
+            ttl: self.ttl.attr,
             attr: self
-                .get_fs()
                 .get_attr(inode)
                 .await
-                .map_err(|_err| Errno::from(ENOENT))?
+                .map_err(|err| Errno::from(errno(&err)))?
                 .into(),
         })
     }
 
+    #[instrument(skip(self), err(level = Level::ERROR))]
+    async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
+        trace!("");
+
+        match self.get_fs(inode).read_link(inode).await {
+            Err(err) => {
+                error!(err = %err);
+                Err(errno(&err).into())
+            }
+            Ok(target) => Ok(ReplyData {
+                data: Bytes::from(target.into_bytes()),
+            }),
+        }
+    }
+
+    #[instrument(
+        skip(self, name, link),
+        fields(name = %name.to_string_lossy(), link = ?link),
+        err(level = Level::ERROR),
+        ret(level = Level::DEBUG)
+    )]
+    async fn symlink(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        self.check_name_len(name)?;
+        let name = name.to_str().ok_or(EINVAL)?;
+        let Some(target) = link.to_str() else {
+            warn!("target is not valid UTF-8");
+            return Err(EINVAL.into());
+        };
+
+        let parent_attr = match self.get_fs(parent).get_attr(parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(errno(&err).into());
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
+            return Err(EACCES.into());
+        }
+
+        let mut attr = symlink_attr();
+        attr.uid = req.uid;
+        attr.gid = creation_gid(&parent_attr, req.gid);
+
+        let attr = self
+            .get_fs(parent)
+            .symlink(parent, name, target, attr)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                Errno::from(errno(&err))
+            })?;
+        Ok(ReplyEntry {
+            ttl: self.entry_ttl(),
+            attr: attr.into(),
+            generation: self.add_lookup(attr.ino),
+        })
+    }
+
     #[instrument(
         skip(self, name),
         fields(name = name.to_str().unwrap()),
@@ -539,16 +955,12 @@ impl Filesystem for Fuse3 {
 
         let file_type = mode & libc::S_IFMT;
 
-        if file_type != libc::S_IFREG
-            // && file_type != libc::S_IFLNK as u32
-            && file_type != libc::S_IFDIR
-        {
-            // TODO
-            warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
-            return Err(libc::ENOSYS.into());
+        if file_type == libc::S_IFLNK {
+            // symbolic links need a target, they are created with `symlink`
+            return Err(libc::EINVAL.into());
         }
 
-        self.create_nod(parent, mode, &req, name, false, false)
+        self.create_nod(parent, mode, rdev, &req, name, false, false)
             .await
             .map_err(|err| {
                 error!(err = %err);
@@ -556,9 +968,9 @@ impl Filesystem for Fuse3 {
             })
             .map(|(_, attr)| {
                 Ok(ReplyEntry {
-                    ttl: TTL,
+                    ttl: self.entry_ttl(),
                     attr: attr.into(),
-                    generation: 0,
+                    generation: self.add_lookup(attr.ino),
                 })
             })?
     }
@@ -580,22 +992,17 @@ impl Filesystem for Fuse3 {
         trace!("");
         debug!("mode={mode:o}");
 
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        self.check_name_len(name)?;
+
+        let parent_attr = match self.get_fs(parent).get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
-                return Err(ENOENT.into());
+                return Err(errno(&err).into());
             }
             Ok(parent_attr) => parent_attr,
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
@@ -613,19 +1020,23 @@ impl Filesystem for Fuse3 {
 
         attr.uid = req.uid;
         attr.gid = creation_gid(&parent_attr, req.gid);
+        let acls = self
+            .inherit_acls(parent, FileType::Directory, &mut attr.perm, umask)
+            .await?;
 
         let (_, attr) = self
-            .get_fs()
+            .get_fs(parent)
             .create(parent, name.to_str().unwrap(), attr, false, false)
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                Errno::from(errno(&err))
             })?;
+        self.set_inherited_acls(attr.ino, acls).await?;
         Ok(ReplyEntry {
-            ttl: TTL,
+            ttl: self.entry_ttl(),
             attr: attr.into(),
-            generation: 0,
+            generation: self.add_lookup(attr.ino),
         })
     }
 
@@ -638,34 +1049,29 @@ impl Filesystem for Fuse3 {
     async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
 
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        self.check_name_len(name)?;
+
+        let parent_attr = match self.get_fs(parent).get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
-                return Err(ENOENT.into());
+                return Err(errno(&err).into());
             }
             Ok(attr) => attr,
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
         let attr = match self
-            .get_fs()
+            .get_fs(parent)
             .find_by_name(parent, name.to_str().unwrap())
             .await
         {
             Ok(Some(attr)) => attr,
             Err(err) => {
                 error!(err = %err);
-                return Err(ENOENT.into());
+                return Err(errno(&err).into());
             }
             _ => return Err(ENOENT.into()),
         };
@@ -682,12 +1088,12 @@ impl Filesystem for Fuse3 {
         }
 
         if let Err(err) = self
-            .get_fs()
+            .get_fs(parent)
             .remove_file(parent, name.to_str().unwrap())
             .await
         {
             error!(err = %err);
-            return Err(ENOENT.into());
+            return Err(errno(&err).into());
         }
 
         Ok(())
@@ -702,29 +1108,31 @@ impl Filesystem for Fuse3 {
     async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
 
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
-            error!(parent, "not found");
-            return Err(ENOENT.into());
+        self.check_name_len(name)?;
+
+        let parent_attr = match self.get_fs(parent).get_attr(parent).await {
+            Err(err) => {
+                error!(parent, err = %err, "not found");
+                return Err(errno(&err).into());
+            }
+            Ok(attr) => attr,
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
-        let Ok(Some(attr)) = self
-            .get_fs()
+        let attr = match self
+            .get_fs(parent)
             .find_by_name(parent, name.to_str().unwrap())
             .await
-        else {
-            error!(parent, name = name.to_str().unwrap());
-            return Err(ENOENT.into());
+        {
+            Ok(Some(attr)) => attr,
+            Err(err) => {
+                error!(err = %err);
+                return Err(errno(&err).into());
+            }
+            Ok(None) => return Err(ENOENT.into()),
         };
 
         if attr.kind != FileType::Directory {
@@ -743,15 +1151,12 @@ impl Filesystem for Fuse3 {
         }
 
         if let Err(err) = self
-            .get_fs()
+            .get_fs(parent)
             .remove_dir(parent, name.to_str().unwrap())
             .await
         {
             error!(err = %err);
-            return match err {
-                FsError::NotEmpty => Err(EISDIR.into()),
-                _ => Err(EIO.into()),
-            };
+            return Err(errno(&err).into());
         }
 
         Ok(())
@@ -770,32 +1175,31 @@ impl Filesystem for Fuse3 {
     ) -> Result<()> {
         trace!("");
 
-        let Ok(Some(attr)) = self
-            .get_fs()
+        self.check_name_len(name)?;
+        self.check_name_len(new_name)?;
+
+        let attr = match self
+            .get_fs(parent)
             .find_by_name(parent, name.to_str().unwrap())
             .await
-        else {
-            error!(
-                parent,
-                name = name.to_str().unwrap(),
-                new_name = new_name.to_str().unwrap()
-            );
-            return Err(ENOENT.into());
-        };
+        {
+            Ok(Some(attr)) => attr,
+            Err(err) => {
+                error!(err = %err);
+                return Err(errno(&err).into());
+            }
+            Ok(None) => return Err(ENOENT.into()),
+        };
 
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
-            error!(parent, "parent not found");
-            return Err(ENOENT.into());
+        let parent_attr = match self.get_fs(parent).get_attr(parent).await {
+            Err(err) => {
+                error!(parent, err = %err, "parent not found");
+                return Err(errno(&err).into());
+            }
+            Ok(attr) => attr,
         };
 
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
@@ -809,19 +1213,15 @@ impl Filesystem for Fuse3 {
             return Err(EACCES.into());
         }
 
-        let Ok(new_parent_attr) = self.get_fs().get_attr(new_parent).await else {
-            error!(new_parent, "not found");
-            return Err(ENOENT.into());
+        let new_parent_attr = match self.get_fs(new_parent).get_attr(new_parent).await {
+            Err(err) => {
+                error!(new_parent, err = %err, "not found");
+                return Err(errno(&err).into());
+            }
+            Ok(attr) => attr,
         };
 
-        if !check_access(
-            new_parent_attr.uid,
-            new_parent_attr.gid,
-            new_parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
+        if !self.has_access(&new_parent_attr, &req, libc::W_OK).await {
             return Err(EACCES.into());
         }
 
@@ -829,7 +1229,7 @@ impl Filesystem for Fuse3 {
         #[allow(clippy::cast_possible_truncation)]
         if new_parent_attr.perm & libc::S_ISVTX as u16 != 0 {
             if let Ok(Some(new_attrs)) = self
-                .get_fs()
+                .get_fs(new_parent)
                 .find_by_name(new_parent, new_name.to_str().unwrap())
                 .await
             {
@@ -843,13 +1243,16 @@ impl Filesystem for Fuse3 {
         // because that will change the ".." link in it
         if attr.kind == FileType::Directory
             && parent != new_parent
-            && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+            && !self.has_access(&attr, &req, libc::W_OK).await
         {
             return Err(EACCES.into());
         }
 
-        match self
-            .get_fs()
+        if is_snapshot_inode(parent) != is_snapshot_inode(new_parent) {
+            return Err(EXDEV.into());
+        }
+
+        self.get_fs(parent)
             .rename(
                 parent,
                 name.to_str().unwrap(),
@@ -857,11 +1260,59 @@ impl Filesystem for Fuse3 {
                 new_name.to_str().unwrap(),
             )
             .await
-        {
-            Ok(()) => Ok(()),
-            Err(FsError::NotEmpty) => Err(ENOTEMPTY.into()),
-            _ => Err(ENOENT.into()),
+            .map_err(|err| {
+                error!(err = %err);
+                Errno::from(errno(&err))
+            })
+    }
+
+    #[instrument(
+        skip(self, new_name),
+        fields(new_name = %new_name.to_string_lossy()),
+        err(level = Level::ERROR),
+        ret(level = Level::DEBUG)
+    )]
+    async fn link(
+        &self,
+        req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        self.check_name_len(new_name)?;
+        let new_name = new_name.to_str().ok_or(EINVAL)?;
+
+        let new_parent_attr = match self.get_fs(new_parent).get_attr(new_parent).await {
+            Err(err) => {
+                error!(new_parent, err = %err, "not found");
+                return Err(errno(&err).into());
+            }
+            Ok(attr) => attr,
+        };
+
+        if !self.has_access(&new_parent_attr, &req, libc::W_OK).await {
+            return Err(EACCES.into());
+        }
+
+        if is_snapshot_inode(inode) != is_snapshot_inode(new_parent) {
+            return Err(EXDEV.into());
         }
+
+        let attr = self
+            .get_fs(new_parent)
+            .link(inode, new_parent, new_name)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                Errno::from(errno(&err))
+            })?;
+        Ok(ReplyEntry {
+            ttl: self.entry_ttl(),
+            attr: attr.into(),
+            generation: self.add_lookup(attr.ino),
+        })
     }
 
     #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
@@ -894,26 +1345,29 @@ impl Filesystem for Fuse3 {
         let truncate = flags & libc::O_TRUNC as u32 != 0;
         // let _append = flags & libc::O_APPEND as u32 != 0;
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+        let attr = self.get_fs(inode).get_attr(inode).await.map_err(|err| {
             error!(err = %err);
-            EIO
+            errno(&err)
         })?;
         //
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+        if self.has_access(&attr, &req, access_mask).await {
             if truncate {
-                self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
-                    error!(err = %err);
-                    EIO
-                })?;
+                self.get_fs(inode)
+                    .set_len(attr.ino, 0)
+                    .await
+                    .map_err(|err| {
+                        error!(err = %err);
+                        errno(&err)
+                    })?;
             }
             let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
             let fh = self
-                .get_fs()
+                .get_fs(inode)
                 .open(inode, read, write)
                 .await
                 .map_err(|err| {
                     error!(err = %err);
-                    EIO
+                    errno(&err)
                 })?;
             Ok(ReplyOpen {
                 fh,
@@ -934,12 +1388,13 @@ impl Filesystem for Fuse3 {
         size: u32,
     ) -> Result<ReplyData> {
         trace!("");
+        let _running = self.requests.start(req.unique);
 
         let mut buf = vec![0; size as usize];
-        match self.get_fs().read(inode, offset, &mut buf, fh).await {
+        match self.get_fs(inode).read(inode, offset, &mut buf, fh).await {
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(errno(&err).into());
             }
             Ok(len) => Ok(ReplyData {
                 data: Bytes::copy_from_slice(buf[..len].as_ref()),
@@ -959,18 +1414,16 @@ impl Filesystem for Fuse3 {
         flags: u32,
     ) -> Result<ReplyWrite> {
         trace!("");
+        let _running = self.requests.start(req.unique);
         debug!(size = data.len());
 
         let len = self
-            .get_fs()
+            .get_fs(inode)
             .write(inode, offset, data, fh)
             .await
             .map_err(|err| {
                 error!(err = %err);
-                match err {
-                    FsError::MaxFilesizeExceeded(_) => EFBIG,
-                    _ => EIO,
-                }
+                errno(&err)
             })?;
 
         Ok(ReplyWrite {
@@ -982,8 +1435,23 @@ impl Filesystem for Fuse3 {
     #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
     async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
         trace!("");
-        warn!("implementation is a stub");
-        Ok(STATFS)
+
+        // the snapshots are on the live filesystem, so it's the same for all inodes
+        let stat = self.fs.statfs().await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(errno(&err))
+        })?;
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(ReplyStatFs {
+            blocks: stat.blocks,
+            bfree: stat.bfree,
+            bavail: stat.bavail,
+            files: stat.files,
+            ffree: stat.ffree,
+            bsize: stat.bsize,
+            namelen: self.fs.max_name_len() as u32,
+            frsize: stat.bsize,
+        })
     }
 
     #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
@@ -998,12 +1466,15 @@ impl Filesystem for Fuse3 {
     ) -> Result<()> {
         trace!("");
 
-        let fs = self.get_fs();
+        let fs = self.get_fs(inode);
+
+        // the kernel sets the owner when `flock` locks are released with the file
+        self.flocks.release(inode, lock_owner);
 
         if flush {
             if let Err(err) = fs.flush(fh).await {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(errno(&err).into());
             }
         }
 
@@ -1011,13 +1482,13 @@ impl Filesystem for Fuse3 {
 
         if let Err(err) = fs.release(fh).await {
             error!(err = %err);
-            return Err(EIO.into());
+            return Err(errno(&err).into());
         }
 
         if is_write_handle.await {
             let attr = fs.get_attr(inode).await.map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                Errno::from(errno(&err))
             })?;
             let mut set_attr = SetFileAttr::default();
 
@@ -1026,25 +1497,305 @@ impl Filesystem for Fuse3 {
             set_attr = set_attr.with_perm(clear_suid_sgid(attr.perm));
             fs.set_attr(inode, set_attr).await.map_err(|err| {
                 error!(err = %err, "replace attr");
-                Errno::from(EIO)
+                Errno::from(errno(&err))
             })?;
         }
 
         Ok(())
     }
 
+    #[instrument(
+        skip(self, name, value),
+        fields(name = ?name, len = value.len()),
+        err(level = Level::ERROR)
+    )]
+    async fn setxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+    ) -> Result<()> {
+        trace!("");
+
+        let mode = match flags {
+            0 => SetXattrMode::Any,
+            f if f == libc::XATTR_CREATE as u32 => SetXattrMode::Create,
+            f if f == libc::XATTR_REPLACE as u32 => SetXattrMode::Replace,
+            _ => return Err(EINVAL.into()),
+        };
+        let name = name.to_str().ok_or(Errno::from(EINVAL))?;
+        if inode == ROOT_INODE && name == QUOTA_XATTR {
+            return Err(EPERM.into());
+        }
+        let attr = self.get_attr(inode).await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(errno(&err))
+        })?;
+        if name == ACCESS_XATTR || name == DEFAULT_XATTR {
+            let res = self.set_acl(&attr, &req, name, value).await;
+            self.acls.invalidate(inode);
+            return res.map_err(Errno::from);
+        }
+        self.check_xattr_access(name, &attr, &req, true).await?;
+
+        self.get_fs(inode)
+            .set_xattr(inode, name, value, mode)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                Errno::from(errno(&err))
+            })
+    }
+
+    #[instrument(skip(self, name), fields(name = ?name), err(level = Level::ERROR))]
+    async fn getxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        trace!("");
+
+        // such a name cannot be set
+        let name = name.to_str().ok_or(Errno::from(ENODATA))?;
+        let value = if inode == ROOT_INODE && name == QUOTA_XATTR {
+            let capacity = self.capacity.as_ref().ok_or(Errno::from(ENODATA))?;
+            bincode::serialize(&capacity.quota_status()).map_err(|err| {
+                error!(%err, "cannot serialize quota status");
+                Errno::from(EIO)
+            })?
+        } else {
+            let attr = self.get_attr(inode).await.map_err(|err| {
+                error!(err = %err);
+                Errno::from(errno(&err))
+            })?;
+            // anyone can read the ACLs
+            if name != ACCESS_XATTR && name != DEFAULT_XATTR {
+                self.check_xattr_access(name, &attr, &req, false).await?;
+            }
+            self.get_fs(inode)
+                .get_xattr(inode, name)
+                .await
+                .map_err(|err| {
+                    debug!(err = %err);
+                    Errno::from(errno(&err))
+                })?
+        };
+        #[allow(clippy::cast_possible_truncation)]
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(value.len() as u32));
+        }
+        if value.len() > size as usize {
+            return Err(ERANGE.into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::from(value)))
+    }
+
+    #[instrument(skip(self), err(level = Level::ERROR))]
+    async fn listxattr(&self, req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        trace!("");
+
+        let names = self.get_fs(inode).list_xattr(inode).await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(errno(&err))
+        })?;
+        let mut list = vec![];
+        for name in names {
+            // `trusted.` is only for root, and we don't show what cannot be read
+            if !is_xattr_supported(&name) || (name.starts_with(XATTR_TRUSTED) && req.uid != 0) {
+                continue;
+            }
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        if size == 0 {
+            return Ok(ReplyXAttr::Size(list.len() as u32));
+        }
+        if list.len() > size as usize {
+            return Err(ERANGE.into());
+        }
+        Ok(ReplyXAttr::Data(Bytes::from(list)))
+    }
+
+    #[instrument(skip(self, name), fields(name = ?name), err(level = Level::ERROR))]
+    async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        trace!("");
+
+        let name = name.to_str().ok_or(Errno::from(ENODATA))?;
+        if inode == ROOT_INODE && name == QUOTA_XATTR {
+            return Err(EPERM.into());
+        }
+        let attr = self.get_attr(inode).await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(errno(&err))
+        })?;
+        if name == ACCESS_XATTR || name == DEFAULT_XATTR {
+            let res = self.remove_acl(&attr, &req, name).await;
+            self.acls.invalidate(inode);
+            return res.map_err(Errno::from);
+        }
+        self.check_xattr_access(name, &attr, &req, true).await?;
+
+        self.get_fs(inode)
+            .remove_xattr(inode, name)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                Errno::from(errno(&err))
+            })
+    }
+
     #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
     async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
         trace!("");
+        let _running = self.requests.start(req.unique);
 
-        if let Err(err) = self.get_fs().flush(fh).await {
+        // closing any descriptor of the file releases the POSIX locks of the process on it
+        self.locks.release(inode, lock_owner);
+
+        if let Err(err) = self.get_fs(inode).flush(fh).await {
             error!(err = %err, fh);
-            return Err(EIO.into());
+            return Err(errno(&err).into());
         }
 
         Ok(())
     }
 
+    #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
+    async fn getlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+    ) -> Result<ReplyLock> {
+        trace!("");
+
+        let Some(kind) = lock_kind(r#type)? else {
+            return Err(EINVAL.into());
+        };
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            kind,
+            start,
+            end,
+        };
+        Ok(match self.locks.get(inode, &lock) {
+            Some(other) => ReplyLock {
+                start: other.start,
+                end: other.end,
+                r#type: match other.kind {
+                    LockKind::Shared => libc::F_RDLCK,
+                    LockKind::Exclusive => libc::F_WRLCK,
+                } as u32,
+                pid: other.pid,
+            },
+            None => ReplyLock {
+                start,
+                end,
+                r#type: libc::F_UNLCK as u32,
+                pid: 0,
+            },
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
+    async fn setlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        trace!("");
+
+        let Some(kind) = lock_kind(r#type)? else {
+            self.locks.unlock(inode, lock_owner, start, end);
+            return Ok(());
+        };
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            kind,
+            start,
+            end,
+        };
+        self.locks
+            .set(inode, lock, block, req.unique)
+            .await
+            .map_err(|err| Errno::from(errno(&err)))
+    }
+
+    #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
+    async fn flock(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        trace!("");
+
+        // the owner is the open file, so the descriptors duplicated from it share the lock
+        let Some(kind) = lock_kind(r#type)? else {
+            self.flocks.release(inode, lock_owner);
+            return Ok(());
+        };
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            kind,
+            start: 0,
+            end: u64::MAX,
+        };
+        self.flocks
+            .set(inode, lock, block, req.unique)
+            .await
+            .map_err(|err| Errno::from(errno(&err)))
+    }
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn interrupt(&self, req: Request, unique: u64) -> Result<()> {
+        trace!("");
+
+        // only waits for locks can be interrupted, the other requests we handle finish as usual
+        if self.locks.interrupt(unique)
+            || self.flocks.interrupt(unique)
+            || self.requests.is_running(unique)
+        {
+            return Ok(());
+        }
+        // the request may not have reached us yet, the kernel sends the interrupt again on
+        // `EAGAIN` until the request is done
+        tokio::time::sleep(INTERRUPT_RETRY).await;
+        if self.locks.interrupt(unique)
+            || self.flocks.interrupt(unique)
+            || self.requests.is_running(unique)
+        {
+            Ok(())
+        } else {
+            Err(EAGAIN.into())
+        }
+    }
+
     #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
     #[allow(clippy::cast_possible_wrap)]
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
@@ -1066,15 +1817,16 @@ impl Filesystem for Fuse3 {
             }
         };
 
-        let attr = match self.get_fs().get_attr(inode).await {
+        let attr = match self.get_fs(inode).get_attr(inode).await {
             Err(err) => {
                 error!(err = %err);
-                return Err(ENOENT.into());
+                return Err(errno(&err).into());
             }
             Ok(attr) => attr,
         };
 
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+        if self.has_access(&attr, &req, access_mask).await {
+            self.get_fs(inode).opened_dir(inode);
             let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
             Ok(ReplyOpen {
                 fh: 0, // we don't use handles for directories
@@ -1085,7 +1837,7 @@ impl Filesystem for Fuse3 {
         }
     }
 
-    type DirEntryStream<'a> = Iter<Skip<DirectoryEntryIterator>> where Self: 'a;
+    type DirEntryStream<'a> = BoxStream<'a, Result<DirectoryEntry>> where Self: 'a;
 
     #[instrument(skip(self), err(level = Level::ERROR))]
     async fn readdir(
@@ -1098,19 +1850,35 @@ impl Filesystem for Fuse3 {
         trace!("");
 
         #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir(inode).await {
+        let cookie = offset as u64;
+        let with_snapshots = inode == ROOT_INODE && self.snapshots.is_some();
+        if with_snapshots && cookie == SNAPSHOTS_OFFSET {
+            return Ok(ReplyDirectory {
+                entries: stream::empty().boxed(),
+            });
+        }
+        let mut entries = match self.get_fs(inode).read_dir(inode, cookie).await {
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(errno(&err).into());
             }
-            Ok(iter) => iter,
+            Ok(entries) => entries,
         };
-        let iter = DirectoryEntryIterator(iter, 0);
+        if with_snapshots {
+            entries = entries
+                .chain(stream::once(future::ready(Ok(
+                    crate::fs_model::DirectoryEntry {
+                        ino: SNAPSHOTS_INODE,
+                        name: SNAPSHOTS_DIR.to_string(),
+                        kind: FileType::Directory,
+                        cookie: SNAPSHOTS_OFFSET,
+                    },
+                ))))
+                .boxed();
+        }
 
         Ok(ReplyDirectory {
-            #[allow(clippy::cast_possible_truncation)]
-            #[allow(clippy::cast_sign_loss)]
-            entries: stream::iter(iter.skip(offset as usize)),
+            entries: entries.map(dir_entry).boxed(),
         })
     }
 
@@ -1118,6 +1886,8 @@ impl Filesystem for Fuse3 {
     async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
         trace!("");
 
+        self.get_fs(inode).released_dir(inode);
+
         Ok(())
     }
 
@@ -1125,17 +1895,17 @@ impl Filesystem for Fuse3 {
     async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
         trace!("");
 
-        self.get_fs().get_attr(inode).await.map_or_else(
-            |_| Err(ENOENT.into()),
-            |attr| {
-                #[allow(clippy::cast_possible_wrap)]
-                if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
-                    Ok(())
-                } else {
-                    Err(EACCES.into())
-                }
-            },
-        )
+        let attr = self
+            .get_fs(inode)
+            .get_attr(inode)
+            .await
+            .map_err(|err| Errno::from(errno(&err)))?;
+        #[allow(clippy::cast_possible_wrap)]
+        if self.has_access(&attr, &req, mask as i32).await {
+            Ok(())
+        } else {
+            Err(EACCES.into())
+        }
     }
 
     #[instrument(
@@ -1166,22 +1936,57 @@ impl Filesystem for Fuse3 {
         };
 
         let (handle, attr) = self
-            .create_nod(parent, mode, &req, name, read, write)
+            .create_nod(parent, mode, 0, &req, name, read, write)
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                Errno::from(err)
             })?;
         Ok(ReplyCreated {
-            ttl: TTL,
+            ttl: self.entry_ttl(),
             attr: attr.into(),
-            generation: 0,
+            generation: self.add_lookup(attr.ino),
             fh: handle,
             flags: 0,
         })
     }
 
-    type DirEntryPlusStream<'a> = Iter<Skip<DirectoryEntryPlusIterator>> where Self: 'a;
+    #[instrument(skip(self), err(level = Level::ERROR))]
+    #[allow(clippy::cast_sign_loss)]
+    async fn fallocate(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        trace!("");
+        let _running = self.requests.start(req.unique);
+
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE as u32 != 0;
+        let mode = match mode & !(libc::FALLOC_FL_KEEP_SIZE as u32) {
+            0 => FallocateMode::Allocate { keep_size },
+            // a hole can only be punched keeping the size
+            flags if flags == libc::FALLOC_FL_PUNCH_HOLE as u32 && keep_size => {
+                FallocateMode::PunchHole
+            }
+            flags if flags == libc::FALLOC_FL_ZERO_RANGE as u32 => {
+                FallocateMode::ZeroRange { keep_size }
+            }
+            _ => return Err(EOPNOTSUPP.into()),
+        };
+        self.get_fs(inode)
+            .fallocate(inode, offset, length, mode, fh)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                errno(&err).into()
+            })
+    }
+
+    type DirEntryPlusStream<'a> = DirectoryEntryPlusStream<'a> where Self: 'a;
 
     #[instrument(skip(self), err(level = Level::ERROR))]
     async fn readdirplus(
@@ -1194,22 +1999,73 @@ impl Filesystem for Fuse3 {
     ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
         trace!("");
 
-        #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir_plus(parent).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(EIO.into());
+        let with_snapshots = parent == ROOT_INODE && self.snapshots.is_some();
+        let mut entries = if with_snapshots && offset == SNAPSHOTS_OFFSET {
+            stream::empty().boxed()
+        } else {
+            match self.get_fs(parent).read_dir_plus(parent, offset).await {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(errno(&err).into());
+                }
+                Ok(entries) => entries,
             }
-            Ok(iter) => iter,
         };
-        let iter = DirectoryEntryPlusIterator(iter, 0);
+        if with_snapshots && offset != SNAPSHOTS_OFFSET {
+            let snapshots = self.get_fs(SNAPSHOTS_INODE);
+            entries = entries
+                .chain(stream::once(async move {
+                    snapshots.get_attr(SNAPSHOTS_INODE).await.map(|attr| {
+                        crate::fs_model::DirectoryEntryPlus {
+                            ino: SNAPSHOTS_INODE,
+                            name: SNAPSHOTS_DIR.to_string(),
+                            kind: FileType::Directory,
+                            attr,
+                            cookie: SNAPSHOTS_OFFSET,
+                        }
+                    })
+                }))
+                .boxed();
+        }
 
         Ok(ReplyDirectoryPlus {
-            #[allow(clippy::cast_possible_truncation)]
-            entries: stream::iter(iter.skip(offset as usize)),
+            entries: DirectoryEntryPlusStream {
+                entries,
+                fuse: self,
+                pending: None,
+            },
         })
     }
 
+    #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_possible_wrap)]
+    async fn lseek(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<ReplyLSeek> {
+        trace!("");
+
+        // the kernel handles the other ones itself
+        let whence = match whence as i32 {
+            libc::SEEK_DATA => Whence::Data,
+            libc::SEEK_HOLE => Whence::Hole,
+            _ => return Err(EINVAL.into()),
+        };
+        match self.get_fs(inode).seek(inode, offset, whence).await {
+            Err(err) => {
+                error!(err = %err);
+                Err(errno(&err).into())
+            }
+            // nothing after it
+            Ok(None) => Err(ENXIO.into()),
+            Ok(Some(offset)) => Ok(ReplyLSeek { offset }),
+        }
+    }
+
     #[instrument(skip(self), err(level = Level::ERROR), ret(level = Level::DEBUG))]
     async fn copy_file_range(
         &self,
@@ -1224,10 +2080,15 @@ impl Filesystem for Fuse3 {
         flags: u64,
     ) -> Result<ReplyCopyFileRange> {
         trace!("");
+        let _running = self.requests.start(req.unique);
+
+        if is_snapshot_inode(inode) != is_snapshot_inode(inode_out) {
+            return Err(EXDEV.into());
+        }
 
         #[allow(clippy::cast_possible_truncation)]
         match self
-            .get_fs()
+            .get_fs(inode)
             .copy_file_range(
                 inode,
                 off_in,
@@ -1241,27 +2102,88 @@ impl Filesystem for Fuse3 {
         {
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(errno(&err).into());
             }
             Ok(len) => Ok(ReplyCopyFileRange { copied: len as u64 }),
         }
     }
 }
 
+/// The errno we reply with for an error of the filesystem, the errors from the host keep theirs.
+fn errno(err: &FsError) -> c_int {
+    match err {
+        FsError::Io { source, .. } => source.raw_os_error().unwrap_or(EIO),
+        FsError::NotFound(_) | FsError::InodeNotFound => ENOENT,
+        FsError::InvalidInput(_) | FsError::InvalidInodeType => EINVAL,
+        FsError::DirectoryLink => EPERM,
+        FsError::NotARegularFile => ENODEV,
+        FsError::InvalidFileHandle => EBADF,
+        FsError::AlreadyExists => EEXIST,
+        FsError::AlreadyOpenForWrite => EBUSY,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::MaxFilesizeExceeded(_) => EFBIG,
+        FsError::ReadOnly => EROFS,
+        FsError::CrossDevice => EXDEV,
+        FsError::XattrNotFound => ENODATA,
+        FsError::NoSpace => ENOSPC,
+        FsError::QuotaExceeded => EDQUOT,
+        FsError::PermissionDenied => EACCES,
+        FsError::NameTooLong => ENAMETOOLONG,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::IsADirectory => EISDIR,
+        FsError::WouldBlock => EAGAIN,
+        FsError::Deadlock => EDEADLK,
+        FsError::Interrupted => EINTR,
+        FsError::SerializeError { .. }
+        | FsError::Other(_)
+        | FsError::InvalidPassword
+        | FsError::Encryption(_)
+        | FsError::Compression(_)
+        | FsError::InvalidDataDirStructure
+        | FsError::ParseIntError { .. }
+        | FsError::JoinError { .. } => EIO,
+    }
+}
+
+/// The umask of a process, for the requests which don't have it.
+fn get_umask(pid: u32) -> u32 {
+    #[cfg(not(target_os = "macos"))]
+    {
+        let path = format!("/proc/{pid}/task/{pid}/status");
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines().map_while(io::Result::ok) {
+                if let Some(umask) = line.strip_prefix("Umask:") {
+                    if let Ok(umask) = u32::from_str_radix(umask.trim(), 8) {
+                        return umask;
+                    }
+                }
+            }
+        }
+    }
+
+    0o022
+}
+
+/// The supplementary groups of the process, none if we cannot read them, it may be gone already.
 fn get_groups(pid: u32) -> Vec<u32> {
     #[cfg(not(target_os = "macos"))]
     {
         let path = format!("/proc/{pid}/task/{pid}/status");
-        let file = File::open(path).unwrap();
-        for line in BufReader::new(file).lines() {
-            let line = line.unwrap();
-            if line.starts_with("Groups:") {
-                return line["Groups: ".len()..]
-                    .split(' ')
-                    .filter(|x| !x.trim().is_empty())
-                    .map(|x| x.parse::<u32>().unwrap())
-                    .collect();
+        let groups = File::open(path).and_then(|file| {
+            for line in BufReader::new(file).lines() {
+                if let Some(groups) = line?.strip_prefix("Groups:") {
+                    return groups
+                        .split_whitespace()
+                        .map(str::parse::<u32>)
+                        .collect::<std::result::Result<_, _>>()
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+                }
             }
+            Ok(Vec::new())
+        });
+        match groups {
+            Ok(groups) => return groups,
+            Err(err) => warn!(pid, %err, "cannot read the groups of the process"),
         }
     }
 
@@ -1283,12 +2205,21 @@ fn as_file_kind(mut mode: u32) -> FileType {
 
     if mode == libc::S_IFREG {
         FileType::RegularFile
-        // } else if mode == libc::S_IFLNK as u32 {
-        //     return FileType::Symlink;
+    } else if mode == libc::S_IFLNK {
+        FileType::Symlink
     } else if mode == libc::S_IFDIR {
         FileType::Directory
+    } else if mode == libc::S_IFIFO {
+        FileType::NamedPipe
+    } else if mode == libc::S_IFCHR {
+        FileType::CharDevice
+    } else if mode == libc::S_IFBLK {
+        FileType::BlockDevice
+    } else if mode == libc::S_IFSOCK {
+        FileType::Socket
     } else {
-        unimplemented!("{mode}");
+        // the kernel checks the type, no type bits means a regular file like for mknod(2)
+        FileType::RegularFile
     }
 }
 
@@ -1303,6 +2234,18 @@ const fn dir_attr() -> CreateFileAttr {
     }
 }
 
+/// The permissions of a symbolic link are not used, they are always shown as `rwxrwxrwx`.
+const fn symlink_attr() -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::Symlink,
+        perm: 0o777,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
 const fn file_attr() -> CreateFileAttr {
     CreateFileAttr {
         kind: FileType::RegularFile,
@@ -1349,6 +2292,25 @@ fn check_access(
     access_mask == 0
 }
 
+fn is_xattr_supported(name: &str) -> bool {
+    name == ACCESS_XATTR
+        || name == DEFAULT_XATTR
+        || [XATTR_USER, XATTR_TRUSTED, XATTR_SECURITY]
+            .iter()
+            .any(|namespace| name.starts_with(namespace))
+}
+
+/// The kind of lock for `F_RDLCK` and `F_WRLCK`, `None` for `F_UNLCK`.
+#[allow(clippy::cast_possible_wrap)]
+fn lock_kind(r#type: u32) -> Result<Option<LockKind>> {
+    match r#type as i32 {
+        libc::F_RDLCK => Ok(Some(LockKind::Shared)),
+        libc::F_WRLCK => Ok(Some(LockKind::Exclusive)),
+        libc::F_UNLCK => Ok(None),
+        _ => Err(EINVAL.into()),
+    }
+}
+
 #[allow(clippy::cast_sign_loss)]
 fn system_time_from_timestamp(t: Timestamp) -> SystemTime {
     UNIX_EPOCH + Duration::new(t.sec as u64, t.nsec)
@@ -1357,6 +2319,14 @@ fn system_time_from_timestamp(t: Timestamp) -> SystemTime {
 #[allow(clippy::struct_excessive_bools)]
 pub struct MountPointImpl {
     mountpoint: PathBuf,
+    backend: Backend,
+    password_provider: Option<Box<dyn PasswordProvider>>,
+    cipher: Cipher,
+    compression: Option<Compression>,
+    dedup: bool,
+    snapshots: bool,
+    capacity: Capacity,
+    ttl: Ttl,
     allow_root: bool,
     allow_other: bool,
     direct_io: bool,
@@ -1367,6 +2337,14 @@ pub struct MountPointImpl {
 impl MountPoint for MountPointImpl {
     fn new(
         mountpoint: PathBuf,
+        backend: Backend,
+        password_provider: Option<Box<dyn PasswordProvider>>,
+        cipher: Cipher,
+        compression: Option<Compression>,
+        dedup: bool,
+        snapshots: bool,
+        capacity: Capacity,
+        ttl: Ttl,
         allow_root: bool,
         allow_other: bool,
         direct_io: bool,
@@ -1374,6 +2352,14 @@ impl MountPoint for MountPointImpl {
     ) -> Self {
         Self {
             mountpoint,
+            backend,
+            password_provider,
+            cipher,
+            compression,
+            dedup,
+            snapshots,
+            capacity,
+            ttl,
             allow_root,
             allow_other,
             direct_io,
@@ -1384,6 +2370,14 @@ impl MountPoint for MountPointImpl {
     async fn mount(mut self) -> FsResult<mount::MountHandle> {
         let handle = mount_fuse(
             self.mountpoint.clone(),
+            &self.backend,
+            self.password_provider.take(),
+            self.cipher,
+            self.compression,
+            self.dedup,
+            self.snapshots,
+            self.capacity.clone(),
+            self.ttl,
             self.allow_root,
             self.allow_other,
             self.direct_io,
@@ -1415,9 +2409,18 @@ impl MountHandleInner for MountHandleInnerImpl {
     }
 }
 
-#[instrument]
+#[instrument(skip(password_provider))]
+#[allow(clippy::too_many_arguments)]
 async fn mount_fuse(
     mountpoint: PathBuf,
+    backend: &Backend,
+    password_provider: Option<Box<dyn PasswordProvider>>,
+    cipher: Cipher,
+    compression: Option<Compression>,
+    dedup: bool,
+    snapshots: bool,
+    capacity: Capacity,
+    ttl: Ttl,
     allow_root: bool,
     allow_other: bool,
     direct_io: bool,
@@ -1431,14 +2434,414 @@ async fn mount_fuse(
         }
     }
     let mount_options = mount_options
-        .read_only(false)
+        .read_only(backend.is_read_only())
         .allow_root(allow_root)
         .allow_other(allow_other)
+        // we apply the umask, it must not be applied when the parent has a default ACL
+        .dont_mask(true)
         .clone();
     let mount_path = OsStr::new(mountpoint.to_str().unwrap());
+    if snapshots && backend.is_changed_outside() {
+        // changes from outside don't keep the content shared with the snapshots
+        return Err(FsError::InvalidInput(
+            "snapshots cannot be taken of files changed from outside",
+        ));
+    }
+
+    let invalidator = Invalidator::default();
+    let mut fs = backend
+        .create_fs(
+            password_provider.as_deref().map(|p| (p, cipher)),
+            compression,
+            dedup,
+            &invalidator,
+        )
+        .await?;
+    let capacity = if capacity.is_limited() {
+        let capacity = CapacityFilesystem::new(fs, capacity).await?;
+        fs = capacity.clone();
+        Some(capacity)
+    } else {
+        None
+    };
+    let snapshots = if snapshots {
+        let snapshots = Snapshots::new(fs, capacity.clone()).await?;
+        fs = SnapshotFilesystem::new(snapshots.clone());
+        Some(snapshots)
+    } else {
+        None
+    };
+
+    info!("Mounting FUSE filesystem");
+    let handle = Session::new(mount_options)
+        .mount_with_unprivileged(
+            Fuse3::new(fs, snapshots, capacity, ttl, direct_io, suid_support),
+            mount_path,
+        )
+        .await?;
+    invalidator.connect(handle.as_fd());
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryFilesystem;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn fuse3(ttl: Ttl) -> Fuse3 {
+        Fuse3::new(MemoryFilesystem::new(), None, None, ttl, false, false)
+    }
+
+    fn req() -> Request {
+        Request {
+            unique: 1,
+            uid: 0,
+            gid: 0,
+            pid: std::process::id(),
+        }
+    }
+
+    #[tokio::test]
+    async fn symlink() {
+        let fuse = fuse3(Ttl::default());
+        let target = "some/where/else";
+        let entry = fuse
+            .symlink(req(), ROOT_INODE, OsStr::new("link"), OsStr::new(target))
+            .await
+            .unwrap();
+        assert_eq!(entry.attr.kind, fuse3::FileType::Symlink);
+        assert_eq!(entry.attr.size, target.len() as u64);
+
+        let reply = fuse.readlink(req(), entry.attr.ino).await.unwrap();
+        assert_eq!(reply.data, target.as_bytes());
+        let reply = fuse.getattr(req(), entry.attr.ino, None, 0).await.unwrap();
+        assert_eq!(reply.attr.size, target.len() as u64);
+        assert_eq!(reply.attr.perm, 0o777);
+        // only symbolic links have a target
+        assert!(matches!(
+            fuse.readlink(req(), ROOT_INODE).await,
+            Err(errno) if errno == EINVAL.into()
+        ));
+        // names which are not UTF-8 are refused
+        assert!(matches!(
+            fuse.symlink(req(), ROOT_INODE, OsStr::from_bytes(b"\xff"), OsStr::new(target)).await,
+            Err(errno) if errno == EINVAL.into()
+        ));
+    }
+
+    #[tokio::test]
+    async fn mknod() {
+        let fuse = fuse3(Ttl::default());
+        let rdev = libc::makedev(8, 1);
+        let nodes = [
+            ("fifo", libc::S_IFIFO, 0, fuse3::FileType::NamedPipe),
+            ("socket", libc::S_IFSOCK, 0, fuse3::FileType::Socket),
+            ("char", libc::S_IFCHR, rdev, fuse3::FileType::CharDevice),
+            ("block", libc::S_IFBLK, rdev, fuse3::FileType::BlockDevice),
+            // no type is a regular file
+            ("file", 0, 0, fuse3::FileType::RegularFile),
+        ];
+        for (name, kind, rdev, expected) in nodes {
+            #[allow(clippy::cast_possible_truncation)]
+            let rdev = rdev as u32;
+            let entry = fuse
+                .mknod(req(), ROOT_INODE, OsStr::new(name), kind | 0o640, rdev)
+                .await
+                .unwrap();
+            let reply = fuse.getattr(req(), entry.attr.ino, None, 0).await.unwrap();
+            assert_eq!(reply.attr.kind, expected, "{name}");
+            assert_eq!(reply.attr.perm, 0o640, "{name}");
+            assert_eq!(reply.attr.rdev, rdev, "{name}");
+        }
+        // symbolic links need a target
+        assert!(matches!(
+            fuse.mknod(req(), ROOT_INODE, OsStr::new("link"), libc::S_IFLNK | 0o777, 0)
+                .await,
+            Err(errno) if errno == EINVAL.into()
+        ));
+    }
+
+    #[tokio::test]
+    async fn xattrs() {
+        let fuse = fuse3(Ttl::default());
+        let name = OsStr::new("user.a");
+        let (create, replace) = (libc::XATTR_CREATE as u32, libc::XATTR_REPLACE as u32);
+        let is = |res: Result<()>, errno: c_int| matches!(res, Err(err) if err == errno.into());
+
+        assert!(is(
+            fuse.setxattr(req(), ROOT_INODE, name, b"a", replace, 0)
+                .await,
+            ENODATA
+        ));
+        fuse.setxattr(req(), ROOT_INODE, name, b"value", create, 0)
+            .await
+            .unwrap();
+        assert!(is(
+            fuse.setxattr(req(), ROOT_INODE, name, b"a", create, 0)
+                .await,
+            EEXIST
+        ));
+        fuse.setxattr(req(), ROOT_INODE, name, b"new value", replace, 0)
+            .await
+            .unwrap();
+
+        // the size is asked first, then the value with a buffer which fits it
+        assert!(matches!(
+            fuse.getxattr(req(), ROOT_INODE, name, 0).await,
+            Ok(ReplyXAttr::Size(9))
+        ));
+        assert!(matches!(
+            fuse.getxattr(req(), ROOT_INODE, name, 9).await,
+            Ok(ReplyXAttr::Data(data)) if data == b"new value"[..]
+        ));
+        assert!(matches!(
+            fuse.getxattr(req(), ROOT_INODE, name, 8).await,
+            Err(errno) if errno == ERANGE.into()
+        ));
+        assert!(matches!(
+            fuse.listxattr(req(), ROOT_INODE, 0).await,
+            Ok(ReplyXAttr::Size(7))
+        ));
+
+        fuse.removexattr(req(), ROOT_INODE, name).await.unwrap();
+        assert!(matches!(
+            fuse.getxattr(req(), ROOT_INODE, name, 0).await,
+            Err(errno) if errno == ENODATA.into()
+        ));
+        assert!(is(fuse.removexattr(req(), ROOT_INODE, name).await, ENODATA));
+    }
+
+    #[tokio::test]
+    async fn fallocate() {
+        let fuse = fuse3(Ttl::default());
+        let created = fuse
+            .create(
+                req(),
+                ROOT_INODE,
+                OsStr::new("a"),
+                libc::S_IFREG | 0o644,
+                libc::O_RDWR as u32,
+            )
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+        fuse.write(req(), ino, fh, 0, &[1; 100], 0, 0)
+            .await
+            .unwrap();
+        let fuse = &fuse;
+        let size =
+            move || async move { fuse.getattr(req(), ino, None, 0).await.unwrap().attr.size };
+        let (keep_size, punch_hole, zero_range) = (
+            libc::FALLOC_FL_KEEP_SIZE as u32,
+            libc::FALLOC_FL_PUNCH_HOLE as u32,
+            libc::FALLOC_FL_ZERO_RANGE as u32,
+        );
+
+        fuse.fallocate(req(), ino, fh, 0, 200, keep_size)
+            .await
+            .unwrap();
+        assert_eq!(size().await, 100);
+        fuse.fallocate(req(), ino, fh, 100, 100, 0).await.unwrap();
+        assert_eq!(size().await, 200);
+        fuse.fallocate(req(), ino, fh, 10, 10, punch_hole | keep_size)
+            .await
+            .unwrap();
+        fuse.fallocate(req(), ino, fh, 90, 20, zero_range | keep_size)
+            .await
+            .unwrap();
+        assert_eq!(size().await, 200);
+        fuse.fallocate(req(), ino, fh, 250, 50, zero_range)
+            .await
+            .unwrap();
+        assert_eq!(size().await, 300);
+        let mut expected = vec![1; 100];
+        expected[10..20].fill(0);
+        expected[90..].fill(0);
+        expected.resize(300, 0);
+        assert_eq!(
+            fuse.read(req(), ino, fh, 0, 1000).await.unwrap().data,
+            expected
+        );
+
+        // a hole can only be punched keeping the size, and the other modes are not supported
+        for mode in [
+            punch_hole,
+            libc::FALLOC_FL_COLLAPSE_RANGE as u32,
+            libc::FALLOC_FL_INSERT_RANGE as u32,
+        ] {
+            assert!(matches!(
+                fuse.fallocate(req(), ino, fh, 0, 10, mode).await,
+                Err(errno) if errno == EOPNOTSUPP.into()
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn flock() {
+        let fuse = fuse3(Ttl::default());
+        let created = fuse
+            .create(
+                req(),
+                ROOT_INODE,
+                OsStr::new("a"),
+                libc::S_IFREG | 0o644,
+                libc::O_RDWR as u32,
+            )
+            .await
+            .unwrap();
+        let (ino, first) = (created.attr.ino, created.fh);
+        let second = fuse.open(req(), ino, libc::O_RDWR as u32).await.unwrap().fh;
+        let (shared, exclusive, unlock) = (
+            libc::F_RDLCK as u32,
+            libc::F_WRLCK as u32,
+            libc::F_UNLCK as u32,
+        );
+        let pid = std::process::id();
+
+        // each open file is an owner, the lock is on the whole file
+        fuse.flock(req(), ino, first, 1, exclusive, pid, false)
+            .await
+            .unwrap();
+        assert!(matches!(
+            fuse.flock(req(), ino, second, 2, shared, pid, false).await,
+            Err(errno) if errno == EAGAIN.into()
+        ));
+        // POSIX locks don't conflict with them
+        fuse.setlk(req(), ino, second, 2, 0, u64::MAX, exclusive, pid, false)
+            .await
+            .unwrap();
 
-    info!("Checking password and mounting FUSE filesystem");
-    Ok(Session::new(mount_options)
-        .mount_with_unprivileged(Fuse3::new(direct_io, suid_support).await?, mount_path)
-        .await?)
+        fuse.flock(req(), ino, first, 1, shared, pid, false)
+            .await
+            .unwrap();
+        fuse.flock(req(), ino, second, 2, shared, pid, false)
+            .await
+            .unwrap();
+        fuse.flock(req(), ino, second, 2, unlock, pid, false)
+            .await
+            .unwrap();
+
+        // closing the file releases its lock, which the one waiting for it gets
+        let (locked, released) = tokio::join!(
+            fuse.flock(req(), ino, second, 2, exclusive, pid, true),
+            fuse.release(req(), ino, first, 0, 1, false),
+        );
+        released.unwrap();
+        locked.unwrap();
+    }
+
+    #[tokio::test]
+    async fn errno_per_variant() {
+        let join = tokio::spawn(future::pending::<()>());
+        join.abort();
+        let errors = [
+            // the host errors keep theirs
+            (io::Error::from_raw_os_error(ENXIO).into(), ENXIO),
+            (io::Error::from(io::ErrorKind::NotFound).into(), EIO),
+            (Box::new(bincode::ErrorKind::SizeLimit).into(), EIO),
+            (FsError::NotFound("a"), ENOENT),
+            (FsError::InodeNotFound, ENOENT),
+            (FsError::InvalidInput("a"), EINVAL),
+            (FsError::InvalidInodeType, EINVAL),
+            (FsError::DirectoryLink, EPERM),
+            (FsError::NotARegularFile, ENODEV),
+            (FsError::InvalidFileHandle, EBADF),
+            (FsError::AlreadyExists, EEXIST),
+            (FsError::AlreadyOpenForWrite, EBUSY),
+            (FsError::NotEmpty, ENOTEMPTY),
+            (FsError::Other("a"), EIO),
+            (FsError::InvalidPassword, EIO),
+            (FsError::Encryption("a"), EIO),
+            (FsError::Compression("a"), EIO),
+            (FsError::InvalidDataDirStructure, EIO),
+            ("a".parse::<u32>().unwrap_err().into(), EIO),
+            (join.await.unwrap_err().into(), EIO),
+            (FsError::MaxFilesizeExceeded(1), EFBIG),
+            (FsError::ReadOnly, EROFS),
+            (FsError::CrossDevice, EXDEV),
+            (FsError::XattrNotFound, ENODATA),
+            (FsError::NoSpace, ENOSPC),
+            (FsError::QuotaExceeded, EDQUOT),
+            (FsError::PermissionDenied, EACCES),
+            (FsError::NameTooLong, ENAMETOOLONG),
+            (FsError::NotADirectory, ENOTDIR),
+            (FsError::IsADirectory, EISDIR),
+            (FsError::WouldBlock, EAGAIN),
+            (FsError::Deadlock, EDEADLK),
+            (FsError::Interrupted, EINTR),
+        ];
+        for (err, expected) in errors {
+            assert_eq!(errno(&err), expected, "{err}");
+        }
+        // the host denying access is the same as us denying it
+        assert!(matches!(
+            io::Error::from_raw_os_error(EACCES).into(),
+            FsError::PermissionDenied
+        ));
+    }
+
+    #[tokio::test]
+    async fn batch_forget() {
+        let fuse = fuse3(Ttl::default());
+        let name = OsStr::new("a");
+        let created = fuse
+            .create(req(), ROOT_INODE, name, libc::S_IFREG | 0o644, 0)
+            .await
+            .unwrap();
+        fuse.release(req(), created.attr.ino, created.fh, 0, 0, false)
+            .await
+            .unwrap();
+        let ino = created.attr.ino;
+        for _ in 0..2 {
+            let entry = fuse.lookup(req(), ROOT_INODE, name).await.unwrap();
+            assert_eq!((entry.attr.ino, entry.generation), (ino, 0));
+        }
+        fuse.unlink(req(), ROOT_INODE, name).await.unwrap();
+
+        // the node is kept until the kernel forgets all three references
+        fuse.batch_forget(req(), &[(ino, 2)]).await;
+        assert!(fuse.getattr(req(), ino, None, 0).await.is_ok());
+        fuse.batch_forget(req(), &[(ino, 1)]).await;
+        assert!(fuse.getattr(req(), ino, None, 0).await.is_err());
+
+        // its number is reused with the next generation
+        let entry = fuse
+            .mknod(req(), ROOT_INODE, OsStr::new("b"), libc::S_IFIFO | 0o644, 0)
+            .await
+            .unwrap();
+        assert_eq!((entry.attr.ino, entry.generation), (ino, 1));
+    }
+
+    #[tokio::test]
+    async fn ttl() {
+        let fuse = fuse3(Ttl {
+            attr: Duration::from_secs(3),
+            entry: Duration::from_secs(4),
+            negative: Duration::from_secs(5),
+        });
+        let missing = OsStr::new("missing");
+        // inode 0 tells the kernel to keep the name as not found
+        let entry = fuse.lookup(req(), ROOT_INODE, missing).await.unwrap();
+        assert_eq!((entry.attr.ino, entry.ttl), (0, Duration::from_secs(5)));
+
+        fuse.mkdir(req(), ROOT_INODE, OsStr::new("dir"), 0o755, 0)
+            .await
+            .unwrap();
+        // the shorter one, as the entry and its attributes get one TTL
+        let entry = fuse
+            .lookup(req(), ROOT_INODE, OsStr::new("dir"))
+            .await
+            .unwrap();
+        assert_eq!(entry.ttl, Duration::from_secs(3));
+        let reply = fuse.getattr(req(), entry.attr.ino, None, 0).await.unwrap();
+        assert_eq!(reply.ttl, Duration::from_secs(3));
+
+        // without a negative TTL it's not found
+        let fuse = fuse3(Ttl::default());
+        assert!(matches!(
+            fuse.lookup(req(), ROOT_INODE, missing).await,
+            Err(errno) if errno == ENOENT.into()
+        ));
+    }
 }
@@ -0,0 +1,353 @@
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::pin::pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::select;
+use tokio::sync::Notify;
+
+use crate::fs_model::{FsError, FsResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockKind {
+    /// `F_RDLCK`, any number of owners can have one on the same range.
+    Shared,
+    /// `F_WRLCK`, only one owner can have a lock on the range.
+    Exclusive,
+}
+
+/// A POSIX lock on the bytes from `start` to `end`, `end` included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Lock {
+    /// The `lock_owner` the kernel gives, one for each table of open files, so the threads of a
+    /// process share their locks.
+    pub owner: u64,
+    pub pid: u32,
+    pub kind: LockKind,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Lock {
+    const fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &Self) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.kind == LockKind::Exclusive || other.kind == LockKind::Exclusive)
+    }
+}
+
+/// A request waiting for a lock.
+struct Wait {
+    owner: u64,
+    /// The owner of the lock in the way, to find deadlocks.
+    other: u64,
+    /// Notified when the request is interrupted.
+    interrupted: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Locks of each inode, an owner has at most one lock on each byte.
+    locks: HashMap<u64, Vec<Lock>>,
+    /// Requests waiting for a lock by their `unique`, an owner can have more of them, from
+    /// different threads.
+    waiting: HashMap<u64, Wait>,
+}
+
+/// Keeps the POSIX locks of the files, by inode and lock owner. Only the processes using the mount
+/// see them, the backend doesn't know about them. The `flock` locks are kept in another one, as
+/// locks on the whole file owned by the open file.
+#[derive(Default)]
+pub(crate) struct LockManager {
+    state: Mutex<State>,
+    /// Notified when locks are released or changed, the blocked requests check again.
+    changed: Notify,
+}
+
+impl LockManager {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("locks lock poisoned")
+    }
+
+    /// The first lock of another owner which is in the way of `lock`.
+    pub fn get(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.state().conflict(ino, lock)
+    }
+
+    /// Take `lock`, it replaces the locks the owner has on the range. If another owner is in the
+    /// way it fails with [`FsError::WouldBlock`], or waits if `block` is set, until the lock can
+    /// be taken, a deadlock is found or the request with `unique` is interrupted.
+    pub async fn set(&self, ino: u64, lock: Lock, block: bool, unique: u64) -> FsResult<()> {
+        loop {
+            let mut changed = pin!(self.changed.notified());
+            // so we don't miss a release between checking and waiting
+            changed.as_mut().enable();
+            let interrupted = {
+                let mut state = self.state();
+                match state.conflict(ino, &lock) {
+                    None => {
+                        state.waiting.remove(&unique);
+                        state.insert(ino, lock);
+                        drop(state);
+                        self.changed.notify_waiters();
+                        return Ok(());
+                    }
+                    Some(_) if !block => return Err(FsError::WouldBlock),
+                    Some(other) => {
+                        if state.would_deadlock(lock.owner, other.owner) {
+                            state.waiting.remove(&unique);
+                            return Err(FsError::Deadlock);
+                        }
+                        let wait = state.waiting.entry(unique).or_insert_with(|| Wait {
+                            owner: lock.owner,
+                            other: other.owner,
+                            interrupted: Arc::default(),
+                        });
+                        wait.other = other.owner;
+                        wait.interrupted.clone()
+                    }
+                }
+            };
+            select! {
+                () = changed => {}
+                () = interrupted.notified() => {
+                    self.state().waiting.remove(&unique);
+                    return Err(FsError::Interrupted);
+                }
+            }
+        }
+    }
+
+    /// Release the locks `owner` has from `start` to `end`, the parts of them outside the range
+    /// are kept.
+    pub fn unlock(&self, ino: u64, owner: u64, start: u64, end: u64) {
+        self.state().remove(ino, owner, start, end);
+        self.changed.notify_waiters();
+    }
+
+    /// Release all the locks of `owner` on the inode, when it closes the file.
+    pub fn release(&self, ino: u64, owner: u64) {
+        self.unlock(ino, owner, 0, u64::MAX);
+    }
+
+    /// Cancel the wait of the request with `unique`, `false` if it's not waiting for a lock.
+    pub fn interrupt(&self, unique: u64) -> bool {
+        let state = self.state();
+        let Some(wait) = state.waiting.get(&unique) else {
+            return false;
+        };
+        // stores a permit if it's not waiting on it right now
+        wait.interrupted.notify_one();
+        true
+    }
+}
+
+impl State {
+    fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|other| other.conflicts(lock))
+            .copied()
+    }
+
+    /// Waiting for `other` is a deadlock if it waits, maybe through others, for `owner`.
+    fn would_deadlock(&self, owner: u64, other: u64) -> bool {
+        let mut seen = HashSet::new();
+        let mut next = vec![other];
+        while let Some(waiting) = next.pop() {
+            if waiting == owner {
+                return true;
+            }
+            if seen.insert(waiting) {
+                next.extend(
+                    self.waiting
+                        .values()
+                        .filter(|wait| wait.owner == waiting)
+                        .map(|wait| wait.other),
+                );
+            }
+        }
+        false
+    }
+
+    fn insert(&mut self, ino: u64, lock: Lock) {
+        self.remove(ino, lock.owner, lock.start, lock.end);
+        let locks = self.locks.entry(ino).or_default();
+        locks.push(lock);
+        // merge the ones of the owner which are next to each other and of the same kind
+        locks.sort_unstable_by_key(|lock| (lock.owner, lock.start));
+        let mut merged: Vec<Lock> = Vec::with_capacity(locks.len());
+        for lock in locks.drain(..) {
+            if let Some(last) = merged.last_mut() {
+                if last.owner == lock.owner
+                    && last.kind == lock.kind
+                    && lock.start <= last.end.saturating_add(1)
+                {
+                    last.end = max(last.end, lock.end);
+                    last.pid = lock.pid;
+                    continue;
+                }
+            }
+            merged.push(lock);
+        }
+        *locks = merged;
+    }
+
+    fn remove(&mut self, ino: u64, owner: u64, start: u64, end: u64) {
+        let Some(locks) = self.locks.get_mut(&ino) else {
+            return;
+        };
+        let mut split = vec![];
+        locks.retain(|lock| {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                return true;
+            }
+            if lock.start < start {
+                split.push(Lock {
+                    end: start - 1,
+                    ..*lock
+                });
+            }
+            if lock.end > end {
+                split.push(Lock {
+                    start: end + 1,
+                    ..*lock
+                });
+            }
+            false
+        });
+        locks.extend(split);
+        if locks.is_empty() {
+            self.locks.remove(&ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    const fn lock(owner: u64, kind: LockKind, start: u64, end: u64) -> Lock {
+        Lock {
+            owner,
+            pid: 1,
+            kind,
+            start,
+            end,
+        }
+    }
+
+    #[tokio::test]
+    async fn conflicts() {
+        let locks = LockManager::default();
+        locks
+            .set(1, lock(1, LockKind::Shared, 0, 99), false, 1)
+            .await
+            .unwrap();
+        // shared locks of others are fine, exclusive ones are not
+        locks
+            .set(1, lock(2, LockKind::Shared, 50, 149), false, 2)
+            .await
+            .unwrap();
+        let exclusive = lock(3, LockKind::Exclusive, 90, 200);
+        assert_eq!(locks.get(1, &exclusive).map(|l| l.owner), Some(1));
+        assert!(matches!(
+            locks.set(1, exclusive, false, 3).await,
+            Err(FsError::WouldBlock)
+        ));
+        // other inodes and ranges are not in the way
+        locks.set(2, exclusive, false, 4).await.unwrap();
+        locks
+            .set(1, lock(3, LockKind::Exclusive, 150, 200), false, 5)
+            .await
+            .unwrap();
+        // the owner can change its own lock
+        locks
+            .set(1, lock(1, LockKind::Exclusive, 0, 49), false, 6)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unlock_splits() {
+        let locks = LockManager::default();
+        locks
+            .set(1, lock(1, LockKind::Exclusive, 0, 99), false, 1)
+            .await
+            .unwrap();
+        locks.unlock(1, 1, 40, 59);
+        let other = |start, end| lock(2, LockKind::Exclusive, start, end);
+        assert!(locks.get(1, &other(40, 59)).is_none());
+        assert_eq!(
+            locks.get(1, &other(0, 40)).map(|l| (l.start, l.end)),
+            Some((0, 39))
+        );
+        assert_eq!(
+            locks.get(1, &other(59, 70)).map(|l| (l.start, l.end)),
+            Some((60, 99))
+        );
+        locks.release(1, 1);
+        assert!(locks.get(1, &other(0, u64::MAX)).is_none());
+    }
+
+    #[tokio::test]
+    async fn waits_for_unlock() {
+        let locks = Arc::new(LockManager::default());
+        locks
+            .set(1, lock(1, LockKind::Exclusive, 0, 9), false, 1)
+            .await
+            .unwrap();
+        let waiting = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                locks
+                    .set(1, lock(2, LockKind::Exclusive, 5, 5), true, 2)
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        locks.unlock(1, 1, 0, 9);
+        waiting.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn deadlock_and_interrupt() {
+        let locks = Arc::new(LockManager::default());
+        locks
+            .set(1, lock(1, LockKind::Exclusive, 0, 0), false, 1)
+            .await
+            .unwrap();
+        locks
+            .set(1, lock(2, LockKind::Exclusive, 1, 1), false, 2)
+            .await
+            .unwrap();
+        let waiting = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                locks
+                    .set(1, lock(1, LockKind::Exclusive, 1, 1), true, 3)
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // owner 1 waits for 2, so 2 waiting for 1 is a deadlock
+        assert!(matches!(
+            locks
+                .set(1, lock(2, LockKind::Exclusive, 0, 0), true, 4)
+                .await,
+            Err(FsError::Deadlock)
+        ));
+        assert!(locks.interrupt(3));
+        assert!(matches!(waiting.await.unwrap(), Err(FsError::Interrupted)));
+        assert!(!locks.interrupt(3));
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+struct Lookup {
+    count: u64,
+    generation: u64,
+}
+
+/// Counts the references the kernel has to each inode, one for each entry we reply with, until it
+/// forgets them. The filesystem is told about the first and the last one, so it keeps the node
+/// while the kernel can still use it.
+#[derive(Default)]
+pub(crate) struct Lookups {
+    lookups: Mutex<HashMap<u64, Lookup>>,
+}
+
+impl Lookups {
+    fn lookups(&self) -> MutexGuard<'_, HashMap<u64, Lookup>> {
+        self.lookups.lock().expect("lookups lock poisoned")
+    }
+
+    /// Count a reference to `ino`, `referenced` is called on the first one and gives the
+    /// generation. It returns the generation to reply with.
+    pub fn add(&self, ino: u64, referenced: impl FnOnce() -> u64) -> u64 {
+        let mut lookups = self.lookups();
+        let lookup = lookups.entry(ino).or_insert_with(|| Lookup {
+            count: 0,
+            generation: referenced(),
+        });
+        lookup.count += 1;
+        lookup.generation
+    }
+
+    /// Drop `count` references to `ino`, `true` if it has none left.
+    pub fn forget(&self, ino: u64, count: u64) -> bool {
+        let mut lookups = self.lookups();
+        match lookups.get_mut(&ino) {
+            Some(lookup) if lookup.count > count => {
+                lookup.count -= count;
+                false
+            }
+            Some(_) => {
+                lookups.remove(&ino);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let lookups = Lookups::default();
+        let referenced = Cell::new(0);
+        let add = |ino| {
+            lookups.add(ino, || {
+                referenced.set(referenced.get() + 1);
+                7
+            })
+        };
+        // told only about the first one
+        assert_eq!((add(2), add(2), add(2)), (7, 7, 7));
+        assert_eq!(referenced.get(), 1);
+
+        assert!(!lookups.forget(2, 2));
+        assert!(lookups.forget(2, 1));
+        assert!(!lookups.forget(2, 1));
+        // forgetting more than there are drops them all
+        add(2);
+        assert_eq!(referenced.get(), 2);
+        assert!(lookups.forget(2, 5));
+        assert!(!lookups.forget(3, 1));
+    }
+}
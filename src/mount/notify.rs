@@ -0,0 +1,82 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::fd::BorrowedFd;
+use std::sync::{Arc, OnceLock};
+
+use tracing::{debug, warn};
+
+/// `fuse_notify_code` of the messages we send.
+const FUSE_NOTIFY_INVAL_INODE: i32 = 2;
+const FUSE_NOTIFY_INVAL_ENTRY: i32 = 3;
+
+/// Size of `fuse_out_header`, notifications start with one like the replies.
+const OUT_HEADER_SIZE: usize = 16;
+
+/// Tells the kernel to drop what it cached about inodes and entries, for backends which can be
+/// changed without going through the mount. The clones share the connection, until it's connected
+/// after mounting the calls do nothing, there is nothing cached yet.
+#[derive(Clone, Default)]
+pub(crate) struct Invalidator {
+    device: Arc<OnceLock<File>>,
+}
+
+impl Invalidator {
+    /// Send the notifications on `device`, the connection of the mount. We write them ourselves
+    /// and not with the replies of the session, the kernel can wait with a notification for a
+    /// request to finish, which would then wait for its reply.
+    pub(crate) fn connect(&self, device: BorrowedFd<'_>) {
+        // a duplicate, so it stays valid as long as we need it
+        match device.try_clone_to_owned() {
+            Ok(fd) => {
+                debug!("connected to invalidate the kernel cache");
+                let _ = self.device.set(File::from(fd));
+            }
+            Err(err) => warn!(%err, "cannot duplicate the FUSE connection"),
+        }
+    }
+
+    /// Drop the cached attributes and content of `ino`, for a directory also its entries listed
+    /// with `readdir`.
+    pub fn inode(&self, ino: u64) -> io::Result<()> {
+        let mut out = Vec::with_capacity(24);
+        out.extend_from_slice(&ino.to_ne_bytes());
+        // offset 0 and length 0 for all the content
+        out.extend_from_slice(&0i64.to_ne_bytes());
+        out.extend_from_slice(&0i64.to_ne_bytes());
+        self.send(FUSE_NOTIFY_INVAL_INODE, &out)
+    }
+
+    /// Drop the cached entry `name` in `parent`, the kernel looks it up again on the next use. It
+    /// works for names cached as not found too.
+    pub fn entry(&self, parent: u64, name: &str) -> io::Result<()> {
+        let mut out = Vec::with_capacity(16 + name.len() + 1);
+        out.extend_from_slice(&parent.to_ne_bytes());
+        #[allow(clippy::cast_possible_truncation)]
+        out.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+        // flags
+        out.extend_from_slice(&0u32.to_ne_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        self.send(FUSE_NOTIFY_INVAL_ENTRY, &out)
+    }
+
+    fn send(&self, code: i32, out: &[u8]) -> io::Result<()> {
+        let Some(mut device) = self.device.get() else {
+            return Ok(());
+        };
+        let mut message = Vec::with_capacity(OUT_HEADER_SIZE + out.len());
+        #[allow(clippy::cast_possible_truncation)]
+        message.extend_from_slice(&((OUT_HEADER_SIZE + out.len()) as u32).to_ne_bytes());
+        // the code goes in place of the error, with no request
+        message.extend_from_slice(&code.to_ne_bytes());
+        message.extend_from_slice(&0u64.to_ne_bytes());
+        message.extend_from_slice(out);
+        match device.write(&message) {
+            // it's not cached
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(err) => Err(err),
+            Ok(_) => Ok(()),
+        }
+    }
+}
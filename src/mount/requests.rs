@@ -0,0 +1,40 @@
+use std::collections::HashSet;
+use std::sync::{Mutex, MutexGuard};
+
+/// The requests which can take long and are being handled, by their `unique`, so we can tell
+/// the kernel an interrupt for them arrived. We can't stop them, they finish as usual.
+#[derive(Default)]
+pub(crate) struct Requests {
+    running: Mutex<HashSet<u64>>,
+}
+
+/// Removes the request from the running ones when it's done.
+pub(crate) struct Running<'a> {
+    requests: &'a Requests,
+    unique: u64,
+}
+
+impl Requests {
+    fn running(&self) -> MutexGuard<'_, HashSet<u64>> {
+        self.running.lock().expect("requests lock poisoned")
+    }
+
+    /// The request with `unique` is being handled until the returned guard is dropped.
+    pub fn start(&self, unique: u64) -> Running<'_> {
+        self.running().insert(unique);
+        Running {
+            requests: self,
+            unique,
+        }
+    }
+
+    pub fn is_running(&self, unique: u64) -> bool {
+        self.running().contains(&unique)
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.requests.running().remove(&self.unique);
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::fs_model::{FsError, FsResult};
+
+/// Used when the config doesn't set one, the same as the default of Linux quotas.
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Extended attribute of the root of the mount with the [`QuotaStatus`] of the users and groups,
+/// read by the `quota` command. It's not listed and it cannot be set.
+pub const QUOTA_XATTR: &str = "user.fuse3_template.quota";
+
+/// Since when each owner is over a soft limit.
+pub(crate) type Graces = HashMap<(Owner, Resource), SystemTime>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Owner {
+    User(u32),
+    Group(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Resource {
+    Bytes,
+    Inodes,
+}
+
+/// Bytes of the files and number of nodes owned by a user or a group.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+/// Limits of a user or a group. Going over a hard limit fails with `EDQUOT`, a soft limit can be
+/// exceeded for the grace period, after that it's enforced like a hard one until the usage goes
+/// under it again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaLimits {
+    pub soft_bytes: Option<u64>,
+    pub hard_bytes: Option<u64>,
+    pub soft_inodes: Option<u64>,
+    pub hard_inodes: Option<u64>,
+}
+
+/// Quotas of the users and groups, read from a TOML file like this:
+///
+/// ```toml
+/// # how long the soft limits can be exceeded, in seconds, 7 days if not set
+/// grace_period = 86400
+///
+/// [[user]]
+/// id = 1000
+/// soft_bytes = 1073741824
+/// hard_bytes = 2147483648
+/// hard_inodes = 100000
+///
+/// [[group]]
+/// id = 100
+/// hard_bytes = 10737418240
+/// ```
+///
+/// Users and groups which are not in the file have no limits.
+#[derive(Debug, Clone, Default)]
+pub struct Quotas {
+    pub grace_period: Duration,
+    pub users: HashMap<u32, QuotaLimits>,
+    pub groups: HashMap<u32, QuotaLimits>,
+    /// Where the grace periods are saved, next to the config with `.grace` added to its name, so
+    /// they go on after a remount.
+    pub grace_file: Option<PathBuf>,
+}
+
+/// Usage, limits and grace periods of a user or a group, as the mount enforces them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaStatus {
+    pub owner: Owner,
+    pub usage: Usage,
+    pub limits: QuotaLimits,
+    /// When the grace period of the bytes ends, if it's over the soft limit.
+    pub bytes_grace: Option<SystemTime>,
+    /// When the grace period of the inodes ends, if it's over the soft limit.
+    pub inodes_grace: Option<SystemTime>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuotaFile {
+    grace_period: Option<u64>,
+    #[serde(default)]
+    user: Vec<QuotaEntry>,
+    #[serde(default)]
+    group: Vec<QuotaEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuotaEntry {
+    id: u32,
+    #[serde(flatten)]
+    limits: QuotaLimits,
+}
+
+impl Quotas {
+    /// Read the quotas from a TOML file, see [`Quotas`] for the format.
+    pub fn load(path: &Path) -> FsResult<Self> {
+        let file: QuotaFile = toml::from_str(&fs::read_to_string(path)?).map_err(|err| {
+            error!(%err, path = %path.display(), "cannot parse quota config");
+            FsError::InvalidInput("invalid quota config")
+        })?;
+        let mut quotas = Self {
+            grace_period: file
+                .grace_period
+                .map_or(DEFAULT_GRACE_PERIOD, Duration::from_secs),
+            grace_file: Some(grace_file(path)),
+            ..Self::default()
+        };
+        for (entries, limits) in [
+            (file.user, &mut quotas.users),
+            (file.group, &mut quotas.groups),
+        ] {
+            for entry in entries {
+                let QuotaLimits {
+                    soft_bytes,
+                    hard_bytes,
+                    soft_inodes,
+                    hard_inodes,
+                } = entry.limits;
+                if is_above(soft_bytes, hard_bytes) || is_above(soft_inodes, hard_inodes) {
+                    return Err(FsError::InvalidInput("soft limit is above the hard one"));
+                }
+                if limits.insert(entry.id, entry.limits).is_some() {
+                    return Err(FsError::InvalidInput("quota set twice for the same id"));
+                }
+            }
+        }
+        Ok(quotas)
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+}
+
+fn is_above(soft: Option<u64>, hard: Option<u64>) -> bool {
+    matches!((soft, hard), (Some(soft), Some(hard)) if soft > hard)
+}
+
+fn grace_file(config: &Path) -> PathBuf {
+    let mut path = OsString::from(config);
+    path.push(".grace");
+    PathBuf::from(path)
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedGrace {
+    owner: Owner,
+    resource: Resource,
+    /// Seconds since the epoch.
+    since: u64,
+}
+
+/// The grace periods saved with [`save_graces`], none if the file is missing or it cannot be read.
+pub(crate) fn load_graces(path: &Path) -> Graces {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Graces::new(),
+        Err(err) => {
+            warn!(%err, path = %path.display(), "cannot read grace periods, they start again");
+            return Graces::new();
+        }
+    };
+    match bincode::deserialize::<Vec<SavedGrace>>(&data) {
+        Ok(saved) => saved
+            .into_iter()
+            .map(|grace| {
+                let since = UNIX_EPOCH + Duration::from_secs(grace.since);
+                ((grace.owner, grace.resource), since)
+            })
+            .collect(),
+        Err(err) => {
+            warn!(%err, path = %path.display(), "invalid grace periods, they start again");
+            Graces::new()
+        }
+    }
+}
+
+/// Save the grace periods, the file is replaced only after the new one is on disk.
+pub(crate) fn save_graces(path: &Path, graces: &Graces) -> io::Result<()> {
+    let saved: Vec<_> = graces
+        .iter()
+        .map(|(&(owner, resource), since)| SavedGrace {
+            owner,
+            resource,
+            since: since
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })
+        .collect();
+    let data = bincode::serialize(&saved).map_err(io::Error::other)?;
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// The quotas of the users and groups of the mount at `path`, from [`QUOTA_XATTR`].
+pub fn status(path: &Path) -> io::Result<Vec<QuotaStatus>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(QUOTA_XATTR)?;
+    loop {
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(clippy::cast_sign_loss)]
+        let mut buf = vec![0_u8; len as usize];
+        let len = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            // it grew in between
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(err);
+        }
+        #[allow(clippy::cast_sign_loss)]
+        buf.truncate(len as usize);
+        return bincode::deserialize(&buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+}